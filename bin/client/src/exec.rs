@@ -0,0 +1,111 @@
+use agent_lib::{
+    AgentServiceClient, ExecRequest, ExecResponse, OutputChunk, OutputStream, PollExecRequest,
+    PollExecResponse, MAX_EXEC_WAIT_MILLIS,
+};
+use anyhow::bail;
+use tarpc::context;
+
+/// Run a command on the remote, printing it's stdout and stderr as they come, each line prefixed
+/// with the peer, then how it exited.
+pub async fn exec(
+    client: &AgentServiceClient,
+    peer: &str,
+    request: ExecRequest,
+) -> anyhow::Result<()> {
+    let program = request.program.clone();
+    let timeout_secs = request.timeout_secs;
+    let exec_id = match client.exec(context::current(), request).await? {
+        ExecResponse::Started { exec_id } => exec_id,
+        ExecResponse::Forbidden { message } => bail!("{message}"),
+        ExecResponse::Failed { message } => bail!("{message}"),
+        ExecResponse::Error => bail!("unable to run {}", program.display()),
+    };
+
+    let mut lines = Lines::default();
+    loop {
+        let request = PollExecRequest {
+            exec_id,
+            wait_millis: MAX_EXEC_WAIT_MILLIS,
+        };
+        match client.poll_exec(context::current(), request).await? {
+            PollExecResponse::Output {
+                chunks,
+                exit,
+                timed_out,
+            } => {
+                for chunk in &chunks {
+                    for (stream, line) in lines.push(chunk) {
+                        print_line(peer, stream, &line);
+                    }
+                }
+                let Some(exit) = exit else {
+                    continue;
+                };
+                for (stream, line) in lines.flush() {
+                    print_line(peer, stream, &line);
+                }
+                if timed_out {
+                    println!(
+                        "{peer}: {} was killed after {timeout_secs}s",
+                        program.display()
+                    );
+                } else {
+                    println!("{peer}: {} exited with {exit}", program.display());
+                }
+                return Ok(());
+            }
+            PollExecResponse::NotFound => bail!("the remote stopped running the command"),
+            PollExecResponse::Error => bail!("unable to read the output of the command"),
+        }
+    }
+}
+
+fn print_line(peer: &str, stream: OutputStream, line: &str) {
+    match stream {
+        OutputStream::Stdout => println!("{peer}: {line}"),
+        OutputStream::Stderr => eprintln!("{peer}: {line}"),
+    }
+}
+
+/// Splits each stream into lines, holding on to the start of a line until it's end arrives.
+#[derive(Default)]
+struct Lines {
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+}
+
+impl Lines {
+    fn buffer(&mut self, stream: OutputStream) -> &mut Vec<u8> {
+        match stream {
+            OutputStream::Stdout => &mut self.stdout,
+            OutputStream::Stderr => &mut self.stderr,
+        }
+    }
+
+    /// The lines `chunk` completes.
+    fn push(&mut self, chunk: &OutputChunk) -> Vec<(OutputStream, String)> {
+        let buffer = self.buffer(chunk.stream);
+        buffer.extend_from_slice(&chunk.data);
+        let Some(last_newline) = buffer.iter().rposition(|byte| *byte == b'\n') else {
+            return Vec::new();
+        };
+        let rest = buffer.split_off(last_newline + 1);
+        let complete = std::mem::replace(buffer, rest);
+        complete[..last_newline]
+            .split(|byte| *byte == b'\n')
+            .map(|line| (chunk.stream, String::from_utf8_lossy(line).into_owned()))
+            .collect()
+    }
+
+    /// The last lines, which didn't end with a newline.
+    fn flush(&mut self) -> Vec<(OutputStream, String)> {
+        [OutputStream::Stdout, OutputStream::Stderr]
+            .into_iter()
+            .filter_map(|stream| {
+                let buffer = std::mem::take(self.buffer(stream));
+                (!buffer.is_empty())
+                    .then(|| (stream, String::from_utf8_lossy(&buffer).into_owned()))
+            })
+            .collect()
+    }
+}
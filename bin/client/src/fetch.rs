@@ -0,0 +1,344 @@
+use std::{
+    collections::BTreeSet,
+    fs::{self, File, OpenOptions},
+    io::BufReader,
+    os::unix::fs::{FileExt, PermissionsExt},
+    path::{Component, Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use agent_lib::{
+    blake3_hash_file, codec::Compression, file_name_from_path, hash_to_hex, AgentServiceClient,
+    ChecksumRequest, ChecksumResponse, FetchFileChunkRequest, FetchFileChunkResponse, FileKind,
+    FileStat,
+};
+use anyhow::{anyhow, bail};
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
+use tarpc::context;
+
+/// The chunk size used for each file of a fetched directory.
+const DEFAULT_CHUNK_SIZE: u64 = 5 * 1024 * 1024;
+
+/// How long to wait for the remote to hash a whole file, which for a large one takes far longer
+/// than serving a chunk.
+const HASH_DEADLINE: Duration = Duration::from_secs(30 * 60);
+
+#[derive(Clone, Debug, StructOpt)]
+pub struct FetchFileChunked {
+    /// Path of the file on the remote.
+    host_src_path: PathBuf,
+    /// Directory to write the file into, a subdirectory is created per peer.
+    #[structopt(long, default_value = "./fetch")]
+    target_dir: PathBuf,
+    /// Size in bytes of the (uncompressed) chunks to request, 5 MiB by default.
+    #[structopt(long, default_value = "5242880")]
+    chunk_size: u64,
+    /// How the remote compresses chunks: `none`, `zstd[:<level>]`, or `auto[:<level>]` to skip
+    /// compressing chunks that don't shrink.
+    #[structopt(long, default_value = "zstd")]
+    compression: Compression,
+}
+
+/// Progress of a chunked fetch, persisted next to the partially written file so that an
+/// interrupted fetch can be resumed.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct FetchState {
+    file_size: u64,
+    modified: Option<SystemTime>,
+    chunk_size: u64,
+    num_chunks: Option<u64>,
+    received_chunks: BTreeSet<u64>,
+}
+
+impl FetchState {
+    fn load(path: &Path) -> Option<Self> {
+        let reader = BufReader::new(File::open(path).ok()?);
+        serde_yaml::from_reader(reader).ok()
+    }
+
+    fn save(&self, path: &Path) -> Result<(), anyhow::Error> {
+        fs::write(path, serde_yaml::to_string(self)?)?;
+        Ok(())
+    }
+
+    fn missing_chunks(&self) -> Vec<u64> {
+        match self.num_chunks {
+            Some(num_chunks) => (0..num_chunks)
+                .filter(|chunk_id| !self.received_chunks.contains(chunk_id))
+                .collect(),
+            // we don't know the size of the file yet, start with the first chunk.
+            None => vec![0],
+        }
+    }
+}
+
+/// Fetch a remote file chunk by chunk, writing each chunk to disk as it arrives. Memory use is
+/// bounded by the chunk size.
+pub async fn fetch_file_chunked(
+    client: &AgentServiceClient,
+    peer: &str,
+    fetch: FetchFileChunked,
+) -> Result<PathBuf, anyhow::Error> {
+    let FetchFileChunked {
+        host_src_path,
+        target_dir,
+        chunk_size,
+        compression,
+    } = fetch;
+    let target_dir = target_dir.join(peer);
+    fs::create_dir_all(&target_dir)?;
+    let target_path = target_dir.join(file_name_from_path(&host_src_path)?);
+    fetch_file_to(
+        client,
+        peer,
+        &host_src_path,
+        &target_path,
+        chunk_size,
+        compression,
+    )
+    .await?;
+    Ok(target_path)
+}
+
+/// Fetch a remote file chunk by chunk to `target_path`, whose directory must exist. Progress is
+/// kept alongside it, so an interrupted fetch resumes.
+async fn fetch_file_to(
+    client: &AgentServiceClient,
+    peer: &str,
+    host_src_path: &Path,
+    target_path: &Path,
+    chunk_size: u64,
+    compression: Compression,
+) -> Result<(), anyhow::Error> {
+    let filename = file_name_from_path(target_path)?;
+    let part_path = target_path.with_file_name(format!("{filename}.part"));
+    let state_path = target_path.with_file_name(format!("{filename}.part.yaml"));
+
+    let mut state = match FetchState::load(&state_path) {
+        Some(state) if state.chunk_size == chunk_size && part_path.exists() => {
+            println!(
+                "{peer}: resuming fetch of {}, {} chunks already received",
+                host_src_path.display(),
+                state.received_chunks.len()
+            );
+            state
+        }
+        _ => FetchState {
+            chunk_size,
+            ..Default::default()
+        },
+    };
+    let part_file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .read(true)
+        .truncate(false)
+        .open(&part_path)?;
+
+    loop {
+        let missing_chunks = state.missing_chunks();
+        let Some(&chunk_id) = missing_chunks.first() else {
+            break;
+        };
+        let response = client
+            .fetch_file_chunk(
+                context::current(),
+                FetchFileChunkRequest {
+                    host_src_path: host_src_path.to_path_buf(),
+                    chunk_id,
+                    chunk_size,
+                    compression,
+                },
+            )
+            .await?;
+        let (file_size, modified, chunk) = match response {
+            FetchFileChunkResponse::Success {
+                file_size,
+                modified,
+                chunk,
+            } => (file_size, modified, chunk),
+            FetchFileChunkResponse::Forbidden { path } => {
+                bail!("reading {} is forbidden", path.display())
+            }
+            FetchFileChunkResponse::Error => bail!("fetch of chunk {chunk_id} failed"),
+        };
+
+        if state.num_chunks.is_some()
+            && (state.file_size != file_size || state.modified != Some(modified))
+        {
+            println!(
+                "{peer}: {} changed on the remote, restarting fetch",
+                host_src_path.display()
+            );
+            state = FetchState {
+                chunk_size,
+                ..Default::default()
+            };
+            part_file.set_len(0)?;
+            continue;
+        }
+        chunk.verify()?;
+
+        let data = chunk.codec.decode_bulk(
+            &chunk.zstd_compressed_data_chunk,
+            chunk_size as usize,
+            None,
+        )?;
+        part_file.write_all_at(&data, chunk_id * chunk_size)?;
+        state.file_size = file_size;
+        state.modified = Some(modified);
+        state.num_chunks = Some(chunk.num_chunks);
+        state.received_chunks.insert(chunk_id);
+        state.save(&state_path)?;
+    }
+
+    let (file_size, file_hash) = request_file_hash(client, host_src_path).await?;
+    if file_size != state.file_size {
+        fs::remove_file(&state_path)?;
+        bail!(
+            "{} changed on the remote during the fetch, run again to fetch it again",
+            host_src_path.display()
+        );
+    }
+    part_file.set_len(state.file_size)?;
+    part_file.sync_all()?;
+    let local_hash = blake3_hash_file(&part_path)?;
+    if local_hash != file_hash {
+        fs::remove_file(&state_path)?;
+        bail!(
+            "hash mismatch for fetched file, expected {} got {}",
+            hash_to_hex(&file_hash),
+            hash_to_hex(&local_hash)
+        );
+    }
+    fs::rename(&part_path, target_path)?;
+    fs::remove_file(&state_path)?;
+    Ok(())
+}
+
+/// Fetch the entries of a remote directory, as listed by the agent, into `target_dir`. Files are
+/// fetched one at a time in chunks, so memory use is bounded by the chunk size however large the
+/// tree is. Modes and symlinks are kept. Returns the number of entries fetched.
+pub async fn fetch_tree(
+    client: &AgentServiceClient,
+    peer: &str,
+    dir: &Path,
+    entries: &[FileStat],
+    target_dir: &Path,
+) -> Result<u64, anyhow::Error> {
+    fs::create_dir_all(target_dir)?;
+    let root = fs::canonicalize(target_dir)?;
+    let mut dirs = Vec::new();
+    let mut symlinks = Vec::new();
+    let mut fetched = 0;
+    for entry in entries {
+        let target_path = target_dir.join(relative_path(dir, &entry.path)?);
+        match &entry.kind {
+            FileKind::Dir => {
+                fs::create_dir_all(&target_path)?;
+                dirs.push((target_path, entry.mode));
+            }
+            FileKind::File => {
+                let parent = target_path.parent().unwrap_or(target_dir);
+                fs::create_dir_all(parent)?;
+                // never follow a symlink left by an earlier fetch out of the target dir.
+                if !fs::canonicalize(parent)?.starts_with(&root) {
+                    bail!(
+                        "{} is outside of {}",
+                        parent.display(),
+                        target_dir.display()
+                    );
+                }
+                fetch_file_to(
+                    client,
+                    peer,
+                    &entry.path,
+                    &target_path,
+                    DEFAULT_CHUNK_SIZE,
+                    Compression::default(),
+                )
+                .await?;
+                fs::set_permissions(&target_path, fs::Permissions::from_mode(entry.mode))?;
+            }
+            // made once every file is in place, so no file is written through one.
+            FileKind::Symlink { target } => symlinks.push((target_path, target.clone())),
+            FileKind::Other => {
+                println!("{peer}: skipping {}, not a file", entry.path.display());
+                continue;
+            }
+        }
+        fetched += 1;
+    }
+    for (path, target) in symlinks {
+        if fs::symlink_metadata(&path).is_ok() {
+            fs::remove_file(&path)?;
+        }
+        std::os::unix::fs::symlink(target, &path)?;
+    }
+    // deepest first, so a read-only directory is only made so once it's filled.
+    for (path, mode) in dirs.into_iter().rev() {
+        fs::set_permissions(&path, fs::Permissions::from_mode(mode))?;
+    }
+    Ok(fetched)
+}
+
+/// `path` relative to `dir`, refused if it could land outside of wherever it's fetched to.
+fn relative_path<'a>(dir: &Path, path: &'a Path) -> Result<&'a Path, anyhow::Error> {
+    let rel_path = path
+        .strip_prefix(dir)
+        .map_err(|_| anyhow!("{} is not in {}", path.display(), dir.display()))?;
+    if rel_path.as_os_str().is_empty()
+        || !rel_path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+    {
+        bail!("{} is not a plain relative path", rel_path.display());
+    }
+    Ok(rel_path)
+}
+
+/// Ask the remote for the size and hash of the whole file, in a call of it's own as it reads all
+/// of it.
+async fn request_file_hash(
+    client: &AgentServiceClient,
+    host_src_path: &Path,
+) -> Result<(u64, [u8; 32]), anyhow::Error> {
+    let mut ctx = context::current();
+    ctx.deadline = SystemTime::now() + HASH_DEADLINE;
+    let request = ChecksumRequest {
+        path: host_src_path.to_path_buf(),
+    };
+    match client.checksum(ctx, request).await? {
+        ChecksumResponse::Success { size, content_hash } => Ok((size, content_hash)),
+        ChecksumResponse::NotFound => {
+            bail!("{} was removed on the remote", host_src_path.display())
+        }
+        ChecksumResponse::Forbidden { path } => bail!("reading {} is forbidden", path.display()),
+        ChecksumResponse::Error => Err(anyhow!(
+            "unable to fetch hash of {}",
+            host_src_path.display()
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_relative_path_stays_inside() {
+        let dir = Path::new("/var/lib/casper/debug/1700000000000-perf");
+        assert_eq!(
+            relative_path(dir, &dir.join("perf.data")).unwrap(),
+            Path::new("perf.data")
+        );
+        assert_eq!(
+            relative_path(dir, &dir.join("a/b")).unwrap(),
+            Path::new("a/b")
+        );
+        assert!(relative_path(dir, Path::new("/etc/passwd")).is_err());
+        assert!(relative_path(dir, &dir.join("../../../../etc/passwd")).is_err());
+        assert!(relative_path(dir, dir).is_err());
+    }
+}
@@ -0,0 +1,128 @@
+use agent_lib::{
+    agent_version, AgentInfo, AgentServiceClient, HelloRequest, HostInfo, HostInfoResponse,
+    PROTOCOL_VERSION,
+};
+use anyhow::{anyhow, bail};
+use tarpc::context;
+
+use crate::inspect::{format_bytes, format_mount_usage};
+
+/// Greet an agent, as is done first on every connection, and check that this client can talk to
+/// it.
+pub async fn hello(client: &AgentServiceClient) -> anyhow::Result<AgentInfo> {
+    let request = HelloRequest {
+        client_version: agent_version(),
+        protocol_version: PROTOCOL_VERSION,
+    };
+    let info = client
+        .hello(context::current(), request)
+        .await
+        .map_err(|err| {
+            anyhow!(
+                "the agent didn't answer hello, it may be too old for this client, which speaks \
+                 protocol version {PROTOCOL_VERSION}: {err}"
+            )
+        })?;
+    check_compatible(&info)?;
+    Ok(info)
+}
+
+fn check_compatible(info: &AgentInfo) -> anyhow::Result<()> {
+    if info.protocol_version != PROTOCOL_VERSION {
+        bail!(
+            "the agent is version {} and speaks protocol version {}, but this client is version {} \
+             and speaks protocol version {PROTOCOL_VERSION}, use a client of the agent's version",
+            info.agent_version,
+            info.protocol_version,
+            agent_version()
+        );
+    }
+    Ok(())
+}
+
+/// Describe the host an agent runs on.
+pub async fn host_info(client: &AgentServiceClient) -> anyhow::Result<HostInfo> {
+    match client.host_info(context::current()).await? {
+        HostInfoResponse::Success { host } => Ok(host),
+        HostInfoResponse::Error => bail!("the agent was unable to describe it's host"),
+    }
+}
+
+/// Describe an agent, a line at a time.
+pub fn format_info(info: &AgentInfo) -> Vec<String> {
+    let AgentInfo {
+        agent_version,
+        protocol_version,
+        features,
+    } = info;
+    let list = |items: &[String]| {
+        if items.is_empty() {
+            "none".to_string()
+        } else {
+            items.join(", ")
+        }
+    };
+    vec![
+        format!("agent version {agent_version}, protocol version {protocol_version}"),
+        format!(
+            "services managed with {} (available: {})",
+            features.service_backend,
+            list(&features.service_backends)
+        ),
+        format!("wrappers: {}", list(&features.wrappers)),
+        format!("codecs: {}", list(&features.codecs)),
+    ]
+}
+
+/// Describe a host, a line at a time.
+pub fn format_host(host: &HostInfo) -> Vec<String> {
+    let mut lines = vec![
+        format!(
+            "{}, {}, kernel {}, {}",
+            host.hostname, host.os, host.kernel, host.arch
+        ),
+        format!(
+            "{} cpus, {} of {} memory available",
+            host.cpus,
+            format_bytes(host.memory_available_bytes),
+            format_bytes(host.memory_total_bytes)
+        ),
+    ];
+    lines.extend(host.disks.iter().map(format_mount_usage));
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use agent_lib::AgentFeatures;
+
+    use super::*;
+
+    #[test]
+    fn test_incompatible_protocol_is_refused() {
+        let info = |protocol_version| AgentInfo {
+            agent_version: 7,
+            protocol_version,
+            features: AgentFeatures {
+                service_backend: "supervisor".to_string(),
+                service_backends: vec!["supervisor".to_string()],
+                wrappers: Vec::new(),
+                codecs: vec!["zstd".to_string()],
+            },
+        };
+        assert!(check_compatible(&info(PROTOCOL_VERSION)).is_ok());
+        let err = check_compatible(&info(PROTOCOL_VERSION + 1)).unwrap_err();
+        assert!(err.to_string().contains(&format!(
+            "the agent is version 7 and speaks protocol version {}",
+            PROTOCOL_VERSION + 1
+        )));
+        assert!(format_info(&info(PROTOCOL_VERSION)).contains(&"wrappers: none".to_string()));
+        let host = HostInfo {
+            hostname: "node-1".to_string(),
+            cpus: 4,
+            ..HostInfo::default()
+        };
+        assert!(format_host(&host)[0].starts_with("node-1, "));
+        assert!(format_host(&host)[1].starts_with("4 cpus, "));
+    }
+}
@@ -0,0 +1,132 @@
+use std::{
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use agent_lib::{FileKind, FileStat, MountUsage};
+use structopt::StructOpt;
+
+#[derive(Clone, Debug, StructOpt)]
+pub struct Checksum {
+    /// Path of the file on the remote.
+    pub path: PathBuf,
+    /// A local file to compare the remote file against, e.g. `target/release/casper-node`.
+    #[structopt(long)]
+    pub compare: Option<PathBuf>,
+}
+
+/// Format a stat like a line of `ls -l`.
+pub fn format_stat(stat: &FileStat) -> String {
+    let kind = match stat.kind {
+        FileKind::File => '-',
+        FileKind::Dir => 'd',
+        FileKind::Symlink { .. } => 'l',
+        FileKind::Other => '?',
+    };
+    let mut line = format!(
+        "{kind}{} {:>5} {:>5} {:>10} {} {}",
+        format_mode(stat.mode),
+        stat.uid,
+        stat.gid,
+        stat.size,
+        format_time(stat.modified),
+        stat.path.display()
+    );
+    if let FileKind::Symlink { target } = &stat.kind {
+        line.push_str(&format!(" -> {}", target.display()));
+    }
+    line
+}
+
+/// Format a mount's usage like a line of `df -h`.
+pub fn format_mount_usage(mount: &MountUsage) -> String {
+    let used = mount.total_bytes - mount.free_bytes;
+    // as df does, the percentage is of what non-root users can use.
+    let usable = used + mount.available_bytes;
+    let percent_used = if usable == 0 {
+        0
+    } else {
+        (used * 100).div_ceil(usable)
+    };
+    format!(
+        "{:<24} {:>8} {:>8} {:>8} {:>3}% {} ({}, {} free inodes)",
+        mount.device,
+        format_bytes(mount.total_bytes),
+        format_bytes(used),
+        format_bytes(mount.available_bytes),
+        percent_used,
+        mount.mount_point.display(),
+        mount.fs_type,
+        mount.free_inodes
+    )
+}
+
+fn format_mode(mode: u32) -> String {
+    let mut formatted = String::with_capacity(9);
+    for shift in [6, 3, 0] {
+        let bits = (mode >> shift) & 0o7;
+        formatted.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+        formatted.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+        formatted.push(if bits & 0o1 != 0 { 'x' } else { '-' });
+    }
+    formatted
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "K", "M", "G", "T"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes}B")
+    } else {
+        format!("{value:.1}{}", UNITS[unit])
+    }
+}
+
+/// Format a time as UTC `YYYY-MM-DD HH:MM:SS`.
+fn format_time(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+    // days since the epoch to a civil date, from Howard Hinnant's `civil_from_days`.
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02}",
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_formatting() {
+        assert_eq!(format_mode(0o754), "rwxr-xr--");
+        assert_eq!(format_bytes(512), "512B");
+        assert_eq!(format_bytes(3 * 1024 * 1024 / 2), "1.5M");
+        assert_eq!(format_time(UNIX_EPOCH), "1970-01-01 00:00:00");
+        assert_eq!(
+            format_time(UNIX_EPOCH + Duration::from_secs(1_709_210_096)),
+            "2024-02-29 12:34:56"
+        );
+    }
+}
@@ -1,19 +1,59 @@
+mod exec;
+mod fetch;
+mod info;
+mod inspect;
+mod signal;
+mod status;
+mod tail;
+mod update;
+mod upload;
+
 use std::{
     fs::{self, File},
     io::BufReader,
     net::SocketAddr,
-    path::PathBuf,
+    path::{Path, PathBuf},
     str::FromStr,
+    sync::Arc,
+    time::{Duration, SystemTime},
 };
 
 use agent_lib::{
-    file_name_from_path, tls, AgentServiceClient, FetchFileRequest, FetchFileResponse,
-    PutFileRequest, StartServiceRequest, StopServiceRequest,
+    archive::{pack_dir, ArchiveFilter},
+    blake3_hash_file,
+    codec::{Compression, Dictionary, Encoding},
+    file_name_from_path, hash_from_hex, hash_to_hex, parse_mode, tls, verify_file_hash,
+    AgentServiceClient, CancelTransferRequest, ChecksumRequest, ChecksumResponse,
+    CompressedChunkReader, CompressedWireFile, DiskUsageResponse, EnableCoreDumpsRequest,
+    EnableCoreDumpsResponse, EnableServiceRequest, EnableServiceResponse, ExecRequest,
+    FetchCrashRequest, FetchCrashResponse, FetchDebugOutputRequest, FetchDebugOutputResponse,
+    FetchDirRequest, FetchDirResponse, FetchFileRequest, FetchFileResponse,
+    FindNodeProcessesRequest, FindNodeProcessesResponse, GcBlobsRequest, HasBlobsRequest,
+    HasBlobsResponse, HasDictionaryRequest, HasDictionaryResponse, ListCrashesRequest,
+    ListCrashesResponse, ListDirRequest, ListDirResponse, ListTransfersResponse, MessageError,
+    MkdirRequest, MkdirResponse, PutDictionaryRequest, PutDictionaryResponse, PutDirRequest,
+    PutFileFromBlobRequest, PutFileRequest, PutFileResponse, RemoveRequest, RemoveResponse,
+    RenameRequest, RenameResponse, RestartServiceRequest, RestartServiceResponse,
+    ServiceStatusRequest, StartServiceRequest, StartServiceResponse, StatRequest, StatResponse,
+    StopServiceRequest, StopServiceResponse, MAX_LIST_DIR_ENTRIES,
 };
 use serde::Deserialize;
 use structopt::StructOpt;
 use tarpc::{client, context, tokio_serde::formats::Bincode};
 
+use exec::exec;
+use fetch::{fetch_file_chunked, fetch_tree, FetchFileChunked};
+use info::{format_host, format_info, hello, host_info};
+use inspect::{format_mount_usage, format_stat, Checksum};
+use signal::{send_signal, Pause, SendSignal};
+use status::{format_crash, format_node_process};
+use tail::{tail, Tail};
+use update::{update_agent, UpdateAgent};
+use upload::{put_file_chunked, PutFileChunked, Throttle};
+
+/// How long to wait for a service to start, stop or restart.
+const SERVICE_DEADLINE: Duration = Duration::from_secs(120);
+
 #[derive(Debug, structopt::StructOpt)]
 struct Args {
     #[structopt(short)]
@@ -28,13 +68,79 @@ struct Args {
 
 #[derive(Clone, Debug, structopt::StructOpt)]
 enum Rpc {
+    /// Show the agent's version and what it supports, and the host's OS, CPUs, memory and disks.
+    Info,
+    /// Start a service, e.g. `casper-node-launcher`, unless it's already running.
     StartService(StartServiceRequest),
+    /// Stop a running service.
     StopService(StopServiceRequest),
+    /// Restart a service, starting it if it isn't running.
+    RestartService(RestartServiceRequest),
+    /// Enable a service to be started at boot.
+    EnableService(EnableServiceRequest),
+    /// Show a table of a service's state, pid, uptime, restarts and resource use on every remote.
+    ServiceStatus(ServiceStatusRequest),
+    /// Fetch what a service's wrapper recorded, e.g. `perf.data`, into
+    /// `./fetch/<peer>/debug/<service>/<run>/`.
+    FetchDebugOutput(FetchDebugOutputRequest),
+    /// Collect core dumps of a service, and of the processes it runs, from it's next start.
+    EnableCoreDumps(EnableCoreDumpsRequest),
+    /// List the crash bundles collected for a service.
+    ListCrashes(ListCrashesRequest),
+    /// Fetch a crash bundle into `./fetch/<peer>/crashes/<service>/<crash>/`.
+    FetchCrash(FetchCrashRequest),
+    /// Find the node's processes, their version and the ports they listen on.
+    FindNodeProcesses(FindNodeProcessesRequest),
+    /// Send a signal to a service's main process, or any process, e.g. `SIGKILL` to crash it.
+    Signal(SendSignal),
+    /// Stop a process with `SIGSTOP`, and have the remote send `SIGCONT` after a while.
+    Pause(Pause),
     FetchFile(FetchFileRequest),
+    /// Fetch a file in chunks, streaming it to disk. Interrupted fetches are resumed.
+    FetchFileChunked(FetchFileChunked),
     PutFile(PutFile),
 
     /// `cargo run --bin client -- -d bin/client/network.yaml put-file-chunked target/debug/daemon a/path/to/daemon
-    PutFileChunked(PutFile),
+    PutFileChunked(PutFileChunked),
+    /// Put a directory tree on the remote, as a zstd compressed tar archive.
+    PutDir(PutDir),
+    /// Fetch a directory tree from the remote into `./fetch/<peer>/`.
+    FetchDir(FetchDirRequest),
+    /// Garbage collect the remote's store of previously uploaded files.
+    GcBlobs(GcBlobsRequest),
+    /// List chunked transfers in flight on the remote.
+    ListTransfers,
+    /// Cancel an in-flight chunked transfer, by the hex encoded content hash and target shown by
+    /// `list-transfers`.
+    CancelTransfer {
+        #[structopt(parse(try_from_str = parse_hash))]
+        content_hash: [u8; 32],
+        target_path: PathBuf,
+    },
+    /// Show the size, mode, mtime and owner of a remote file.
+    Stat(StatRequest),
+    /// List a remote directory.
+    ListDir(ListDirRequest),
+    /// Compute the blake3 hash of a remote file, without fetching it.
+    Checksum(Checksum),
+    /// Show the size and free space of each filesystem mounted on the remote.
+    DiskUsage,
+    /// Print the last lines of a remote file, and with `--follow` keep printing new ones.
+    Tail(Tail),
+    /// Run a command on the remote, e.g. `exec journalctl -- -u casper-node-launcher -n 100`.
+    /// Only executables the remote allows can be run.
+    Exec(ExecRequest),
+    /// Remove a remote file, or with `--recursive` a directory and everything in it.
+    Remove(RemoveRequest),
+    /// Rename or move a remote file or directory.
+    Rename(RenameRequest),
+    /// Create a remote directory and any missing parents, like `mkdir -p`.
+    Mkdir(MkdirRequest),
+    /// Train a zstd dictionary from sample files, for `put-file --dictionary`. Runs locally.
+    TrainDictionary(TrainDictionary),
+    /// Replace the agent on the remote with the one in a `cargo xtask dist` tarball, if the
+    /// remote allows it.
+    UpdateAgent(UpdateAgent),
 }
 
 #[derive(Debug, structopt::StructOpt, Deserialize)]
@@ -74,12 +180,126 @@ impl FromStr for Peers {
 pub struct PutFile {
     source_file: PathBuf,
     target_path: PathBuf,
+    /// Permissions (octal) to apply to the file on the remote.
+    #[structopt(long, default_value = "666", parse(try_from_str = parse_mode))]
+    mode: u32,
+    /// uid to chown the file to on the remote.
+    #[structopt(long)]
+    owner: Option<u32>,
+    /// gid to chown the file to on the remote.
+    #[structopt(long)]
+    group: Option<u32>,
+    /// How to compress the file: `none`, `zstd[:<level>]`, or `auto[:<level>]` to send it
+    /// uncompressed if it doesn't shrink, e.g. when already compressed.
+    #[structopt(long, default_value = "zstd")]
+    compression: Compression,
+    /// A zstd dictionary, see `train-dictionary`, to compress with. Uploaded to the remote first
+    /// if it doesn't have it yet.
+    #[structopt(long)]
+    dictionary: Option<PathBuf>,
+}
+
+impl PutFile {
+    fn encoding(&self) -> Result<Encoding, std::io::Error> {
+        let dictionary = match &self.dictionary {
+            Some(path) => Some(Dictionary::load(path)?),
+            None => None,
+        };
+        Ok(Encoding::new(self.compression, dictionary))
+    }
+
+    /// blake3 hash of the file, which is all that's needed to find out whether the remote already
+    /// has it.
+    fn content_hash(&self) -> Result<[u8; 32], MessageError> {
+        blake3_hash_file(&self.source_file).map_err(|err| MessageError::ReadFile {
+            path: self.source_file.clone(),
+            err,
+        })
+    }
+
+    fn to_request(
+        &self,
+        encoding: &Encoding,
+        content_hash: [u8; 32],
+    ) -> Result<PutFileRequest, MessageError> {
+        Ok(PutFileRequest {
+            target_perms: self.mode,
+            target_path: self.target_path.clone(),
+            target_owner: self.owner,
+            target_group: self.group,
+            file: CompressedWireFile::load_and_compress(
+                &self.source_file,
+                &self.target_path,
+                encoding,
+            )?,
+            content_hash,
+        })
+    }
+
+    fn to_blob_request(&self, content_hash: [u8; 32]) -> PutFileFromBlobRequest {
+        PutFileFromBlobRequest {
+            content_hash,
+            target_perms: self.mode,
+            target_path: self.target_path.clone(),
+            target_owner: self.owner,
+            target_group: self.group,
+        }
+    }
+}
+
+#[derive(Clone, Debug, StructOpt)]
+pub struct PutDir {
+    source_dir: PathBuf,
+    target_dir: PathBuf,
+    #[structopt(flatten)]
+    filter: ArchiveFilter,
+    /// Replace the target directory, deleting anything in it that isn't being put, excluded paths
+    /// included, instead of unpacking over it.
+    #[structopt(long)]
+    replace: bool,
+}
+
+#[derive(Clone, Debug, StructOpt)]
+pub struct TrainDictionary {
+    /// Where to write the dictionary.
+    output: PathBuf,
+    /// Files similar to the ones that will be sent, e.g. the chainspecs and configs of other
+    /// networks.
+    #[structopt(required = true)]
+    samples: Vec<PathBuf>,
+    /// Maximum size of the dictionary in bytes.
+    #[structopt(long, default_value = "112640")]
+    max_size: usize,
+}
+
+/// Starting or stopping a service waits for it to come up or shut down, which takes longer than
+/// the default deadline. systemd itself gives up after 90s by default.
+fn service_context() -> context::Context {
+    let mut ctx = context::current();
+    ctx.deadline = SystemTime::now() + SERVICE_DEADLINE;
+    ctx
+}
+
+fn parse_hash(s: &str) -> Result<[u8; 32], anyhow::Error> {
+    hash_from_hex(s).ok_or_else(|| anyhow::anyhow!("expected a 64 character hex encoded hash"))
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let opts: Args = Args::from_args();
 
+    if let Rpc::TrainDictionary(train) = &opts.rpc {
+        let dictionary = Dictionary::train(&train.samples, train.max_size)?;
+        fs::write(&train.output, dictionary.data())?;
+        println!(
+            "wrote {} byte dictionary {} to {}",
+            dictionary.data().len(),
+            hash_to_hex(&dictionary.hash()),
+            train.output.display()
+        );
+        return Ok(());
+    }
+
     let peers = match opts.daemon_peers {
         Some(Peers::List(peers)) => peers,
         Some(Peers::Yaml { path }) => {
@@ -97,65 +317,650 @@ async fn main() -> anyhow::Result<()> {
     println!("using peers {:?}", peers);
 
     let mut clients = Vec::new();
+    let mut unreachable = Vec::new();
+    let mut infos = Vec::new();
     for peer in peers.peers.iter() {
         println!("connecting to {}", peer);
-        let tls = tls::connect(peer, &opts.cert, &opts.key).await.unwrap();
-        let transport = tarpc::serde_transport::Transport::from((tls, Bincode::default()));
+        let tls = match tls::connect(peer, &opts.cert, &opts.key).await {
+            Ok(tls) => tls,
+            Err(err) => {
+                println!("{peer}: unable to connect: {err:#}");
+                unreachable.push((*peer, format!("{err:#}")));
+                continue;
+            }
+        };
+        let transport = tarpc::serde_transport::new(tls::client_framed(tls), Bincode::default());
         let client = AgentServiceClient::new(client::Config::default(), transport).spawn();
-        clients.push(client);
+        match hello(&client).await {
+            Ok(info) => infos.push((*peer, info)),
+            Err(err) => {
+                println!("{peer}: refusing to talk to the agent: {err:#}");
+                unreachable.push((*peer, format!("{err:#}")));
+                continue;
+            }
+        }
+        clients.push((*peer, client));
+    }
+
+    if let Rpc::Info = &opts.rpc {
+        // a client is kept for each agent that answered hello.
+        for ((peer, info), (_, client)) in infos.iter().zip(&clients) {
+            for line in format_info(info) {
+                println!("{peer}: {line}");
+            }
+            match host_info(client).await {
+                Ok(host) => {
+                    for line in format_host(&host) {
+                        println!("{peer}: {line}");
+                    }
+                }
+                Err(err) => println!("{peer}: {err:#}"),
+            }
+        }
+        return Ok(());
     }
 
+    if let Rpc::ServiceStatus(status) = &opts.rpc {
+        status::fleet_status(&clients, &unreachable, status.clone()).await;
+        return Ok(());
+    }
+
+    let peers = clients.iter().map(|(peer, _)| *peer).collect::<Vec<_>>();
+    // shared by all peers, so the bandwidth cap applies to the total upload rate.
+    let throttle = match &opts.rpc {
+        Rpc::PutFileChunked(put) => put
+            .max_bytes_per_sec
+            .map(|cap| Arc::new(Throttle::new(cap))),
+        _ => None,
+    };
     let mut responses = Vec::new();
-    for client in clients {
+    for (peer, client) in clients {
         let rpc = opts.rpc.clone();
+        let throttle = throttle.clone();
         let response_future = async move {
             match rpc {
-                Rpc::StopService(_stop) => todo!(),
+                Rpc::StopService(stop) => {
+                    let service = stop.service.clone();
+                    match client.stop_service(service_context(), stop).await? {
+                        StopServiceResponse::Stopped => println!("{peer}: stopped {service}"),
+                        StopServiceResponse::NotRunning => {
+                            println!("{peer}: {service} was not running")
+                        }
+                        StopServiceResponse::NotFound => println!("{peer}: {service} not found"),
+                        StopServiceResponse::Failed { message } => {
+                            println!("{peer}: stopping {service} failed: {message}")
+                        }
+                        StopServiceResponse::Error => println!("{peer}: stop service failed"),
+                    }
+                }
+                Rpc::FetchDebugOutput(fetch) => {
+                    let service = fetch.service.clone();
+                    match client.fetch_debug_output(context::current(), fetch).await? {
+                        FetchDebugOutputResponse::Success { run, dir, entries } => {
+                            let target_dir = PathBuf::from("./fetch")
+                                .join(peer.to_string())
+                                .join("debug")
+                                .join(&service)
+                                .join(&run);
+                            let fetched =
+                                fetch_tree(&client, &peer.to_string(), &dir, &entries, &target_dir)
+                                    .await?;
+                            println!(
+                                "{peer}: fetched {fetched} entries of run {run} into {}",
+                                target_dir.display()
+                            );
+                        }
+                        FetchDebugOutputResponse::Running { run } => println!(
+                            "{peer}: run {run} of {service} is still going, stop the service first"
+                        ),
+                        FetchDebugOutputResponse::NotFound => {
+                            println!("{peer}: {service} has no debug output")
+                        }
+                        FetchDebugOutputResponse::Error => {
+                            println!("{peer}: fetch debug output failed")
+                        }
+                    }
+                }
+                Rpc::Signal(signal) => {
+                    send_signal(&client, &peer.to_string(), signal.to_request()).await?
+                }
+                Rpc::Pause(pause) => {
+                    send_signal(&client, &peer.to_string(), pause.to_request()).await?
+                }
+                Rpc::EnableCoreDumps(enable) => {
+                    let service = enable.service.clone();
+                    match client.enable_core_dumps(service_context(), enable).await? {
+                        EnableCoreDumpsResponse::Enabled { core_dir } => println!(
+                            "{peer}: collecting cores of {service} into {} from it's next start",
+                            core_dir.display()
+                        ),
+                        EnableCoreDumpsResponse::NotFound => {
+                            println!("{peer}: {service} not found")
+                        }
+                        EnableCoreDumpsResponse::Failed { message } => {
+                            println!("{peer}: enabling core dumps of {service} failed: {message}")
+                        }
+                        EnableCoreDumpsResponse::Error => {
+                            println!("{peer}: enable core dumps failed")
+                        }
+                    }
+                }
+                Rpc::ListCrashes(list) => {
+                    let service = list.service.clone();
+                    match client.list_crashes(context::current(), list).await? {
+                        ListCrashesResponse::Success { crashes } => {
+                            if crashes.is_empty() {
+                                println!("{peer}: no crashes of {service}");
+                            }
+                            for crash in crashes {
+                                println!("{peer}: {}", format_crash(&crash));
+                            }
+                        }
+                        ListCrashesResponse::NotFound => {
+                            println!("{peer}: {service} not found")
+                        }
+                        ListCrashesResponse::Error => println!("{peer}: list crashes failed"),
+                    }
+                }
+                Rpc::FetchCrash(fetch) => {
+                    let service = fetch.service.clone();
+                    match client.fetch_crash(context::current(), fetch).await? {
+                        FetchCrashResponse::Success {
+                            crash,
+                            dir,
+                            entries,
+                        } => {
+                            let target_dir = PathBuf::from("./fetch")
+                                .join(peer.to_string())
+                                .join("crashes")
+                                .join(&service)
+                                .join(&crash);
+                            let fetched =
+                                fetch_tree(&client, &peer.to_string(), &dir, &entries, &target_dir)
+                                    .await?;
+                            println!(
+                                "{peer}: fetched {fetched} entries of crash {crash} into {}",
+                                target_dir.display()
+                            );
+                        }
+                        FetchCrashResponse::NotFound => {
+                            println!("{peer}: no such crash of {service}")
+                        }
+                        FetchCrashResponse::Error => println!("{peer}: fetch crash failed"),
+                    }
+                }
+                Rpc::FindNodeProcesses(find) => {
+                    let name = find.name.clone();
+                    match client.find_node_processes(context::current(), find).await? {
+                        FindNodeProcessesResponse::Success { processes } => {
+                            if processes.is_empty() {
+                                println!("{peer}: no {name} processes");
+                            }
+                            for process in processes {
+                                print!("{}", format_node_process(&peer, &process));
+                            }
+                        }
+                        FindNodeProcessesResponse::Error => {
+                            println!("{peer}: finding {name} processes failed")
+                        }
+                    }
+                }
+                Rpc::RestartService(restart) => {
+                    let service = restart.service.clone();
+                    match client.restart_service(service_context(), restart).await? {
+                        RestartServiceResponse::Restarted => {
+                            println!("{peer}: restarted {service}")
+                        }
+                        RestartServiceResponse::NotFound => {
+                            println!("{peer}: {service} not found")
+                        }
+                        RestartServiceResponse::Failed { message } => {
+                            println!("{peer}: restarting {service} failed: {message}")
+                        }
+                        RestartServiceResponse::Error => {
+                            println!("{peer}: restart service failed")
+                        }
+                    }
+                }
+                Rpc::EnableService(enable) => {
+                    let service = enable.service.clone();
+                    match client.enable_service(service_context(), enable).await? {
+                        EnableServiceResponse::Enabled => println!("{peer}: enabled {service}"),
+                        EnableServiceResponse::AlreadyEnabled => {
+                            println!("{peer}: {service} was already enabled")
+                        }
+                        EnableServiceResponse::NotFound => {
+                            println!("{peer}: {service} not found")
+                        }
+                        EnableServiceResponse::Failed { message } => {
+                            println!("{peer}: enabling {service} failed: {message}")
+                        }
+                        EnableServiceResponse::Error => println!("{peer}: enable service failed"),
+                    }
+                }
+                Rpc::ServiceStatus(_) => unreachable!("handled after connecting"),
                 Rpc::FetchFile(fetch) => {
-                    let filename = file_name_from_path(&fetch.filename).unwrap();
-                    let response = client.fetch_file(context::current(), fetch).await?;
-                    fs::create_dir_all("./fetch")?;
-                    if let FetchFileResponse::Success { file } = response {
-                        let target_path = PathBuf::from(format!("./fetch/{}", filename));
-                        file.into_file_on_disk(&target_path).unwrap();
-                        println!("fetch file succeeded. TODO FILE SIZES, times?");
-                    } else {
-                        println!("fetch file failed");
-                        todo!()
+                    let filename = file_name_from_path(&fetch.filename)?;
+                    match client.fetch_file(context::current(), fetch).await? {
+                        FetchFileResponse::Success { file, content_hash } => {
+                            fs::create_dir_all("./fetch")?;
+                            let target_path = PathBuf::from("./fetch").join(filename);
+                            file.into_file_on_disk(&target_path)?;
+                            if let Err(err) = verify_file_hash(&target_path, &content_hash) {
+                                fs::remove_file(&target_path)?;
+                                return Err(err.into());
+                            }
+                            println!(
+                                "{peer}: fetched file to {}, content hash {}",
+                                target_path.display(),
+                                hash_to_hex(&content_hash)
+                            );
+                        }
+                        FetchFileResponse::Forbidden { path } => {
+                            println!("{peer}: reading {} is forbidden", path.display())
+                        }
+                        FetchFileResponse::Error => println!("{peer}: fetch file failed"),
                     }
                 }
+                Rpc::FetchFileChunked(fetch) => {
+                    let target_path = fetch_file_chunked(&client, &peer.to_string(), fetch).await?;
+                    println!("{peer}: fetched file to {}", target_path.display());
+                }
                 Rpc::PutFileChunked(put) => {
-                    let req =
-                        PutFileRequest::new_with_default_perms(&put.source_file, &put.target_path)?;
-                    let chunks = req.into_chunked_requests(5242880);
-                    for chunked_req in chunks.into_iter() {
-                        println!("chunked put file request: {chunked_req:?}");
-                        let response = client
-                            .put_file_chunk(context::current(), chunked_req)
-                            .await?;
-                        println!("chunked put file response: {response:?}");
+                    let encoding = put.file.encoding()?;
+                    send_dictionary_if_missing(&client, &peer, &encoding).await?;
+                    let reader = CompressedChunkReader::open(
+                        &put.file.source_file,
+                        &put.file.target_path,
+                        put.chunk_size,
+                        encoding,
+                    )?;
+                    let content_hash = reader.content_hash()?;
+                    let blob_request = put.file.to_blob_request(content_hash);
+                    if put_from_blob_if_present(&client, &peer, blob_request).await? {
+                        return Ok(());
                     }
+                    put_file_chunked(
+                        &client,
+                        &peer.to_string(),
+                        &put,
+                        reader,
+                        content_hash,
+                        throttle,
+                    )
+                    .await?;
                 }
                 Rpc::PutFile(put) => {
-                    let put_file_request =
-                        PutFileRequest::new_with_default_perms(&put.source_file, &put.target_path)?;
+                    let encoding = put.encoding()?;
+                    send_dictionary_if_missing(&client, &peer, &encoding).await?;
+                    let content_hash = put.content_hash()?;
+                    if put_from_blob_if_present(&client, &peer, put.to_blob_request(content_hash))
+                        .await?
+                    {
+                        return Ok(());
+                    }
+                    let put_file_request = put.to_request(&encoding, content_hash)?;
                     let response = client
                         .put_file(context::current(), put_file_request)
                         .await?;
-
-                    println!("put file response: {response:?}");
+                    report_put_file(&peer, &put.target_path, &content_hash, &response)?;
                 }
 
                 Rpc::StartService(start) => {
-                    let response = client.start_service(context::current(), start).await?;
-                    println!("called start and got response {response:?}");
+                    let service = start.service.clone();
+                    let wrapper = start.wrapper.as_ref().map(|wrapper| wrapper.name());
+                    match client.start_service(service_context(), start).await? {
+                        StartServiceResponse::Started => match wrapper {
+                            Some(wrapper) => println!("{peer}: started {service} under {wrapper}"),
+                            None => println!("{peer}: started {service}"),
+                        },
+                        StartServiceResponse::AlreadyRunning => {
+                            println!("{peer}: {service} is already running")
+                        }
+                        StartServiceResponse::NotFound => println!("{peer}: {service} not found"),
+                        StartServiceResponse::Failed { message } => {
+                            println!("{peer}: starting {service} failed: {message}")
+                        }
+                        StartServiceResponse::Error => println!("{peer}: start service failed"),
+                    }
+                }
+                Rpc::PutDir(put) => {
+                    let (archive, entries) = pack_dir(&put.source_dir, &put.filter)?;
+                    println!(
+                        "{peer}: putting {entries} entries from {}",
+                        put.source_dir.display()
+                    );
+                    let response = client
+                        .put_dir(
+                            context::current(),
+                            PutDirRequest {
+                                target_dir: put.target_dir,
+                                archive,
+                                replace: put.replace,
+                            },
+                        )
+                        .await?;
+                    println!("{peer}: put dir response: {response:?}");
+                }
+                Rpc::FetchDir(fetch) => {
+                    let dirname = file_name_from_path(&fetch.host_src_dir)?;
+                    match client.fetch_dir(context::current(), fetch).await? {
+                        FetchDirResponse::Success { dir, entries } => {
+                            let target_dir = PathBuf::from("./fetch")
+                                .join(peer.to_string())
+                                .join(dirname);
+                            let fetched =
+                                fetch_tree(&client, &peer.to_string(), &dir, &entries, &target_dir)
+                                    .await?;
+                            println!(
+                                "{peer}: fetched {fetched} entries into {}",
+                                target_dir.display()
+                            );
+                        }
+                        FetchDirResponse::Forbidden { path } => {
+                            println!("{peer}: reading {} is forbidden", path.display())
+                        }
+                        FetchDirResponse::TooManyEntries => println!(
+                            "{peer}: more than {MAX_LIST_DIR_ENTRIES} entries matched, fetch the \
+                             tree in parts with --include or --exclude"
+                        ),
+                        FetchDirResponse::Error => println!("{peer}: fetch dir failed"),
+                    }
+                }
+                Rpc::GcBlobs(gc) => {
+                    let response = client.gc_blobs(context::current(), gc).await?;
+                    println!("{peer}: gc blobs response: {response:?}");
+                }
+                Rpc::ListTransfers => {
+                    let ListTransfersResponse::Success { transfers } =
+                        client.list_transfers(context::current()).await?;
+                    if transfers.is_empty() {
+                        println!("{peer}: no transfers in flight");
+                    }
+                    for transfer in transfers {
+                        println!(
+                            "{peer}: {} {} -> {} {}/{} chunks, idle {}s",
+                            hash_to_hex(&transfer.content_hash),
+                            transfer.filename,
+                            transfer.target_path.display(),
+                            transfer.received_chunks,
+                            transfer.num_chunks,
+                            transfer.idle_secs
+                        );
+                    }
+                }
+                Rpc::CancelTransfer {
+                    content_hash,
+                    target_path,
+                } => {
+                    let response = client
+                        .cancel_transfer(
+                            context::current(),
+                            CancelTransferRequest {
+                                content_hash,
+                                target_path: target_path.clone(),
+                            },
+                        )
+                        .await?;
+                    println!("{peer}: cancel transfer response: {response:?}");
+                }
+                Rpc::Stat(stat) => match client.stat(context::current(), stat.clone()).await? {
+                    StatResponse::Success { stat } => println!("{peer}: {}", format_stat(&stat)),
+                    StatResponse::NotFound => println!("{peer}: {} not found", stat.path.display()),
+                    StatResponse::Forbidden { path } => {
+                        println!("{peer}: reading {} is forbidden", path.display())
+                    }
+                    StatResponse::Error => println!("{peer}: stat failed"),
+                },
+                Rpc::ListDir(list) => {
+                    match client.list_dir(context::current(), list.clone()).await? {
+                        ListDirResponse::Success { entries, truncated } => {
+                            for entry in entries {
+                                println!("{peer}: {}", format_stat(&entry));
+                            }
+                            if truncated {
+                                println!(
+                                    "{peer}: listing truncated at {MAX_LIST_DIR_ENTRIES} entries"
+                                );
+                            }
+                        }
+                        ListDirResponse::NotFound => {
+                            println!("{peer}: {} not found", list.path.display())
+                        }
+                        ListDirResponse::Forbidden { path } => {
+                            println!("{peer}: reading {} is forbidden", path.display())
+                        }
+                        ListDirResponse::Error => println!("{peer}: list dir failed"),
+                    }
+                }
+                Rpc::Checksum(checksum) => {
+                    let request = ChecksumRequest {
+                        path: checksum.path.clone(),
+                    };
+                    match client.checksum(context::current(), request).await? {
+                        ChecksumResponse::Success { size, content_hash } => {
+                            let comparison = match &checksum.compare {
+                                Some(local_path)
+                                    if blake3_hash_file(local_path)? == content_hash =>
+                                {
+                                    format!(", matches {}", local_path.display())
+                                }
+                                Some(local_path) => {
+                                    format!(", DIFFERS from {}", local_path.display())
+                                }
+                                None => String::new(),
+                            };
+                            println!(
+                                "{peer}: {} {} ({size} bytes){comparison}",
+                                hash_to_hex(&content_hash),
+                                checksum.path.display()
+                            );
+                        }
+                        ChecksumResponse::NotFound => {
+                            println!("{peer}: {} not found", checksum.path.display())
+                        }
+                        ChecksumResponse::Forbidden { path } => {
+                            println!("{peer}: reading {} is forbidden", path.display())
+                        }
+                        ChecksumResponse::Error => println!("{peer}: checksum failed"),
+                    }
+                }
+                Rpc::DiskUsage => match client.disk_usage(context::current()).await? {
+                    DiskUsageResponse::Success { mounts } => {
+                        for mount in mounts {
+                            println!("{peer}: {}", format_mount_usage(&mount));
+                        }
+                    }
+                    DiskUsageResponse::Error => println!("{peer}: disk usage failed"),
+                },
+                Rpc::Tail(tail_args) => tail(&client, &peer.to_string(), tail_args).await?,
+                Rpc::UpdateAgent(update) => {
+                    update_agent(&client, &peer.to_string(), update.to_request()?).await?
+                }
+                Rpc::Exec(request) => {
+                    if let Err(err) = exec(&client, &peer.to_string(), request).await {
+                        println!("{peer}: exec failed: {err:#}");
+                    }
                 }
+                Rpc::Remove(remove) => {
+                    let path = remove.path.clone();
+                    let dry_run = remove.dry_run;
+                    match client.remove(context::current(), remove).await? {
+                        RemoveResponse::Success { removed, count } => {
+                            let verb = if dry_run { "would remove" } else { "removed" };
+                            for removed_path in &removed {
+                                println!("{peer}: {verb} {}", removed_path.display());
+                            }
+                            if count > removed.len() as u64 {
+                                println!("{peer}: ... and {} more", count - removed.len() as u64);
+                            }
+                        }
+                        RemoveResponse::NotFound => {
+                            println!("{peer}: {} not found", path.display())
+                        }
+                        RemoveResponse::NotEmpty => println!(
+                            "{peer}: {} is not empty, use --recursive to remove it",
+                            path.display()
+                        ),
+                        RemoveResponse::Forbidden { path } => {
+                            println!("{peer}: removing {} is forbidden", path.display())
+                        }
+                        RemoveResponse::Error => println!("{peer}: remove failed"),
+                    }
+                }
+                Rpc::Rename(rename) => {
+                    let (from, to) = (rename.from.clone(), rename.to.clone());
+                    match client.rename(context::current(), rename).await? {
+                        RenameResponse::Success => {
+                            println!("{peer}: renamed {} to {}", from.display(), to.display())
+                        }
+                        RenameResponse::NotFound => {
+                            println!("{peer}: {} not found", from.display())
+                        }
+                        RenameResponse::AlreadyExists => println!(
+                            "{peer}: {} already exists, use --overwrite to replace it",
+                            to.display()
+                        ),
+                        RenameResponse::Forbidden { path } => {
+                            println!("{peer}: writing {} is forbidden", path.display())
+                        }
+                        RenameResponse::Error => println!("{peer}: rename failed"),
+                    }
+                }
+                Rpc::Mkdir(mkdir) => {
+                    let path = mkdir.path.clone();
+                    match client.mkdir(context::current(), mkdir).await? {
+                        MkdirResponse::Success { created } if created.is_empty() => {
+                            println!("{peer}: {} already exists", path.display())
+                        }
+                        MkdirResponse::Success { created } => {
+                            for dir in created {
+                                println!("{peer}: created {}", dir.display());
+                            }
+                        }
+                        MkdirResponse::Forbidden { path } => {
+                            println!("{peer}: writing {} is forbidden", path.display())
+                        }
+                        MkdirResponse::Error => println!("{peer}: mkdir failed"),
+                    }
+                }
+                Rpc::TrainDictionary(_) => unreachable!("handled before connecting"),
+                Rpc::Info => unreachable!("handled after connecting"),
             }
             Ok::<(), anyhow::Error>(())
         };
         responses.push(response_future);
     }
 
-    futures::future::join_all(responses).await;
+    let results = futures::future::join_all(responses).await;
+    for (peer, result) in peers.iter().zip(results) {
+        if let Err(err) = result {
+            println!("{peer}: error: {err:#}");
+        }
+    }
+    Ok(())
+}
+
+/// If the daemon already has the contents of this file in it's blob store, ask it to write that
+/// to the target path instead of uploading the file again. Returns true if no upload is needed.
+async fn put_from_blob_if_present(
+    client: &AgentServiceClient,
+    peer: &SocketAddr,
+    blob_request: PutFileFromBlobRequest,
+) -> Result<bool, anyhow::Error> {
+    let HasBlobsResponse::Success { present } = client
+        .has_blobs(
+            context::current(),
+            HasBlobsRequest {
+                hashes: vec![blob_request.content_hash],
+            },
+        )
+        .await?;
+    if !present.contains(&blob_request.content_hash) {
+        return Ok(false);
+    }
+    let (target_path, content_hash) = (blob_request.target_path.clone(), blob_request.content_hash);
+    println!("{peer}: already has {}", target_path.display());
+    let response = client
+        .put_file_from_blob(context::current(), blob_request)
+        .await?;
+    let written = report_put_file(peer, &target_path, &content_hash, &response)?;
+    // uploading the file instead would be forbidden just the same.
+    Ok(written || matches!(response, PutFileResponse::Forbidden { .. }))
+}
+
+/// Upload the dictionary `encoding` compresses with, unless the daemon already has it.
+async fn send_dictionary_if_missing(
+    client: &AgentServiceClient,
+    peer: &SocketAddr,
+    encoding: &Encoding,
+) -> Result<(), anyhow::Error> {
+    let Some(dictionary) = &encoding.dictionary else {
+        return Ok(());
+    };
+    let dict_hash = dictionary.hash();
+    let response = client
+        .has_dictionary(context::current(), HasDictionaryRequest { dict_hash })
+        .await?;
+    if matches!(response, HasDictionaryResponse::Present) {
+        return Ok(());
+    }
+    let request = PutDictionaryRequest {
+        dictionary: dictionary.data().to_vec(),
+    };
+    match client.put_dictionary(context::current(), request).await? {
+        PutDictionaryResponse::Success { .. } => {
+            println!("{peer}: sent dictionary {}", hash_to_hex(&dict_hash));
+            Ok(())
+        }
+        PutDictionaryResponse::Error => anyhow::bail!("the remote failed to store the dictionary"),
+    }
+}
+
+/// Print the outcome of writing a file on a peer, returning true if it was written. Fails if the
+/// peer wrote something other than `content_hash`.
+fn report_put_file(
+    peer: &SocketAddr,
+    target_path: &Path,
+    content_hash: &[u8; 32],
+    response: &PutFileResponse,
+) -> anyhow::Result<bool> {
+    match response {
+        PutFileResponse::Success {
+            content_hash: written_hash,
+        } => {
+            check_written_hash(target_path, content_hash, written_hash)?;
+            println!(
+                "{peer}: wrote {} with content hash {}",
+                target_path.display(),
+                hash_to_hex(written_hash)
+            );
+            Ok(true)
+        }
+        PutFileResponse::Forbidden { path } => {
+            println!("{peer}: writing to {} is forbidden", path.display());
+            Ok(false)
+        }
+        PutFileResponse::Error => {
+            println!("{peer}: failed to write {}", target_path.display());
+            Ok(false)
+        }
+    }
+}
+
+/// Check the hash a peer reports having written to `target_path` is that of the file sent.
+fn check_written_hash(
+    target_path: &Path,
+    content_hash: &[u8; 32],
+    written_hash: &[u8; 32],
+) -> anyhow::Result<()> {
+    if written_hash != content_hash {
+        anyhow::bail!(
+            "the remote wrote {} with content hash {}, but {} was sent",
+            target_path.display(),
+            hash_to_hex(written_hash),
+            hash_to_hex(content_hash)
+        );
+    }
     Ok(())
 }
@@ -0,0 +1,83 @@
+use agent_lib::{AgentServiceClient, Signal, SignalRequest, SignalResponse, SignalTarget};
+use structopt::StructOpt;
+use tarpc::context;
+
+/// The process to signal.
+#[derive(Clone, Debug, StructOpt)]
+pub struct Target {
+    /// A service, whose main process is signalled.
+    #[structopt(long, required_unless = "pid", conflicts_with = "pid")]
+    service: Option<String>,
+    /// A process found with `find-node-processes`, a service's main process, or one they run.
+    #[structopt(long)]
+    pid: Option<u32>,
+}
+
+impl Target {
+    fn to_target(&self) -> SignalTarget {
+        match (&self.service, self.pid) {
+            (Some(service), _) => SignalTarget::Service(service.clone()),
+            (None, Some(pid)) => SignalTarget::Pid(pid),
+            (None, None) => unreachable!("structopt requires one of them"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, StructOpt)]
+pub struct SendSignal {
+    /// e.g. `SIGSTOP`, `stop` or `19`.
+    signal: Signal,
+    #[structopt(flatten)]
+    target: Target,
+    /// Send `SIGCONT` this many seconds later, even if the client has gone.
+    #[structopt(long)]
+    resume_after_secs: Option<u64>,
+}
+
+impl SendSignal {
+    pub fn to_request(&self) -> SignalRequest {
+        SignalRequest {
+            target: self.target.to_target(),
+            signal: self.signal,
+            resume_after_secs: self.resume_after_secs,
+        }
+    }
+}
+
+#[derive(Clone, Debug, StructOpt)]
+pub struct Pause {
+    /// How long to stop the process for.
+    secs: u64,
+    #[structopt(flatten)]
+    target: Target,
+}
+
+impl Pause {
+    pub fn to_request(&self) -> SignalRequest {
+        SignalRequest {
+            target: self.target.to_target(),
+            signal: Signal::STOP,
+            resume_after_secs: Some(self.secs),
+        }
+    }
+}
+
+pub async fn send_signal(
+    client: &AgentServiceClient,
+    peer: &str,
+    request: SignalRequest,
+) -> anyhow::Result<()> {
+    let (signal, resume_after_secs) = (request.signal, request.resume_after_secs);
+    match client.signal(context::current(), request).await? {
+        SignalResponse::Sent { pid } => match resume_after_secs {
+            Some(secs) => println!("{peer}: sent {signal} to {pid}, resuming it in {secs}s"),
+            None => println!("{peer}: sent {signal} to {pid}"),
+        },
+        SignalResponse::NotFound => {
+            println!("{peer}: no such process, or the service isn't running")
+        }
+        SignalResponse::Forbidden => println!("{peer}: not allowed to signal that process"),
+        SignalResponse::Error => println!("{peer}: sending {signal} failed"),
+    }
+    Ok(())
+}
@@ -0,0 +1,277 @@
+use std::net::SocketAddr;
+
+use agent_lib::{
+    AgentServiceClient, CrashBundle, NodeProcess, Protocol, ServiceStatus, ServiceStatusRequest,
+    ServiceStatusResponse,
+};
+use tarpc::context;
+
+use crate::inspect::format_bytes;
+
+const HEADER: [&str; 12] = [
+    "PEER",
+    "STATE",
+    "PID",
+    "UPTIME",
+    "RESTARTS",
+    "LAST EXIT",
+    "RSS",
+    "CPU",
+    "FDS",
+    "THREADS",
+    "CGROUP MEM",
+    "OOM KILLS",
+];
+
+/// Ask every peer for the status of a service, and print a table with a row per peer, including
+/// those that couldn't be reached.
+pub async fn fleet_status(
+    clients: &[(SocketAddr, AgentServiceClient)],
+    unreachable: &[(SocketAddr, String)],
+    request: ServiceStatusRequest,
+) {
+    let responses = futures::future::join_all(
+        clients
+            .iter()
+            .map(|(_, client)| client.service_status(context::current(), request.clone())),
+    )
+    .await;
+
+    let mut rows = Vec::new();
+    let mut running = 0;
+    for ((peer, _), response) in clients.iter().zip(responses) {
+        let row = match response {
+            Ok(ServiceStatusResponse::Success { status }) => {
+                if status.state.is_running() {
+                    running += 1;
+                }
+                status_row(peer, &status)
+            }
+            Ok(ServiceStatusResponse::NotFound) => problem_row(peer, "not found"),
+            Ok(ServiceStatusResponse::Error) => problem_row(peer, "error"),
+            Err(err) => problem_row(peer, &format!("rpc failed: {err}")),
+        };
+        rows.push(row);
+    }
+    for (peer, err) in unreachable {
+        rows.push(problem_row(peer, &format!("unreachable: {err}")));
+    }
+    rows.sort();
+
+    print_table(&rows);
+    println!(
+        "{running}/{} peers running {}",
+        clients.len() + unreachable.len(),
+        request.service
+    );
+}
+
+fn status_row(peer: &SocketAddr, status: &ServiceStatus) -> Vec<String> {
+    let dash = || "-".to_string();
+    let process = status.process.as_ref();
+    vec![
+        peer.to_string(),
+        format!("{} ({})", status.state, status.sub_state),
+        status.main_pid.map_or_else(dash, |pid| pid.to_string()),
+        process.map_or_else(dash, |process| format_duration(process.uptime_secs)),
+        status.restarts.to_string(),
+        status.last_exit.map_or_else(dash, |exit| exit.to_string()),
+        process.map_or_else(dash, |process| format_bytes(process.rss_bytes)),
+        process.map_or_else(dash, |process| {
+            format_duration((process.user_cpu_millis + process.system_cpu_millis) / 1000)
+        }),
+        process
+            .and_then(|process| process.open_fds)
+            .map_or_else(dash, |fds| fds.to_string()),
+        process.map_or_else(dash, |process| process.threads.to_string()),
+        match status
+            .cgroup
+            .as_ref()
+            .map(|cgroup| (cgroup.memory_bytes, cgroup.memory_max_bytes))
+        {
+            Some((Some(bytes), Some(max))) => {
+                format!("{}/{}", format_bytes(bytes), format_bytes(max))
+            }
+            Some((Some(bytes), None)) => format_bytes(bytes),
+            _ => dash(),
+        },
+        status
+            .cgroup
+            .as_ref()
+            .map_or_else(dash, |cgroup| cgroup.oom_kills.to_string()),
+    ]
+}
+
+fn problem_row(peer: &SocketAddr, problem: &str) -> Vec<String> {
+    let mut row = vec![peer.to_string(), problem.to_string()];
+    row.resize(HEADER.len(), "-".to_string());
+    row
+}
+
+fn print_table(rows: &[Vec<String>]) {
+    let header = HEADER.map(String::from).to_vec();
+    let mut widths = vec![0; HEADER.len()];
+    for row in std::iter::once(&header).chain(rows) {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    for row in std::iter::once(&header).chain(rows) {
+        let line = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{cell:<width$}"))
+            .collect::<Vec<_>>()
+            .join("  ");
+        println!("{}", line.trim_end());
+    }
+}
+
+/// A node process and the sockets it listens on, over several lines.
+pub fn format_node_process(peer: &SocketAddr, process: &NodeProcess) -> String {
+    let launcher = process
+        .launcher_pid
+        .map(|pid| format!(", run by launcher {pid}"))
+        .unwrap_or_default();
+    let version = process
+        .version
+        .as_ref()
+        .map(|version| format!(", version {version}"))
+        .unwrap_or_default();
+    let mut formatted = format!(
+        "{peer}: pid {}{launcher}{version}\n    {} {}\n",
+        process.pid,
+        process.binary.display(),
+        process.args.join(" ")
+    );
+    if process.listening.is_empty() {
+        formatted.push_str("    no listening sockets, or not allowed to see them\n");
+    }
+    for socket in &process.listening {
+        let role = default_port_role(socket.protocol, socket.addr.port())
+            .map(|role| format!(" ({role})"))
+            .unwrap_or_default();
+        formatted.push_str(&format!("    {} {}{role}\n", socket.protocol, socket.addr));
+    }
+    formatted
+}
+
+/// A crash bundle on one line: when, how and what crashed.
+pub fn format_crash(crash: &CrashBundle) -> String {
+    let version = crash
+        .version
+        .as_ref()
+        .map(|version| format!(" version {version}"))
+        .unwrap_or_default();
+    format!(
+        "{} {} {} pid {}{version}, blake3 {}, core {}",
+        crash.crash,
+        crash.signal,
+        crash.binary.display(),
+        crash.pid,
+        crash.binary_hash.get(..16).unwrap_or(&crash.binary_hash),
+        format_bytes(crash.core_bytes)
+    )
+}
+
+/// What a port is for, if it's one of casper-node's defaults.
+fn default_port_role(protocol: Protocol, port: u16) -> Option<&'static str> {
+    match (protocol, port) {
+        (Protocol::Tcp, 35000) => Some("gossip"),
+        (Protocol::Tcp, 7777) => Some("rpc"),
+        (Protocol::Tcp, 7778) => Some("speculative exec"),
+        (Protocol::Tcp, 8888) => Some("rest"),
+        (Protocol::Tcp, 9999) => Some("sse"),
+        _ => None,
+    }
+}
+
+/// Format a duration in its two largest units, e.g. `3d04h` or `5m07s`.
+pub fn format_duration(secs: u64) -> String {
+    let (days, hours, minutes, secs) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60, secs % 60);
+    if days > 0 {
+        format!("{days}d{hours:02}h")
+    } else if hours > 0 {
+        format!("{hours}h{minutes:02}m")
+    } else if minutes > 0 {
+        format!("{minutes}m{secs:02}s")
+    } else {
+        format!("{secs}s")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use agent_lib::{CgroupStats, ExitReason, ProcessStats, ServiceState};
+
+    use super::*;
+
+    #[test]
+    fn test_status_row() {
+        let peer = "10.0.0.1:8081".parse().unwrap();
+        let status = ServiceStatus {
+            service: "casper-node-launcher".to_string(),
+            state: ServiceState::Active,
+            sub_state: "running".to_string(),
+            main_pid: Some(4242),
+            enabled: true,
+            restarts: 1,
+            last_exit: Some(ExitReason::Signal(9)),
+            process: Some(ProcessStats {
+                uptime_secs: 3 * 86400 + 4 * 3600 + 59,
+                rss_bytes: 3 * 1024 * 1024 * 1024,
+                user_cpu_millis: 250_000,
+                system_cpu_millis: 57_000,
+                open_fds: None,
+                threads: 37,
+            }),
+            cgroup: Some(CgroupStats {
+                memory_bytes: Some(3 * 1024 * 1024 * 1024),
+                memory_max_bytes: Some(4 * 1024 * 1024 * 1024),
+                oom_kills: 2,
+                ..CgroupStats::default()
+            }),
+        };
+        assert_eq!(
+            status_row(&peer, &status),
+            [
+                "10.0.0.1:8081",
+                "active (running)",
+                "4242",
+                "3d04h",
+                "1",
+                "signal 9 (SIGKILL)",
+                "3.0G",
+                "5m07s",
+                "-",
+                "37",
+                "3.0G/4.0G",
+                "2"
+            ]
+        );
+        let process = NodeProcess {
+            pid: 4243,
+            parent_pid: 4242,
+            launcher_pid: Some(4242),
+            binary: "/var/lib/casper/bin/1_5_2/casper-node".into(),
+            version: Some("1_5_2".to_string()),
+            args: vec![
+                "validator".to_string(),
+                "/etc/casper/1_5_2/config.toml".to_string(),
+            ],
+            listening: vec![agent_lib::ListeningSocket {
+                protocol: Protocol::Tcp,
+                addr: "0.0.0.0:8888".parse().unwrap(),
+            }],
+        };
+        assert_eq!(
+            format_node_process(&peer, &process),
+            "10.0.0.1:8081: pid 4243, run by launcher 4242, version 1_5_2\n    \
+             /var/lib/casper/bin/1_5_2/casper-node validator /etc/casper/1_5_2/config.toml\n    \
+             tcp 0.0.0.0:8888 (rest)\n"
+        );
+
+        assert_eq!(format_duration(42), "42s");
+        assert_eq!(format_duration(3600), "1h00m");
+    }
+}
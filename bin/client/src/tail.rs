@@ -0,0 +1,70 @@
+use std::path::PathBuf;
+
+use agent_lib::{
+    AgentServiceClient, FollowFileRequest, FollowFileResponse, FollowFrom, PollFollowRequest,
+    PollFollowResponse, StopFollowRequest, MAX_FOLLOW_WAIT_MILLIS,
+};
+use anyhow::bail;
+use structopt::StructOpt;
+use tarpc::context;
+
+#[derive(Clone, Debug, StructOpt)]
+pub struct Tail {
+    /// Path of the file on the remote, e.g. `/var/log/casper/casper-node.log`.
+    path: PathBuf,
+    /// Start with this many lines from the end of the file.
+    #[structopt(long, short = "n", default_value = "10")]
+    lines: u64,
+    /// Keep printing lines as they are appended, following the file across log rotations.
+    #[structopt(long, short)]
+    follow: bool,
+}
+
+/// Print the end of a remote file, each line prefixed with the peer, and with `--follow` keep
+/// printing new lines until interrupted.
+pub async fn tail(client: &AgentServiceClient, peer: &str, tail: Tail) -> anyhow::Result<()> {
+    let Tail {
+        path,
+        lines,
+        follow,
+    } = tail;
+    let request = FollowFileRequest {
+        path: path.clone(),
+        from: FollowFrom::LastLines(lines),
+    };
+    let follow_id = match client.follow_file(context::current(), request).await? {
+        FollowFileResponse::Started { follow_id } => follow_id,
+        FollowFileResponse::NotFound => bail!("{} not found", path.display()),
+        FollowFileResponse::Forbidden { path } => {
+            bail!("reading {} is forbidden", path.display())
+        }
+        FollowFileResponse::Error => bail!("unable to follow {}", path.display()),
+    };
+
+    let wait_millis = if follow { MAX_FOLLOW_WAIT_MILLIS } else { 0 };
+    loop {
+        let request = PollFollowRequest {
+            follow_id,
+            wait_millis,
+        };
+        match client.poll_follow(context::current(), request).await? {
+            PollFollowResponse::Lines { lines, rotated, .. } => {
+                if rotated {
+                    println!("{peer}: --- {} was rotated ---", path.display());
+                }
+                if lines.is_empty() && !follow {
+                    break;
+                }
+                for line in lines {
+                    println!("{peer}: {line}");
+                }
+            }
+            PollFollowResponse::NotFound => bail!("the remote stopped following the file"),
+            PollFollowResponse::Error => bail!("unable to read {}", path.display()),
+        }
+    }
+    client
+        .stop_follow(context::current(), StopFollowRequest { follow_id })
+        .await?;
+    Ok(())
+}
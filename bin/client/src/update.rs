@@ -0,0 +1,95 @@
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use agent_lib::{
+    codec::{Compression, Encoding},
+    AgentServiceClient, AgentUpdateRequest, AgentUpdateResponse, CompressedWireFile,
+};
+use anyhow::anyhow;
+use structopt::StructOpt;
+use tarpc::context;
+
+/// How long to wait for the remote to install the new agent and check that it's healthy.
+const UPDATE_DEADLINE: Duration = Duration::from_secs(120);
+
+#[derive(Clone, Debug, StructOpt)]
+pub struct UpdateAgent {
+    /// A tarball built by `cargo xtask dist <version>`, e.g. `target/dist-2.tar`.
+    tarball: PathBuf,
+    /// The version the tarball was built as, by default from it's name.
+    #[structopt(long)]
+    new_version: Option<u32>,
+}
+
+impl UpdateAgent {
+    pub fn to_request(&self) -> anyhow::Result<AgentUpdateRequest> {
+        let new_version = match self.new_version {
+            Some(version) => version,
+            None => version_from_name(&self.tarball).ok_or_else(|| {
+                anyhow!(
+                    "{} isn't named dist-<version>.tar, give the version with --new-version",
+                    self.tarball.display()
+                )
+            })?,
+        };
+        let dist_tarball = CompressedWireFile::load_and_compress(
+            &self.tarball,
+            &self.tarball,
+            &Encoding::new(Compression::default(), None),
+        )?;
+        Ok(AgentUpdateRequest {
+            new_version,
+            dist_tarball,
+        })
+    }
+}
+
+/// The version in a tarball named `dist-<version>.tar`.
+fn version_from_name(tarball: &Path) -> Option<u32> {
+    tarball
+        .file_name()?
+        .to_str()?
+        .strip_prefix("dist-")?
+        .strip_suffix(".tar")?
+        .parse()
+        .ok()
+}
+
+pub async fn update_agent(
+    client: &AgentServiceClient,
+    peer: &str,
+    request: AgentUpdateRequest,
+) -> anyhow::Result<()> {
+    let mut ctx = context::current();
+    ctx.deadline = SystemTime::now() + UPDATE_DEADLINE;
+    match client.update_agent(ctx, request).await? {
+        AgentUpdateResponse::Success {
+            old_version,
+            new_version,
+            new_pid,
+        } => println!("{peer}: updated from version {old_version} to {new_version}, pid {new_pid}"),
+        AgentUpdateResponse::Invalid { message } => println!("{peer}: invalid update, {message}"),
+        AgentUpdateResponse::RolledBack { message } => {
+            println!("{peer}: rolled back the update, {message}")
+        }
+        AgentUpdateResponse::Forbidden { message } => println!("{peer}: {message}"),
+        AgentUpdateResponse::Error => println!("{peer}: update failed"),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_version_from_name() {
+        assert_eq!(version_from_name(Path::new("target/dist-12.tar")), Some(12));
+        assert_eq!(version_from_name(Path::new("dist-2.tar")), Some(2));
+        assert_eq!(version_from_name(Path::new("target/dist-x.tar")), None);
+        assert_eq!(version_from_name(Path::new("target/agent-2.tar")), None);
+        assert_eq!(version_from_name(Path::new("target/dist-2.tar.zst")), None);
+    }
+}
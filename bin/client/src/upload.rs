@@ -0,0 +1,236 @@
+use std::{
+    collections::HashSet,
+    num::NonZeroU64,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use agent_lib::{
+    hash_to_hex, AgentServiceClient, CancelTransferRequest, CompressedChunkReader,
+    PutFileChunkRequest, PutFileChunkResponse, TransferStatusRequest, TransferStatusResponse,
+};
+use anyhow::bail;
+use futures::StreamExt;
+use structopt::StructOpt;
+use tarpc::context;
+
+use crate::{check_written_hash, inspect::format_bytes, PutFile};
+
+/// How long the remote has to receive and write a chunk. Generous, as with a window of chunks in
+/// flight each one shares the bandwidth with the others.
+const CHUNK_DEADLINE: Duration = Duration::from_secs(120);
+
+#[derive(Clone, Debug, StructOpt)]
+pub struct PutFileChunked {
+    #[structopt(flatten)]
+    pub file: PutFile,
+    /// Size in bytes of the (uncompressed) chunks to send.
+    #[structopt(long, default_value = "5242880")]
+    pub chunk_size: u64,
+    /// How many chunks to have in flight at once, so throughput isn't bound by round trip time.
+    #[structopt(long, default_value = "8")]
+    pub window: usize,
+    /// Limit the (compressed) bytes sent per second, across all peers.
+    #[structopt(long)]
+    pub max_bytes_per_sec: Option<NonZeroU64>,
+}
+
+/// Paces sends so that on average no more than a given number of bytes are sent per second.
+#[derive(Debug)]
+pub struct Throttle {
+    bytes_per_sec: NonZeroU64,
+    next_send: Mutex<Instant>,
+}
+
+impl Throttle {
+    pub fn new(bytes_per_sec: NonZeroU64) -> Self {
+        Self {
+            bytes_per_sec,
+            next_send: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Wait until `bytes` more may be sent.
+    pub async fn wait(&self, bytes: u64) {
+        let send_at = {
+            let mut next_send = self.next_send.lock().expect("throttle lock poisoned");
+            let send_at = (*next_send).max(Instant::now());
+            *next_send =
+                send_at + Duration::from_secs_f64(bytes as f64 / self.bytes_per_sec.get() as f64);
+            send_at
+        };
+        tokio::time::sleep_until(send_at.into()).await;
+    }
+}
+
+/// Send the chunks of a file the remote doesn't have yet, keeping up to `window` of them in
+/// flight. The remote writes chunks in whatever order they arrive, and an interrupted upload is
+/// resumed by only sending what's missing.
+pub async fn put_file_chunked(
+    client: &AgentServiceClient,
+    peer: &str,
+    put: &PutFileChunked,
+    reader: CompressedChunkReader,
+    content_hash: [u8; 32],
+    throttle: Option<Arc<Throttle>>,
+) -> anyhow::Result<()> {
+    let (num_chunks, chunk_size) = (reader.num_chunks(), reader.chunk_size());
+    let status = client
+        .transfer_status(
+            context::current(),
+            TransferStatusRequest {
+                content_hash,
+                target_path: put.file.target_path.clone(),
+            },
+        )
+        .await?;
+    let received_chunks = match status {
+        TransferStatusResponse::InProgress {
+            chunk_size: in_flight_chunk_size,
+            codec,
+            ..
+        } if in_flight_chunk_size != chunk_size || codec != reader.codec() => {
+            // chunks of a transfer must all be the same, start it over as this one.
+            println!(
+                "{peer}: restarting transfer of {}, it was started with {in_flight_chunk_size} \
+                 byte chunks as {codec:?}",
+                put.file.source_file.display()
+            );
+            client
+                .cancel_transfer(
+                    context::current(),
+                    CancelTransferRequest {
+                        content_hash,
+                        target_path: put.file.target_path.clone(),
+                    },
+                )
+                .await?;
+            HashSet::new()
+        }
+        TransferStatusResponse::InProgress {
+            received_chunks,
+            missing_chunks,
+            ..
+        } => {
+            println!(
+                "{peer}: resuming transfer of {}, {} chunks missing",
+                put.file.source_file.display(),
+                missing_chunks.len()
+            );
+            received_chunks.into_iter().collect()
+        }
+        TransferStatusResponse::NotFound => HashSet::new(),
+    };
+    let missing_chunks = (0..num_chunks)
+        .filter(|chunk_id| !received_chunks.contains(chunk_id))
+        .collect::<Vec<_>>();
+
+    let started = Instant::now();
+    let mut bytes_sent = 0;
+    let reader = Arc::new(Mutex::new(reader));
+    // chunks are only read and compressed as the window has room for them, so memory use is
+    // bounded by `window` chunks.
+    let mut responses = futures::stream::iter(missing_chunks)
+        .map(|chunk_id| {
+            let reader = reader.clone();
+            let throttle = throttle.clone();
+            async move {
+                // reading and compressing a chunk blocks, keep it off the runtime.
+                let chunk = tokio::task::spawn_blocking(move || {
+                    reader
+                        .lock()
+                        .expect("chunk reader lock poisoned")
+                        .read_chunk(chunk_id)
+                })
+                .await??;
+                let compressed_len = chunk.zstd_compressed_data_chunk.len() as u64;
+                if let Some(throttle) = throttle {
+                    throttle.wait(compressed_len).await;
+                }
+                let request = PutFileChunkRequest::new(
+                    chunk_size,
+                    content_hash,
+                    put.file.mode,
+                    put.file.target_path.clone(),
+                    put.file.owner,
+                    put.file.group,
+                    chunk,
+                );
+                let mut ctx = context::current();
+                ctx.deadline = SystemTime::now() + CHUNK_DEADLINE;
+                let response = client.put_file_chunk(ctx, request).await?;
+                Ok::<_, anyhow::Error>((response, compressed_len))
+            }
+        })
+        .buffer_unordered(put.window.max(1));
+
+    let mut complete = false;
+    while let Some(result) = responses.next().await {
+        let (response, compressed_len) = result?;
+        bytes_sent += compressed_len;
+        match response {
+            PutFileChunkResponse::Progress {
+                chunk_id,
+                seen_chunks,
+            } => println!("{peer}: chunk {chunk_id} written, {seen_chunks}/{num_chunks}"),
+            PutFileChunkResponse::Duplicate { chunk_id } => {
+                println!("{peer}: chunk {chunk_id} was already written")
+            }
+            PutFileChunkResponse::Complete {
+                content_hash: written_hash,
+                ..
+            } => {
+                check_written_hash(&put.file.target_path, &content_hash, &written_hash)?;
+                complete = true;
+                let elapsed = started.elapsed();
+                println!(
+                    "{peer}: wrote {} with content hash {}, sent {} in {:.1}s ({}/s)",
+                    put.file.target_path.display(),
+                    hash_to_hex(&written_hash),
+                    format_bytes(bytes_sent),
+                    elapsed.as_secs_f64(),
+                    format_bytes((bytes_sent as f64 / elapsed.as_secs_f64().max(0.001)) as u64)
+                );
+            }
+            PutFileChunkResponse::Forbidden { path, .. } => {
+                bail!("writing {} is forbidden", path.display())
+            }
+            PutFileChunkResponse::Error { chunk_id } => {
+                bail!("the remote failed to write chunk {chunk_id}, run again to resume")
+            }
+            PutFileChunkResponse::Failed { message, .. } => {
+                bail!(
+                    "the remote received every chunk but failed to write {}, {message}, run again \
+                     to upload it from the start",
+                    put.file.target_path.display()
+                )
+            }
+        }
+    }
+    if !complete {
+        // e.g. every chunk was sent before, but the remote didn't get to write the file.
+        bail!(
+            "the remote didn't write {} after receiving every chunk, run again to resume",
+            put.file.target_path.display()
+        )
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_throttle_paces_sends() {
+        let throttle = Throttle::new(NonZeroU64::new(10_000).unwrap());
+        let started = Instant::now();
+        // the first send goes straight away, each later one waits for the previous to drain.
+        for _ in 0..3 {
+            throttle.wait(1_000).await;
+        }
+        let elapsed = started.elapsed();
+        // only a lower bound, a loaded machine may well sleep for longer.
+        assert!(elapsed >= Duration::from_millis(200), "{elapsed:?}");
+    }
+}
@@ -0,0 +1,255 @@
+use std::{
+    fs::{self, File},
+    io::{BufReader, Read, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use agent_lib::{codec::Codec, create_staging_file, hash_from_hex, hash_to_hex, staging_path_for};
+
+/// Length of the header each blob starts with: a byte for the codec, then the dictionary hash
+/// (all zeros unless it is [`Codec::ZstdDict`]).
+const HEADER_LEN: usize = 33;
+
+/// Content-addressed store of uploaded files, keyed by the blake3 hash of their uncompressed
+/// contents and kept encoded as they were sent.
+///
+/// Anything uploaded to the daemon is kept here, so the same file (e.g. a casper-node binary sent
+/// to every node and every upgrade version) only needs to be transferred once, whether it was sent
+/// whole or in chunks.
+#[derive(Debug, Clone)]
+pub struct BlobStore {
+    dir: PathBuf,
+}
+
+/// Limits applied when garbage collecting the blob store.
+#[derive(Debug, Clone, Copy)]
+pub struct BlobGcPolicy {
+    /// Blobs that have not been stored or used within this long are removed.
+    pub max_age: Option<Duration>,
+    /// The least recently used blobs are removed until the store fits within this many bytes.
+    pub max_total_bytes: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+pub struct BlobGcStats {
+    pub removed: u64,
+    pub remaining: u64,
+    pub remaining_bytes: u64,
+}
+
+impl BlobStore {
+    pub fn new(dir: PathBuf) -> Result<Self, std::io::Error> {
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    fn blob_path(&self, content_hash: &[u8; 32]) -> PathBuf {
+        self.dir.join(format!("{}.blob", hash_to_hex(content_hash)))
+    }
+
+    pub fn contains(&self, content_hash: &[u8; 32]) -> bool {
+        self.blob_path(content_hash).is_file()
+    }
+
+    /// Store a file whose contents hash to `content_hash`, read from `reader` encoded with
+    /// `codec`. Storing a blob that is already present only refreshes it's last used time, and
+    /// leaves `reader` unread.
+    pub fn insert(
+        &self,
+        content_hash: &[u8; 32],
+        codec: Codec,
+        mut reader: impl Read,
+    ) -> Result<(), std::io::Error> {
+        let path = self.blob_path(content_hash);
+        if path.is_file() {
+            return touch(&path);
+        }
+        let temp_path = staging_path_for(&path)?;
+        let result = create_staging_file(&temp_path).and_then(|mut temp_file| {
+            temp_file.write_all(&header(codec))?;
+            std::io::copy(&mut reader, &mut temp_file)?;
+            temp_file.sync_all()?;
+            fs::rename(&temp_path, &path)
+        });
+        if result.is_err() {
+            let _ = fs::remove_file(&temp_path);
+        }
+        result
+    }
+
+    /// Open a stored blob, returning the codec it's encoded with and a reader of the encoded data,
+    /// to be streamed through [`Codec::decoder`]. It's contents are only checked against
+    /// `content_hash` once decoded, see [`BlobStore::remove`].
+    pub fn open(
+        &self,
+        content_hash: &[u8; 32],
+    ) -> Result<(Codec, BufReader<File>), std::io::Error> {
+        let path = self.blob_path(content_hash);
+        let mut reader = BufReader::new(File::open(&path)?);
+        let mut header = [0; HEADER_LEN];
+        let codec = match reader.read_exact(&mut header) {
+            Ok(()) => parse_header(&header),
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => None,
+            Err(err) => return Err(err),
+        };
+        let Some(codec) = codec else {
+            // a corrupt blob is worse than a missing one, drop it so it is uploaded again.
+            fs::remove_file(&path)?;
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("blob {} is corrupt", hash_to_hex(content_hash)),
+            ));
+        };
+        touch(&path)?;
+        Ok((codec, reader))
+    }
+
+    /// Drop a blob, e.g. one that didn't decode to it's content hash, so it is uploaded again.
+    pub fn remove(&self, content_hash: &[u8; 32]) -> Result<(), std::io::Error> {
+        match fs::remove_file(self.blob_path(content_hash)) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    /// Remove blobs according to the given policy.
+    pub fn gc(&self, policy: BlobGcPolicy) -> Result<BlobGcStats, std::io::Error> {
+        let mut blobs = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let is_blob = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.strip_suffix(".blob"))
+                .and_then(hash_from_hex)
+                .is_some();
+            if !is_blob {
+                continue;
+            }
+            let metadata = entry.metadata()?;
+            blobs.push((entry.path(), metadata.modified()?, metadata.len()));
+        }
+        // oldest first.
+        blobs.sort_by_key(|(_, modified, _)| *modified);
+
+        let mut stats = BlobGcStats::default();
+        let now = SystemTime::now();
+        let mut total_bytes = blobs.iter().map(|(_, _, len)| len).sum::<u64>();
+        for (path, modified, len) in blobs {
+            let expired = policy
+                .max_age
+                .is_some_and(|max_age| now.duration_since(modified).unwrap_or_default() > max_age);
+            let over_size = policy
+                .max_total_bytes
+                .is_some_and(|max_total_bytes| total_bytes > max_total_bytes);
+            if expired || over_size {
+                fs::remove_file(&path)?;
+                total_bytes -= len;
+                stats.removed += 1;
+            } else {
+                stats.remaining += 1;
+            }
+        }
+        stats.remaining_bytes = total_bytes;
+        Ok(stats)
+    }
+}
+
+fn header(codec: Codec) -> [u8; HEADER_LEN] {
+    let mut header = [0; HEADER_LEN];
+    header[0] = match codec {
+        Codec::None => 0,
+        Codec::Zstd => 1,
+        Codec::ZstdDict { .. } => 2,
+    };
+    if let Some(dict_hash) = codec.dict_hash() {
+        header[1..].copy_from_slice(&dict_hash);
+    }
+    header
+}
+
+fn parse_header(data: &[u8]) -> Option<Codec> {
+    let header = data.get(..HEADER_LEN)?;
+    match header[0] {
+        0 => Some(Codec::None),
+        1 => Some(Codec::Zstd),
+        2 => Some(Codec::ZstdDict {
+            dict_hash: header[1..].try_into().ok()?,
+        }),
+        _ => None,
+    }
+}
+
+/// Mark a blob as recently used, the blob store uses the mtime for age and LRU eviction.
+fn touch(path: &Path) -> Result<(), std::io::Error> {
+    File::options()
+        .append(true)
+        .open(path)?
+        .set_modified(SystemTime::now())
+}
+
+/// Periodically garbage collect the blob store.
+pub async fn gc_blobs_periodically(blob_store: BlobStore, policy: BlobGcPolicy) {
+    loop {
+        tokio::time::sleep(Duration::from_secs(600)).await;
+        match blob_store.gc(policy) {
+            Ok(stats) if stats.removed > 0 => println!("blob gc: {stats:?}"),
+            Ok(_) => {}
+            Err(err) => println!("err during blob gc {err:?}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use agent_lib::test_util::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_gc_evicts_least_recently_used_first() {
+        let temp = TempDir::new("daemon-blobs");
+        let dir = temp.path().to_path_buf();
+        let store = BlobStore::new(dir.clone()).unwrap();
+        let (old, new) = ([1; 32], [2; 32]);
+        store.insert(&old, Codec::Zstd, &[1; 100][..]).unwrap();
+        let dict_codec = Codec::ZstdDict { dict_hash: [9; 32] };
+        store.insert(&new, dict_codec, &[2; 100][..]).unwrap();
+        File::options()
+            .append(true)
+            .open(store.blob_path(&old))
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(60))
+            .unwrap();
+
+        let stats = store
+            .gc(BlobGcPolicy {
+                max_age: None,
+                max_total_bytes: Some(150),
+            })
+            .unwrap();
+        assert_eq!(stats.removed, 1);
+        assert!(!store.contains(&old));
+        assert!(store.contains(&new));
+        let (codec, mut reader) = store.open(&new).unwrap();
+        assert_eq!(codec, dict_codec);
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(data, vec![2; 100]);
+
+        // a truncated blob is dropped rather than served.
+        fs::write(store.blob_path(&new), [2]).unwrap();
+        assert!(store.open(&new).is_err());
+        assert!(!store.contains(&new));
+
+        store.insert(&old, Codec::None, &[3; 10][..]).unwrap();
+        let stats = store
+            .gc(BlobGcPolicy {
+                max_age: Some(Duration::ZERO),
+                max_total_bytes: None,
+            })
+            .unwrap();
+        assert_eq!(stats.remaining, 0);
+    }
+}
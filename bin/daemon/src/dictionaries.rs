@@ -0,0 +1,49 @@
+use std::{fs, path::PathBuf};
+
+use agent_lib::{codec::Dictionary, hash_to_hex, write_then_rename};
+
+/// zstd dictionaries uploaded by clients, keyed by [`Dictionary::hash`].
+///
+/// Dictionaries are small and few, and files encoded with one can't be decoded without it, so
+/// unlike blobs they are never garbage collected.
+#[derive(Debug, Clone)]
+pub struct DictionaryStore {
+    dir: PathBuf,
+}
+
+impl DictionaryStore {
+    pub fn new(dir: PathBuf) -> Result<Self, std::io::Error> {
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    fn dictionary_path(&self, hash: &[u8; 32]) -> PathBuf {
+        self.dir.join(format!("{}.dict", hash_to_hex(hash)))
+    }
+
+    pub fn contains(&self, hash: &[u8; 32]) -> bool {
+        self.dictionary_path(hash).is_file()
+    }
+
+    pub fn insert(&self, dictionary: &Dictionary) -> Result<(), std::io::Error> {
+        let path = self.dictionary_path(&dictionary.hash());
+        if path.is_file() {
+            return Ok(());
+        }
+        write_then_rename(&path, dictionary.data())
+    }
+
+    /// Load a stored dictionary, verifying it's hash.
+    pub fn load(&self, hash: &[u8; 32]) -> Result<Dictionary, std::io::Error> {
+        let path = self.dictionary_path(hash);
+        let dictionary = Dictionary::load(&path)?;
+        if dictionary.hash() != *hash {
+            fs::remove_file(&path)?;
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("dictionary {} is corrupt", hash_to_hex(hash)),
+            ));
+        }
+        Ok(dictionary)
+    }
+}
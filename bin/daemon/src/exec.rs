@@ -0,0 +1,388 @@
+use std::{
+    collections::HashMap,
+    fs,
+    os::unix::process::ExitStatusExt,
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use agent_lib::{ExecRequest, ExitReason, OutputChunk, OutputStream, MAX_EXEC_POLL_BYTES};
+use async_mutex::Mutex;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    process::Command,
+};
+
+/// Output not yet polled past this size stops being read, so a client that polls slowly slows
+/// the command down rather than filling the daemon's memory.
+const MAX_BUFFERED_BYTES: usize = 16 * 1024 * 1024;
+
+/// The environment commands are run with, rather than the daemon's.
+const BASE_ENV: &[(&str, &str)] = &[
+    (
+        "PATH",
+        "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin",
+    ),
+    ("LANG", "C.UTF-8"),
+];
+/// The environment variables clients may set. Others, such as the dynamic linker's `LD_*`, could
+/// have an allowed executable run any code.
+const ALLOWED_ENV: &[&str] = &[
+    "LANG",
+    "LC_ALL",
+    "TZ",
+    "NO_COLOR",
+    "RUST_BACKTRACE",
+    "RUST_LOG",
+];
+
+#[derive(thiserror::Error, Debug)]
+pub enum ExecError {
+    #[error("{0} isn't an allowed executable")]
+    NotAllowed(PathBuf),
+    #[error("setting {0} isn't allowed, only {}", ALLOWED_ENV.join(", "))]
+    EnvNotAllowed(String),
+    #[error("unable to run {0}: {1}")]
+    Spawn(PathBuf, std::io::Error),
+}
+
+/// Commands run on behalf of clients, keyed by exec id.
+///
+/// Only executables on the allowlist are run, so that the agent isn't an open shell. Like follows,
+/// an exec is a session the client polls for output, since tarpc has no server streaming.
+#[derive(Clone)]
+pub struct Execs {
+    allowed: Arc<Vec<PathBuf>>,
+    sessions: Arc<Mutex<HashMap<u64, Arc<Mutex<ExecSession>>>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+/// New output of a command, and how it exited once all of it has been read.
+#[derive(Debug, Default)]
+pub struct ExecOutput {
+    pub chunks: Vec<OutputChunk>,
+    pub exit: Option<ExitReason>,
+    pub timed_out: bool,
+}
+
+impl Execs {
+    pub fn new(allowed: Vec<PathBuf>) -> Self {
+        Self {
+            allowed: Arc::new(allowed),
+            sessions: Arc::default(),
+            next_id: Arc::default(),
+        }
+    }
+
+    pub fn allowed(&self) -> &[PathBuf] {
+        &self.allowed
+    }
+
+    /// The allowed executable `program` is, by file name, or by a path to the same file.
+    fn resolve(&self, program: &Path) -> Result<&Path, ExecError> {
+        let not_allowed = || ExecError::NotAllowed(program.to_path_buf());
+        if program.components().count() == 1 {
+            return self
+                .allowed
+                .iter()
+                .find(|allowed| allowed.file_name() == Some(program.as_os_str()))
+                .map(PathBuf::as_path)
+                .ok_or_else(not_allowed);
+        }
+        let program = fs::canonicalize(program).map_err(|_| not_allowed())?;
+        self.allowed
+            .iter()
+            .find(|allowed| fs::canonicalize(allowed).ok().as_ref() == Some(&program))
+            .map(PathBuf::as_path)
+            .ok_or_else(not_allowed)
+    }
+
+    pub async fn start(&self, request: ExecRequest) -> Result<u64, ExecError> {
+        let program = self.resolve(&request.program)?.to_path_buf();
+        if let Some((key, _)) = request
+            .env
+            .iter()
+            .find(|(key, _)| !ALLOWED_ENV.contains(&key.as_str()))
+        {
+            return Err(ExecError::EnvNotAllowed(key.clone()));
+        }
+        let mut command = Command::new(&program);
+        command
+            .args(&request.args)
+            .env_clear()
+            .envs(BASE_ENV.iter().copied())
+            .envs(request.env.iter().cloned())
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            // it's own process group, so that anything it spawns can be killed with it.
+            .process_group(0)
+            .kill_on_drop(true);
+        if let Some(cwd) = &request.cwd {
+            command.current_dir(cwd);
+        }
+        let mut child = command
+            .spawn()
+            .map_err(|err| ExecError::Spawn(program.clone(), err))?;
+        let pid = child
+            .id()
+            .expect("a child that hasn't been waited for has a pid");
+
+        let output = Arc::new(Mutex::new(ExecOutput::default()));
+        let stdout = child
+            .stdout
+            .take()
+            .map(|stdout| tokio::spawn(pump(stdout, OutputStream::Stdout, output.clone())));
+        let stderr = child
+            .stderr
+            .take()
+            .map(|stderr| tokio::spawn(pump(stderr, OutputStream::Stderr, output.clone())));
+        let timeout = Duration::from_secs(request.timeout_secs);
+        let finished = output.clone();
+        tokio::spawn(async move {
+            let (status, timed_out) = match tokio::time::timeout(timeout, child.wait()).await {
+                Ok(status) => (status, false),
+                Err(_) => {
+                    kill_group(pid);
+                    (child.wait().await, true)
+                }
+            };
+            // anything it left running would hold the pipes open, and outlive the exec.
+            kill_group(pid);
+            for pump in stdout.into_iter().chain(stderr) {
+                let _ = pump.await;
+            }
+            let mut output = finished.lock().await;
+            output.timed_out = timed_out;
+            output.exit = Some(match status {
+                Ok(status) => status
+                    .code()
+                    .map(ExitReason::Code)
+                    .or_else(|| status.signal().map(ExitReason::Signal))
+                    .unwrap_or(ExitReason::Code(-1)),
+                Err(err) => {
+                    println!("err while waiting for {pid} {err:?}");
+                    ExitReason::Code(-1)
+                }
+            });
+        });
+
+        let exec_id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        println!("running {} as exec {exec_id}, pid {pid}", program.display());
+        let session = ExecSession {
+            pid,
+            output,
+            last_polled: Instant::now(),
+        };
+        self.sessions
+            .lock()
+            .await
+            .insert(exec_id, Arc::new(Mutex::new(session)));
+        Ok(exec_id)
+    }
+
+    /// Wait up to `wait` for output. Returns `None` if there is no such exec. The exec is removed
+    /// once it's exit has been returned.
+    pub async fn poll(&self, exec_id: u64, wait: Duration) -> Option<ExecOutput> {
+        let session = self.sessions.lock().await.get(&exec_id)?.clone();
+        let mut session = session.lock().await;
+        let deadline = Instant::now() + wait;
+        loop {
+            session.last_polled = Instant::now();
+            let polled = session.take_output().await;
+            if !polled.chunks.is_empty() || polled.exit.is_some() || Instant::now() >= deadline {
+                if polled.exit.is_some() {
+                    self.sessions.lock().await.remove(&exec_id);
+                }
+                return Some(polled);
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    /// Periodically kill commands whose output hasn't been polled within `ttl`, e.g. because the
+    /// client went away.
+    pub async fn reap_idle(self, ttl: Duration) {
+        loop {
+            tokio::time::sleep(ttl).await;
+            let mut sessions = self.sessions.lock().await;
+            let mut idle = Vec::new();
+            for (exec_id, session) in sessions.iter() {
+                // a session that is locked is being polled right now.
+                if let Some(session) = session.try_lock() {
+                    if session.last_polled.elapsed() > ttl {
+                        idle.push(*exec_id);
+                    }
+                }
+            }
+            for exec_id in idle {
+                sessions.remove(&exec_id);
+            }
+        }
+    }
+}
+
+struct ExecSession {
+    pid: u32,
+    output: Arc<Mutex<ExecOutput>>,
+    last_polled: Instant,
+}
+
+impl ExecSession {
+    /// The output read since the last poll, up to [`MAX_EXEC_POLL_BYTES`], and the exit once
+    /// there is no more.
+    async fn take_output(&mut self) -> ExecOutput {
+        let mut output = self.output.lock().await;
+        let mut bytes = 0;
+        let count = output
+            .chunks
+            .iter()
+            .take_while(|chunk| {
+                bytes += chunk.data.len() as u64;
+                bytes <= MAX_EXEC_POLL_BYTES
+            })
+            .count();
+        let chunks: Vec<_> = output.chunks.drain(..count).collect();
+        ExecOutput {
+            exit: output.exit.filter(|_| output.chunks.is_empty()),
+            timed_out: output.timed_out,
+            chunks,
+        }
+    }
+}
+
+impl Drop for ExecSession {
+    /// Reaped or not, the exec is over, so the command mustn't keep running.
+    fn drop(&mut self) {
+        if let Some(output) = self.output.try_lock() {
+            if output.exit.is_some() {
+                return;
+            }
+        }
+        kill_group(self.pid);
+    }
+}
+
+async fn pump(
+    mut stream: impl AsyncRead + Unpin,
+    which: OutputStream,
+    output: Arc<Mutex<ExecOutput>>,
+) {
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        loop {
+            let buffered: usize = output
+                .lock()
+                .await
+                .chunks
+                .iter()
+                .map(|chunk| chunk.data.len())
+                .sum();
+            if buffered < MAX_BUFFERED_BYTES {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        match stream.read(&mut buf).await {
+            Ok(0) => return,
+            Ok(read) => output.lock().await.chunks.push(OutputChunk {
+                stream: which,
+                data: buf[..read].to_vec(),
+            }),
+            Err(err) => {
+                println!("err while reading the output of an exec {err:?}");
+                return;
+            }
+        }
+    }
+}
+
+/// `SIGKILL` a command's process group, which is gone already if it and everything it spawned
+/// have exited.
+fn kill_group(pid: u32) {
+    // SAFETY: kill has no memory safety requirements.
+    unsafe { libc::kill(-(pid as i32), libc::SIGKILL) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn run_to_exit(execs: &Execs, exec_id: u64) -> (Vec<OutputChunk>, ExecOutput) {
+        let mut chunks = Vec::new();
+        loop {
+            let output = execs.poll(exec_id, Duration::from_secs(1)).await.unwrap();
+            chunks.extend(output.chunks.iter().cloned());
+            if output.exit.is_some() {
+                return (chunks, output);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_exec_allowlist_output_and_timeout() {
+        let execs = Execs::new(vec![PathBuf::from("/bin/sh")]);
+        let request = |program: &str, args: &[&str], timeout_secs| ExecRequest {
+            program: program.into(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+            env: vec![("RUST_LOG".to_string(), "debug".to_string())],
+            cwd: Some("/".into()),
+            timeout_secs,
+        };
+
+        let exec_id = execs
+            .start(request(
+                "sh",
+                &["-c", "echo $RUST_LOG from $PWD$HOME; echo oops >&2; exit 3"],
+                10,
+            ))
+            .await
+            .unwrap();
+        let (chunks, output) = run_to_exit(&execs, exec_id).await;
+        let stream = |which| {
+            chunks
+                .iter()
+                .filter(|chunk| chunk.stream == which)
+                .flat_map(|chunk| chunk.data.clone())
+                .collect::<Vec<u8>>()
+        };
+        // without the daemon's environment.
+        assert_eq!(stream(OutputStream::Stdout), b"debug from /\n");
+        assert_eq!(stream(OutputStream::Stderr), b"oops\n");
+        assert_eq!(output.exit, Some(ExitReason::Code(3)));
+        assert!(!output.timed_out);
+        // the exec is gone once it's exit has been returned.
+        assert!(execs.poll(exec_id, Duration::ZERO).await.is_none());
+
+        // killed, along with what it spawned, when it runs past it's timeout.
+        let exec_id = execs
+            .start(request("/bin/sh", &["-c", "sleep 30 & sleep 30"], 1))
+            .await
+            .unwrap();
+        let (_, output) = run_to_exit(&execs, exec_id).await;
+        assert_eq!(output.exit, Some(ExitReason::Signal(libc::SIGKILL)));
+        assert!(output.timed_out);
+
+        assert!(matches!(
+            execs.start(request("/bin/ls", &[], 10)).await,
+            Err(ExecError::NotAllowed(_))
+        ));
+        let mut preload = request("sh", &[], 10);
+        preload.env = vec![("LD_PRELOAD".to_string(), "/tmp/evil.so".to_string())];
+        assert!(matches!(
+            execs.start(preload).await,
+            Err(ExecError::EnvNotAllowed(_))
+        ));
+        let mut path = request("sh", &[], 10);
+        path.env = vec![("PATH".to_string(), "/tmp".to_string())];
+        assert!(matches!(
+            execs.start(path).await,
+            Err(ExecError::EnvNotAllowed(_))
+        ));
+    }
+}
@@ -0,0 +1,150 @@
+use std::{
+    fs::{self, DirBuilder},
+    io::ErrorKind,
+    os::unix::fs::{DirBuilderExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+use agent_lib::MAX_LIST_DIR_ENTRIES;
+use walkdir::WalkDir;
+
+/// Remove a file, symlink or empty directory, or with `recursive` a directory and everything in
+/// it. Symlinks are removed, never followed. With `dry_run` nothing is removed, but what would be
+/// is still listed. Returns at most [`MAX_LIST_DIR_ENTRIES`] of the removed paths, and how many
+/// there were.
+pub fn remove(
+    path: &Path,
+    recursive: bool,
+    dry_run: bool,
+) -> Result<(Vec<PathBuf>, u64), std::io::Error> {
+    let metadata = fs::symlink_metadata(path)?;
+    if !metadata.is_dir() {
+        if !dry_run {
+            fs::remove_file(path)?;
+        }
+        return Ok((vec![path.to_path_buf()], 1));
+    }
+    if !recursive {
+        if dry_run && fs::read_dir(path)?.next().is_some() {
+            return Err(ErrorKind::DirectoryNotEmpty.into());
+        }
+        if !dry_run {
+            fs::remove_dir(path)?;
+        }
+        return Ok((vec![path.to_path_buf()], 1));
+    }
+
+    let mut removed = Vec::new();
+    let mut count = 0;
+    for entry in WalkDir::new(path).follow_links(false).contents_first(true) {
+        let entry = entry?;
+        if !dry_run {
+            if entry.file_type().is_dir() {
+                fs::remove_dir(entry.path())?;
+            } else {
+                fs::remove_file(entry.path())?;
+            }
+        }
+        count += 1;
+        if removed.len() < MAX_LIST_DIR_ENTRIES {
+            removed.push(entry.into_path());
+        }
+    }
+    Ok((removed, count))
+}
+
+/// Rename `from` to `to`, which must be on the same filesystem. Unless `overwrite` is set, fails
+/// with [`ErrorKind::AlreadyExists`] if `to` exists.
+pub fn rename(from: &Path, to: &Path, overwrite: bool) -> Result<(), std::io::Error> {
+    fs::symlink_metadata(from)?;
+    if !overwrite && fs::symlink_metadata(to).is_ok() {
+        return Err(ErrorKind::AlreadyExists.into());
+    }
+    fs::rename(from, to)
+}
+
+/// Create `path` and any missing parents with the permission bits `mode`, regardless of the
+/// umask. Existing directories are left as they are. Returns the directories created, parents
+/// first.
+pub fn mkdir(path: &Path, mode: u32) -> Result<Vec<PathBuf>, std::io::Error> {
+    let mut missing = Vec::new();
+    let mut existing = path;
+    while fs::symlink_metadata(existing).is_err() {
+        missing.push(existing.to_path_buf());
+        match existing.parent() {
+            Some(parent) => existing = parent,
+            None => break,
+        }
+    }
+    if !fs::metadata(existing)?.is_dir() {
+        return Err(ErrorKind::NotADirectory.into());
+    }
+
+    let mut created = Vec::new();
+    for dir in missing.into_iter().rev() {
+        match DirBuilder::new().mode(mode).create(&dir) {
+            Ok(()) => {
+                fs::set_permissions(&dir, fs::Permissions::from_mode(mode))?;
+                created.push(dir);
+            }
+            // created concurrently.
+            Err(err) if err.kind() == ErrorKind::AlreadyExists && dir.is_dir() => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(created)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::MetadataExt;
+
+    use agent_lib::test_util::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_mkdir_rename_and_remove() {
+        let temp = TempDir::new("daemon-fileops");
+        let dir = temp.path().to_path_buf();
+        let outside = dir.join("outside");
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(outside.join("keep"), b"keep").unwrap();
+
+        let bin = dir.join("bin/1_0_0");
+        assert_eq!(
+            mkdir(&bin, 0o750).unwrap(),
+            vec![dir.join("bin"), bin.clone()]
+        );
+        assert_eq!(fs::metadata(&bin).unwrap().mode() & 0o7777, 0o750);
+        assert!(mkdir(&bin, 0o750).unwrap().is_empty());
+
+        fs::write(dir.join("casper-node.staged"), b"node").unwrap();
+        fs::write(bin.join("casper-node"), b"old").unwrap();
+        let err = rename(
+            &dir.join("casper-node.staged"),
+            &bin.join("casper-node"),
+            false,
+        );
+        assert_eq!(err.unwrap_err().kind(), ErrorKind::AlreadyExists);
+        rename(
+            &dir.join("casper-node.staged"),
+            &bin.join("casper-node"),
+            true,
+        )
+        .unwrap();
+        assert_eq!(fs::read(bin.join("casper-node")).unwrap(), b"node");
+
+        std::os::unix::fs::symlink(&outside, bin.join("link")).unwrap();
+        let err = remove(&dir.join("bin"), false, true).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::DirectoryNotEmpty);
+        let (listed, count) = remove(&dir.join("bin"), true, true).unwrap();
+        assert_eq!(count, 4);
+        assert_eq!(listed.last(), Some(&dir.join("bin")));
+        assert!(bin.join("casper-node").exists());
+
+        remove(&dir.join("bin"), true, false).unwrap();
+        assert!(!dir.join("bin").exists());
+        assert!(outside.join("keep").exists());
+    }
+}
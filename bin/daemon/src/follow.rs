@@ -0,0 +1,256 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{Read, Seek, SeekFrom},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use agent_lib::{FollowFrom, MAX_FOLLOW_POLL_BYTES};
+use async_mutex::Mutex;
+
+/// Files being followed on behalf of clients, keyed by follow id.
+///
+/// tarpc has no server streaming, so a follow is a session the client polls. Each session holds
+/// the file open, so when the log is rotated the rest of the old file can still be read before
+/// moving on to the new one.
+#[derive(Clone, Default)]
+pub struct FollowSessions {
+    sessions: Arc<Mutex<HashMap<u64, Arc<Mutex<FollowSession>>>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+/// New lines read from a followed file.
+#[derive(Debug, Default)]
+pub struct FollowedLines {
+    pub lines: Vec<String>,
+    pub offset: u64,
+    pub rotated: bool,
+}
+
+impl FollowSessions {
+    pub async fn start(&self, path: &Path, from: FollowFrom) -> Result<u64, std::io::Error> {
+        let session = FollowSession::open(path, from)?;
+        let follow_id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.sessions
+            .lock()
+            .await
+            .insert(follow_id, Arc::new(Mutex::new(session)));
+        Ok(follow_id)
+    }
+
+    /// Wait up to `wait` for new lines. Returns `None` if there is no such follow.
+    pub async fn poll(
+        &self,
+        follow_id: u64,
+        wait: Duration,
+    ) -> Option<Result<FollowedLines, std::io::Error>> {
+        let session = self.sessions.lock().await.get(&follow_id)?.clone();
+        let mut session = session.lock().await;
+        let deadline = Instant::now() + wait;
+        loop {
+            session.last_polled = Instant::now();
+            let lines = match session.read_lines() {
+                Ok(lines) => lines,
+                Err(err) => return Some(Err(err)),
+            };
+            if !lines.lines.is_empty() || lines.rotated || Instant::now() >= deadline {
+                return Some(Ok(lines));
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    pub async fn stop(&self, follow_id: u64) -> bool {
+        self.sessions.lock().await.remove(&follow_id).is_some()
+    }
+
+    /// Periodically stop follows that have not been polled within `ttl`, e.g. because the client
+    /// went away.
+    pub async fn reap_idle(self, ttl: Duration) {
+        loop {
+            tokio::time::sleep(ttl).await;
+            let mut sessions = self.sessions.lock().await;
+            let mut idle = Vec::new();
+            for (follow_id, session) in sessions.iter() {
+                // a session that is locked is being polled right now.
+                if let Some(session) = session.try_lock() {
+                    if session.last_polled.elapsed() > ttl {
+                        idle.push(*follow_id);
+                    }
+                }
+            }
+            for follow_id in idle {
+                sessions.remove(&follow_id);
+            }
+        }
+    }
+}
+
+struct FollowSession {
+    path: PathBuf,
+    file: File,
+    /// (dev, inode) of the open file, to notice when the path is rotated to a new file.
+    identity: (u64, u64),
+    position: u64,
+    /// The start of a line whose newline hasn't been written yet.
+    partial_line: Vec<u8>,
+    last_polled: Instant,
+}
+
+impl FollowSession {
+    fn open(path: &Path, from: FollowFrom) -> Result<Self, std::io::Error> {
+        let mut file = File::open(path)?;
+        let metadata = file.metadata()?;
+        let position = match from {
+            FollowFrom::Start => 0,
+            FollowFrom::End => metadata.len(),
+            FollowFrom::Offset(offset) => offset.min(metadata.len()),
+            FollowFrom::LastLines(lines) => offset_of_last_lines(&mut file, metadata.len(), lines)?,
+        };
+        file.seek(SeekFrom::Start(position))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            identity: (metadata.dev(), metadata.ino()),
+            position,
+            partial_line: Vec::new(),
+            last_polled: Instant::now(),
+        })
+    }
+
+    /// Read whatever has been appended since the last read, switching to the new file if the log
+    /// has been rotated and the old one is exhausted.
+    fn read_lines(&mut self) -> Result<FollowedLines, std::io::Error> {
+        let mut lines = FollowedLines::default();
+        let mut data = Vec::new();
+        self.read_available(&mut data)?;
+        if data.is_empty() {
+            match fs::metadata(&self.path) {
+                Ok(metadata) if (metadata.dev(), metadata.ino()) != self.identity => {
+                    // rotated, and everything in the old file has been read.
+                    self.flush_partial_line(&mut lines);
+                    self.file = File::open(&self.path)?;
+                    self.identity = (metadata.dev(), metadata.ino());
+                    self.position = 0;
+                    lines.rotated = true;
+                    self.read_available(&mut data)?;
+                }
+                Ok(metadata) if metadata.len() < self.position => {
+                    // truncated in place, e.g. by `logrotate`'s copytruncate.
+                    self.flush_partial_line(&mut lines);
+                    self.file.seek(SeekFrom::Start(0))?;
+                    self.position = 0;
+                    lines.rotated = true;
+                    self.read_available(&mut data)?;
+                }
+                // not rotated, or mid-rotation with nothing at the path yet.
+                _ => {}
+            }
+        }
+
+        self.partial_line.extend_from_slice(&data);
+        if let Some(last_newline) = self.partial_line.iter().rposition(|byte| *byte == b'\n') {
+            let rest = self.partial_line.split_off(last_newline + 1);
+            let complete = std::mem::replace(&mut self.partial_line, rest);
+            lines.lines.extend(
+                complete[..last_newline]
+                    .split(|byte| *byte == b'\n')
+                    .map(|line| String::from_utf8_lossy(line).into_owned()),
+            );
+        } else if self.partial_line.len() as u64 >= MAX_FOLLOW_POLL_BYTES {
+            // don't buffer a runaway line forever.
+            self.flush_partial_line(&mut lines);
+        }
+        lines.offset = self.position;
+        Ok(lines)
+    }
+
+    fn read_available(&mut self, data: &mut Vec<u8>) -> Result<(), std::io::Error> {
+        let read = (&mut self.file)
+            .take(MAX_FOLLOW_POLL_BYTES)
+            .read_to_end(data)?;
+        self.position += read as u64;
+        Ok(())
+    }
+
+    fn flush_partial_line(&mut self, lines: &mut FollowedLines) {
+        if !self.partial_line.is_empty() {
+            lines
+                .lines
+                .push(String::from_utf8_lossy(&self.partial_line).into_owned());
+            self.partial_line.clear();
+        }
+    }
+}
+
+/// The offset at which the last `lines` lines of a file start. A trailing newline at the very end
+/// of the file does not start another line.
+fn offset_of_last_lines(file: &mut File, len: u64, lines: u64) -> Result<u64, std::io::Error> {
+    const BLOCK_SIZE: u64 = 8192;
+    if lines == 0 {
+        return Ok(len);
+    }
+    let mut newlines = 0;
+    let mut end = len;
+    let mut block = vec![0; BLOCK_SIZE as usize];
+    while end > 0 {
+        let start = end.saturating_sub(BLOCK_SIZE);
+        let block = &mut block[..(end - start) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(block)?;
+        for (i, byte) in block.iter().enumerate().rev() {
+            let offset = start + i as u64;
+            if *byte != b'\n' || offset == len - 1 {
+                continue;
+            }
+            newlines += 1;
+            if newlines == lines {
+                return Ok(offset + 1);
+            }
+        }
+        end = start;
+    }
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use agent_lib::test_util::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_follow_last_lines_and_rotation() {
+        let temp = TempDir::new("daemon-follow");
+        let dir = temp.path().to_path_buf();
+        let path = dir.join("stdout.log");
+        fs::write(&path, "one\ntwo\nthree\n").unwrap();
+
+        let mut session = FollowSession::open(&path, FollowFrom::LastLines(2)).unwrap();
+        assert_eq!(session.read_lines().unwrap().lines, vec!["two", "three"]);
+
+        let mut log = File::options().append(true).open(&path).unwrap();
+        log.write_all(b"four\nfi").unwrap();
+        assert_eq!(session.read_lines().unwrap().lines, vec!["four"]);
+
+        // rotate, the end of the old file is still read before the new one.
+        log.write_all(b"ve\nsix").unwrap();
+        fs::rename(&path, dir.join("stdout.log.1")).unwrap();
+        fs::write(&path, "seven\n").unwrap();
+        let lines = session.read_lines().unwrap();
+        assert_eq!(lines.lines, vec!["five"]);
+        assert!(!lines.rotated);
+        let lines = session.read_lines().unwrap();
+        assert_eq!(lines.lines, vec!["six", "seven"]);
+        assert!(lines.rotated);
+        assert_eq!(lines.offset, 6);
+    }
+}
@@ -0,0 +1,112 @@
+use std::{fs, path::Path};
+
+use agent_lib::{codec::CODECS, wrapper::WRAPPER_TOOLS, AgentFeatures, HostInfo};
+
+use crate::{inspect, services::BackendKind};
+
+/// What the agent supports on this host, which doesn't change while it runs.
+pub fn features(service_backend: BackendKind, allow_wrappers: bool) -> AgentFeatures {
+    let mut service_backends = Vec::new();
+    // as sd_booted(3) checks.
+    if Path::new("/run/systemd/system").is_dir() {
+        service_backends.push(BackendKind::Systemd.name().to_string());
+    }
+    service_backends.push(BackendKind::Supervisor.name().to_string());
+    let wrappers = match service_backend {
+        _ if !allow_wrappers => Vec::new(),
+        // systemd units can't be started under a wrapper.
+        BackendKind::Systemd => Vec::new(),
+        BackendKind::Supervisor => WRAPPER_TOOLS
+            .iter()
+            .filter(|tool| on_path(tool))
+            .map(|tool| tool.to_string())
+            .collect(),
+    };
+    AgentFeatures {
+        service_backend: service_backend.name().to_string(),
+        service_backends,
+        wrappers,
+        codecs: CODECS.iter().map(|codec| codec.to_string()).collect(),
+    }
+}
+
+pub fn host_info() -> Result<HostInfo, std::io::Error> {
+    let meminfo = fs::read_to_string("/proc/meminfo")?;
+    let os = fs::read_to_string("/etc/os-release")
+        .ok()
+        .and_then(|os_release| os_name(&os_release))
+        .unwrap_or_else(|| "unknown".to_string());
+    let disks = inspect::disk_usage()?
+        .into_iter()
+        .filter(|mount| mount.device.starts_with("/dev/") || mount.mount_point == Path::new("/"))
+        .collect();
+    // SAFETY: sysconf has no memory safety requirements.
+    let cpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) };
+    Ok(HostInfo {
+        hostname: read_trimmed("/proc/sys/kernel/hostname")?,
+        os,
+        kernel: read_trimmed("/proc/sys/kernel/osrelease")?,
+        arch: std::env::consts::ARCH.to_string(),
+        cpus: cpus.max(1) as u32,
+        memory_total_bytes: meminfo_bytes(&meminfo, "MemTotal").unwrap_or_default(),
+        memory_available_bytes: meminfo_bytes(&meminfo, "MemAvailable").unwrap_or_default(),
+        disks,
+    })
+}
+
+fn read_trimmed(path: &str) -> Result<String, std::io::Error> {
+    Ok(fs::read_to_string(path)?.trim().to_string())
+}
+
+/// `PRETTY_NAME` from `/etc/os-release`, or failing that `NAME` and `VERSION_ID`.
+fn os_name(os_release: &str) -> Option<String> {
+    let field = |key: &str| {
+        os_release.lines().find_map(|line| {
+            let value = line.strip_prefix(key)?.strip_prefix('=')?;
+            Some(value.trim().trim_matches('"').to_string())
+        })
+    };
+    field("PRETTY_NAME").or_else(|| {
+        let name = field("NAME")?;
+        Some(match field("VERSION_ID") {
+            Some(version) => format!("{name} {version}"),
+            None => name,
+        })
+    })
+}
+
+/// A field of `/proc/meminfo`, which is in kiB.
+fn meminfo_bytes(meminfo: &str, key: &str) -> Option<u64> {
+    meminfo.lines().find_map(|line| {
+        let value = line.strip_prefix(key)?.strip_prefix(':')?;
+        let kib: u64 = value.trim().trim_end_matches("kB").trim().parse().ok()?;
+        Some(kib * 1024)
+    })
+}
+
+fn on_path(program: &str) -> bool {
+    std::env::var_os("PATH")
+        .is_some_and(|path| std::env::split_paths(&path).any(|dir| dir.join(program).is_file()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_os_name_and_meminfo() {
+        let ubuntu = "NAME=\"Ubuntu\"\nVERSION_ID=\"22.04\"\nPRETTY_NAME=\"Ubuntu 22.04.3 LTS\"\n";
+        assert_eq!(os_name(ubuntu).as_deref(), Some("Ubuntu 22.04.3 LTS"));
+        assert_eq!(
+            os_name("NAME=Alpine\nVERSION_ID=3.19.1\n").as_deref(),
+            Some("Alpine 3.19.1")
+        );
+        assert_eq!(os_name("ID=unknown\n"), None);
+
+        let meminfo =
+            "MemTotal:       16318480 kB\nMemFree:         1234 kB\nMemAvailable:    8000000 kB\n";
+        assert_eq!(meminfo_bytes(meminfo, "MemTotal"), Some(16318480 * 1024));
+        assert_eq!(meminfo_bytes(meminfo, "MemAvailable"), Some(8000000 * 1024));
+        assert_eq!(meminfo_bytes(meminfo, "SwapTotal"), None);
+    }
+}
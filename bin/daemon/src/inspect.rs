@@ -0,0 +1,209 @@
+use std::{
+    ffi::CString,
+    fs::{self, Metadata},
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::{Path, PathBuf},
+};
+
+use agent_lib::{
+    archive::{filtered_paths, ArchiveFilter, MATCH_OPTIONS},
+    FileKind, FileStat, MessageError, MountUsage, MAX_LIST_DIR_ENTRIES,
+};
+use glob::Pattern;
+use walkdir::WalkDir;
+
+/// Stat `path`, without following a final symlink.
+pub fn stat(path: &Path) -> Result<FileStat, std::io::Error> {
+    let metadata = fs::symlink_metadata(path)?;
+    file_stat(path, &metadata)
+}
+
+fn file_stat(path: &Path, metadata: &Metadata) -> Result<FileStat, std::io::Error> {
+    let file_type = metadata.file_type();
+    let kind = if file_type.is_symlink() {
+        FileKind::Symlink {
+            target: fs::read_link(path)?,
+        }
+    } else if file_type.is_dir() {
+        FileKind::Dir
+    } else if file_type.is_file() {
+        FileKind::File
+    } else {
+        FileKind::Other
+    };
+    Ok(FileStat {
+        path: path.to_path_buf(),
+        kind,
+        size: metadata.len(),
+        mode: metadata.mode() & 0o7777,
+        modified: metadata.modified()?,
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+    })
+}
+
+/// List the entries of `dir`, recursively if asked, keeping those whose path relative to `dir`
+/// matches `glob`. Symlinks are listed, not followed. Returns the entries and whether the listing
+/// was cut short at [`MAX_LIST_DIR_ENTRIES`].
+pub fn list_dir(
+    dir: &Path,
+    recursive: bool,
+    glob: Option<&Pattern>,
+) -> Result<(Vec<FileStat>, bool), std::io::Error> {
+    if !fs::metadata(dir)?.is_dir() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{} is not a directory", dir.display()),
+        ));
+    }
+    let walker = WalkDir::new(dir)
+        .follow_links(false)
+        .min_depth(1)
+        .max_depth(if recursive { usize::MAX } else { 1 })
+        .sort_by_file_name();
+    let mut entries = Vec::new();
+    for entry in walker {
+        let entry = entry?;
+        let rel_path = entry.path().strip_prefix(dir).unwrap_or(entry.path());
+        if glob.is_some_and(|glob| !glob.matches_path_with(rel_path, MATCH_OPTIONS)) {
+            continue;
+        }
+        if entries.len() == MAX_LIST_DIR_ENTRIES {
+            return Ok((entries, true));
+        }
+        entries.push(file_stat(entry.path(), &entry.metadata()?)?);
+    }
+    Ok((entries, false))
+}
+
+/// Stat everything under `dir` that `filter` keeps, parents before their contents, for a client to
+/// fetch file by file. Fails if there are more than [`MAX_LIST_DIR_ENTRIES`], rather than leave
+/// some out of a fetch.
+pub fn list_tree(dir: &Path, filter: &ArchiveFilter) -> Result<Vec<FileStat>, MessageError> {
+    let paths = filtered_paths(dir, filter)?;
+    if paths.len() > MAX_LIST_DIR_ENTRIES {
+        return Err(MessageError::TooManyEntries {
+            path: dir.to_path_buf(),
+        });
+    }
+    paths
+        .into_iter()
+        .map(|path| stat(&path).map_err(|err| MessageError::ReadFile { path, err }))
+        .collect()
+}
+
+/// Space used and free on every mounted filesystem that has a size, i.e. not `proc`, `sysfs` and
+/// the like.
+// the statvfs field types differ between 32 and 64 bit targets.
+#[allow(clippy::unnecessary_cast)]
+pub fn disk_usage() -> Result<Vec<MountUsage>, std::io::Error> {
+    let mounts = fs::read_to_string("/proc/self/mounts")?;
+    let mut usage = Vec::new();
+    for line in mounts.lines() {
+        let mut fields = line.split_whitespace();
+        let (Some(device), Some(mount_point), Some(fs_type)) =
+            (fields.next(), fields.next(), fields.next())
+        else {
+            continue;
+        };
+        let mount_point = PathBuf::from(unescape_mount_field(mount_point));
+        let Ok(stat) = statvfs(&mount_point) else {
+            // e.g. a mount we don't have permission to look at.
+            continue;
+        };
+        if stat.f_blocks == 0 {
+            continue;
+        }
+        let block_size = stat.f_frsize as u64;
+        usage.push(MountUsage {
+            device: unescape_mount_field(device),
+            mount_point,
+            fs_type: fs_type.to_string(),
+            total_bytes: stat.f_blocks as u64 * block_size,
+            free_bytes: stat.f_bfree as u64 * block_size,
+            available_bytes: stat.f_bavail as u64 * block_size,
+            total_inodes: stat.f_files as u64,
+            free_inodes: stat.f_ffree as u64,
+        });
+    }
+    Ok(usage)
+}
+
+fn statvfs(path: &Path) -> Result<libc::statvfs, std::io::Error> {
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: `path` is a valid nul terminated string, and `stat` is only read if the call
+    // succeeded and initialized it.
+    let result = unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(unsafe { stat.assume_init() })
+}
+
+/// `/proc/self/mounts` escapes spaces, tabs, newlines and backslashes as octal, e.g. `\040`.
+fn unescape_mount_field(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let octal = bytes
+            .get(i + 1..i + 4)
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .and_then(|digits| u8::from_str_radix(digits, 8).ok());
+        match (bytes[i], octal) {
+            (b'\\', Some(byte)) => {
+                unescaped.push(byte);
+                i += 4;
+            }
+            (byte, _) => {
+                unescaped.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&unescaped).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use agent_lib::test_util::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_list_dir_recursion_and_glob() {
+        let temp = TempDir::new("daemon-inspect");
+        let dir = temp.path().to_path_buf();
+        fs::create_dir_all(dir.join("1_0_0")).unwrap();
+        fs::write(dir.join("1_0_0/casper-node"), b"node").unwrap();
+        fs::write(dir.join("1_0_0/config.toml"), b"config").unwrap();
+        std::os::unix::fs::symlink("1_0_0", dir.join("current")).unwrap();
+
+        let (entries, truncated) = list_dir(&dir, false, None).unwrap();
+        assert!(!truncated);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].kind, FileKind::Dir);
+        assert_eq!(
+            entries[1].kind,
+            FileKind::Symlink {
+                target: PathBuf::from("1_0_0")
+            }
+        );
+
+        let glob = Pattern::new("*/casper-node").unwrap();
+        let (entries, _) = list_dir(&dir, true, Some(&glob)).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].path, dir.join("1_0_0/casper-node"));
+        assert_eq!(entries[0].size, 4);
+
+        assert!(list_dir(&dir.join("1_0_0/casper-node"), false, None).is_err());
+    }
+
+    #[test]
+    fn test_unescape_mount_field() {
+        assert_eq!(unescape_mount_field("/mnt/my\\040disk"), "/mnt/my disk");
+        assert_eq!(unescape_mount_field("/plain"), "/plain");
+    }
+}
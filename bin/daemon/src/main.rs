@@ -1,19 +1,91 @@
-use std::{collections::HashMap, net::SocketAddr, path::PathBuf, sync::Arc, time::Instant};
+mod blobs;
+mod dictionaries;
+mod exec;
+mod fileops;
+mod follow;
+mod info;
+mod inspect;
+mod procfs;
+mod sandbox;
+mod services;
+mod signals;
+mod transfers;
+mod update;
+
+use std::{
+    collections::{hash_map::Entry, BTreeSet, HashSet},
+    fs,
+    io::ErrorKind,
+    net::SocketAddr,
+    os::fd::AsRawFd,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use agent_lib::{
-    tls, AgentService, CompressedWireFile, CompressedWireFileChunk, FetchFileRequest,
-    FetchFileResponse, PutFileChunkRequest, PutFileChunkResponse, PutFileRequest, PutFileResponse,
-    StartServiceRequest, StartServiceResponse,
+    agent_version,
+    archive::{unpack_dir, unpack_dir_replacing},
+    blake3_hash_file,
+    codec::{Codec, Dictionary, Encoding},
+    hash_to_hex, parse_size, staging_path_for, tls, write_file_atomic, AgentFeatures, AgentInfo,
+    AgentService, AgentUpdateRequest, AgentUpdateResponse, CancelTransferRequest,
+    CancelTransferResponse, ChecksumRequest, ChecksumResponse, CompressedWireFile,
+    CompressedWireFileChunk, DiskUsageResponse, EnableCoreDumpsRequest, EnableCoreDumpsResponse,
+    EnableServiceRequest, EnableServiceResponse, ExecRequest, ExecResponse, FetchCrashRequest,
+    FetchCrashResponse, FetchDebugOutputRequest, FetchDebugOutputResponse, FetchDirRequest,
+    FetchDirResponse, FetchFileChunkRequest, FetchFileChunkResponse, FetchFileRequest,
+    FetchFileResponse, FindNodeProcessesRequest, FindNodeProcessesResponse, FollowFileRequest,
+    FollowFileResponse, GcBlobsRequest, GcBlobsResponse, HasBlobsRequest, HasBlobsResponse,
+    HasDictionaryRequest, HasDictionaryResponse, HelloRequest, HostInfoResponse,
+    ListCrashesRequest, ListCrashesResponse, ListDirRequest, ListDirResponse,
+    ListTransfersResponse, MessageError, MkdirRequest, MkdirResponse, PollExecRequest,
+    PollExecResponse, PollFollowRequest, PollFollowResponse, PutDictionaryRequest,
+    PutDictionaryResponse, PutDirRequest, PutDirResponse, PutFileChunkRequest,
+    PutFileChunkResponse, PutFileFromBlobRequest, PutFileRequest, PutFileResponse, RemoveRequest,
+    RemoveResponse, RenameRequest, RenameResponse, RestartServiceRequest, RestartServiceResponse,
+    ServiceStatusRequest, ServiceStatusResponse, SignalRequest, SignalResponse, SignalTarget,
+    StartServiceRequest, StartServiceResponse, StatRequest, StatResponse, StopFollowRequest,
+    StopFollowResponse, StopServiceRequest, StopServiceResponse, TransferStatusRequest,
+    TransferStatusResponse, MAX_CHUNK_SIZE, MAX_EXEC_WAIT_MILLIS, MAX_FOLLOW_WAIT_MILLIS,
+    PROTOCOL_VERSION,
 };
 use async_mutex::Mutex;
 use futures::{future, StreamExt};
+use glob::Pattern;
 use structopt::StructOpt;
 use tarpc::{
     context::Context,
     server::{self, incoming::Incoming, Channel},
     tokio_serde::formats::Bincode,
 };
+use tokio::net::TcpListener;
+
+use blobs::{gc_blobs_periodically, BlobGcPolicy, BlobStore};
+use dictionaries::DictionaryStore;
+use exec::{ExecError, ExecOutput, Execs};
+use follow::{FollowSessions, FollowedLines};
+use sandbox::{PathPolicy, DEFAULT_WRITE_ROOTS};
+use services::{
+    collect_core, install_core_handler, restore_core_pattern, restore_core_pattern_on_exit,
+    BackendKind, ServiceBackend, ServiceError, Services, Supervisor, SupervisorConfig, Systemd,
+};
+use signals::Signals;
+use transfers::{
+    parse_ttl_secs, reap_expired_transfers, transfer_key, InFlightTransfer, InFlightTransfers,
+    TransferStore,
+};
+use update::{UpdateError, Updater};
+
+/// Follows that haven't been polled for this long are stopped, e.g. because the client went away.
+const FOLLOW_IDLE_TTL: Duration = Duration::from_secs(60);
+/// Commands whose output hasn't been polled for this long are killed.
+const EXEC_IDLE_TTL: Duration = Duration::from_secs(60);
+/// How long describing the host may take, e.g. when a network filesystem hangs.
+const HOST_INFO_TIMEOUT: Duration = Duration::from_secs(5);
 
+// parsed once, at startup.
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, StructOpt)]
 enum Args {
     Serve {
@@ -23,17 +95,219 @@ enum Args {
         cert: PathBuf,
         #[structopt(default_value = "assets/agent-key.pem")]
         key: PathBuf,
+        /// Directory in which the daemon keeps state that must survive a restart.
+        #[structopt(long, default_value = "agent-state")]
+        state_dir: PathBuf,
+        /// Chunked transfers that have not received a chunk for this many seconds are dropped.
+        #[structopt(long, default_value = "3600", parse(try_from_str = parse_ttl_secs))]
+        transfer_ttl_secs: u64,
+        /// Blobs not stored or used for this many seconds are garbage collected, a week by
+        /// default.
+        #[structopt(long, default_value = "604800")]
+        blob_max_age_secs: u64,
+        /// The least recently used blobs are garbage collected to keep the store under this size,
+        /// e.g. `512M` or `10G`.
+        #[structopt(long, default_value = "10G", parse(try_from_str = parse_size))]
+        blob_max_bytes: u64,
+        /// Directories clients may read files from, in addition to the writable ones.
+        #[structopt(long = "allow-read")]
+        read_roots: Vec<PathBuf>,
+        /// Directories clients may write files to, defaults to `/etc/casper`, `/var/lib/casper`
+        /// and `/var/log/casper`.
+        #[structopt(long = "allow-write")]
+        write_roots: Vec<PathBuf>,
+        /// Executables clients may run with `exec`, by absolute path. None are allowed by default.
+        #[structopt(long = "allow-exec")]
+        exec_allowlist: Vec<PathBuf>,
+        /// Manage services with `systemd`, or with the daemon's own `supervisor` where there is no
+        /// systemd, e.g. in containers.
+        #[structopt(long, default_value = "systemd")]
+        service_backend: BackendKind,
+        /// YAML file of the services the supervisor may run.
+        #[structopt(long)]
+        services: Option<PathBuf>,
+        /// Allow clients to replace the daemon with `update-agent`.
+        #[structopt(long)]
+        allow_update: bool,
+        /// Allow clients to start services under a debugging tool, such as gdb or perf, with the
+        /// supervisor.
+        #[structopt(long)]
+        allow_wrappers: bool,
+        /// Have the kernel pipe every core dumped on the host to the daemon, for `enable-core-dumps`
+        /// with the supervisor. This replaces the host-wide `kernel.core_pattern`, which is put
+        /// back when the daemon is stopped.
+        #[structopt(long)]
+        collect_cores: bool,
+    },
+    /// Print the daemon's version, as given to `cargo xtask dist`.
+    Version,
+    /// Keep a core piped from the kernel, when the daemon is started with `--collect-cores`. Not
+    /// for running by hand.
+    CollectCore {
+        /// The daemon's `<state-dir>/services`.
+        services_dir: PathBuf,
+        pid: u32,
+        signal: i32,
+        /// When the process crashed, in seconds since the unix epoch.
+        time: u64,
     },
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let args = Args::from_args();
+    if let Args::Version = args {
+        println!("{}", agent_version());
+        return Ok(());
+    }
+    if let Args::CollectCore {
+        services_dir,
+        pid,
+        signal,
+        time,
+    } = args
+    {
+        return collect_core(
+            &services_dir,
+            pid,
+            signal,
+            time,
+            &mut std::io::stdin().lock(),
+        );
+    }
 
-    let Args::Serve { addr, cert, key } = args;
+    let Args::Serve {
+        addr,
+        cert,
+        key,
+        state_dir,
+        transfer_ttl_secs,
+        blob_max_age_secs,
+        blob_max_bytes,
+        mut read_roots,
+        mut write_roots,
+        exec_allowlist,
+        service_backend,
+        services,
+        allow_update,
+        allow_wrappers,
+        collect_cores,
+    } = args
+    else {
+        unreachable!("handled above")
+    };
+    let inherited_listener = update::inherited_listener()?;
+    let ready_pipe = update::ready_pipe()?;
+    let handed_over = inherited_listener.is_some();
+    if handed_over {
+        println!("taking over from the daemon being updated");
+    }
     //sudo::escalate_if_needed().unwrap();
     // println!("Successfully escalated privileges...");
-    let listener = tls::serve(addr, cert, key, Bincode::default).await?;
+
+    let blob_store = BlobStore::new(state_dir.join("blobs"))?;
+    tokio::spawn(gc_blobs_periodically(
+        blob_store.clone(),
+        BlobGcPolicy {
+            max_age: Some(Duration::from_secs(blob_max_age_secs)),
+            max_total_bytes: Some(blob_max_bytes),
+        },
+    ));
+
+    let transfer_store = TransferStore::new(state_dir.join("transfers"))?;
+    let mut transfers = transfer_store.load_all()?;
+    let committed = transfers::commit_complete(&mut transfers, &transfer_store, &blob_store);
+    if committed > 0 {
+        println!("committed {committed} transfers that had received all of their chunks");
+    }
+    if !transfers.is_empty() {
+        println!("resuming {} in-flight transfers", transfers.len());
+    }
+    let in_flight_transfers = Arc::new(Mutex::new(transfers));
+    tokio::spawn(reap_expired_transfers(
+        in_flight_transfers.clone(),
+        transfer_store.clone(),
+        Duration::from_secs(transfer_ttl_secs),
+    ));
+
+    let dictionary_store = DictionaryStore::new(state_dir.join("dictionaries"))?;
+
+    if write_roots.is_empty() {
+        write_roots = DEFAULT_WRITE_ROOTS.iter().map(PathBuf::from).collect();
+    }
+    if let BackendKind::Supervisor = service_backend {
+        // so that what the supervisor keeps for services, such as the output of debugging tools,
+        // can be fetched a file at a time.
+        let services_dir = state_dir.join("services");
+        fs::create_dir_all(&services_dir)?;
+        if collect_cores {
+            install_core_handler(&services_dir)?;
+            tokio::spawn(restore_core_pattern_on_exit(services_dir.clone()));
+        } else {
+            // in case the daemon was last run with --collect-cores and didn't get to put it back.
+            restore_core_pattern(&services_dir)?;
+        }
+        read_roots.push(services_dir);
+    } else if collect_cores {
+        anyhow::bail!("cores are only collected with the supervisor, use coredumpctl with systemd");
+    }
+    let path_policy = PathPolicy::new(read_roots, write_roots);
+    println!(
+        "allowing reads from {:?} and writes to {:?}",
+        path_policy.read_roots(),
+        path_policy.write_roots()
+    );
+
+    let follow_sessions = FollowSessions::default();
+    tokio::spawn(follow_sessions.clone().reap_idle(FOLLOW_IDLE_TTL));
+    if let Some(relative) = exec_allowlist.iter().find(|path| !path.is_absolute()) {
+        anyhow::bail!("executables allowed to run must be absolute paths, not {relative:?}");
+    }
+    let execs = Execs::new(exec_allowlist);
+    println!("allowing clients to run {:?}", execs.allowed());
+    tokio::spawn(execs.clone().reap_idle(EXEC_IDLE_TTL));
+    let backend: Arc<dyn ServiceBackend> = match service_backend {
+        BackendKind::Systemd => Arc::new(Systemd::default()),
+        BackendKind::Supervisor => {
+            let config = match &services {
+                Some(path) => SupervisorConfig::load(path)?,
+                None => SupervisorConfig::default(),
+            };
+            let supervisor = Arc::new(Supervisor::new(state_dir.join("services"), config));
+            if handed_over {
+                // the old daemon stops the services it runs before it exits, and records them for
+                // this one to start again.
+                let supervisor = supervisor.clone();
+                tokio::spawn(async move {
+                    update::old_daemon_exited().await;
+                    tokio::task::spawn_blocking(move || supervisor.start_services()).await
+                });
+            } else {
+                supervisor.start_services();
+            }
+            supervisor
+        }
+    };
+    let services = Services::new(backend, allow_wrappers);
+    let signals = Signals::default();
+    let features = Arc::new(info::features(service_backend, allow_wrappers));
+    println!("supporting {features:?}");
+
+    let listener = match inherited_listener {
+        Some(listener) => TcpListener::from_std(listener)?,
+        None => TcpListener::bind(addr).await?,
+    };
+    let updater = Updater::new(
+        allow_update,
+        std::env::current_exe()?,
+        std::env::args_os().skip(1).collect(),
+        state_dir.join("updates"),
+        listener.as_raw_fd(),
+    );
+    let listener = tls::serve_on(listener, cert, key, Bincode::default)?;
+    if let Some(ready) = ready_pipe {
+        update::signal_ready(ready);
+    }
     listener
         .filter_map(|r| {
             let transport = match r {
@@ -54,6 +328,17 @@ async fn main() -> anyhow::Result<()> {
                     .transport()
                     .peer_addr()
                     .expect("TODO: handle client closed connection"),
+                in_flight_transfers.clone(),
+                transfer_store.clone(),
+                blob_store.clone(),
+                dictionary_store.clone(),
+                path_policy.clone(),
+                follow_sessions.clone(),
+                execs.clone(),
+                services.clone(),
+                signals.clone(),
+                updater.clone(),
+                features.clone(),
             )
             .expect("unable to create agent");
             channel.execute(server.serve())
@@ -70,144 +355,1020 @@ pub enum AgentError {}
 #[derive(Clone)]
 struct Agent {
     _addr: SocketAddr,
-    in_flight_transfers: Arc<Mutex<HashMap<[u8; 32], InFlightTransfer>>>,
-}
-
-#[derive(Debug, Clone)]
-struct InFlightTransfer {
-    target_path: PathBuf,
-    target_perms: u32,
-    last_updated: Instant,
-    chunks: Vec<CompressedWireFileChunk>,
+    in_flight_transfers: InFlightTransfers,
+    transfer_store: TransferStore,
+    blob_store: BlobStore,
+    dictionary_store: DictionaryStore,
+    path_policy: PathPolicy,
+    follow_sessions: FollowSessions,
+    execs: Execs,
+    services: Services,
+    signals: Signals,
+    updater: Updater,
+    features: Arc<AgentFeatures>,
 }
 
 impl Agent {
-    fn new(addr: SocketAddr) -> Result<Self, AgentError> {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        addr: SocketAddr,
+        in_flight_transfers: InFlightTransfers,
+        transfer_store: TransferStore,
+        blob_store: BlobStore,
+        dictionary_store: DictionaryStore,
+        path_policy: PathPolicy,
+        follow_sessions: FollowSessions,
+        execs: Execs,
+        services: Services,
+        signals: Signals,
+        updater: Updater,
+        features: Arc<AgentFeatures>,
+    ) -> Result<Self, AgentError> {
         Ok(Self {
             _addr: addr,
-            in_flight_transfers: Arc::new(Mutex::new(HashMap::new())),
+            in_flight_transfers,
+            transfer_store,
+            blob_store,
+            dictionary_store,
+            path_policy,
+            follow_sessions,
+            execs,
+            services,
+            signals,
+            updater,
+            features,
         })
     }
 }
 
 #[tarpc::server]
 impl AgentService for Agent {
+    async fn hello(self, _: Context, req: HelloRequest) -> AgentInfo {
+        if req.protocol_version != PROTOCOL_VERSION {
+            println!(
+                "a client of version {} speaks protocol version {}, not {PROTOCOL_VERSION}",
+                req.client_version, req.protocol_version
+            );
+        }
+        AgentInfo {
+            agent_version: agent_version(),
+            protocol_version: PROTOCOL_VERSION,
+            features: self.features.as_ref().clone(),
+        }
+    }
+
     async fn put_file_chunk(self, _: Context, req: PutFileChunkRequest) -> PutFileChunkResponse {
         let PutFileChunkRequest {
-            file_hash,
+            chunk_size,
+            content_hash,
             target_perms,
             target_path,
+            target_owner,
+            target_group,
             chunk,
         } = req;
         let chunk_id = chunk.chunk_id;
-        let complete_transfer = {
+        let target_path = match self.path_policy.check_write(&target_path) {
+            Ok(target_path) => target_path,
+            Err(err) => {
+                println!("{err}");
+                return PutFileChunkResponse::Forbidden {
+                    chunk_id,
+                    path: target_path,
+                };
+            }
+        };
+        let key = transfer_key(&content_hash, &target_path);
+        if chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE {
+            println!("refusing chunks of {chunk_size} bytes");
+            return PutFileChunkResponse::Error { chunk_id };
+        }
+        let staging_path = {
             let mut lock = self.in_flight_transfers.lock().await;
-            {
-                let transfer = lock.entry(file_hash).or_insert_with(|| InFlightTransfer {
-                    last_updated: Instant::now(),
-                    target_path,
-                    target_perms,
-                    chunks: Vec::new(),
-                });
-                if transfer.chunks.iter().any(|c| c.chunk_id == chunk_id) {
-                    println!("already have chunk with id {chunk_id}");
-                    return PutFileChunkResponse::Duplicate { chunk_id };
-                }
-                transfer.last_updated = Instant::now();
-                transfer.chunks.push(chunk);
-
-                if transfer.chunks.len() == transfer.chunks[0].num_chunks as usize {
-                    lock.remove(&file_hash).expect("transfer must exist")
-                } else {
-                    return PutFileChunkResponse::Progress {
-                        chunk_id,
-                        seen_chunks: transfer.chunks.len() as u64,
+            let transfer = match lock.entry(key) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    let staging_path = match staging_path_for(&target_path) {
+                        Ok(staging_path) => staging_path,
+                        Err(err) => {
+                            println!(
+                                "err while staging transfer to {} {err:?}",
+                                target_path.display()
+                            );
+                            return PutFileChunkResponse::Error { chunk_id };
+                        }
+                    };
+                    let transfer = InFlightTransfer {
+                        filename: chunk.filename.clone(),
+                        target_path,
+                        target_perms,
+                        target_owner,
+                        target_group,
+                        num_chunks: chunk.num_chunks,
+                        chunk_size,
+                        codec: chunk.codec,
+                        content_hash,
+                        staging_path,
+                        last_updated: Instant::now(),
+                        received_chunks: BTreeSet::new(),
                     };
+                    if let Err(err) = self.transfer_store.create(&key, &transfer) {
+                        println!("err while persisting new transfer {err:?}");
+                        return PutFileChunkResponse::Error { chunk_id };
+                    }
+                    entry.insert(transfer)
                 }
+            };
+            if chunk_id >= transfer.num_chunks
+                || chunk_size != transfer.chunk_size
+                || chunk.codec != transfer.codec
+            {
+                println!(
+                    "chunk {chunk_id} of {chunk_size} bytes as {:?} does not fit transfer of {} chunks of {} bytes as {:?}",
+                    chunk.codec, transfer.num_chunks, transfer.chunk_size, transfer.codec
+                );
+                return PutFileChunkResponse::Error { chunk_id };
             }
+            if transfer.received_chunks.contains(&chunk_id) {
+                println!("already have chunk with id {chunk_id}");
+                return PutFileChunkResponse::Duplicate { chunk_id };
+            }
+            transfer.last_updated = Instant::now();
+            transfer.staging_path.clone()
         };
 
-        match CompressedWireFile::from_chunks(complete_transfer.chunks) {
-            Ok(file) => {
-                let b3_hash = file.blake3_hash();
-                if b3_hash != file_hash {
-                    println!("file hash mismatch - expected {file_hash:x?} got {b3_hash:x?}");
-                    return PutFileChunkResponse::Error { chunk_id };
-                }
-                let temp_path = match file.into_temp_file_on_disk() {
-                    Ok(temp_path) => temp_path,
-                    Err(err) => {
-                        println!("err while assembling file from chunks {err:?}");
-                        return PutFileChunkResponse::Error { chunk_id };
-                    }
+        let dictionary = match self.dictionary_for(chunk.codec) {
+            Ok(dictionary) => dictionary,
+            Err(err) => {
+                println!("err while loading dictionary for chunk {chunk_id} {err:?}");
+                return PutFileChunkResponse::Error { chunk_id };
+            }
+        };
+        // chunks of a transfer arrive concurrently, decompress and fsync them on the blocking pool
+        // rather than stalling the runtime.
+        let transfer_store = self.transfer_store.clone();
+        let written = tokio::task::spawn_blocking(move || {
+            transfer_store.write_chunk(&key, &staging_path, chunk_size, &chunk, dictionary.as_ref())
+        })
+        .await
+        .unwrap_or_else(|err| Err(std::io::Error::other(err)));
+        if let Err(err) = written {
+            println!("err while writing chunk {chunk_id} {err:?}");
+            return PutFileChunkResponse::Error { chunk_id };
+        }
+
+        let complete_transfer = {
+            let mut lock = self.in_flight_transfers.lock().await;
+            let Some(transfer) = lock.get_mut(&key) else {
+                // the transfer completed (or was dropped) while this chunk was being written.
+                return PutFileChunkResponse::Duplicate { chunk_id };
+            };
+            transfer.received_chunks.insert(chunk_id);
+            if !transfer.is_complete() {
+                return PutFileChunkResponse::Progress {
+                    chunk_id,
+                    seen_chunks: transfer.received_chunks.len() as u64,
                 };
+            }
+            lock.remove(&key).expect("transfer must exist")
+        };
 
-                println!("TODO do more than copy file to temp dir - this needs to implement the copy to dest as well.");
-                println!(
-                    "would write to disk: {}, with perms {target_perms} temp file in {}",
-                    temp_path.display(),
-                    complete_transfer.target_path.display()
-                );
+        let transfer_store = self.transfer_store.clone();
+        let blob_store = self.blob_store.clone();
+        let committed = tokio::task::spawn_blocking(move || {
+            transfers::commit(&transfer_store, &blob_store, &key, &complete_transfer)
+        })
+        .await;
+        match committed {
+            Ok(Ok(())) => PutFileChunkResponse::Complete {
+                chunk_id,
+                content_hash,
+            },
+            Ok(Err(message)) => {
+                println!("err while completing transfer {message}");
+                PutFileChunkResponse::Failed { chunk_id, message }
             }
             Err(err) => {
-                println!("err while assembling file from chunks {err:?}");
-                return PutFileChunkResponse::Error { chunk_id };
+                println!("err while completing transfer {err:?}");
+                PutFileChunkResponse::Error { chunk_id }
             }
         }
+    }
 
-        PutFileChunkResponse::Complete { chunk_id }
+    async fn list_transfers(self, _: Context) -> ListTransfersResponse {
+        let lock = self.in_flight_transfers.lock().await;
+        ListTransfersResponse::Success {
+            transfers: transfers::summaries(&lock),
+        }
+    }
+
+    async fn cancel_transfer(
+        self,
+        _: Context,
+        req: CancelTransferRequest,
+    ) -> CancelTransferResponse {
+        let Ok(target_path) = self.path_policy.check_write(&req.target_path) else {
+            return CancelTransferResponse::NotFound;
+        };
+        let key = transfer_key(&req.content_hash, &target_path);
+        let mut lock = self.in_flight_transfers.lock().await;
+        match transfers::cancel(&mut lock, &self.transfer_store, &key) {
+            Ok(Some(_)) => CancelTransferResponse::Cancelled,
+            Ok(None) => CancelTransferResponse::NotFound,
+            Err(err) => {
+                println!("err while removing cancelled transfer state {err:?}");
+                CancelTransferResponse::Error
+            }
+        }
+    }
+
+    async fn transfer_status(
+        self,
+        _: Context,
+        req: TransferStatusRequest,
+    ) -> TransferStatusResponse {
+        let Ok(target_path) = self.path_policy.check_write(&req.target_path) else {
+            return TransferStatusResponse::NotFound;
+        };
+        let key = transfer_key(&req.content_hash, &target_path);
+        let lock = self.in_flight_transfers.lock().await;
+        match lock.get(&key) {
+            Some(transfer) => TransferStatusResponse::InProgress {
+                num_chunks: transfer.num_chunks,
+                chunk_size: transfer.chunk_size,
+                codec: transfer.codec,
+                received_chunks: transfer.received_chunks.iter().copied().collect(),
+                missing_chunks: transfer.missing_chunks(),
+            },
+            None => TransferStatusResponse::NotFound,
+        }
     }
 
     async fn put_file(self, _ctx: Context, req: PutFileRequest) -> PutFileResponse {
         let PutFileRequest {
             target_path,
             target_perms,
+            target_owner,
+            target_group,
             file,
+            content_hash,
         } = req;
+        let target_path = match self.path_policy.check_write(&target_path) {
+            Ok(target_path) => target_path,
+            Err(err) => {
+                println!("{err}");
+                return PutFileResponse::Forbidden { path: target_path };
+            }
+        };
 
-        let temp_path = file
-            .into_temp_file_on_disk()
-            .expect("TODO - unable to write temp file");
-
-        println!("do more than copy file to temp dir - this needs to implement the copy to dest as well.");
+        let dictionary = match self.dictionary_for(file.codec) {
+            Ok(dictionary) => dictionary,
+            Err(err) => {
+                println!("err while loading dictionary {err:?}");
+                return PutFileResponse::Error;
+            }
+        };
+        if let Err(err) = file.write_to_disk_atomic(
+            &target_path,
+            target_perms,
+            target_owner,
+            target_group,
+            &content_hash,
+            dictionary.as_ref(),
+        ) {
+            println!(
+                "err while writing file to {} {err:?}",
+                target_path.display()
+            );
+            return PutFileResponse::Error;
+        }
+        // only once it has been checked against it's hash.
+        self.store_blob(&content_hash, &file);
         println!(
-            "would write to disk: {}, with perms {target_perms} temp file in {}",
-            temp_path.display(),
-            target_path.display()
+            "wrote {} with perms {target_perms:o} and hash {}",
+            target_path.display(),
+            hash_to_hex(&content_hash)
         );
-        PutFileResponse::Success
+        PutFileResponse::Success { content_hash }
     }
 
     async fn fetch_file(self, _ctx: Context, req: FetchFileRequest) -> FetchFileResponse {
         let FetchFileRequest {
             host_src_path,
             filename,
+            compression,
         } = req;
-        match CompressedWireFile::load_and_compress(&host_src_path, &filename) {
-            Ok(file) => FetchFileResponse::Success { file },
+        let host_src_path = match self.path_policy.check_read(&host_src_path) {
+            Ok(host_src_path) => host_src_path,
+            Err(err) => {
+                println!("{err}");
+                return FetchFileResponse::Forbidden {
+                    path: host_src_path,
+                };
+            }
+        };
+        let encoding = Encoding::new(compression, None);
+        let file = match CompressedWireFile::load_and_compress(&host_src_path, &filename, &encoding)
+        {
+            Ok(file) => file,
             Err(err) => {
                 println!("err while loading file for fetching {err:?}");
+                return FetchFileResponse::Error;
+            }
+        };
+        match blake3_hash_file(&host_src_path) {
+            Ok(content_hash) => FetchFileResponse::Success { file, content_hash },
+            Err(err) => {
+                println!("err while hashing file for fetching {err:?}");
                 FetchFileResponse::Error
             }
         }
     }
 
-    async fn stop_service(
+    async fn fetch_file_chunk(
         self,
         _ctx: Context,
-        _request: StartServiceRequest,
-    ) -> StartServiceResponse {
-        todo!()
+        req: FetchFileChunkRequest,
+    ) -> FetchFileChunkResponse {
+        let FetchFileChunkRequest {
+            host_src_path,
+            chunk_id,
+            chunk_size,
+            compression,
+        } = req;
+        let host_src_path = match self.path_policy.check_read(&host_src_path) {
+            Ok(host_src_path) => host_src_path,
+            Err(err) => {
+                println!("{err}");
+                return FetchFileChunkResponse::Forbidden {
+                    path: host_src_path,
+                };
+            }
+        };
+        if chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE {
+            println!("refusing to fetch chunks of {chunk_size} bytes");
+            return FetchFileChunkResponse::Error;
+        }
+        let metadata = match fs::metadata(&host_src_path) {
+            Ok(metadata) => metadata,
+            Err(err) => {
+                println!(
+                    "err while reading metadata of {} {err:?}",
+                    host_src_path.display()
+                );
+                return FetchFileChunkResponse::Error;
+            }
+        };
+        let (file_size, modified) = match metadata.modified() {
+            Ok(modified) => (metadata.len(), modified),
+            Err(err) => {
+                println!("err while reading mtime {err:?}");
+                return FetchFileChunkResponse::Error;
+            }
+        };
+        let chunk = match CompressedWireFileChunk::load_and_compress_range(
+            &host_src_path,
+            file_size,
+            chunk_id,
+            chunk_size,
+            &Encoding::new(compression, None),
+        ) {
+            Ok(chunk) => chunk,
+            Err(err) => {
+                println!("err while loading chunk {chunk_id} for fetching {err:?}");
+                return FetchFileChunkResponse::Error;
+            }
+        };
+        FetchFileChunkResponse::Success {
+            file_size,
+            modified,
+            chunk,
+        }
+    }
+
+    async fn has_blobs(self, _: Context, req: HasBlobsRequest) -> HasBlobsResponse {
+        let present = req
+            .hashes
+            .into_iter()
+            .filter(|hash| self.blob_store.contains(hash))
+            .collect();
+        HasBlobsResponse::Success { present }
+    }
+
+    async fn put_file_from_blob(self, _: Context, req: PutFileFromBlobRequest) -> PutFileResponse {
+        let PutFileFromBlobRequest {
+            content_hash,
+            target_perms,
+            target_path,
+            target_owner,
+            target_group,
+        } = req;
+        let target_path = match self.path_policy.check_write(&target_path) {
+            Ok(target_path) => target_path,
+            Err(err) => {
+                println!("{err}");
+                return PutFileResponse::Forbidden { path: target_path };
+            }
+        };
+        let (codec, reader) = match self.blob_store.open(&content_hash) {
+            Ok(blob) => blob,
+            Err(err) => {
+                println!(
+                    "err while loading blob {} {err:?}",
+                    hash_to_hex(&content_hash)
+                );
+                return PutFileResponse::Error;
+            }
+        };
+        let dictionary = match self.dictionary_for(codec) {
+            Ok(dictionary) => dictionary,
+            Err(err) => {
+                println!("err while loading dictionary {err:?}");
+                return PutFileResponse::Error;
+            }
+        };
+        // decoded straight from the blob on disk into the staging file.
+        let written = codec
+            .decoder(reader, dictionary.as_ref())
+            .and_then(|decoder| {
+                write_file_atomic(
+                    decoder,
+                    &target_path,
+                    target_perms,
+                    target_owner,
+                    target_group,
+                    &content_hash,
+                )
+            });
+        if let Err(err) = written {
+            println!(
+                "err while writing blob to {} {err:?}",
+                target_path.display()
+            );
+            self.drop_blob_if_invalid(&content_hash, &err);
+            return PutFileResponse::Error;
+        }
+        println!(
+            "wrote blob {} to {} with perms {target_perms:o}",
+            hash_to_hex(&content_hash),
+            target_path.display()
+        );
+        PutFileResponse::Success { content_hash }
     }
 
-    async fn start_service(
+    async fn gc_blobs(self, _: Context, req: GcBlobsRequest) -> GcBlobsResponse {
+        let policy = BlobGcPolicy {
+            max_age: req.max_age_secs.map(Duration::from_secs),
+            max_total_bytes: req.max_total_bytes,
+        };
+        match self.blob_store.gc(policy) {
+            Ok(stats) => GcBlobsResponse::Success {
+                removed: stats.removed,
+                remaining: stats.remaining,
+                remaining_bytes: stats.remaining_bytes,
+            },
+            Err(err) => {
+                println!("err during blob gc {err:?}");
+                GcBlobsResponse::Error
+            }
+        }
+    }
+
+    async fn put_dir(self, _ctx: Context, req: PutDirRequest) -> PutDirResponse {
+        let PutDirRequest {
+            target_dir,
+            archive,
+            replace,
+        } = req;
+        // a root can't be replaced, nor unpacked over, as what's in it is the whole of what's
+        // allowed to be written.
+        let target_dir = match self.path_policy.check_write_below_root(&target_dir) {
+            Ok(target_dir) => target_dir,
+            Err(err) => {
+                println!("{err}");
+                return PutDirResponse::Forbidden { path: target_dir };
+            }
+        };
+        let unpacked = if replace {
+            unpack_dir_replacing(archive, &target_dir)
+        } else {
+            unpack_dir(archive, &target_dir)
+        };
+        match unpacked {
+            Ok(entries) => {
+                println!("unpacked {entries} entries into {}", target_dir.display());
+                PutDirResponse::Success { entries }
+            }
+            Err(err) => {
+                println!("err while unpacking dir {err:?}");
+                PutDirResponse::Error
+            }
+        }
+    }
+
+    async fn fetch_dir(self, _ctx: Context, req: FetchDirRequest) -> FetchDirResponse {
+        let FetchDirRequest {
+            host_src_dir,
+            filter,
+        } = req;
+        let host_src_dir = match self.path_policy.check_read(&host_src_dir) {
+            Ok(host_src_dir) => host_src_dir,
+            Err(err) => {
+                println!("{err}");
+                return FetchDirResponse::Forbidden { path: host_src_dir };
+            }
+        };
+        match inspect::list_tree(&host_src_dir, &filter) {
+            Ok(entries) => FetchDirResponse::Success {
+                dir: host_src_dir,
+                entries,
+            },
+            Err(err @ MessageError::TooManyEntries { .. }) => {
+                println!("{err}");
+                FetchDirResponse::TooManyEntries
+            }
+            Err(err) => {
+                println!("err while listing dir for fetching {err:?}");
+                FetchDirResponse::Error
+            }
+        }
+    }
+
+    async fn stat(self, _: Context, req: StatRequest) -> StatResponse {
+        let path = match self.path_policy.check_read_no_follow(&req.path) {
+            Ok(path) => path,
+            Err(err) => {
+                println!("{err}");
+                return StatResponse::Forbidden { path: req.path };
+            }
+        };
+        match inspect::stat(&path) {
+            Ok(stat) => StatResponse::Success { stat },
+            Err(err) if err.kind() == ErrorKind::NotFound => StatResponse::NotFound,
+            Err(err) => {
+                println!("err while stating {} {err:?}", path.display());
+                StatResponse::Error
+            }
+        }
+    }
+
+    async fn list_dir(self, _: Context, req: ListDirRequest) -> ListDirResponse {
+        let ListDirRequest {
+            path,
+            recursive,
+            glob,
+        } = req;
+        let path = match self.path_policy.check_read(&path) {
+            Ok(resolved) => resolved,
+            Err(err) => {
+                println!("{err}");
+                return ListDirResponse::Forbidden { path };
+            }
+        };
+        let glob = match glob.as_deref().map(Pattern::new).transpose() {
+            Ok(glob) => glob,
+            Err(err) => {
+                println!("invalid glob {err:?}");
+                return ListDirResponse::Error;
+            }
+        };
+        match inspect::list_dir(&path, recursive, glob.as_ref()) {
+            Ok((entries, truncated)) => ListDirResponse::Success { entries, truncated },
+            Err(err) if err.kind() == ErrorKind::NotFound => ListDirResponse::NotFound,
+            Err(err) => {
+                println!("err while listing {} {err:?}", path.display());
+                ListDirResponse::Error
+            }
+        }
+    }
+
+    async fn checksum(self, _: Context, req: ChecksumRequest) -> ChecksumResponse {
+        let path = match self.path_policy.check_read(&req.path) {
+            Ok(path) => path,
+            Err(err) => {
+                println!("{err}");
+                return ChecksumResponse::Forbidden { path: req.path };
+            }
+        };
+        // hashing a large file takes a while, keep it off the connection's task.
+        let hashed_path = path.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            fs::metadata(&hashed_path).and_then(|metadata| {
+                let content_hash = blake3_hash_file(&hashed_path)?;
+                Ok((metadata.len(), content_hash))
+            })
+        })
+        .await
+        .unwrap_or_else(|err| Err(std::io::Error::other(err)));
+        match result {
+            Ok((size, content_hash)) => ChecksumResponse::Success { size, content_hash },
+            Err(err) if err.kind() == ErrorKind::NotFound => ChecksumResponse::NotFound,
+            Err(err) => {
+                println!("err while hashing {} {err:?}", path.display());
+                ChecksumResponse::Error
+            }
+        }
+    }
+
+    async fn disk_usage(self, _: Context) -> DiskUsageResponse {
+        match inspect::disk_usage() {
+            Ok(mounts) => DiskUsageResponse::Success { mounts },
+            Err(err) => {
+                println!("err while reading disk usage {err:?}");
+                DiskUsageResponse::Error
+            }
+        }
+    }
+
+    async fn follow_file(self, _: Context, req: FollowFileRequest) -> FollowFileResponse {
+        let path = match self.path_policy.check_read(&req.path) {
+            Ok(path) => path,
+            Err(err) => {
+                println!("{err}");
+                return FollowFileResponse::Forbidden { path: req.path };
+            }
+        };
+        match self.follow_sessions.start(&path, req.from).await {
+            Ok(follow_id) => FollowFileResponse::Started { follow_id },
+            Err(err) if err.kind() == ErrorKind::NotFound => FollowFileResponse::NotFound,
+            Err(err) => {
+                println!("err while following {} {err:?}", path.display());
+                FollowFileResponse::Error
+            }
+        }
+    }
+
+    async fn poll_follow(self, _: Context, req: PollFollowRequest) -> PollFollowResponse {
+        let wait = Duration::from_millis(req.wait_millis.min(MAX_FOLLOW_WAIT_MILLIS));
+        match self.follow_sessions.poll(req.follow_id, wait).await {
+            Some(Ok(FollowedLines {
+                lines,
+                offset,
+                rotated,
+            })) => PollFollowResponse::Lines {
+                lines,
+                offset,
+                rotated,
+            },
+            Some(Err(err)) => {
+                println!("err while polling follow {} {err:?}", req.follow_id);
+                PollFollowResponse::Error
+            }
+            None => PollFollowResponse::NotFound,
+        }
+    }
+
+    async fn stop_follow(self, _: Context, req: StopFollowRequest) -> StopFollowResponse {
+        if self.follow_sessions.stop(req.follow_id).await {
+            StopFollowResponse::Stopped
+        } else {
+            StopFollowResponse::NotFound
+        }
+    }
+
+    async fn exec(self, _: Context, req: ExecRequest) -> ExecResponse {
+        match self.execs.start(req).await {
+            Ok(exec_id) => ExecResponse::Started { exec_id },
+            Err(err @ (ExecError::NotAllowed(_) | ExecError::EnvNotAllowed(_))) => {
+                println!("{err}");
+                ExecResponse::Forbidden {
+                    message: err.to_string(),
+                }
+            }
+            Err(err @ ExecError::Spawn(..)) => ExecResponse::Failed {
+                message: err.to_string(),
+            },
+        }
+    }
+
+    async fn poll_exec(self, _: Context, req: PollExecRequest) -> PollExecResponse {
+        let wait = Duration::from_millis(req.wait_millis.min(MAX_EXEC_WAIT_MILLIS));
+        match self.execs.poll(req.exec_id, wait).await {
+            Some(ExecOutput {
+                chunks,
+                exit,
+                timed_out,
+            }) => PollExecResponse::Output {
+                chunks,
+                exit,
+                timed_out,
+            },
+            None => PollExecResponse::NotFound,
+        }
+    }
+
+    async fn remove(self, _: Context, req: RemoveRequest) -> RemoveResponse {
+        let RemoveRequest {
+            path,
+            recursive,
+            dry_run,
+        } = req;
+        let path = match self.path_policy.check_write_no_follow(&path) {
+            Ok(resolved) => resolved,
+            Err(err) => {
+                println!("{err}");
+                return RemoveResponse::Forbidden { path };
+            }
+        };
+        match fileops::remove(&path, recursive, dry_run) {
+            Ok((removed, count)) => {
+                if !dry_run {
+                    println!("removed {} ({count} entries)", path.display());
+                }
+                RemoveResponse::Success { removed, count }
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => RemoveResponse::NotFound,
+            Err(err) if err.kind() == ErrorKind::DirectoryNotEmpty => RemoveResponse::NotEmpty,
+            Err(err) => {
+                println!("err while removing {} {err:?}", path.display());
+                RemoveResponse::Error
+            }
+        }
+    }
+
+    async fn rename(self, _: Context, req: RenameRequest) -> RenameResponse {
+        let RenameRequest {
+            from,
+            to,
+            overwrite,
+        } = req;
+        let (from, to) = match (
+            self.path_policy.check_write_no_follow(&from),
+            self.path_policy.check_write_no_follow(&to),
+        ) {
+            (Ok(from), Ok(to)) => (from, to),
+            (Err(err), _) | (_, Err(err)) => {
+                println!("{err}");
+                let MessageError::Forbidden { path } = err else {
+                    return RenameResponse::Error;
+                };
+                return RenameResponse::Forbidden { path };
+            }
+        };
+        match fileops::rename(&from, &to, overwrite) {
+            Ok(()) => {
+                println!("renamed {} to {}", from.display(), to.display());
+                RenameResponse::Success
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => RenameResponse::NotFound,
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => RenameResponse::AlreadyExists,
+            Err(err) => {
+                println!(
+                    "err while renaming {} to {} {err:?}",
+                    from.display(),
+                    to.display()
+                );
+                RenameResponse::Error
+            }
+        }
+    }
+
+    async fn mkdir(self, _: Context, req: MkdirRequest) -> MkdirResponse {
+        let path = match self.path_policy.check_write(&req.path) {
+            Ok(path) => path,
+            Err(err) => {
+                println!("{err}");
+                return MkdirResponse::Forbidden { path: req.path };
+            }
+        };
+        match fileops::mkdir(&path, req.mode) {
+            Ok(created) => MkdirResponse::Success { created },
+            Err(err) => {
+                println!("err while creating {} {err:?}", path.display());
+                MkdirResponse::Error
+            }
+        }
+    }
+
+    async fn has_dictionary(self, _: Context, req: HasDictionaryRequest) -> HasDictionaryResponse {
+        if self.dictionary_store.contains(&req.dict_hash) {
+            HasDictionaryResponse::Present
+        } else {
+            HasDictionaryResponse::Missing
+        }
+    }
+
+    async fn put_dictionary(self, _: Context, req: PutDictionaryRequest) -> PutDictionaryResponse {
+        let dictionary = Dictionary::new(req.dictionary);
+        match self.dictionary_store.insert(&dictionary) {
+            Ok(()) => {
+                println!("stored dictionary {}", hash_to_hex(&dictionary.hash()));
+                PutDictionaryResponse::Success {
+                    dict_hash: dictionary.hash(),
+                }
+            }
+            Err(err) => {
+                println!("err while storing dictionary {err:?}");
+                PutDictionaryResponse::Error
+            }
+        }
+    }
+
+    async fn update_agent(self, _: Context, request: AgentUpdateRequest) -> AgentUpdateResponse {
+        let new_version = request.new_version;
+        match self.updater.update(request).await {
+            Ok(new_pid) => {
+                println!("updated to version {new_version}, handing over to {new_pid}");
+                tokio::spawn(update::hand_over(new_pid, self.services.clone()));
+                AgentUpdateResponse::Success {
+                    old_version: agent_version(),
+                    new_version,
+                    new_pid,
+                }
+            }
+            Err(err @ (UpdateError::NotAllowed | UpdateError::InProgress)) => {
+                AgentUpdateResponse::Forbidden {
+                    message: err.to_string(),
+                }
+            }
+            Err(UpdateError::Invalid(message)) => AgentUpdateResponse::Invalid { message },
+            Err(UpdateError::RolledBack(message)) => {
+                println!("rolled back the update to version {new_version}, {message}");
+                AgentUpdateResponse::RolledBack { message }
+            }
+            Err(UpdateError::Io(err)) => {
+                println!("err while updating to version {new_version} {err:?}");
+                AgentUpdateResponse::Error
+            }
+        }
+    }
+
+    async fn host_info(self, _: Context) -> HostInfoResponse {
+        let host = tokio::time::timeout(
+            HOST_INFO_TIMEOUT,
+            tokio::task::spawn_blocking(info::host_info),
+        )
+        .await;
+        match host {
+            Ok(Ok(Ok(host))) => HostInfoResponse::Success { host },
+            Ok(Ok(Err(err))) => {
+                println!("err while reading host info {err:?}");
+                HostInfoResponse::Error
+            }
+            Ok(Err(err)) => {
+                println!("err while reading host info {err:?}");
+                HostInfoResponse::Error
+            }
+            Err(_) => {
+                println!(
+                    "err while reading host info, it took more than {}s",
+                    HOST_INFO_TIMEOUT.as_secs()
+                );
+                HostInfoResponse::Error
+            }
+        }
+    }
+
+    async fn stop_service(self, _: Context, req: StopServiceRequest) -> StopServiceResponse {
+        self.services.stop(req.service).await
+    }
+
+    async fn start_service(self, _: Context, req: StartServiceRequest) -> StartServiceResponse {
+        self.services.start(req).await
+    }
+
+    async fn restart_service(
+        self,
+        _: Context,
+        req: RestartServiceRequest,
+    ) -> RestartServiceResponse {
+        self.services.restart(req.service).await
+    }
+
+    async fn enable_service(self, _: Context, req: EnableServiceRequest) -> EnableServiceResponse {
+        self.services.enable(req.service).await
+    }
+
+    async fn service_status(self, _: Context, req: ServiceStatusRequest) -> ServiceStatusResponse {
+        self.services.status(req.service).await
+    }
+
+    async fn fetch_debug_output(
+        self,
+        _: Context,
+        req: FetchDebugOutputRequest,
+    ) -> FetchDebugOutputResponse {
+        self.services.fetch_debug_output(req).await
+    }
+
+    async fn signal(self, _: Context, req: SignalRequest) -> SignalResponse {
+        let pid = match req.target {
+            SignalTarget::Pid(pid) => match self.may_signal(pid).await {
+                Ok(true) => pid,
+                Ok(false) => {
+                    println!("refusing to signal {pid}, which isn't a service or node process");
+                    return SignalResponse::Forbidden;
+                }
+                Err(err) if err.kind() == ErrorKind::NotFound => return SignalResponse::NotFound,
+                Err(err) => {
+                    println!("err while checking whether {pid} may be signalled {err:?}");
+                    return SignalResponse::Error;
+                }
+            },
+            SignalTarget::Service(service) => match self.services.main_pid(service.clone()).await {
+                Ok(Some(pid)) => pid,
+                Ok(None) | Err(ServiceError::NotFound) => return SignalResponse::NotFound,
+                Err(err) => {
+                    println!("err while finding the main pid of {service} {err:?}");
+                    return SignalResponse::Error;
+                }
+            },
+        };
+        let resume_after = req.resume_after_secs.map(Duration::from_secs);
+        match self.signals.send(pid, req.signal, resume_after).await {
+            Ok(()) => SignalResponse::Sent { pid },
+            Err(err) if err.kind() == ErrorKind::NotFound => SignalResponse::NotFound,
+            Err(err) if err.kind() == ErrorKind::PermissionDenied => SignalResponse::Forbidden,
+            Err(err) => {
+                println!("err while sending {} to {pid} {err:?}", req.signal);
+                SignalResponse::Error
+            }
+        }
+    }
+
+    async fn enable_core_dumps(
         self,
         _: Context,
-        _request: StartServiceRequest,
-    ) -> StartServiceResponse {
-        for i in 0..10 {
-            println!("did some work {i}");
+        req: EnableCoreDumpsRequest,
+    ) -> EnableCoreDumpsResponse {
+        self.services.enable_core_dumps(req.service).await
+    }
+
+    async fn list_crashes(self, _: Context, req: ListCrashesRequest) -> ListCrashesResponse {
+        self.services.list_crashes(req.service).await
+    }
+
+    async fn fetch_crash(self, _: Context, req: FetchCrashRequest) -> FetchCrashResponse {
+        self.services.fetch_crash(req).await
+    }
+
+    async fn find_node_processes(
+        self,
+        _: Context,
+        req: FindNodeProcessesRequest,
+    ) -> FindNodeProcessesResponse {
+        match procfs::find_node_processes(&req.name) {
+            Ok(processes) => FindNodeProcessesResponse::Success { processes },
+            Err(err) => {
+                println!("err while finding {} processes {err:?}", req.name);
+                FindNodeProcessesResponse::Error
+            }
+        }
+    }
+}
+
+impl Agent {
+    /// Whether a client may signal `pid`, which must be the main process of a service, a node
+    /// process (or it's launcher), or a descendant of one of those.
+    async fn may_signal(&self, pid: u32) -> Result<bool, std::io::Error> {
+        let main_pids = self
+            .services
+            .main_pids()
+            .await
+            .map_err(std::io::Error::other)?;
+        tokio::task::spawn_blocking(move || {
+            let mut allowed = main_pids.into_iter().collect::<HashSet<_>>();
+            for process in procfs::find_node_processes(procfs::NODE_BINARY)? {
+                allowed.insert(process.pid);
+                allowed.extend(process.launcher_pid);
+            }
+            procfs::descends_from(pid, &allowed)
+        })
+        .await
+        .unwrap_or_else(|err| Err(std::io::Error::other(err)))
+    }
+
+    /// Load the dictionary needed to decode data encoded with `codec`, if any.
+    fn dictionary_for(&self, codec: Codec) -> Result<Option<Dictionary>, std::io::Error> {
+        codec
+            .dict_hash()
+            .map(|dict_hash| self.dictionary_store.load(&dict_hash))
+            .transpose()
+    }
+
+    /// Keep a copy of an uploaded file in the blob store. Failing to do so only means it will need
+    /// to be uploaded again next time, so it isn't treated as an error.
+    fn store_blob(&self, content_hash: &[u8; 32], file: &CompressedWireFile) {
+        if let Err(err) = self.blob_store.insert(
+            content_hash,
+            file.codec,
+            file.zstd_compressed_data.as_slice(),
+        ) {
+            println!("err while storing blob for {} {err:?}", file.filename);
+        }
+    }
+
+    /// Drop the blob for `content_hash` if writing it failed because it didn't decode to that
+    /// hash, as a corrupt blob is worse than a missing one.
+    fn drop_blob_if_invalid(&self, content_hash: &[u8; 32], err: &std::io::Error) {
+        if err.kind() != std::io::ErrorKind::InvalidData {
+            return;
+        }
+        if let Err(err) = self.blob_store.remove(content_hash) {
+            println!(
+                "err while removing blob {} {err:?}",
+                hash_to_hex(content_hash)
+            );
         }
-        StartServiceResponse::Error
     }
 }
@@ -0,0 +1,369 @@
+use std::{
+    collections::HashSet,
+    ffi::OsStr,
+    fs,
+    io::ErrorKind,
+    net::{IpAddr, SocketAddr},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use agent_lib::{ListeningSocket, NodeProcess, ProcessStats, Protocol};
+
+const LAUNCHER: &str = "casper-node-launcher";
+/// The file name of the node's binary.
+pub const NODE_BINARY: &str = "casper-node";
+
+fn proc_dir(pid: u32) -> PathBuf {
+    PathBuf::from(format!("/proc/{pid}"))
+}
+
+fn invalid_data(what: &str) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, what.to_string())
+}
+
+/// Resource use of a running process, from `/proc/<pid>/stat` and `/proc/<pid>/fd`.
+pub fn process_stats(pid: u32) -> Result<ProcessStats, std::io::Error> {
+    let stat = fs::read_to_string(proc_dir(pid).join("stat"))?;
+    let uptime = fs::read_to_string("/proc/uptime")?;
+    let system_uptime_secs = uptime
+        .split_whitespace()
+        .next()
+        .and_then(|secs| secs.parse::<f64>().ok())
+        .ok_or_else(|| invalid_data("unable to parse /proc/uptime"))?;
+    let open_fds = match fs::read_dir(proc_dir(pid).join("fd")) {
+        Ok(fds) => Some(fds.count() as u64),
+        Err(err) if err.kind() == ErrorKind::PermissionDenied => None,
+        Err(err) => return Err(err),
+    };
+    // SAFETY: sysconf has no memory safety requirements.
+    let (ticks_per_sec, page_size) = unsafe {
+        (
+            libc::sysconf(libc::_SC_CLK_TCK),
+            libc::sysconf(libc::_SC_PAGESIZE),
+        )
+    };
+    parse_stat(
+        &stat,
+        system_uptime_secs,
+        ticks_per_sec.max(1) as u64,
+        page_size.max(1) as u64,
+        open_fds,
+    )
+}
+
+/// When a process started, in clock ticks since boot, to tell it apart from a later process that
+/// reused it's pid.
+pub fn start_time(pid: u32) -> Result<u64, std::io::Error> {
+    stat_field(&fs::read_to_string(proc_dir(pid).join("stat"))?, 22)
+}
+
+/// The pid of a process's parent, 0 for init.
+pub fn parent_pid(pid: u32) -> Result<u32, std::io::Error> {
+    Ok(stat_field(&fs::read_to_string(proc_dir(pid).join("stat"))?, 4)? as u32)
+}
+
+/// Whether `pid` is one of `ancestors`, or a descendant of one.
+pub fn descends_from(pid: u32, ancestors: &HashSet<u32>) -> Result<bool, std::io::Error> {
+    let mut current = pid;
+    while current > 1 {
+        if ancestors.contains(&current) {
+            return Ok(true);
+        }
+        current = parent_pid(current)?;
+    }
+    Ok(false)
+}
+
+/// Field `n` of `/proc/<pid>/stat`, numbered as in proc(5).
+fn stat_field(stat: &str, n: usize) -> Result<u64, std::io::Error> {
+    // the command name is in parentheses and may contain anything, including spaces and ')'.
+    let (_, fields) = stat
+        .rsplit_once(')')
+        .ok_or_else(|| invalid_data("unable to parse /proc/<pid>/stat"))?;
+    // the fields after it start from the state, the 3rd.
+    fields
+        .split_whitespace()
+        .nth(n - 3)
+        .and_then(|field| field.parse::<u64>().ok())
+        .ok_or_else(|| invalid_data("unable to parse /proc/<pid>/stat"))
+}
+
+/// Parse `/proc/<pid>/stat`, see proc(5).
+fn parse_stat(
+    stat: &str,
+    system_uptime_secs: f64,
+    ticks_per_sec: u64,
+    page_size: u64,
+    open_fds: Option<u64>,
+) -> Result<ProcessStats, std::io::Error> {
+    let field = |n: usize| stat_field(stat, n);
+    let ticks_to_millis = |ticks: u64| ticks * 1000 / ticks_per_sec;
+    let started_secs = field(22)? as f64 / ticks_per_sec as f64;
+    Ok(ProcessStats {
+        uptime_secs: (system_uptime_secs - started_secs).max(0.0) as u64,
+        rss_bytes: field(24)? * page_size,
+        user_cpu_millis: ticks_to_millis(field(14)?),
+        system_cpu_millis: ticks_to_millis(field(15)?),
+        open_fds,
+        threads: field(20)?,
+    })
+}
+
+/// Find the processes running a binary with the file name `name`, e.g. `casper-node`, whichever
+/// directory it's in.
+pub fn find_node_processes(name: &str) -> Result<Vec<NodeProcess>, std::io::Error> {
+    let mut processes = Vec::new();
+    for entry in fs::read_dir("/proc")? {
+        let pid = match entry?.file_name().to_str().map(str::parse::<u32>) {
+            Some(Ok(pid)) => pid,
+            _ => continue,
+        };
+        match node_process(pid, name) {
+            Ok(Some(process)) => processes.push(process),
+            Ok(None) => {}
+            // exited while we were looking at it, or isn't ours to look at.
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    ErrorKind::NotFound | ErrorKind::PermissionDenied
+                ) => {}
+            Err(err) => return Err(err),
+        }
+    }
+    processes.sort_by_key(|process| process.pid);
+    Ok(processes)
+}
+
+fn node_process(pid: u32, name: &str) -> Result<Option<NodeProcess>, std::io::Error> {
+    let cmdline = fs::read(proc_dir(pid).join("cmdline"))?;
+    let binary = process_binary(pid, &cmdline)?;
+    if binary.file_name() != Some(OsStr::new(name)) {
+        return Ok(None);
+    }
+    let parent_pid = parent_pid(pid)?;
+    let launcher_pid = fs::read(proc_dir(parent_pid).join("cmdline"))
+        .and_then(|cmdline| process_binary(parent_pid, &cmdline))
+        .ok()
+        .filter(|parent| parent.file_name() == Some(OsStr::new(LAUNCHER)))
+        .map(|_| parent_pid);
+    let version = version_dir(&binary);
+    let listening = match listening_sockets(pid) {
+        Ok(listening) => listening,
+        Err(err) if err.kind() == ErrorKind::PermissionDenied => Vec::new(),
+        Err(err) => return Err(err),
+    };
+    Ok(Some(NodeProcess {
+        pid,
+        parent_pid,
+        launcher_pid,
+        binary,
+        version,
+        args: cmdline_args(&cmdline).skip(1).collect(),
+        listening,
+    }))
+}
+
+/// The binary a process is running, or if we aren't allowed to see it, the path it was run as.
+pub fn process_binary(pid: u32, cmdline: &[u8]) -> Result<PathBuf, std::io::Error> {
+    match fs::read_link(proc_dir(pid).join("exe")) {
+        // the launcher replaces binaries on upgrade, while the old one may still be running.
+        Ok(exe) => Ok(
+            match exe.to_str().and_then(|exe| exe.strip_suffix(" (deleted)")) {
+                Some(exe) => PathBuf::from(exe),
+                None => exe,
+            },
+        ),
+        Err(err) if err.kind() == ErrorKind::PermissionDenied => {
+            cmdline_args(cmdline).next().map(PathBuf::from).ok_or(err)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+pub fn cmdline_args(cmdline: &[u8]) -> impl Iterator<Item = String> + '_ {
+    let cmdline = cmdline.strip_suffix(&[0]).unwrap_or(cmdline);
+    cmdline
+        .split(|byte| *byte == 0)
+        .filter(move |_| !cmdline.is_empty())
+        .map(|arg| OsStr::from_bytes(arg).to_string_lossy().into_owned())
+}
+
+/// The name of the directory a binary is in, if it's one of the launcher's version directories.
+pub fn version_dir(binary: &Path) -> Option<String> {
+    binary
+        .parent()
+        .and_then(|dir| dir.file_name())
+        .and_then(|dir| dir.to_str())
+        .filter(|dir| is_version(dir))
+        .map(String::from)
+}
+
+/// Whether a directory is named like the launcher's version directories, e.g. `1_5_2`.
+fn is_version(dir: &str) -> bool {
+    dir.starts_with(|c: char| c.is_ascii_digit())
+        && dir
+            .chars()
+            .all(|c| c.is_ascii_digit() || c == '_' || c == '.')
+}
+
+/// The TCP sockets a process listens on, and the UDP sockets it has bound, matched by inode from
+/// it's fds to the tables in `/proc/<pid>/net`, which are those of it's network namespace.
+fn listening_sockets(pid: u32) -> Result<Vec<ListeningSocket>, std::io::Error> {
+    let mut inodes = HashSet::new();
+    for fd in fs::read_dir(proc_dir(pid).join("fd"))? {
+        // fds may be closed while we look at them.
+        let target = match fs::read_link(fd?.path()) {
+            Ok(target) => target,
+            Err(_) => continue,
+        };
+        let inode = target
+            .to_str()
+            .and_then(|target| target.strip_prefix("socket:["))
+            .and_then(|target| target.strip_suffix(']'))
+            .and_then(|inode| inode.parse::<u64>().ok());
+        inodes.extend(inode);
+    }
+
+    let mut sockets = Vec::new();
+    for (table, protocol) in [
+        ("tcp", Protocol::Tcp),
+        ("tcp6", Protocol::Tcp),
+        ("udp", Protocol::Udp),
+        ("udp6", Protocol::Udp),
+    ] {
+        let table = match fs::read_to_string(proc_dir(pid).join("net").join(table)) {
+            Ok(table) => table,
+            // without IPv6.
+            Err(err) if err.kind() == ErrorKind::NotFound => continue,
+            Err(err) => return Err(err),
+        };
+        sockets.extend(
+            parse_net_table(&table, protocol)?
+                .into_iter()
+                .filter(|(inode, _)| inodes.contains(inode))
+                .map(|(_, socket)| socket),
+        );
+    }
+    sockets.sort();
+    sockets.dedup();
+    Ok(sockets)
+}
+
+/// Parse the listening sockets, with their inodes, from `/proc/net/{tcp,tcp6,udp,udp6}`.
+fn parse_net_table(
+    table: &str,
+    protocol: Protocol,
+) -> Result<Vec<(u64, ListeningSocket)>, std::io::Error> {
+    let invalid = || invalid_data("unable to parse /proc/<pid>/net");
+    let mut sockets = Vec::new();
+    // the first line is a header.
+    for line in table.lines().skip(1) {
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        let (local, state, inode) = match (fields.get(1), fields.get(3), fields.get(9)) {
+            (Some(local), Some(state), Some(inode)) => (local, state, inode),
+            _ => return Err(invalid()),
+        };
+        // TCP_LISTEN, or TCP_CLOSE for a UDP socket that's bound but not connected.
+        let listening = match protocol {
+            Protocol::Tcp => *state == "0A",
+            Protocol::Udp => *state == "07",
+        };
+        if !listening {
+            continue;
+        }
+        let addr = parse_net_addr(local).ok_or_else(invalid)?;
+        let inode = inode.parse::<u64>().map_err(|_| invalid())?;
+        sockets.push((inode, ListeningSocket { protocol, addr }));
+    }
+    Ok(sockets)
+}
+
+/// Parse an address like `0100007F:1F90`, the IP as hex 32 bit words in host byte order.
+fn parse_net_addr(addr: &str) -> Option<SocketAddr> {
+    let (ip, port) = addr.split_once(':')?;
+    let port = u16::from_str_radix(port, 16).ok()?;
+    let mut bytes = Vec::with_capacity(16);
+    for word in 0..ip.len() / 8 {
+        let word = u32::from_str_radix(ip.get(word * 8..word * 8 + 8)?, 16).ok()?;
+        bytes.extend(word.to_ne_bytes());
+    }
+    let ip = match bytes.len() {
+        4 => IpAddr::from(<[u8; 4]>::try_from(bytes).ok()?),
+        16 => IpAddr::from(<[u8; 16]>::try_from(bytes).ok()?),
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{TcpListener, UdpSocket};
+
+    use super::*;
+
+    #[test]
+    fn test_parse_stat() {
+        let stat = "4242 (casper-node (1)) S 1 4242 4242 0 -1 4194560 52014 0 3 0 1250 310 0 0 20 0 37 0 12000 3081670656 25600 18446744073709551615 1 1 0 0 0 0 0 4096 1260 0 0 0 17 3 0 0 0 0 0";
+        let stats = parse_stat(stat, 1000.5, 100, 4096, Some(212)).unwrap();
+        assert_eq!(
+            stats,
+            ProcessStats {
+                uptime_secs: 880,
+                rss_bytes: 25600 * 4096,
+                user_cpu_millis: 12_500,
+                system_cpu_millis: 3_100,
+                open_fds: Some(212),
+                threads: 37,
+            }
+        );
+        assert_eq!(stat_field(stat, 4).unwrap(), 1);
+
+        let stats = process_stats(std::process::id()).unwrap();
+        assert!(stats.rss_bytes > 0);
+        assert!(stats.threads >= 1);
+        assert!(stats.open_fds.unwrap() >= 3);
+    }
+
+    #[test]
+    fn test_find_node_processes() {
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let connected = std::net::TcpStream::connect(tcp.local_addr().unwrap()).unwrap();
+
+        let exe = std::env::current_exe().unwrap();
+        let name = exe.file_name().unwrap().to_str().unwrap();
+        let processes = find_node_processes(name).unwrap();
+        let process = processes
+            .iter()
+            .find(|process| process.pid == std::process::id())
+            .unwrap();
+        assert_eq!(process.binary, exe);
+        // other tests may have sockets of their own.
+        for socket in [
+            ListeningSocket {
+                protocol: Protocol::Tcp,
+                addr: tcp.local_addr().unwrap(),
+            },
+            ListeningSocket {
+                protocol: Protocol::Udp,
+                addr: udp.local_addr().unwrap(),
+            },
+        ] {
+            assert!(process.listening.contains(&socket), "{socket:?}");
+        }
+        assert!(process
+            .listening
+            .iter()
+            .all(|socket| socket.addr.port() != connected.local_addr().unwrap().port()));
+
+        let own_pid = std::process::id();
+        let parent = parent_pid(own_pid).unwrap();
+        assert!(descends_from(own_pid, &HashSet::from([parent])).unwrap());
+        assert!(descends_from(own_pid, &HashSet::from([own_pid])).unwrap());
+        assert!(!descends_from(parent, &HashSet::from([own_pid])).unwrap());
+
+        assert!(is_version("1_5_2"));
+        assert!(!is_version("bin"));
+    }
+}
@@ -0,0 +1,192 @@
+use std::{
+    io::ErrorKind,
+    path::{Component, Path, PathBuf},
+};
+
+use agent_lib::MessageError;
+
+/// Roots the daemon may write to (and read from) unless others are given on the commandline.
+pub const DEFAULT_WRITE_ROOTS: &[&str] = &["/etc/casper", "/var/lib/casper", "/var/log/casper"];
+
+/// The directories clients may read from and write to through the daemon.
+///
+/// Paths are canonicalized before being checked, so `..` components and symlinks cannot be used
+/// to escape a root. Every root that may be written to may also be read from.
+#[derive(Debug, Clone)]
+pub struct PathPolicy {
+    read_roots: Vec<PathBuf>,
+    write_roots: Vec<PathBuf>,
+}
+
+impl PathPolicy {
+    pub fn new(read_roots: Vec<PathBuf>, write_roots: Vec<PathBuf>) -> Self {
+        let resolve_root = |root: &PathBuf| resolve(root).unwrap_or_else(|| root.clone());
+        let write_roots = write_roots.iter().map(resolve_root).collect::<Vec<_>>();
+        let read_roots = read_roots
+            .iter()
+            .map(resolve_root)
+            .chain(write_roots.iter().cloned())
+            .collect();
+        Self {
+            read_roots,
+            write_roots,
+        }
+    }
+
+    pub fn read_roots(&self) -> &[PathBuf] {
+        &self.read_roots
+    }
+
+    pub fn write_roots(&self) -> &[PathBuf] {
+        &self.write_roots
+    }
+
+    /// Resolve a path to be read, which must be inside one of the read roots.
+    pub fn check_read(&self, path: &Path) -> Result<PathBuf, MessageError> {
+        check(path, &self.read_roots)
+    }
+
+    /// As [`Self::check_read`], but a final symlink is not followed so that the link itself can be
+    /// inspected.
+    pub fn check_read_no_follow(&self, path: &Path) -> Result<PathBuf, MessageError> {
+        match (path.parent(), path.file_name()) {
+            // a root itself is readable, even though it's parent may not be.
+            (Some(parent), Some(name)) => self
+                .check_read(parent)
+                .map(|parent| parent.join(name))
+                .or_else(|_| self.check_read(path)),
+            _ => self.check_read(path),
+        }
+    }
+
+    /// Resolve a path to be written, which must be inside one of the write roots. The path (and
+    /// it's parents) need not exist yet.
+    pub fn check_write(&self, path: &Path) -> Result<PathBuf, MessageError> {
+        check(path, &self.write_roots)
+    }
+
+    /// As [`Self::check_write`], but roots themselves are forbidden, for writes that may replace
+    /// or fill the whole of `path`.
+    pub fn check_write_below_root(&self, path: &Path) -> Result<PathBuf, MessageError> {
+        match self.check_write(path)? {
+            resolved if self.write_roots.contains(&resolved) => Err(MessageError::Forbidden {
+                path: path.to_path_buf(),
+            }),
+            resolved => Ok(resolved),
+        }
+    }
+
+    /// As [`Self::check_write`], but a final symlink is not followed so that the link itself can
+    /// be removed or renamed. Roots themselves are forbidden, they can't be removed or renamed.
+    pub fn check_write_no_follow(&self, path: &Path) -> Result<PathBuf, MessageError> {
+        match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => self
+                .check_write(parent)
+                .map(|parent| parent.join(name))
+                .map_err(|_| MessageError::Forbidden {
+                    path: path.to_path_buf(),
+                }),
+            _ => Err(MessageError::Forbidden {
+                path: path.to_path_buf(),
+            }),
+        }
+    }
+}
+
+fn check(path: &Path, roots: &[PathBuf]) -> Result<PathBuf, MessageError> {
+    let forbidden = || MessageError::Forbidden {
+        path: path.to_path_buf(),
+    };
+    // `..` after a symlink can't be resolved without following it, so don't allow it at all.
+    if !path.is_absolute() || path.components().any(|c| c == Component::ParentDir) {
+        return Err(forbidden());
+    }
+    match resolve(path) {
+        Some(resolved) if roots.iter().any(|root| resolved.starts_with(root)) => Ok(resolved),
+        _ => Err(forbidden()),
+    }
+}
+
+/// Canonicalize the longest existing prefix of `path`, following any symlinks in it, then append
+/// the rest, which can't contain symlinks as it doesn't exist yet.
+fn resolve(path: &Path) -> Option<PathBuf> {
+    let mut existing = path;
+    let mut rest = Vec::new();
+    let mut resolved = loop {
+        match existing.canonicalize() {
+            Ok(canonical) => break canonical,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                rest.push(existing.file_name()?);
+                existing = existing.parent()?;
+            }
+            // e.g. permission denied, don't guess.
+            Err(_) => return None,
+        }
+    };
+    for name in rest.into_iter().rev() {
+        resolved.push(name);
+    }
+    Some(resolved)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use agent_lib::test_util::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_rejects_traversal_and_symlink_escapes() {
+        let temp = TempDir::new("daemon-sandbox");
+        let dir = temp.path().to_path_buf();
+        let root = dir.join("root");
+        let outside = dir.join("outside");
+        fs::create_dir_all(root.join("config")).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(outside.join("passwd"), b"root:x:0:0").unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("escape")).unwrap();
+        let policy = PathPolicy::new(vec![], vec![root.clone()]);
+        let root = root.canonicalize().unwrap();
+
+        assert_eq!(
+            policy.check_write(&root.join("config/new/file")).unwrap(),
+            root.join("config/new/file")
+        );
+        assert_eq!(
+            policy.check_read(&root.join("config/./")).unwrap(),
+            root.join("config")
+        );
+        assert_eq!(
+            policy.check_write_no_follow(&root.join("escape")).unwrap(),
+            root.join("escape")
+        );
+        assert!(policy.check_write_no_follow(&root).is_err());
+        std::os::unix::fs::symlink(&root, root.join("itself")).unwrap();
+        assert_eq!(
+            policy.check_write_below_root(&root.join("config")).unwrap(),
+            root.join("config")
+        );
+        assert!(policy.check_write_below_root(&root).is_err());
+        assert!(policy.check_write_below_root(&root.join("itself")).is_err());
+        for path in [
+            root.join("../outside/passwd"),
+            root.join("config/../config/file"),
+            root.join("escape/../root/file"),
+            root.join("escape/passwd"),
+            root.join("escape/new-file"),
+            PathBuf::from("/etc/passwd"),
+            PathBuf::from("relative/path"),
+        ] {
+            assert!(
+                matches!(
+                    policy.check_write(&path),
+                    Err(MessageError::Forbidden { .. })
+                ),
+                "{} should be forbidden",
+                path.display()
+            );
+        }
+    }
+}
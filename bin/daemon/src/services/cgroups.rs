@@ -0,0 +1,367 @@
+//! Resource limits of services with cgroup v2, see the kernel's
+//! `Documentation/admin-guide/cgroup-v2.rst`.
+//!
+//! Services get a cgroup each, under the cgroup the daemon was started in. Only leaves of a cgroup
+//! tree may have processes once controllers are enabled for their children, so the daemon first
+//! moves itself out of it's cgroup into a leaf of it's own, [`DAEMON_LEAF`]. It won't move anything
+//! else, so the cgroup must be the daemon's alone, which under systemd takes `Delegate=yes` in the
+//! agent's unit.
+
+use std::{
+    collections::HashSet,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use agent_lib::{CgroupStats, ResourceLimits};
+
+use super::ServiceError;
+use crate::procfs;
+
+pub const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// Where the daemon moves itself, and recognises it's been started again in, e.g. when updated.
+const DAEMON_LEAF: &str = "agent";
+/// Holds the cgroup of each service.
+const SERVICES: &str = "agent-services";
+/// The controllers enabled for services, if available, those their limits are set with.
+const CONTROLLERS: &[&str] = &["cpu", "io", "memory", "pids"];
+/// The period the CPU quota is a share of, the kernel's default.
+const CPU_PERIOD_MICROS: u64 = 100_000;
+
+/// The cgroups the daemon makes for services.
+#[derive(Debug)]
+pub struct Cgroups {
+    root: PathBuf,
+    /// The cgroup the daemon was started in, relative to `root`, e.g.
+    /// `/system.slice/casper-agent.service`.
+    parent: String,
+    /// The controllers enabled for services, once they are.
+    enabled: Mutex<Option<Vec<String>>>,
+}
+
+impl Cgroups {
+    /// The cgroups under the one the daemon is in, or why there can't be any.
+    pub fn own(root: &Path) -> Result<Self, String> {
+        if !root.join("cgroup.controllers").exists() {
+            return Err(format!("cgroup v2 isn't mounted at {}", root.display()));
+        }
+        // the v2 hierarchy is the one numbered 0.
+        let cgroup = fs::read_to_string("/proc/self/cgroup").map_err(|err| err.to_string())?;
+        let own = cgroup
+            .lines()
+            .find_map(|line| line.strip_prefix("0::"))
+            .ok_or_else(|| "the daemon isn't in a cgroup v2".to_string())?;
+        let parent = match own.strip_suffix(DAEMON_LEAF) {
+            Some(parent) if parent.ends_with('/') && parent.len() > 1 => {
+                parent.trim_end_matches('/')
+            }
+            _ => own,
+        };
+        Ok(Self::new(root, parent))
+    }
+
+    fn new(root: &Path, parent: &str) -> Self {
+        Self {
+            root: root.to_path_buf(),
+            parent: parent.to_string(),
+            enabled: Mutex::new(None),
+        }
+    }
+
+    fn dir(&self, path: &str) -> PathBuf {
+        self.root.join(path.trim_start_matches('/'))
+    }
+
+    /// Move the daemon, and the processes it runs, into it's leaf, and enable the available [`CONTROLLERS`] for services.
+    fn enable_controllers(&self) -> Result<Vec<String>, ServiceError> {
+        let mut enabled = self.enabled.lock().expect("cgroups lock poisoned");
+        if let Some(enabled) = &*enabled {
+            return Ok(enabled.clone());
+        }
+        let parent = self.dir(&self.parent);
+        let available = fs::read_to_string(parent.join("cgroup.controllers"))?;
+        let controllers: Vec<String> = CONTROLLERS
+            .iter()
+            .filter(|controller| available.split_whitespace().any(|c| c == **controller))
+            .map(|controller| controller.to_string())
+            .collect();
+        // the root cgroup is exempt from having only leaves hold processes.
+        if self.parent != "/" {
+            let procs = fs::read_to_string(parent.join("cgroup.procs"))?;
+            let daemon = HashSet::from([std::process::id()]);
+            let mut own = Vec::new();
+            let mut others = Vec::new();
+            for pid in procs.split_whitespace().filter_map(|pid| pid.parse().ok()) {
+                match procfs::descends_from(pid, &daemon) {
+                    Ok(true) => own.push(pid),
+                    Ok(false) => others.push(pid),
+                    // it exited.
+                    Err(_) if !Path::new(&format!("/proc/{pid}")).exists() => {}
+                    Err(err) => return Err(err.into()),
+                }
+            }
+            if !others.is_empty() {
+                return Err(ServiceError::Failed(format!(
+                    "the cgroup {} has processes other than the daemon's, {others:?}, which it won't \
+                     move, it needs a cgroup of it's own, i.e. Delegate=yes in it's systemd unit",
+                    self.parent
+                )));
+            }
+            if !own.is_empty() {
+                let leaf = parent.join(DAEMON_LEAF);
+                fs::create_dir_all(&leaf)?;
+                for pid in own {
+                    match write(&leaf, "cgroup.procs", &pid.to_string()) {
+                        Ok(()) => {}
+                        // it exited.
+                        Err(_) if !Path::new(&format!("/proc/{pid}")).exists() => {}
+                        Err(err) => return Err(err),
+                    }
+                }
+            }
+        }
+        let enable = controllers
+            .iter()
+            .map(|controller| format!("+{controller}"))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let services = parent.join(SERVICES);
+        fs::create_dir_all(&services)?;
+        if !enable.is_empty() {
+            write(&parent, "cgroup.subtree_control", &enable)?;
+            write(&services, "cgroup.subtree_control", &enable)?;
+        }
+        *enabled = Some(controllers.clone());
+        Ok(controllers)
+    }
+
+    /// Make the cgroup of `service`, or reset the one it had, with `limits`. Returns it's path,
+    /// relative to the cgroup root.
+    pub fn create(&self, service: &str, limits: &ResourceLimits) -> Result<String, ServiceError> {
+        let enabled = self.enable_controllers()?;
+        let required = [
+            ("cpu", limits.cpu_quota_percent.is_some()),
+            ("io", limits.io_weight.is_some()),
+            ("memory", limits.memory_max.is_some()),
+            ("pids", limits.pids_max.is_some()),
+        ];
+        for (controller, _) in required.iter().filter(|(_, required)| *required) {
+            if !enabled.iter().any(|enabled| enabled == controller) {
+                return Err(ServiceError::Failed(format!(
+                    "the {controller} controller isn't available in the cgroup {}",
+                    self.parent
+                )));
+            }
+        }
+        if let Some(weight) = limits
+            .io_weight
+            .filter(|weight| !(1..=10_000).contains(weight))
+        {
+            return Err(ServiceError::Failed(format!(
+                "the io weight must be from 1 to 10000, not {weight}"
+            )));
+        }
+
+        let path = format!("{}/{SERVICES}/{service}", self.parent.trim_end_matches('/'));
+        let dir = self.dir(&path);
+        fs::create_dir_all(&dir)?;
+        // limits that aren't given are written too, to clear those of an earlier start.
+        let max = |limit: Option<u64>| limit.map_or("max".to_string(), |limit| limit.to_string());
+        for controller in &enabled {
+            match controller.as_str() {
+                "cpu" => {
+                    let quota = limits
+                        .cpu_quota_percent
+                        .map(|percent| u64::from(percent) * CPU_PERIOD_MICROS / 100);
+                    write(
+                        &dir,
+                        "cpu.max",
+                        &format!("{} {CPU_PERIOD_MICROS}", max(quota)),
+                    )?
+                }
+                "io" => write(
+                    &dir,
+                    "io.weight",
+                    &format!("default {}", limits.io_weight.unwrap_or(100)),
+                )?,
+                "memory" => write(&dir, "memory.max", &max(limits.memory_max))?,
+                "pids" => write(&dir, "pids.max", &max(limits.pids_max))?,
+                _ => {}
+            }
+        }
+        Ok(path)
+    }
+
+    /// The file a process joins the cgroup at `path` by writing it's pid, or `0`, to.
+    pub fn procs_file(&self, path: &str) -> PathBuf {
+        self.dir(path).join("cgroup.procs")
+    }
+}
+
+fn write(dir: &Path, file: &str, value: &str) -> Result<(), ServiceError> {
+    let path = dir.join(file);
+    fs::write(&path, value).map_err(|err| {
+        ServiceError::Failed(format!(
+            "unable to write {value:?} to {}: {err}",
+            path.display()
+        ))
+    })
+}
+
+/// Resource use of the cgroup at `path`, relative to `root`.
+pub fn stats(root: &Path, path: &str) -> Result<CgroupStats, std::io::Error> {
+    let dir = root.join(path.trim_start_matches('/'));
+    // every cgroup has these, whichever controllers it has.
+    let cpu_stat = fs::read_to_string(dir.join("cpu.stat"))?;
+    let read = |file: &str| match fs::read_to_string(dir.join(file)) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    };
+    // single values, `None` when unlimited.
+    let value = |file: &str| -> Result<Option<u64>, std::io::Error> {
+        Ok(read(file)?.and_then(|contents| contents.trim().parse().ok()))
+    };
+    let memory_events = read("memory.events")?.unwrap_or_default();
+    let (mut io_read_bytes, mut io_write_bytes) = (0, 0);
+    // a line per device, e.g. `8:0 rbytes=1024 wbytes=0 rios=1 wios=0 dbytes=0 dios=0`.
+    for line in read("io.stat")?.unwrap_or_default().lines() {
+        for field in line.split_whitespace() {
+            match field.split_once('=') {
+                Some(("rbytes", bytes)) => io_read_bytes += bytes.parse().unwrap_or(0),
+                Some(("wbytes", bytes)) => io_write_bytes += bytes.parse().unwrap_or(0),
+                _ => {}
+            }
+        }
+    }
+    Ok(CgroupStats {
+        path: path.to_string(),
+        memory_bytes: value("memory.current")?,
+        memory_peak_bytes: value("memory.peak")?,
+        memory_max_bytes: value("memory.max")?,
+        oom_events: keyed(&memory_events, "oom").unwrap_or(0),
+        oom_kills: keyed(&memory_events, "oom_kill").unwrap_or(0),
+        cpu_usage_micros: keyed(&cpu_stat, "usage_usec").unwrap_or(0),
+        cpu_throttled_micros: keyed(&cpu_stat, "throttled_usec").unwrap_or(0),
+        pids: value("pids.current")?,
+        pids_max: value("pids.max")?,
+        io_read_bytes,
+        io_write_bytes,
+    })
+}
+
+/// A value of a flat keyed file, with a `<key> <value>` line per key.
+fn keyed(contents: &str, key: &str) -> Option<u64> {
+    contents.lines().find_map(|line| {
+        line.strip_prefix(key)?
+            .strip_prefix(' ')?
+            .trim()
+            .parse()
+            .ok()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use agent_lib::test_util::TempDir;
+
+    use super::*;
+
+    /// A cgroup tree made of plain files, as it looks to the daemon before the kernel has
+    /// populated the cgroups it makes.
+    #[test]
+    fn test_create_and_read_service_cgroup() {
+        let temp = TempDir::new("daemon-cgroups");
+        let root = temp.path().to_path_buf();
+        let parent = root.join("system.slice/casper-agent.service");
+        fs::create_dir_all(&parent).unwrap();
+        fs::write(
+            parent.join("cgroup.controllers"),
+            "cpuset cpu io memory pids\n",
+        )
+        .unwrap();
+        let cgroups = Cgroups::new(&root, "/system.slice/casper-agent.service");
+        // init is never the daemon's.
+        fs::write(
+            parent.join("cgroup.procs"),
+            format!("{}\n1\n", std::process::id()),
+        )
+        .unwrap();
+        assert!(matches!(
+            cgroups.create("casper-node-launcher", &ResourceLimits::default()),
+            Err(ServiceError::Failed(message)) if message.contains("Delegate=yes")
+        ));
+        assert!(!parent.join("agent").exists());
+        fs::write(
+            parent.join("cgroup.procs"),
+            format!("{}\n", std::process::id()),
+        )
+        .unwrap();
+
+        let limits = ResourceLimits {
+            cpu_quota_percent: Some(150),
+            memory_max: Some(1 << 30),
+            io_weight: None,
+            pids_max: Some(100),
+        };
+        let path = cgroups.create("casper-node-launcher", &limits).unwrap();
+        assert_eq!(
+            path,
+            "/system.slice/casper-agent.service/agent-services/casper-node-launcher"
+        );
+        let read = |path: PathBuf| fs::read_to_string(path).unwrap();
+        assert_eq!(
+            read(parent.join("agent/cgroup.procs")),
+            std::process::id().to_string()
+        );
+        assert_eq!(
+            read(parent.join("cgroup.subtree_control")),
+            "+cpu +io +memory +pids"
+        );
+        let dir = parent.join("agent-services/casper-node-launcher");
+        assert_eq!(read(dir.join("cpu.max")), "150000 100000");
+        assert_eq!(read(dir.join("io.weight")), "default 100");
+        assert_eq!(read(dir.join("memory.max")), "1073741824");
+        assert_eq!(read(dir.join("pids.max")), "100");
+        assert_eq!(cgroups.procs_file(&path), dir.join("cgroup.procs"));
+
+        let invalid = ResourceLimits {
+            io_weight: Some(0),
+            ..ResourceLimits::default()
+        };
+        assert!(matches!(
+            cgroups.create("casper-node-launcher", &invalid),
+            Err(ServiceError::Failed(_))
+        ));
+
+        fs::write(dir.join("memory.current"), "52428800\n").unwrap();
+        fs::write(
+            dir.join("memory.events"),
+            "low 0\nhigh 0\nmax 12\noom 2\noom_kill 1\noom_group_kill 0\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("cpu.stat"),
+            "usage_usec 2500000\nuser_usec 2000000\nsystem_usec 500000\nnr_periods 40\nnr_throttled 3\nthrottled_usec 120000\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("io.stat"),
+            "8:0 rbytes=4096 wbytes=8192 rios=1 wios=2 dbytes=0 dios=0\n259:0 rbytes=1024 wbytes=0 rios=1 wios=0 dbytes=0 dios=0\n",
+        )
+        .unwrap();
+        let stats = stats(&root, &path).unwrap();
+        assert_eq!(stats.memory_bytes, Some(50 * 1024 * 1024));
+        assert_eq!(stats.memory_max_bytes, Some(1 << 30));
+        assert_eq!(stats.memory_peak_bytes, None);
+        assert_eq!((stats.oom_events, stats.oom_kills), (2, 1));
+        assert_eq!(stats.cpu_usage_micros, 2_500_000);
+        assert_eq!(stats.cpu_throttled_micros, 120_000);
+        assert_eq!(stats.pids, None);
+        assert_eq!(stats.pids_max, Some(100));
+        assert_eq!((stats.io_read_bytes, stats.io_write_bytes), (5120, 8192));
+    }
+}
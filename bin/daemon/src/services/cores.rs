@@ -0,0 +1,277 @@
+//! Collection of core dumps, see core(5).
+//!
+//! `core_pattern` is host-wide, so it's only taken over when the daemon is started with
+//! `--collect-cores`. The kernel then pipes every core dumped on the host to `daemon
+//! collect-core`, which keeps those of processes run with [`CORE_DIR_ENV`] set, i.e. services and
+//! anything they spawn, in a bundle with a copy of the binary, taken while the crashed process is
+//! still there to copy it from. Other cores are dropped. The pattern the daemon replaced is put
+//! back when it's stopped, or when it's next started without `--collect-cores`.
+
+use std::{
+    ffi::OsStr,
+    fs::{self, File},
+    io::{BufWriter, ErrorKind, Read, Write},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use agent_lib::{blake3_hash_file, hash_to_hex, CrashBundle, Signal};
+use tokio::signal::unix::{signal, SignalKind};
+
+use super::{check_service_name, ServiceError};
+use crate::procfs;
+
+const CORE_PATTERN: &str = "/proc/sys/kernel/core_pattern";
+/// The kernel truncates longer core patterns.
+const MAX_CORE_PATTERN_LEN: usize = 127;
+/// Kept in the services dir, the core pattern the daemon replaced, to be put back.
+const PREVIOUS_CORE_PATTERN: &str = "core_pattern.previous";
+
+/// Where the cores of a process go, inherited by everything a service spawns.
+pub const CORE_DIR_ENV: &str = "AGENT_CORE_DIR";
+
+/// Describes a bundle. It's written last, so bundles without it are incomplete.
+const CRASH_FILE: &str = "crash.yaml";
+
+/// Have the kernel pipe every core dumped on the host to `daemon collect-core`, for services in
+/// `services_dir`. The pattern it replaces is kept, for [`restore_core_pattern`] to put back.
+pub fn install_core_handler(services_dir: &Path) -> Result<(), ServiceError> {
+    let pattern = core_pattern(services_dir)?;
+    let current = read_core_pattern()?;
+    if current == pattern {
+        return Ok(());
+    }
+    let previous = services_dir.join(PREVIOUS_CORE_PATTERN);
+    // if it's already kept, the daemon didn't get to put it back, and the current pattern is an
+    // earlier daemon's.
+    if !previous.exists() {
+        fs::write(&previous, &current)?;
+    }
+    write_core_pattern(&pattern)
+}
+
+/// Whether cores are piped to this daemon, for services in `services_dir`.
+pub fn core_handler_installed(services_dir: &Path) -> Result<bool, ServiceError> {
+    Ok(read_core_pattern()? == core_pattern(services_dir)?)
+}
+
+/// Put back the core pattern replaced by [`install_core_handler`], unless it has been changed
+/// since by something other than a daemon.
+pub fn restore_core_pattern(services_dir: &Path) -> Result<(), ServiceError> {
+    let path = services_dir.join(PREVIOUS_CORE_PATTERN);
+    let previous = match fs::read_to_string(&path) {
+        Ok(previous) => previous,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+    let services_dir = fs::canonicalize(services_dir)?;
+    let suffix = format!(" collect-core {} %P %s %t", services_dir.display());
+    let current = read_core_pattern()?;
+    if current.starts_with('|') && current.ends_with(&suffix) {
+        write_core_pattern(&previous)?;
+        println!("restored the core pattern {previous:?}");
+    }
+    fs::remove_file(path)?;
+    Ok(())
+}
+
+/// Wait for the daemon to be stopped with `SIGTERM` or `SIGINT`, then put back the core pattern
+/// and exit.
+pub async fn restore_core_pattern_on_exit(services_dir: PathBuf) -> Result<(), std::io::Error> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = interrupt.recv() => {}
+    }
+    if let Err(err) = restore_core_pattern(&services_dir) {
+        println!("err while restoring the core pattern {err:?}");
+    }
+    std::process::exit(0);
+}
+
+fn core_pattern(services_dir: &Path) -> Result<String, ServiceError> {
+    let exe = std::env::current_exe()?;
+    let services_dir = fs::canonicalize(services_dir)?;
+    let pattern = format!(
+        "|{} collect-core {} %P %s %t",
+        exe.display(),
+        services_dir.display()
+    );
+    // the kernel splits the pattern into arguments at spaces.
+    if exe.to_string_lossy().contains(' ') || services_dir.to_string_lossy().contains(' ') {
+        return Err(ServiceError::Failed(format!(
+            "core patterns can't have spaces in paths, {pattern:?}"
+        )));
+    }
+    if pattern.len() > MAX_CORE_PATTERN_LEN {
+        return Err(ServiceError::Failed(format!(
+            "the core pattern {pattern:?} is too long"
+        )));
+    }
+    Ok(pattern)
+}
+
+fn read_core_pattern() -> Result<String, std::io::Error> {
+    Ok(fs::read_to_string(CORE_PATTERN)?.trim_end().to_string())
+}
+
+fn write_core_pattern(pattern: &str) -> Result<(), ServiceError> {
+    fs::write(CORE_PATTERN, pattern).map_err(|err| {
+        ServiceError::Failed(format!(
+            "unable to set {CORE_PATTERN}, which needs root: {err}"
+        ))
+    })
+}
+
+/// Allow the process to dump cores of any size. Called between fork and exec, so it mustn't
+/// allocate.
+pub fn raise_core_limit() -> Result<(), std::io::Error> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: `limit` is a valid rlimit for both calls to read from and write to.
+    unsafe {
+        if libc::getrlimit(libc::RLIMIT_CORE, &mut limit) == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+        limit.rlim_cur = limit.rlim_max;
+        if libc::setrlimit(libc::RLIMIT_CORE, &limit) == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Keep the core of `pid`, read from `core`, if it's a service's. Only core dirs of services in
+/// `services_dir` are written to, since the environment is the crashed process's to set.
+pub fn collect_core(
+    services_dir: &Path,
+    pid: u32,
+    signal: i32,
+    time: u64,
+    core: &mut impl Read,
+) -> Result<(), anyhow::Error> {
+    let environ = fs::read(format!("/proc/{pid}/environ"))?;
+    let prefix = format!("{CORE_DIR_ENV}=");
+    let core_dir = environ
+        .split(|byte| *byte == 0)
+        .find_map(|var| var.strip_prefix(prefix.as_bytes()))
+        .map(|dir| PathBuf::from(OsStr::from_bytes(dir)));
+    let Some(core_dir) = core_dir else {
+        return Ok(());
+    };
+    let service = core_dir
+        .strip_prefix(services_dir)
+        .ok()
+        .filter(|relative| relative.file_name() == Some(OsStr::new("cores")))
+        .and_then(|relative| relative.parent())
+        .and_then(|service| service.to_str())
+        .filter(|service| check_service_name(service).is_ok());
+    if service.is_none() || !core_dir.is_dir() {
+        anyhow::bail!("{} isn't a service's core dir", core_dir.display());
+    }
+
+    let crash = format!("{time}-{pid}");
+    let dir = core_dir.join(&crash);
+    fs::create_dir(&dir)?;
+    // the process can't go away before it's core is read, so neither can it's binary, even if
+    // it's been replaced since it started.
+    let cmdline = fs::read(format!("/proc/{pid}/cmdline"))?;
+    let binary = procfs::process_binary(pid, &cmdline)?;
+    fs::copy(format!("/proc/{pid}/exe"), dir.join("binary"))?;
+    let binary_hash = hash_to_hex(&blake3_hash_file(&dir.join("binary"))?);
+    let mut core_file = BufWriter::new(File::create(dir.join("core"))?);
+    let core_bytes = std::io::copy(core, &mut core_file)?;
+    core_file.flush()?;
+
+    let bundle = CrashBundle {
+        crash,
+        pid,
+        signal: Signal(signal),
+        time,
+        version: procfs::version_dir(&binary),
+        binary,
+        binary_hash,
+        args: procfs::cmdline_args(&cmdline).skip(1).collect(),
+        core_bytes,
+    };
+    serde_yaml::to_writer(File::create(dir.join(CRASH_FILE))?, &bundle)?;
+    Ok(())
+}
+
+/// The complete crash bundles in `core_dir`, oldest first.
+pub fn list_crashes(core_dir: &Path) -> Result<Vec<CrashBundle>, std::io::Error> {
+    let entries = match fs::read_dir(core_dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+    let mut crashes = Vec::new();
+    for entry in entries {
+        let crash_file = match File::open(entry?.path().join(CRASH_FILE)) {
+            Ok(crash_file) => crash_file,
+            Err(err) if err.kind() == ErrorKind::NotFound => continue,
+            Err(err) => return Err(err),
+        };
+        match serde_yaml::from_reader::<_, CrashBundle>(crash_file) {
+            Ok(crash) => crashes.push(crash),
+            Err(err) => println!(
+                "err while reading a crash in {} {err:?}",
+                core_dir.display()
+            ),
+        }
+    }
+    crashes.sort_by(|a, b| (a.time, &a.crash).cmp(&(b.time, &b.crash)));
+    Ok(crashes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::{Command, Stdio};
+
+    use agent_lib::test_util::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_collect_core() {
+        let temp = TempDir::new("daemon-cores");
+        let services_dir = temp.path().to_path_buf();
+        let core_dir = services_dir.join("casper-node-launcher").join("cores");
+        fs::create_dir_all(&core_dir).unwrap();
+        let mut child = Command::new("sleep")
+            .arg("100")
+            .env(CORE_DIR_ENV, &core_dir)
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap();
+        let pid = child.id();
+        // until it has exec'd, it has the test's environment.
+        while fs::read(format!("/proc/{pid}/cmdline")).unwrap() != b"sleep\x00100\x00" {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+
+        collect_core(&services_dir, pid, 11, 1700000000, &mut &b"core"[..]).unwrap();
+        // not a service's.
+        let elsewhere = std::env::temp_dir();
+        assert!(collect_core(&elsewhere, pid, 11, 1700000001, &mut &b"core"[..]).is_err());
+        child.kill().unwrap();
+        child.wait().unwrap();
+
+        let crashes = list_crashes(&core_dir).unwrap();
+        assert_eq!(crashes.len(), 1);
+        let crash = &crashes[0];
+        assert_eq!(crash.crash, format!("1700000000-{pid}"));
+        assert_eq!(crash.signal, Signal(11));
+        assert_eq!(crash.args, ["100"]);
+        assert_eq!(crash.core_bytes, 4);
+        let bundle = core_dir.join(&crash.crash);
+        assert_eq!(fs::read(bundle.join("core")).unwrap(), b"core");
+        assert_eq!(
+            crash.binary_hash,
+            hash_to_hex(&blake3_hash_file(&crash.binary).unwrap())
+        );
+    }
+}
@@ -0,0 +1,115 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    thread,
+};
+
+/// A log file that is rotated to `<path>.1`, `<path>.2`, ... once it grows past `max_bytes`,
+/// keeping at most `keep` rotated files.
+#[derive(Debug)]
+pub struct RotatingLog {
+    path: PathBuf,
+    max_bytes: u64,
+    keep: usize,
+    file: File,
+    len: u64,
+}
+
+impl RotatingLog {
+    pub fn open(path: &Path, max_bytes: u64, keep: usize) -> Result<Self, io::Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let len = file.metadata()?.len();
+        Ok(Self {
+            path: path.to_path_buf(),
+            max_bytes,
+            keep,
+            file,
+            len,
+        })
+    }
+
+    fn rotated_path(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{n}"));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> Result<(), io::Error> {
+        for n in (1..self.keep).rev() {
+            let from = self.rotated_path(n);
+            if from.exists() {
+                fs::rename(&from, self.rotated_path(n + 1))?;
+            }
+        }
+        if self.keep > 0 {
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)?;
+        self.len = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingLog {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.len > 0 && self.len + buf.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.len += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Copy everything read from `reader`, e.g. a child's stdout, into `log` on a thread of it's own.
+/// The thread ends when the reader is closed.
+pub fn pump_to_log(mut reader: impl Read + Send + 'static, mut log: RotatingLog) {
+    thread::spawn(move || {
+        if let Err(err) = io::copy(&mut reader, &mut log) {
+            println!("err while writing {} {err:?}", log.path.display());
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use agent_lib::test_util::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_rotation_keeps_the_newest_files() {
+        let temp = TempDir::new("daemon-logs");
+        let dir = temp.path().to_path_buf();
+        let path = dir.join("stdout.log");
+
+        let mut log = RotatingLog::open(&path, 10, 2).unwrap();
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            log.write_all(line.as_bytes()).unwrap();
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\n");
+        assert_eq!(
+            fs::read_to_string(dir.join("stdout.log.1")).unwrap(),
+            "third\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("stdout.log.2")).unwrap(),
+            "second\n"
+        );
+        assert!(!dir.join("stdout.log.3").exists());
+
+        // appends to what's already there, rather than starting over.
+        let mut log = RotatingLog::open(&path, 10, 2).unwrap();
+        log.write_all(b"5\n").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\n5\n");
+    }
+}
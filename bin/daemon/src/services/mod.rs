@@ -0,0 +1,606 @@
+//! Control of long running services, such as `casper-node-launcher`, through a
+//! [`ServiceBackend`].
+
+mod cgroups;
+mod cores;
+mod logs;
+mod supervisor;
+mod systemd;
+mod wrappers;
+
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+
+use crate::{inspect, procfs};
+
+use agent_lib::{
+    archive::ArchiveFilter, EnableCoreDumpsResponse, EnableServiceResponse, FetchCrashRequest,
+    FetchCrashResponse, FetchDebugOutputRequest, FetchDebugOutputResponse, ListCrashesResponse,
+    RestartServiceResponse, ServiceStatus, ServiceStatusResponse, StartServiceRequest,
+    StartServiceResponse, StopServiceResponse,
+};
+
+pub use cores::{
+    collect_core, install_core_handler, restore_core_pattern, restore_core_pattern_on_exit,
+};
+pub use supervisor::{Supervisor, SupervisorConfig};
+pub use systemd::Systemd;
+
+/// Which [`ServiceBackend`] the daemon manages services with.
+#[derive(Clone, Copy, Debug)]
+pub enum BackendKind {
+    Systemd,
+    Supervisor,
+}
+
+impl BackendKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            BackendKind::Systemd => "systemd",
+            BackendKind::Supervisor => "supervisor",
+        }
+    }
+}
+
+impl FromStr for BackendKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "systemd" => Ok(BackendKind::Systemd),
+            "supervisor" => Ok(BackendKind::Supervisor),
+            _ => Err(format!(
+                "unknown service backend {s:?}, expected systemd or supervisor"
+            )),
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ServiceError {
+    #[error("no such service")]
+    NotFound,
+    #[error("invalid service name {0:?}")]
+    InvalidName(String),
+    /// The service manager ran, but refused or failed to do what was asked.
+    #[error("{0}")]
+    Failed(String),
+    #[error("unable to run the service manager {0:?}")]
+    Io(#[from] std::io::Error),
+}
+
+/// The output of one run of a service under a [`agent_lib::wrapper::Wrapper`].
+#[derive(Debug)]
+pub struct DebugRun {
+    pub run: String,
+    pub dir: PathBuf,
+    /// Whether the run is still going, and the tool still writing.
+    pub running: bool,
+}
+
+/// Something that starts and stops services, e.g. systemd. Calls block until the service manager
+/// is done, so are run on the blocking pool.
+pub trait ServiceBackend: Send + Sync {
+    fn start(&self, request: &StartServiceRequest) -> Result<(), ServiceError>;
+    fn stop(&self, service: &str) -> Result<(), ServiceError>;
+    fn restart(&self, service: &str) -> Result<(), ServiceError>;
+    fn enable(&self, service: &str) -> Result<(), ServiceError>;
+    /// The status of a service, or [`ServiceError::NotFound`] if the backend doesn't know it.
+    fn status(&self, service: &str) -> Result<ServiceStatus, ServiceError>;
+    /// A run of the service under a wrapper, by default the latest. Backends that can't run
+    /// services under a wrapper have none.
+    fn debug_run(&self, _service: &str, _run: Option<&str>) -> Result<DebugRun, ServiceError> {
+        Err(ServiceError::NotFound)
+    }
+    /// Collect cores of the service, and of the processes it runs, from it's next start, into the
+    /// returned directory, see [`cores`].
+    fn enable_core_dumps(&self, service: &str) -> Result<PathBuf, ServiceError> {
+        Err(ServiceError::Failed(format!(
+            "the service backend can't collect cores of {service}"
+        )))
+    }
+    /// Where a service's crash bundles are kept.
+    fn core_dir(&self, _service: &str) -> Result<PathBuf, ServiceError> {
+        Err(ServiceError::NotFound)
+    }
+    /// The service's cgroup v2, relative to the cgroup root, if it's in one of it's own.
+    fn cgroup(&self, _service: &str) -> Result<Option<String>, ServiceError> {
+        Ok(None)
+    }
+    /// The main pids of the running services the backend keeps track of itself. Backends that
+    /// don't, like systemd with it's many units, have none.
+    fn main_pids(&self) -> Vec<u32> {
+        Vec::new()
+    }
+    /// Stop the services the daemon runs itself, before it exits, recording those that were
+    /// running for the next daemon to start again. Services run by a service manager outlive the
+    /// daemon.
+    fn shutdown(&self) {}
+}
+
+/// Reject names that could be mistaken for options, or escape a directory when used as a path.
+pub fn check_service_name(service: &str) -> Result<(), ServiceError> {
+    let valid = !service.is_empty()
+        && service.len() <= 256
+        && !service.starts_with(['-', '.'])
+        && service
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "@._:-".contains(c));
+    if valid {
+        Ok(())
+    } else {
+        Err(ServiceError::InvalidName(service.to_string()))
+    }
+}
+
+/// Maps the outcome of asking a [`ServiceBackend`] to do something to the RPC responses.
+#[derive(Clone)]
+pub struct Services {
+    backend: Arc<dyn ServiceBackend>,
+    /// Whether services may be started under a [`agent_lib::wrapper::Wrapper`], which runs a
+    /// debugging tool as the service's user with arguments from the client.
+    allow_wrappers: bool,
+}
+
+impl Services {
+    pub fn new(backend: Arc<dyn ServiceBackend>, allow_wrappers: bool) -> Self {
+        Self {
+            backend,
+            allow_wrappers,
+        }
+    }
+
+    async fn run<T, F>(&self, f: F) -> Result<T, ServiceError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&dyn ServiceBackend) -> Result<T, ServiceError> + Send + 'static,
+    {
+        let backend = self.backend.clone();
+        tokio::task::spawn_blocking(move || f(backend.as_ref()))
+            .await
+            .unwrap_or_else(|err| Err(std::io::Error::other(err).into()))
+    }
+
+    pub async fn shutdown(&self) {
+        let _ = self
+            .run(|backend| {
+                backend.shutdown();
+                Ok(())
+            })
+            .await;
+    }
+
+    pub async fn start(&self, request: StartServiceRequest) -> StartServiceResponse {
+        let service = request.service.clone();
+        if let Some(wrapper) = request.wrapper.as_ref().filter(|_| !self.allow_wrappers) {
+            return StartServiceResponse::Failed {
+                message: format!(
+                    "the agent doesn't allow running services under {}, it must be started with \
+                     --allow-wrappers",
+                    wrapper.name()
+                ),
+            };
+        }
+        let result = self
+            .run(move |backend| {
+                if backend.status(&request.service)?.state.is_running() {
+                    return Ok(StartServiceResponse::AlreadyRunning);
+                }
+                backend.start(&request)?;
+                Ok(StartServiceResponse::Started)
+            })
+            .await;
+        match result {
+            Ok(response) => response,
+            Err(ServiceError::NotFound) => StartServiceResponse::NotFound,
+            Err(err @ (ServiceError::Failed(_) | ServiceError::InvalidName(_))) => {
+                StartServiceResponse::Failed {
+                    message: err.to_string(),
+                }
+            }
+            Err(err) => {
+                println!("err while starting {service} {err:?}");
+                StartServiceResponse::Error
+            }
+        }
+    }
+
+    pub async fn stop(&self, service: String) -> StopServiceResponse {
+        let result = self
+            .run({
+                let service = service.clone();
+                move |backend| {
+                    if !backend.status(&service)?.state.is_running() {
+                        return Ok(StopServiceResponse::NotRunning);
+                    }
+                    backend.stop(&service)?;
+                    Ok(StopServiceResponse::Stopped)
+                }
+            })
+            .await;
+        match result {
+            Ok(response) => response,
+            Err(ServiceError::NotFound) => StopServiceResponse::NotFound,
+            Err(err @ (ServiceError::Failed(_) | ServiceError::InvalidName(_))) => {
+                StopServiceResponse::Failed {
+                    message: err.to_string(),
+                }
+            }
+            Err(err) => {
+                println!("err while stopping {service} {err:?}");
+                StopServiceResponse::Error
+            }
+        }
+    }
+
+    pub async fn restart(&self, service: String) -> RestartServiceResponse {
+        let result = self
+            .run({
+                let service = service.clone();
+                move |backend| {
+                    backend.status(&service)?;
+                    backend.restart(&service)
+                }
+            })
+            .await;
+        match result {
+            Ok(()) => RestartServiceResponse::Restarted,
+            Err(ServiceError::NotFound) => RestartServiceResponse::NotFound,
+            Err(err @ (ServiceError::Failed(_) | ServiceError::InvalidName(_))) => {
+                RestartServiceResponse::Failed {
+                    message: err.to_string(),
+                }
+            }
+            Err(err) => {
+                println!("err while restarting {service} {err:?}");
+                RestartServiceResponse::Error
+            }
+        }
+    }
+
+    pub async fn enable(&self, service: String) -> EnableServiceResponse {
+        let result = self
+            .run({
+                let service = service.clone();
+                move |backend| {
+                    if backend.status(&service)?.enabled {
+                        return Ok(EnableServiceResponse::AlreadyEnabled);
+                    }
+                    backend.enable(&service)?;
+                    Ok(EnableServiceResponse::Enabled)
+                }
+            })
+            .await;
+        match result {
+            Ok(response) => response,
+            Err(ServiceError::NotFound) => EnableServiceResponse::NotFound,
+            Err(err @ (ServiceError::Failed(_) | ServiceError::InvalidName(_))) => {
+                EnableServiceResponse::Failed {
+                    message: err.to_string(),
+                }
+            }
+            Err(err) => {
+                println!("err while enabling {service} {err:?}");
+                EnableServiceResponse::Error
+            }
+        }
+    }
+
+    pub async fn fetch_debug_output(
+        &self,
+        request: FetchDebugOutputRequest,
+    ) -> FetchDebugOutputResponse {
+        let service = request.service.clone();
+        let result = self
+            .run(move |backend| {
+                let debug_run = backend.debug_run(&request.service, request.run.as_deref())?;
+                if debug_run.running {
+                    return Ok(FetchDebugOutputResponse::Running { run: debug_run.run });
+                }
+                let entries = inspect::list_tree(&debug_run.dir, &ArchiveFilter::default())
+                    .map_err(|err| ServiceError::Failed(err.to_string()))?;
+                Ok(FetchDebugOutputResponse::Success {
+                    run: debug_run.run,
+                    dir: debug_run.dir,
+                    entries,
+                })
+            })
+            .await;
+        match result {
+            Ok(response) => response,
+            Err(ServiceError::NotFound | ServiceError::InvalidName(_)) => {
+                FetchDebugOutputResponse::NotFound
+            }
+            Err(err) => {
+                println!("err while fetching debug output of {service} {err:?}");
+                FetchDebugOutputResponse::Error
+            }
+        }
+    }
+
+    pub async fn enable_core_dumps(&self, service: String) -> EnableCoreDumpsResponse {
+        let result = self
+            .run({
+                let service = service.clone();
+                move |backend| backend.enable_core_dumps(&service)
+            })
+            .await;
+        match result {
+            Ok(core_dir) => EnableCoreDumpsResponse::Enabled { core_dir },
+            Err(ServiceError::NotFound | ServiceError::InvalidName(_)) => {
+                EnableCoreDumpsResponse::NotFound
+            }
+            Err(ServiceError::Failed(message)) => EnableCoreDumpsResponse::Failed { message },
+            Err(err) => {
+                println!("err while enabling core dumps of {service} {err:?}");
+                EnableCoreDumpsResponse::Error
+            }
+        }
+    }
+
+    pub async fn list_crashes(&self, service: String) -> ListCrashesResponse {
+        let result = self
+            .run({
+                let service = service.clone();
+                move |backend| Ok(cores::list_crashes(&backend.core_dir(&service)?)?)
+            })
+            .await;
+        match result {
+            Ok(crashes) => ListCrashesResponse::Success { crashes },
+            Err(ServiceError::NotFound | ServiceError::InvalidName(_)) => {
+                ListCrashesResponse::NotFound
+            }
+            Err(err) => {
+                println!("err while listing crashes of {service} {err:?}");
+                ListCrashesResponse::Error
+            }
+        }
+    }
+
+    pub async fn fetch_crash(&self, request: FetchCrashRequest) -> FetchCrashResponse {
+        let service = request.service.clone();
+        let result = self
+            .run(move |backend| {
+                let core_dir = backend.core_dir(&request.service)?;
+                let crash = match request.crash {
+                    Some(crash) => {
+                        // crash ids are made of the same characters as service names.
+                        check_service_name(&crash).map_err(|_| ServiceError::NotFound)?;
+                        crash
+                    }
+                    None => {
+                        let crashes = cores::list_crashes(&core_dir)?;
+                        crashes
+                            .into_iter()
+                            .last()
+                            .ok_or(ServiceError::NotFound)?
+                            .crash
+                    }
+                };
+                let dir = core_dir.join(&crash);
+                if !dir.is_dir() {
+                    return Err(ServiceError::NotFound);
+                }
+                let entries = inspect::list_tree(&dir, &request.filter)
+                    .map_err(|err| ServiceError::Failed(err.to_string()))?;
+                Ok(FetchCrashResponse::Success {
+                    crash,
+                    dir,
+                    entries,
+                })
+            })
+            .await;
+        match result {
+            Ok(response) => response,
+            Err(ServiceError::NotFound | ServiceError::InvalidName(_)) => {
+                FetchCrashResponse::NotFound
+            }
+            Err(err) => {
+                println!("err while fetching a crash of {service} {err:?}");
+                FetchCrashResponse::Error
+            }
+        }
+    }
+
+    /// The pid of a service's main process, if it's running.
+    pub async fn main_pid(&self, service: String) -> Result<Option<u32>, ServiceError> {
+        self.run(move |backend| Ok(backend.status(&service)?.main_pid))
+            .await
+    }
+
+    pub async fn main_pids(&self) -> Result<Vec<u32>, ServiceError> {
+        self.run(|backend| Ok(backend.main_pids())).await
+    }
+
+    pub async fn status(&self, service: String) -> ServiceStatusResponse {
+        let result = self
+            .run({
+                let service = service.clone();
+                move |backend| {
+                    let mut status = backend.status(&service)?;
+                    // it may have exited since, or be owned by another user.
+                    status.process = status
+                        .main_pid
+                        .and_then(|pid| procfs::process_stats(pid).ok());
+                    // the cgroup outlives the service, and with it the count of OOM kills.
+                    status.cgroup = backend.cgroup(&service).ok().flatten().and_then(|path| {
+                        cgroups::stats(Path::new(cgroups::CGROUP_ROOT), &path).ok()
+                    });
+                    Ok(status)
+                }
+            })
+            .await;
+        match result {
+            Ok(status) => ServiceStatusResponse::Success {
+                status: Box::new(status),
+            },
+            Err(ServiceError::NotFound | ServiceError::InvalidName(_)) => {
+                ServiceStatusResponse::NotFound
+            }
+            Err(err) => {
+                println!("err while getting status of {service} {err:?}");
+                ServiceStatusResponse::Error
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Mutex};
+
+    use agent_lib::{wrapper::Wrapper, ResourceLimits, ServiceState};
+
+    use super::*;
+
+    /// Services that start and stop instantly, and a `broken` one that fails to start.
+    #[derive(Default)]
+    struct FakeBackend {
+        services: Mutex<HashMap<String, ServiceStatus>>,
+    }
+
+    impl FakeBackend {
+        fn with_services(names: &[&str]) -> Self {
+            let services = names
+                .iter()
+                .map(|name| {
+                    let status = ServiceStatus {
+                        service: name.to_string(),
+                        state: ServiceState::Inactive,
+                        sub_state: "dead".to_string(),
+                        main_pid: None,
+                        enabled: false,
+                        restarts: 0,
+                        last_exit: None,
+                        process: None,
+                        cgroup: None,
+                    };
+                    (name.to_string(), status)
+                })
+                .collect();
+            Self {
+                services: Mutex::new(services),
+            }
+        }
+
+        fn update(
+            &self,
+            service: &str,
+            f: impl FnOnce(&mut ServiceStatus),
+        ) -> Result<(), ServiceError> {
+            let mut services = self.services.lock().unwrap();
+            let status = services.get_mut(service).ok_or(ServiceError::NotFound)?;
+            f(status);
+            Ok(())
+        }
+    }
+
+    impl ServiceBackend for FakeBackend {
+        fn start(&self, request: &StartServiceRequest) -> Result<(), ServiceError> {
+            if request.service == "broken" {
+                return Err(ServiceError::Failed("exited with status 1".to_string()));
+            }
+            // the test itself stands in for the service's process.
+            self.update(&request.service, |status| {
+                status.state = ServiceState::Active;
+                status.main_pid = Some(std::process::id());
+            })
+        }
+
+        fn stop(&self, service: &str) -> Result<(), ServiceError> {
+            self.update(service, |status| {
+                status.state = ServiceState::Inactive;
+                status.main_pid = None;
+            })
+        }
+
+        fn restart(&self, service: &str) -> Result<(), ServiceError> {
+            self.update(service, |status| status.state = ServiceState::Active)
+        }
+
+        fn enable(&self, service: &str) -> Result<(), ServiceError> {
+            self.update(service, |status| status.enabled = true)
+        }
+
+        fn status(&self, service: &str) -> Result<ServiceStatus, ServiceError> {
+            check_service_name(service)?;
+            let services = self.services.lock().unwrap();
+            services.get(service).cloned().ok_or(ServiceError::NotFound)
+        }
+    }
+
+    fn start_request(service: &str) -> StartServiceRequest {
+        StartServiceRequest {
+            service: service.to_string(),
+            limits: ResourceLimits::default(),
+            wrapper: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_responses_follow_service_state() {
+        let services = Services::new(
+            Arc::new(FakeBackend::with_services(&[
+                "casper-node-launcher",
+                "broken",
+            ])),
+            false,
+        );
+        let node = "casper-node-launcher".to_string();
+
+        assert!(matches!(
+            services.stop(node.clone()).await,
+            StopServiceResponse::NotRunning
+        ));
+        assert!(matches!(
+            services.start(start_request(&node)).await,
+            StartServiceResponse::Started
+        ));
+        assert!(matches!(
+            services.start(start_request(&node)).await,
+            StartServiceResponse::AlreadyRunning
+        ));
+        let ServiceStatusResponse::Success { status } = services.status(node.clone()).await else {
+            panic!("expected a status");
+        };
+        assert_eq!(status.state, ServiceState::Active);
+        assert_eq!(status.main_pid, Some(std::process::id()));
+        assert!(status.process.unwrap().threads >= 1);
+        assert!(matches!(
+            services.stop(node.clone()).await,
+            StopServiceResponse::Stopped
+        ));
+
+        assert!(matches!(
+            services.enable(node.clone()).await,
+            EnableServiceResponse::Enabled
+        ));
+        assert!(matches!(
+            services.enable(node.clone()).await,
+            EnableServiceResponse::AlreadyEnabled
+        ));
+        assert!(matches!(
+            services.restart("missing".to_string()).await,
+            RestartServiceResponse::NotFound
+        ));
+        assert!(matches!(
+            services.start(start_request("broken")).await,
+            StartServiceResponse::Failed { message } if message == "exited with status 1"
+        ));
+        assert!(matches!(
+            services.start(start_request("--now")).await,
+            StartServiceResponse::Failed { .. }
+        ));
+        let wrapped = StartServiceRequest {
+            wrapper: Some(Wrapper::Heaptrack),
+            ..start_request("broken")
+        };
+        assert!(matches!(
+            services.start(wrapped).await,
+            StartServiceResponse::Failed { message } if message.contains("--allow-wrappers")
+        ));
+    }
+}
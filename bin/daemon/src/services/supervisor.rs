@@ -0,0 +1,902 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{BufReader, ErrorKind},
+    os::unix::{
+        io::AsRawFd,
+        process::{CommandExt, ExitStatusExt},
+    },
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus, Stdio},
+    sync::{mpsc, Arc, Condvar, Mutex, MutexGuard},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use agent_lib::{
+    wrapper::Wrapper, ExitReason, ResourceLimits, ServiceState, ServiceStatus, StartServiceRequest,
+};
+use serde::Deserialize;
+
+use super::{
+    cgroups::{Cgroups, CGROUP_ROOT},
+    check_service_name,
+    cores::{core_handler_installed, raise_core_limit, CORE_DIR_ENV},
+    logs::{pump_to_log, RotatingLog},
+    wrappers::wrap_command,
+    DebugRun, ServiceBackend, ServiceError,
+};
+
+/// How long to wait for a service to go away after it's been sent `SIGKILL`.
+const KILL_TIMEOUT: Duration = Duration::from_secs(5);
+/// The services a daemon was running when it shut down, e.g. to hand over to an updated daemon,
+/// for the next one to start again. Service names can't start with a dot, so it's no service's dir.
+const RUNNING_FILE: &str = ".running.yaml";
+
+/// When to start a supervised service again after it exits.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    Never,
+    /// When it exits unsuccessfully or is killed by a signal.
+    #[default]
+    OnFailure,
+    Always,
+}
+
+/// How to run a service the daemon supervises itself.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServiceConfig {
+    pub command: PathBuf,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    pub cwd: Option<PathBuf>,
+    #[serde(default)]
+    pub restart: RestartPolicy,
+    /// Give up after this many restarts in a row. A service that stays up for `max_backoff_millis`
+    /// starts counting again from zero.
+    #[serde(default = "default_max_restarts")]
+    pub max_restarts: u32,
+    /// Wait this long before the first restart, doubling for every restart in a row.
+    #[serde(default = "default_initial_backoff_millis")]
+    pub initial_backoff_millis: u64,
+    #[serde(default = "default_max_backoff_millis")]
+    pub max_backoff_millis: u64,
+    /// How long the service has to exit after `SIGTERM`, before it's killed with `SIGKILL`.
+    #[serde(default = "default_stop_timeout_millis")]
+    pub stop_timeout_millis: u64,
+    /// stdout and stderr are rotated once they grow past this size.
+    #[serde(default = "default_log_max_bytes")]
+    pub log_max_bytes: u64,
+    /// How many rotated logs to keep.
+    #[serde(default = "default_log_keep")]
+    pub log_keep: usize,
+    /// Limits of the service, unless others are given when it's started.
+    #[serde(default)]
+    pub limits: ResourceLimits,
+}
+
+fn default_max_restarts() -> u32 {
+    10
+}
+
+fn default_initial_backoff_millis() -> u64 {
+    1_000
+}
+
+fn default_max_backoff_millis() -> u64 {
+    60_000
+}
+
+fn default_stop_timeout_millis() -> u64 {
+    30_000
+}
+
+fn default_log_max_bytes() -> u64 {
+    64 * 1024 * 1024
+}
+
+fn default_log_keep() -> usize {
+    5
+}
+
+/// The services a [`Supervisor`] may run, by name, e.g.
+///
+/// ```yaml
+/// services:
+///   casper-node-launcher:
+///     command: /usr/bin/casper-node-launcher
+///     restart: always
+/// ```
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SupervisorConfig {
+    #[serde(default)]
+    pub services: BTreeMap<String, ServiceConfig>,
+}
+
+impl SupervisorConfig {
+    pub fn load(path: &Path) -> Result<Self, anyhow::Error> {
+        let config: Self = serde_yaml::from_reader(BufReader::new(File::open(path)?))?;
+        for service in config.services.keys() {
+            check_service_name(service)?;
+        }
+        Ok(config)
+    }
+}
+
+/// What is known about a supervised service's process.
+#[derive(Debug)]
+struct Runtime {
+    /// Whether the service should be running. Cleared to stop it.
+    wanted: bool,
+    /// Whether a thread is supervising the service, which it is from being started until it has
+    /// exited for good.
+    supervised: bool,
+    pid: Option<u32>,
+    state: ServiceState,
+    sub_state: &'static str,
+    /// Restarts since the service was last started by request.
+    restarts: u32,
+    last_exit: Option<ExitStatus>,
+    /// The tool the service is run under, for every restart until it's started again.
+    wrapper: Option<Wrapper>,
+    /// The run of the wrapper in progress, see [`DebugRun`].
+    debug_run: Option<String>,
+    /// The limits the service was last started with, kept for restarts like the wrapper.
+    limits: ResourceLimits,
+    /// The service's cgroup, relative to the cgroup root, from when it's first started.
+    cgroup: Option<String>,
+}
+
+struct Supervised {
+    name: String,
+    config: ServiceConfig,
+    cgroups: Arc<Result<Cgroups, String>>,
+    /// Holds the service's logs and debug runs, and marks whether it's enabled.
+    dir: PathBuf,
+    runtime: Mutex<Runtime>,
+    /// Notified whenever `runtime` changes.
+    changed: Condvar,
+}
+
+impl Supervised {
+    fn lock(&self) -> MutexGuard<'_, Runtime> {
+        self.runtime.lock().expect("service runtime lock poisoned")
+    }
+
+    fn enabled_marker(&self) -> PathBuf {
+        self.dir.join("enabled")
+    }
+
+    /// Holds a directory per run under a wrapper, named `<unix millis>-<tool>` so they sort by
+    /// when they started.
+    fn debug_dir(&self) -> PathBuf {
+        self.dir.join("debug")
+    }
+
+    /// Holds the service's crash bundles, if it's cores are collected, see
+    /// [`super::cores`].
+    fn core_dir(&self) -> PathBuf {
+        self.dir.join("cores")
+    }
+
+    /// Make the service's cgroup with `limits`. Without limits it's only for the usage figures, so
+    /// the service runs without one if cgroups can't be used.
+    fn create_cgroup(&self, limits: &ResourceLimits) -> Result<Option<String>, ServiceError> {
+        let created = match &*self.cgroups {
+            Ok(cgroups) => cgroups.create(&self.name, limits),
+            Err(reason) => Err(ServiceError::Failed(format!(
+                "unable to limit the resources of {}, {reason}",
+                self.name
+            ))),
+        };
+        match created {
+            Ok(cgroup) => Ok(Some(cgroup)),
+            Err(err) if limits.is_unlimited() => {
+                if self.cgroups.is_ok() {
+                    println!("err while creating the cgroup of {} {err:?}", self.name);
+                }
+                Ok(None)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Run the service until it's stopped, or exits and the restart policy says to leave it.
+    /// Whether the first spawn worked is sent to `started`.
+    fn supervise(self: Arc<Self>, started: mpsc::Sender<Result<(), String>>) {
+        let mut started = Some(started);
+        let initial_backoff = Duration::from_millis(self.config.initial_backoff_millis);
+        let max_backoff = Duration::from_millis(self.config.max_backoff_millis);
+        let mut backoff = initial_backoff;
+        let mut restarts_in_a_row = 0;
+        loop {
+            let first_run = started.is_some();
+            let ran_at = Instant::now();
+            let exit = self.run_once(&mut started);
+            if ran_at.elapsed() >= max_backoff {
+                backoff = initial_backoff;
+                restarts_in_a_row = 0;
+            }
+
+            let mut runtime = self.lock();
+            runtime.pid = None;
+            runtime.debug_run = None;
+            let failed = match &exit {
+                Ok(status) => {
+                    println!("{} exited with {status}", self.name);
+                    runtime.last_exit = Some(*status);
+                    !status.success()
+                }
+                Err(err) => {
+                    println!("err while running {} {err:?}", self.name);
+                    true
+                }
+            };
+            let restart = runtime.wanted
+                && !(first_run && exit.is_err())
+                && restarts_in_a_row < self.config.max_restarts
+                && match self.config.restart {
+                    RestartPolicy::Never => false,
+                    RestartPolicy::OnFailure => failed,
+                    RestartPolicy::Always => true,
+                };
+            if !restart {
+                // a service stopped by request is inactive, however it exited.
+                (runtime.state, runtime.sub_state) = if runtime.wanted && failed {
+                    (ServiceState::Failed, "failed")
+                } else {
+                    (ServiceState::Inactive, "dead")
+                };
+                runtime.wanted = false;
+                runtime.supervised = false;
+                self.changed.notify_all();
+                return;
+            }
+
+            restarts_in_a_row += 1;
+            runtime.restarts += 1;
+            runtime.state = ServiceState::Activating;
+            runtime.sub_state = "auto-restart";
+            self.changed.notify_all();
+            println!(
+                "restarting {} in {}ms, restart {restarts_in_a_row} of {}",
+                self.name,
+                backoff.as_millis(),
+                self.config.max_restarts
+            );
+            let (mut runtime, _) = self
+                .changed
+                .wait_timeout_while(runtime, backoff, |runtime| runtime.wanted)
+                .expect("service runtime lock poisoned");
+            if !runtime.wanted {
+                runtime.state = ServiceState::Inactive;
+                runtime.sub_state = "dead";
+                runtime.supervised = false;
+                self.changed.notify_all();
+                return;
+            }
+            backoff = (backoff * 2).min(max_backoff);
+        }
+    }
+
+    /// Spawn the service, under it's wrapper if it has one, and wait for it to exit. Only
+    /// spawning it fails.
+    fn run_once(
+        &self,
+        started: &mut Option<mpsc::Sender<Result<(), String>>>,
+    ) -> Result<ExitStatus, std::io::Error> {
+        let (wrapper, cgroup) = {
+            let runtime = self.lock();
+            (runtime.wrapper.clone(), runtime.cgroup.clone())
+        };
+        let debug_run = wrapper.as_ref().map(|wrapper| {
+            let millis = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis();
+            format!("{millis}-{}", wrapper.name())
+        });
+        let (program, args) = match (&wrapper, &debug_run) {
+            (Some(wrapper), Some(debug_run)) => {
+                let output_dir = self.debug_dir().join(debug_run);
+                if let Err(err) = fs::create_dir_all(&output_dir) {
+                    if let Some(started) = started.take() {
+                        let _ = started.send(Err(format!(
+                            "unable to create {}: {err}",
+                            output_dir.display()
+                        )));
+                    }
+                    return Err(err);
+                }
+                wrap_command(
+                    wrapper,
+                    &self.config.command,
+                    &self.config.args,
+                    &output_dir,
+                )
+            }
+            _ => (
+                self.config.command.clone(),
+                self.config.args.iter().map(Into::into).collect(),
+            ),
+        };
+        let mut command = Command::new(&program);
+        command
+            .args(args)
+            .envs(&self.config.env)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            // it's own process group, so that it and anything it spawns can be killed together.
+            .process_group(0);
+        if let Some(cwd) = &self.config.cwd {
+            command.current_dir(cwd);
+        }
+        let core_dumps = self.core_dir().is_dir();
+        if core_dumps {
+            // collect-core only trusts core dirs given by their full path.
+            match fs::canonicalize(self.core_dir()) {
+                Ok(core_dir) => command.env(CORE_DIR_ENV, core_dir),
+                Err(err) => {
+                    if let Some(started) = started.take() {
+                        let _ = started.send(Err(format!("unable to find the core dir: {err}")));
+                    }
+                    return Err(err);
+                }
+            };
+        }
+        // opened before forking, so that the child only has to write to it to join the cgroup,
+        // before it can spawn anything that would be left outside.
+        let cgroup_procs = match (&cgroup, &*self.cgroups) {
+            (Some(cgroup), Ok(cgroups)) => {
+                match File::options().write(true).open(cgroups.procs_file(cgroup)) {
+                    Ok(cgroup_procs) => Some(cgroup_procs),
+                    Err(err) => {
+                        if let Some(started) = started.take() {
+                            let _ = started.send(Err(format!("unable to join {cgroup}: {err}")));
+                        }
+                        return Err(err);
+                    }
+                }
+            }
+            _ => None,
+        };
+        let cgroup_procs_fd = cgroup_procs.as_ref().map(AsRawFd::as_raw_fd);
+        // SAFETY: prctl, write and the rlimit calls are async-signal-safe, nothing is allocated
+        // between fork and exec, and `cgroup_procs` is open until the child has been spawned.
+        unsafe {
+            command.pre_exec(move || {
+                // writing 0 moves the writing process.
+                if let Some(fd) = cgroup_procs_fd {
+                    if libc::write(fd, b"0".as_ptr().cast(), 1) == -1 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+                // the daemon owns the service, so it mustn't outlive it. The parent is this
+                // thread, which lives until the service exits.
+                if libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM) == -1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                if core_dumps {
+                    raise_core_limit()?;
+                }
+                Ok(())
+            });
+        }
+
+        let spawned = self
+            .open_logs()
+            .and_then(|logs| Ok((command.spawn()?, logs)));
+        drop(cgroup_procs);
+        let (mut child, (stdout_log, stderr_log)) = match spawned {
+            Ok(spawned) => spawned,
+            Err(err) => {
+                if let Some(started) = started.take() {
+                    let _ =
+                        started.send(Err(format!("unable to spawn {}: {err}", program.display())));
+                }
+                // there is nothing to fetch from a run that never started.
+                if let Some(debug_run) = &debug_run {
+                    let _ = fs::remove_dir_all(self.debug_dir().join(debug_run));
+                }
+                return Err(err);
+            }
+        };
+        {
+            let mut runtime = self.lock();
+            runtime.pid = Some(child.id());
+            runtime.debug_run = debug_run;
+            runtime.state = ServiceState::Active;
+            runtime.sub_state = "running";
+            self.changed.notify_all();
+        }
+        if let Some(started) = started.take() {
+            let _ = started.send(Ok(()));
+        }
+        // the pumps aren't joined, anything the service spawned may hold it's stdout open.
+        if let Some(stdout) = child.stdout.take() {
+            pump_to_log(stdout, stdout_log);
+        }
+        if let Some(stderr) = child.stderr.take() {
+            pump_to_log(stderr, stderr_log);
+        }
+        child.wait()
+    }
+
+    fn open_logs(&self) -> Result<(RotatingLog, RotatingLog), std::io::Error> {
+        fs::create_dir_all(&self.dir)?;
+        let open = |name: &str| {
+            RotatingLog::open(
+                &self.dir.join(name),
+                self.config.log_max_bytes,
+                self.config.log_keep,
+            )
+        };
+        Ok((open("stdout.log")?, open("stderr.log")?))
+    }
+
+    /// Send `SIGTERM`, then `SIGKILL` to the process group if it's still running after the stop
+    /// timeout. Under a wrapper the tool is the main process, so the whole process group is sent
+    /// `SIGTERM`, which the service sees as it would without the tool.
+    fn stop(&self) -> Result<(), ServiceError> {
+        let mut runtime = self.lock();
+        runtime.wanted = false;
+        self.changed.notify_all();
+        let deadline = Instant::now() + Duration::from_millis(self.config.stop_timeout_millis);
+        let mut terminated = None;
+        while runtime.supervised {
+            if let Some(pid) = runtime.pid.filter(|pid| terminated != Some(*pid)) {
+                let target = if runtime.wrapper.is_some() {
+                    -(pid as i32)
+                } else {
+                    pid as i32
+                };
+                signal(target, libc::SIGTERM)?;
+                terminated = Some(pid);
+            }
+            let Some(timeout) = deadline.checked_duration_since(Instant::now()) else {
+                break;
+            };
+            runtime = self
+                .changed
+                .wait_timeout(runtime, timeout)
+                .expect("service runtime lock poisoned")
+                .0;
+        }
+        if !runtime.supervised {
+            return Ok(());
+        }
+
+        if let Some(pid) = runtime.pid {
+            println!("{} did not exit after SIGTERM, killing it", self.name);
+            signal(-(pid as i32), libc::SIGKILL)?;
+        }
+        let (runtime, _) = self
+            .changed
+            .wait_timeout_while(runtime, KILL_TIMEOUT, |runtime| runtime.supervised)
+            .expect("service runtime lock poisoned");
+        if runtime.supervised {
+            return Err(ServiceError::Failed(format!(
+                "{} is still running after SIGKILL",
+                self.name
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Send `signal` to a process, or with a negative pid to a process group.
+fn signal(pid: i32, signal: i32) -> Result<(), std::io::Error> {
+    // SAFETY: kill has no memory safety requirements.
+    if unsafe { libc::kill(pid, signal) } == -1 {
+        let err = std::io::Error::last_os_error();
+        // it exited in the meantime.
+        if err.raw_os_error() != Some(libc::ESRCH) {
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+/// Runs services as children of the daemon, for hosts without systemd such as containers.
+/// Services only run while the daemon does, those that are enabled are started along with it.
+pub struct Supervisor {
+    dir: PathBuf,
+    services: BTreeMap<String, Arc<Supervised>>,
+}
+
+impl Supervisor {
+    /// Each service keeps it's logs in `<dir>/<service>`, and runs in a cgroup of it's own if
+    /// cgroup v2 can be used, see [`super::cgroups`].
+    pub fn new(dir: PathBuf, config: SupervisorConfig) -> Self {
+        let cgroups = Arc::new(Cgroups::own(Path::new(CGROUP_ROOT)));
+        if let Err(reason) = &*cgroups {
+            println!("services will run without cgroups, {reason}");
+        }
+        let services = config
+            .services
+            .into_iter()
+            .map(|(name, config)| {
+                let supervised = Supervised {
+                    dir: dir.join(&name),
+                    name: name.clone(),
+                    config,
+                    cgroups: cgroups.clone(),
+                    runtime: Mutex::new(Runtime {
+                        wanted: false,
+                        supervised: false,
+                        pid: None,
+                        state: ServiceState::Inactive,
+                        sub_state: "dead",
+                        restarts: 0,
+                        last_exit: None,
+                        wrapper: None,
+                        debug_run: None,
+                        limits: ResourceLimits::default(),
+                        cgroup: None,
+                    }),
+                    changed: Condvar::new(),
+                };
+                (name, Arc::new(supervised))
+            })
+            .collect();
+        Self { dir, services }
+    }
+
+    fn get(&self, service: &str) -> Result<&Arc<Supervised>, ServiceError> {
+        check_service_name(service)?;
+        self.services.get(service).ok_or(ServiceError::NotFound)
+    }
+
+    /// Start the services that are enabled, as at boot, and those the last daemon was running when
+    /// it shut down, with the limits they had.
+    pub fn start_services(&self) {
+        let mut requests: BTreeMap<String, StartServiceRequest> = self
+            .services
+            .iter()
+            .filter(|(_, supervised)| supervised.enabled_marker().exists())
+            .map(|(name, _)| {
+                let request = StartServiceRequest {
+                    service: name.clone(),
+                    limits: ResourceLimits::default(),
+                    wrapper: None,
+                };
+                (name.clone(), request)
+            })
+            .collect();
+        match self.take_running() {
+            Ok(running) => requests.extend(
+                running
+                    .into_iter()
+                    .map(|request| (request.service.clone(), request)),
+            ),
+            Err(err) => println!("err while reading the services that were running {err:?}"),
+        }
+        for (name, request) in &requests {
+            match self.start(request) {
+                Ok(()) => println!("started {name}"),
+                Err(err) => println!("err while starting {name} {err:?}"),
+            }
+        }
+    }
+
+    /// Record the services that are running, for [`Self::start_services`]. Those started under a
+    /// wrapper are recorded without it.
+    fn save_running(&self) -> Result<(), anyhow::Error> {
+        let running: Vec<StartServiceRequest> = self
+            .services
+            .iter()
+            .filter_map(|(name, supervised)| {
+                let runtime = supervised.lock();
+                (runtime.supervised && runtime.wanted).then(|| StartServiceRequest {
+                    service: name.clone(),
+                    limits: runtime.limits.clone(),
+                    wrapper: None,
+                })
+            })
+            .collect();
+        fs::create_dir_all(&self.dir)?;
+        serde_yaml::to_writer(File::create(self.dir.join(RUNNING_FILE))?, &running)?;
+        Ok(())
+    }
+
+    fn take_running(&self) -> Result<Vec<StartServiceRequest>, anyhow::Error> {
+        let path = self.dir.join(RUNNING_FILE);
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err.into()),
+        };
+        let running = serde_yaml::from_reader(BufReader::new(file))?;
+        fs::remove_file(&path)?;
+        Ok(running)
+    }
+}
+
+impl ServiceBackend for Supervisor {
+    fn start(&self, request: &StartServiceRequest) -> Result<(), ServiceError> {
+        let supervised = self.get(&request.service)?;
+        {
+            let mut runtime = supervised.lock();
+            if runtime.supervised {
+                // waiting to be restarted.
+                runtime.wanted = true;
+                return Ok(());
+            }
+            let configured = &supervised.config.limits;
+            let limits = ResourceLimits {
+                cpu_quota_percent: request
+                    .limits
+                    .cpu_quota_percent
+                    .or(configured.cpu_quota_percent),
+                memory_max: request.limits.memory_max.or(configured.memory_max),
+                io_weight: request.limits.io_weight.or(configured.io_weight),
+                pids_max: request.limits.pids_max.or(configured.pids_max),
+            };
+            runtime.cgroup = supervised.create_cgroup(&limits)?;
+            runtime.limits = limits;
+            runtime.wanted = true;
+            runtime.supervised = true;
+            runtime.restarts = 0;
+            runtime.wrapper = request.wrapper.clone();
+            runtime.state = ServiceState::Activating;
+            runtime.sub_state = "start";
+        }
+
+        let (started_tx, started_rx) = mpsc::channel();
+        let supervisor = supervised.clone();
+        thread::Builder::new()
+            .name(format!("supervise-{}", request.service))
+            .spawn(move || supervisor.supervise(started_tx))?;
+        match started_rx.recv() {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(message)) => Err(ServiceError::Failed(message)),
+            Err(_) => Err(ServiceError::Failed(format!(
+                "supervising {} failed",
+                request.service
+            ))),
+        }
+    }
+
+    fn stop(&self, service: &str) -> Result<(), ServiceError> {
+        self.get(service)?.stop()
+    }
+
+    /// Stop the service, and start it again with the same limits, under the same wrapper, if any.
+    fn restart(&self, service: &str) -> Result<(), ServiceError> {
+        let (limits, wrapper) = {
+            let runtime = self.get(service)?.lock();
+            (runtime.limits.clone(), runtime.wrapper.clone())
+        };
+        self.stop(service)?;
+        self.start(&StartServiceRequest {
+            service: service.to_string(),
+            limits,
+            wrapper,
+        })
+    }
+
+    fn main_pids(&self) -> Vec<u32> {
+        self.services
+            .values()
+            .filter_map(|supervised| supervised.lock().pid)
+            .collect()
+    }
+
+    fn shutdown(&self) {
+        if let Err(err) = self.save_running() {
+            println!("err while saving the services that are running {err:?}");
+        }
+        for (name, supervised) in &self.services {
+            if !supervised.lock().supervised {
+                continue;
+            }
+            match supervised.stop() {
+                Ok(()) => println!("stopped {name}"),
+                Err(err) => println!("err while stopping {name} {err:?}"),
+            }
+        }
+    }
+
+    fn enable(&self, service: &str) -> Result<(), ServiceError> {
+        let supervised = self.get(service)?;
+        fs::create_dir_all(&supervised.dir)?;
+        fs::write(supervised.enabled_marker(), b"")?;
+        Ok(())
+    }
+
+    fn debug_run(&self, service: &str, run: Option<&str>) -> Result<DebugRun, ServiceError> {
+        let supervised = self.get(service)?;
+        let run = match run {
+            Some(run) => {
+                // run ids are made of the same characters as service names.
+                check_service_name(run).map_err(|_| ServiceError::NotFound)?;
+                run.to_string()
+            }
+            None => {
+                let entries = match fs::read_dir(supervised.debug_dir()) {
+                    Ok(entries) => entries,
+                    Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                        return Err(ServiceError::NotFound)
+                    }
+                    Err(err) => return Err(err.into()),
+                };
+                let mut runs = Vec::new();
+                for entry in entries {
+                    runs.push(entry?.file_name().to_string_lossy().into_owned());
+                }
+                runs.into_iter().max().ok_or(ServiceError::NotFound)?
+            }
+        };
+        let dir = supervised.debug_dir().join(&run);
+        if !dir.is_dir() {
+            return Err(ServiceError::NotFound);
+        }
+        let running = supervised.lock().debug_run.as_ref() == Some(&run);
+        Ok(DebugRun { run, dir, running })
+    }
+
+    fn enable_core_dumps(&self, service: &str) -> Result<PathBuf, ServiceError> {
+        let supervised = self.get(service)?;
+        fs::create_dir_all(&supervised.dir)?;
+        let services_dir = supervised
+            .dir
+            .parent()
+            .expect("services are kept in a directory");
+        if !core_handler_installed(services_dir)? {
+            return Err(ServiceError::Failed(
+                "the daemon only collects cores if it's started with --collect-cores".to_string(),
+            ));
+        }
+        // the core dir existing is what has the service run with core dumps.
+        fs::create_dir_all(supervised.core_dir())?;
+        Ok(fs::canonicalize(supervised.core_dir())?)
+    }
+
+    fn core_dir(&self, service: &str) -> Result<PathBuf, ServiceError> {
+        Ok(self.get(service)?.core_dir())
+    }
+
+    fn cgroup(&self, service: &str) -> Result<Option<String>, ServiceError> {
+        Ok(self.get(service)?.lock().cgroup.clone())
+    }
+
+    fn status(&self, service: &str) -> Result<ServiceStatus, ServiceError> {
+        let supervised = self.get(service)?;
+        let runtime = supervised.lock();
+        Ok(ServiceStatus {
+            service: service.to_string(),
+            state: runtime.state.clone(),
+            sub_state: runtime.sub_state.to_string(),
+            main_pid: runtime.pid,
+            enabled: supervised.enabled_marker().exists(),
+            restarts: runtime.restarts,
+            last_exit: runtime.last_exit.and_then(|status| {
+                status
+                    .code()
+                    .map(ExitReason::Code)
+                    .or_else(|| status.signal().map(ExitReason::Signal))
+            }),
+            process: None,
+            cgroup: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use agent_lib::test_util::TempDir;
+
+    use super::*;
+
+    fn shell_service(script: &str, restart: RestartPolicy) -> ServiceConfig {
+        ServiceConfig {
+            command: PathBuf::from("/bin/sh"),
+            args: vec!["-c".to_string(), script.to_string()],
+            env: BTreeMap::new(),
+            cwd: None,
+            restart,
+            max_restarts: 2,
+            initial_backoff_millis: 10,
+            max_backoff_millis: 1_000,
+            stop_timeout_millis: 200,
+            log_max_bytes: default_log_max_bytes(),
+            log_keep: default_log_keep(),
+            limits: ResourceLimits::default(),
+        }
+    }
+
+    fn wait_for_state(supervisor: &Supervisor, service: &str, state: ServiceState) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while supervisor.status(service).unwrap().state != state {
+            assert!(Instant::now() < deadline, "{service} never became {state}");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// The logs are written by threads of their own, which may lag behind.
+    fn wait_for_stdout(dir: &Path, expected: &str) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while fs::read_to_string(dir.join("stdout.log")).unwrap_or_default() != expected {
+            assert!(Instant::now() < deadline, "{expected:?} was not logged");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_supervisor_restarts_and_stops_services() {
+        let temp = TempDir::new("daemon-supervisor");
+        let dir = temp.path().to_path_buf();
+        let mut config = SupervisorConfig::default();
+        config.services.insert(
+            "crashing".to_string(),
+            shell_service("echo up; exit 3", RestartPolicy::OnFailure),
+        );
+        config.services.insert(
+            "stubborn".to_string(),
+            shell_service("trap '' TERM; echo ready; sleep 30", RestartPolicy::Always),
+        );
+        config.services.insert(
+            "missing".to_string(),
+            ServiceConfig {
+                command: dir.join("no-such-binary"),
+                ..shell_service("", RestartPolicy::Always)
+            },
+        );
+        let supervisor = Supervisor::new(dir.clone(), config.clone());
+        let start = |service: &str| {
+            supervisor.start(&StartServiceRequest {
+                service: service.to_string(),
+                limits: ResourceLimits::default(),
+                wrapper: None,
+            })
+        };
+
+        // runs once, then restarts twice before giving up.
+        start("crashing").unwrap();
+        wait_for_state(&supervisor, "crashing", ServiceState::Failed);
+        let status = supervisor.status("crashing").unwrap();
+        assert_eq!(status.restarts, 2);
+        assert_eq!(status.last_exit, Some(ExitReason::Code(3)));
+        wait_for_stdout(&dir.join("crashing"), "up\nup\nup\n");
+
+        // ignores SIGTERM, so is killed once the stop timeout passes, and isn't restarted.
+        start("stubborn").unwrap();
+        wait_for_stdout(&dir.join("stubborn"), "ready\n");
+        let pid = supervisor.status("stubborn").unwrap().main_pid.unwrap();
+        let stopping = Instant::now();
+        supervisor.stop("stubborn").unwrap();
+        assert!(stopping.elapsed() >= Duration::from_millis(200));
+        let status = supervisor.status("stubborn").unwrap();
+        assert_eq!(status.state, ServiceState::Inactive);
+        assert_eq!(status.main_pid, None);
+        assert!(!Path::new(&format!("/proc/{pid}")).exists());
+
+        assert!(matches!(start("missing"), Err(ServiceError::Failed(_))));
+        // the failed spawn is reported before the supervising thread records it.
+        wait_for_state(&supervisor, "missing", ServiceState::Failed);
+        assert!(matches!(start("unknown"), Err(ServiceError::NotFound)));
+
+        supervisor.enable("crashing").unwrap();
+        assert!(supervisor.status("crashing").unwrap().enabled);
+
+        // started again by the next supervisor, as after an update.
+        start("stubborn").unwrap();
+        supervisor.shutdown();
+        assert_eq!(
+            supervisor.status("stubborn").unwrap().state,
+            ServiceState::Inactive
+        );
+        let next = Supervisor::new(dir.clone(), config);
+        next.start_services();
+        wait_for_state(&next, "stubborn", ServiceState::Active);
+        wait_for_state(&next, "crashing", ServiceState::Failed);
+        assert!(!dir.join(RUNNING_FILE).exists());
+        next.shutdown();
+    }
+}
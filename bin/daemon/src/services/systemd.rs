@@ -0,0 +1,197 @@
+use std::{path::PathBuf, process::Command};
+
+use agent_lib::{ExitReason, ResourceLimits, ServiceState, ServiceStatus, StartServiceRequest};
+
+use super::{check_service_name, ServiceBackend, ServiceError};
+
+/// Properties read by `systemctl show` to build a [`ServiceStatus`].
+const STATUS_PROPERTIES: &str =
+    "LoadState,ActiveState,SubState,MainPID,UnitFileState,NRestarts,ExecMainCode,ExecMainStatus";
+
+/// Controls systemd units with `systemctl`.
+#[derive(Debug, Clone)]
+pub struct Systemd {
+    systemctl: PathBuf,
+}
+
+impl Default for Systemd {
+    fn default() -> Self {
+        Self {
+            systemctl: PathBuf::from("systemctl"),
+        }
+    }
+}
+
+impl Systemd {
+    /// Run `systemctl <args> -- <service>`, returning it's stdout, or it's stderr as a
+    /// [`ServiceError::Failed`] if it exits unsuccessfully.
+    fn systemctl(&self, args: &[&str], service: &str) -> Result<String, ServiceError> {
+        self.systemctl_with(args, service, &[])
+    }
+
+    /// Like [`Systemd::systemctl`], with `trailing` arguments after the service.
+    fn systemctl_with(
+        &self,
+        args: &[&str],
+        service: &str,
+        trailing: &[String],
+    ) -> Result<String, ServiceError> {
+        check_service_name(service)?;
+        let output = Command::new(&self.systemctl)
+            .args(args)
+            .arg("--")
+            .arg(service)
+            .args(trailing)
+            .output()?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+            return Err(ServiceError::Failed(if stderr.is_empty() {
+                format!("systemctl {} {}", args.join(" "), output.status)
+            } else {
+                stderr
+            }));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+}
+
+impl ServiceBackend for Systemd {
+    fn start(&self, request: &StartServiceRequest) -> Result<(), ServiceError> {
+        if let Some(wrapper) = &request.wrapper {
+            return Err(ServiceError::Failed(format!(
+                "systemd units can't be started under {}, use the supervisor backend or wrap the unit's ExecStart instead",
+                wrapper.name()
+            )));
+        }
+        // the limits last until the host reboots, or are replaced by those of a later start.
+        let properties = limit_properties(&request.limits);
+        if !properties.is_empty() {
+            self.systemctl_with(
+                &["set-property", "--runtime"],
+                &request.service,
+                &properties,
+            )?;
+        }
+        self.systemctl(&["start"], &request.service).map(drop)
+    }
+
+    fn stop(&self, service: &str) -> Result<(), ServiceError> {
+        self.systemctl(&["stop"], service).map(drop)
+    }
+
+    fn restart(&self, service: &str) -> Result<(), ServiceError> {
+        self.systemctl(&["restart"], service).map(drop)
+    }
+
+    fn enable(&self, service: &str) -> Result<(), ServiceError> {
+        self.systemctl(&["enable"], service).map(drop)
+    }
+
+    fn enable_core_dumps(&self, _service: &str) -> Result<PathBuf, ServiceError> {
+        Err(ServiceError::Failed(
+            "the cores of systemd units are kept by systemd-coredump, see coredumpctl, or use the supervisor backend".to_string(),
+        ))
+    }
+
+    fn status(&self, service: &str) -> Result<ServiceStatus, ServiceError> {
+        let output = self.systemctl(&["show", "--property", STATUS_PROPERTIES], service)?;
+        parse_show(service, &output)
+    }
+
+    fn cgroup(&self, service: &str) -> Result<Option<String>, ServiceError> {
+        let output = self.systemctl(&["show", "--property", "ControlGroup", "--value"], service)?;
+        let cgroup = output.trim();
+        Ok((!cgroup.is_empty()).then(|| cgroup.to_string()))
+    }
+}
+
+/// The unit properties setting `limits`, see systemd.resource-control(5).
+fn limit_properties(limits: &ResourceLimits) -> Vec<String> {
+    let ResourceLimits {
+        cpu_quota_percent,
+        memory_max,
+        io_weight,
+        pids_max,
+    } = limits;
+    [
+        cpu_quota_percent.map(|percent| format!("CPUQuota={percent}%")),
+        memory_max.map(|bytes| format!("MemoryMax={bytes}")),
+        io_weight.map(|weight| format!("IOWeight={weight}")),
+        pids_max.map(|pids| format!("TasksMax={pids}")),
+    ]
+    .into_iter()
+    .flatten()
+    .collect()
+}
+
+/// Parse the `Key=Value` lines printed by `systemctl show`.
+fn parse_show(service: &str, output: &str) -> Result<ServiceStatus, ServiceError> {
+    let property = |name: &str| {
+        output
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix('='))
+            .unwrap_or_default()
+    };
+    // systemd reports unknown units as loaded, but `not-found`, rather than failing.
+    if property("LoadState") == "not-found" {
+        return Err(ServiceError::NotFound);
+    }
+    let state = match property("ActiveState") {
+        "active" => ServiceState::Active,
+        "reloading" => ServiceState::Reloading,
+        "inactive" => ServiceState::Inactive,
+        "failed" => ServiceState::Failed,
+        "activating" => ServiceState::Activating,
+        "deactivating" => ServiceState::Deactivating,
+        other => ServiceState::Other(other.to_string()),
+    };
+    // the main process's exit status, as a `CLD_*` code from waitid(2).
+    let exit_status = property("ExecMainStatus").parse().unwrap_or_default();
+    let last_exit = match property("ExecMainCode") {
+        "1" => Some(ExitReason::Code(exit_status)),
+        "2" | "3" => Some(ExitReason::Signal(exit_status)),
+        _ => None,
+    };
+    Ok(ServiceStatus {
+        service: service.to_string(),
+        state,
+        sub_state: property("SubState").to_string(),
+        main_pid: property("MainPID").parse().ok().filter(|pid| *pid != 0),
+        enabled: matches!(property("UnitFileState"), "enabled" | "enabled-runtime"),
+        restarts: property("NRestarts").parse().unwrap_or_default(),
+        last_exit,
+        process: None,
+        cgroup: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_show() {
+        let output = "MainPID=1234\nLoadState=loaded\nActiveState=active\nSubState=running\nUnitFileState=enabled\nNRestarts=2\nExecMainCode=0\nExecMainStatus=0\n";
+        let status = parse_show("casper-node-launcher", output).unwrap();
+        assert_eq!(status.state, ServiceState::Active);
+        assert_eq!(status.sub_state, "running");
+        assert_eq!(status.main_pid, Some(1234));
+        assert!(status.enabled);
+        assert_eq!(status.restarts, 2);
+        assert_eq!(status.last_exit, None);
+
+        let output = "MainPID=0\nLoadState=loaded\nActiveState=failed\nSubState=failed\nUnitFileState=disabled\nNRestarts=0\nExecMainCode=2\nExecMainStatus=11\n";
+        let status = parse_show("casper-node-launcher", output).unwrap();
+        assert_eq!(status.state, ServiceState::Failed);
+        assert_eq!(status.main_pid, None);
+        assert!(!status.enabled);
+        assert_eq!(status.last_exit, Some(ExitReason::Signal(11)));
+
+        let output =
+            "MainPID=0\nLoadState=not-found\nActiveState=inactive\nSubState=dead\nUnitFileState=\n";
+        assert!(matches!(
+            parse_show("missing", output),
+            Err(ServiceError::NotFound)
+        ));
+    }
+}
@@ -0,0 +1,154 @@
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+
+use agent_lib::wrapper::{ValgrindTool, Wrapper};
+
+/// The program and arguments that run `command` under `wrapper`, which writes what it records into
+/// `output_dir`.
+pub fn wrap_command(
+    wrapper: &Wrapper,
+    command: &Path,
+    args: &[String],
+    output_dir: &Path,
+) -> (PathBuf, Vec<OsString>) {
+    let output = |name: &str| {
+        let mut arg = OsString::new();
+        arg.push(output_dir.join(name));
+        arg
+    };
+    let flag_with_output = |flag: &str, name: &str| {
+        let mut arg = OsString::from(flag);
+        arg.push(output(name));
+        arg
+    };
+
+    let mut wrapped = Vec::<OsString>::new();
+    match wrapper {
+        Wrapper::Gdb { commands } => {
+            wrapped.extend(["-q", "-batch", "-nx"].map(OsString::from));
+            let extra = commands.iter().map(|command| command.command().to_string());
+            for ex in [
+                "set pagination off".to_string(),
+                format!("set logging file {}", output_dir.join("gdb.log").display()),
+                "set logging overwrite on".to_string(),
+                "set logging on".to_string(),
+                "run".to_string(),
+                "thread apply all bt full".to_string(),
+            ]
+            .into_iter()
+            .chain(extra)
+            {
+                wrapped.push("-ex".into());
+                wrapped.push(ex.into());
+            }
+            wrapped.push("--args".into());
+        }
+        Wrapper::Valgrind { tool, leak_check } => {
+            wrapped.push(format!("--tool={tool}").into());
+            wrapped.push(flag_with_output("--log-file=", "valgrind.%p.log"));
+            wrapped.push("--trace-children=yes".into());
+            match tool {
+                ValgrindTool::Memcheck if *leak_check => wrapped.push("--leak-check=full".into()),
+                ValgrindTool::Massif => {
+                    wrapped.push(flag_with_output("--massif-out-file=", "massif.out.%p"))
+                }
+                ValgrindTool::Callgrind => wrapped.push(flag_with_output(
+                    "--callgrind-out-file=",
+                    "callgrind.out.%p",
+                )),
+                ValgrindTool::Memcheck | ValgrindTool::Helgrind => {}
+            }
+        }
+        Wrapper::Perf {
+            frequency,
+            call_graph,
+        } => {
+            wrapped.extend(["record".into(), "-F".into(), frequency.to_string().into()]);
+            if let Some(call_graph) = call_graph {
+                wrapped.push(format!("--call-graph={call_graph}").into());
+            }
+            wrapped.extend(["-o".into(), output("perf.data"), "--".into()]);
+        }
+        Wrapper::Heaptrack => wrapped.extend(["-o".into(), output("heaptrack")]),
+        Wrapper::Strace { trace, timestamps } => {
+            wrapped.extend(["-f".into(), "-o".into(), output("strace.log")]);
+            if *timestamps {
+                wrapped.extend(["-tt", "-T"].map(OsString::from));
+            }
+            if let Some(trace) = trace {
+                wrapped.extend(["-e".into(), format!("trace={trace}").into()]);
+            }
+            wrapped.push("--".into());
+        }
+    }
+    wrapped.push(command.into());
+    wrapped.extend(args.iter().map(OsString::from));
+    (PathBuf::from(wrapper.name()), wrapped)
+}
+
+#[cfg(test)]
+mod tests {
+    use agent_lib::wrapper::{CallGraph, GdbCommand};
+
+    use super::*;
+
+    #[test]
+    fn test_wrap_command() {
+        let command = Path::new("/usr/bin/casper-node");
+        let args = [
+            "validator".to_string(),
+            "/etc/casper/config.toml".to_string(),
+        ];
+        let output_dir = Path::new("/state/debug/1-perf");
+        let perf = Wrapper::Perf {
+            frequency: 999,
+            call_graph: Some(CallGraph::Dwarf),
+        };
+        let (program, wrapped) = wrap_command(&perf, command, &args, output_dir);
+        assert_eq!(program, PathBuf::from("perf"));
+        assert_eq!(
+            wrapped,
+            [
+                "record",
+                "-F",
+                "999",
+                "--call-graph=dwarf",
+                "-o",
+                "/state/debug/1-perf/perf.data",
+                "--",
+                "/usr/bin/casper-node",
+                "validator",
+                "/etc/casper/config.toml"
+            ]
+            .map(OsString::from)
+        );
+
+        let valgrind = Wrapper::Valgrind {
+            tool: ValgrindTool::Massif,
+            leak_check: true,
+        };
+        let (program, wrapped) = wrap_command(&valgrind, command, &[], output_dir);
+        assert_eq!(program, PathBuf::from("valgrind"));
+        assert_eq!(
+            wrapped,
+            [
+                "--tool=massif",
+                "--log-file=/state/debug/1-perf/valgrind.%p.log",
+                "--trace-children=yes",
+                "--massif-out-file=/state/debug/1-perf/massif.out.%p",
+                "/usr/bin/casper-node",
+            ]
+            .map(OsString::from)
+        );
+
+        let gdb = Wrapper::Gdb {
+            commands: vec![GdbCommand::InfoRegisters],
+        };
+        let (_, wrapped) = wrap_command(&gdb, command, &args, output_dir);
+        let position = |arg: &str| wrapped.iter().position(|wrapped| wrapped == arg).unwrap();
+        assert!(position("thread apply all bt full") < position("info registers"));
+        assert_eq!(wrapped[position("--args") + 1], "/usr/bin/casper-node");
+    }
+}
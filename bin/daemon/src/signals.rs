@@ -0,0 +1,142 @@
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use agent_lib::Signal;
+use async_mutex::Mutex;
+
+use crate::procfs;
+
+/// Sends signals to processes, to inject faults, and resumes paused processes when their pause is
+/// up.
+///
+/// Pauses are ended by the daemon rather than the client, so that a client that goes away doesn't
+/// leave a process stopped. They don't survive the daemon restarting though.
+#[derive(Clone, Default)]
+pub struct Signals {
+    /// The latest pause of each paused pid, so that an earlier pause doesn't end a later one.
+    pauses: Arc<Mutex<HashMap<u32, u64>>>,
+    next_pause: Arc<AtomicU64>,
+}
+
+impl Signals {
+    /// Send `signal` to `pid`, then `SIGCONT` after `resume_after`, if given.
+    pub async fn send(
+        &self,
+        pid: u32,
+        signal: Signal,
+        resume_after: Option<Duration>,
+    ) -> Result<(), std::io::Error> {
+        let started = procfs::start_time(pid)?;
+        let mut pauses = self.pauses.lock().await;
+        kill(pid, signal)?;
+        if let Some(resume_after) = resume_after {
+            let pause = self.next_pause.fetch_add(1, Ordering::Relaxed);
+            pauses.insert(pid, pause);
+            let pauses = self.pauses.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(resume_after).await;
+                let mut pauses = pauses.lock().await;
+                if pauses.get(&pid) != Some(&pause) {
+                    return;
+                }
+                pauses.remove(&pid);
+                // it may have exited since, and it's pid been reused.
+                if procfs::start_time(pid).ok() != Some(started) {
+                    return;
+                }
+                if let Err(err) = kill(pid, Signal::CONT) {
+                    println!("err while resuming {pid} {err:?}");
+                }
+            });
+        } else if signal == Signal::CONT {
+            pauses.remove(&pid);
+        }
+        Ok(())
+    }
+}
+
+fn kill(pid: u32, signal: Signal) -> Result<(), std::io::Error> {
+    // pids of 0 or less signal process groups, and init and the agent itself are off limits.
+    let pid = match i32::try_from(pid) {
+        Ok(pid) if pid > 1 && pid as u32 != std::process::id() => pid,
+        _ => return Err(ErrorKind::PermissionDenied.into()),
+    };
+    // SAFETY: kill has no memory safety requirements.
+    if unsafe { libc::kill(pid, signal.0) } == -1 {
+        let err = std::io::Error::last_os_error();
+        if err.raw_os_error() == Some(libc::ESRCH) {
+            return Err(std::io::Error::new(ErrorKind::NotFound, err));
+        }
+        return Err(err);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        process::{Command, Stdio},
+        time::Instant,
+    };
+
+    use super::*;
+
+    /// Wait for a process to be in `state`, as in `/proc/<pid>/stat`, which it isn't as soon as
+    /// it's been signalled.
+    async fn wait_for_state(pid: u32, state: char, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            let stat = fs::read_to_string(format!("/proc/{pid}/stat")).unwrap();
+            if stat.rsplit_once(") ").unwrap().1.starts_with(state) {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        false
+    }
+
+    #[tokio::test]
+    async fn test_pause_resumes() {
+        let mut child = Command::new("sleep")
+            .arg("100")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let pid = child.id();
+        let signals = Signals::default();
+
+        signals
+            .send(pid, Signal::STOP, Some(Duration::from_secs(2)))
+            .await
+            .unwrap();
+        assert!(wait_for_state(pid, 'T', Duration::from_secs(1)).await);
+        // a later pause isn't ended by the first.
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        signals
+            .send(pid, Signal::STOP, Some(Duration::from_secs(2)))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert!(!wait_for_state(pid, 'S', Duration::from_millis(100)).await);
+        assert!(wait_for_state(pid, 'S', Duration::from_secs(2)).await);
+
+        let err = signals
+            .send(std::process::id(), Signal::STOP, None)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        signals.send(pid, Signal::KILL, None).await.unwrap();
+        child.wait().unwrap();
+        let err = signals.send(pid, Signal::KILL, None).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+}
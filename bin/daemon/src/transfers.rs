@@ -0,0 +1,530 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs::{self, File, OpenOptions},
+    io::{BufReader, Read},
+    os::unix::{ffi::OsStrExt, fs::FileExt},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use agent_lib::{
+    codec::{Codec, Dictionary},
+    commit_staged_file, hash_from_hex, hash_to_hex, verify_file_hash, write_then_rename,
+    CompressedWireFileChunk, TransferSummary,
+};
+use async_mutex::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::blobs::BlobStore;
+
+const META_FILE: &str = "transfer.yaml";
+
+/// In-flight transfers keyed by [`transfer_key`], shared by all channels.
+pub type InFlightTransfers = Arc<Mutex<HashMap<[u8; 32], InFlightTransfer>>>;
+
+/// Identifies the transfer of the file with `content_hash` to `target_path`, so that the same file
+/// can be uploaded to several targets at once, e.g. a node binary into each version directory.
+pub fn transfer_key(content_hash: &[u8; 32], target_path: &Path) -> [u8; 32] {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(content_hash);
+    hasher.update(target_path.as_os_str().as_bytes());
+    hasher.finalize().into()
+}
+
+/// A chunked upload that has not yet received all of it's chunks.
+///
+/// Chunks are decompressed straight into a staging file next to the target as they arrive, so
+/// memory use is bounded by the chunk size. The metadata is persisted alongside the received
+/// chunks by the [`TransferStore`], so that an upload can be resumed after the daemon restarts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InFlightTransfer {
+    pub filename: String,
+    pub target_path: PathBuf,
+    pub target_perms: u32,
+    pub target_owner: Option<u32>,
+    pub target_group: Option<u32>,
+    pub num_chunks: u64,
+    /// Uncompressed size of every chunk but the last.
+    pub chunk_size: u64,
+    /// How every chunk is encoded, so that the chunks concatenate into a single stream.
+    #[serde(default)]
+    pub codec: Codec,
+    /// blake3 hash of the uncompressed file, checked before it is moved into place.
+    pub content_hash: [u8; 32],
+    /// Where the decompressed file is written, renamed over `target_path` once complete.
+    pub staging_path: PathBuf,
+    #[serde(skip, default = "Instant::now")]
+    pub last_updated: Instant,
+    #[serde(skip)]
+    pub received_chunks: BTreeSet<u64>,
+}
+
+impl InFlightTransfer {
+    pub fn is_complete(&self) -> bool {
+        self.received_chunks.len() as u64 == self.num_chunks
+    }
+
+    pub fn missing_chunks(&self) -> Vec<u64> {
+        (0..self.num_chunks)
+            .filter(|chunk_id| !self.received_chunks.contains(chunk_id))
+            .collect()
+    }
+
+    pub fn summary(&self) -> TransferSummary {
+        TransferSummary {
+            content_hash: self.content_hash,
+            filename: self.filename.clone(),
+            target_path: self.target_path.clone(),
+            num_chunks: self.num_chunks,
+            received_chunks: self.received_chunks.len() as u64,
+            idle_secs: self.last_updated.elapsed().as_secs(),
+        }
+    }
+}
+
+/// On-disk state for in-flight transfers, one directory per transfer named by it's hex encoded
+/// [`transfer_key`].
+#[derive(Debug, Clone)]
+pub struct TransferStore {
+    dir: PathBuf,
+}
+
+impl TransferStore {
+    pub fn new(dir: PathBuf) -> Result<Self, std::io::Error> {
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    fn transfer_dir(&self, key: &[u8; 32]) -> PathBuf {
+        self.dir.join(hash_to_hex(key))
+    }
+
+    fn chunk_path(&self, key: &[u8; 32], chunk_id: u64) -> PathBuf {
+        self.transfer_dir(key).join(format!("chunk-{chunk_id}"))
+    }
+
+    /// Load all transfers left on disk, e.g. by a previous run of the daemon.
+    pub fn load_all(&self) -> Result<HashMap<[u8; 32], InFlightTransfer>, std::io::Error> {
+        let mut transfers = HashMap::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let Some(key) = entry.file_name().to_str().and_then(hash_from_hex) else {
+                continue;
+            };
+            match self.load(&key) {
+                Ok(transfer) => {
+                    transfers.insert(key, transfer);
+                }
+                Err(err) => {
+                    println!(
+                        "discarding unreadable transfer state in {} {err:?}",
+                        entry.path().display()
+                    );
+                    self.remove(&key)?;
+                }
+            }
+        }
+        Ok(transfers)
+    }
+
+    fn load(&self, key: &[u8; 32]) -> Result<InFlightTransfer, std::io::Error> {
+        let dir = self.transfer_dir(key);
+        let reader = BufReader::new(File::open(dir.join(META_FILE))?);
+        let mut transfer: InFlightTransfer = serde_yaml::from_reader(reader)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+        for entry in fs::read_dir(&dir)? {
+            let name = entry?.file_name();
+            if let Some(chunk_id) = name
+                .to_str()
+                .and_then(|name| name.strip_prefix("chunk-"))
+                .and_then(|id| id.parse::<u64>().ok())
+            {
+                transfer.received_chunks.insert(chunk_id);
+            }
+        }
+        Ok(transfer)
+    }
+
+    /// Persist the metadata for a newly started transfer.
+    pub fn create(
+        &self,
+        key: &[u8; 32],
+        transfer: &InFlightTransfer,
+    ) -> Result<(), std::io::Error> {
+        let dir = self.transfer_dir(key);
+        fs::create_dir_all(&dir)?;
+        let meta = serde_yaml::to_string(transfer)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+        write_then_rename(&dir.join(META_FILE), meta.as_bytes())
+    }
+
+    /// Verify and decode a received chunk into the transfer's staging file at it's offset, then
+    /// persist the encoded chunk (which is what marks it received). Chunks are renamed into place
+    /// so a partially written chunk is never seen as received after a restart.
+    pub fn write_chunk(
+        &self,
+        key: &[u8; 32],
+        staging_path: &Path,
+        chunk_size: u64,
+        chunk: &CompressedWireFileChunk,
+        dictionary: Option<&Dictionary>,
+    ) -> Result<(), std::io::Error> {
+        chunk
+            .verify()
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+        let data = chunk.codec.decode_bulk(
+            &chunk.zstd_compressed_data_chunk,
+            chunk_size as usize,
+            dictionary,
+        )?;
+        let staging_file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(staging_path)?;
+        staging_file.write_all_at(&data, chunk.chunk_id * chunk_size)?;
+        staging_file.sync_data()?;
+        write_then_rename(
+            &self.chunk_path(key, chunk.chunk_id),
+            &chunk.zstd_compressed_data_chunk,
+        )
+    }
+
+    /// Read the encoded file back out of the received chunks, one chunk file at a time, removing
+    /// each chunk file once it has been read so the upload isn't kept on disk twice over.
+    pub fn drain_chunks(&self, key: &[u8; 32], transfer: &InFlightTransfer) -> impl Read {
+        ChunkFilesReader {
+            paths: (0..transfer.num_chunks)
+                .rev()
+                .map(|chunk_id| self.chunk_path(key, chunk_id))
+                .collect(),
+            current: None,
+        }
+    }
+
+    /// Drop a transfer that will not complete, along with it's staging file.
+    pub fn discard(
+        &self,
+        key: &[u8; 32],
+        transfer: &InFlightTransfer,
+    ) -> Result<(), std::io::Error> {
+        match fs::remove_file(&transfer.staging_path) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+        self.remove(key)
+    }
+
+    pub fn remove(&self, key: &[u8; 32]) -> Result<(), std::io::Error> {
+        match fs::remove_dir_all(self.transfer_dir(key)) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Reads a sequence of files as if they were concatenated, opening each only once the previous
+/// one is exhausted and removing it then.
+struct ChunkFilesReader {
+    /// Remaining paths, last first.
+    paths: Vec<PathBuf>,
+    current: Option<(PathBuf, File)>,
+}
+
+impl Read for ChunkFilesReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            if let Some((path, file)) = &mut self.current {
+                let read = file.read(buf)?;
+                if read > 0 || buf.is_empty() {
+                    return Ok(read);
+                }
+                fs::remove_file(path)?;
+            }
+            match self.paths.pop() {
+                Some(path) => {
+                    let file = File::open(&path)?;
+                    self.current = Some((path, file));
+                }
+                None => {
+                    self.current = None;
+                    return Ok(0);
+                }
+            }
+        }
+    }
+}
+
+/// Summaries of the in-flight transfers, for `list_transfers`.
+pub fn summaries(transfers: &HashMap<[u8; 32], InFlightTransfer>) -> Vec<TransferSummary> {
+    transfers.values().map(InFlightTransfer::summary).collect()
+}
+
+/// Drop an in-flight transfer along with it's state on disk, returning it if there was one.
+pub fn cancel(
+    transfers: &mut HashMap<[u8; 32], InFlightTransfer>,
+    transfer_store: &TransferStore,
+    key: &[u8; 32],
+) -> Result<Option<InFlightTransfer>, std::io::Error> {
+    let Some(transfer) = transfers.remove(key) else {
+        return Ok(None);
+    };
+    println!(
+        "cancelling transfer of {} to {}",
+        transfer.filename,
+        transfer.target_path.display()
+    );
+    transfer_store.discard(key, &transfer)?;
+    Ok(Some(transfer))
+}
+
+/// Verify a completed transfer against it's hash, move it's chunks into the blob store, then
+/// rename it's staging file over the target. It's state is dropped either way.
+pub fn commit(
+    transfer_store: &TransferStore,
+    blob_store: &BlobStore,
+    key: &[u8; 32],
+    transfer: &InFlightTransfer,
+) -> Result<(), String> {
+    let result = commit_staged_transfer(transfer_store, blob_store, key, transfer);
+    let removed = match result {
+        Ok(()) => transfer_store.remove(key),
+        Err(_) => transfer_store.discard(key, transfer),
+    };
+    if let Err(err) = removed {
+        println!("err while removing transfer state {err:?}");
+    }
+    result
+}
+
+fn commit_staged_transfer(
+    transfer_store: &TransferStore,
+    blob_store: &BlobStore,
+    key: &[u8; 32],
+    transfer: &InFlightTransfer,
+) -> Result<(), String> {
+    verify_file_hash(&transfer.staging_path, &transfer.content_hash)
+        .map_err(|err| format!("unable to verify file assembled from chunks {err}"))?;
+    if let Err(err) = blob_store.insert(
+        &transfer.content_hash,
+        transfer.codec,
+        transfer_store.drain_chunks(key, transfer),
+    ) {
+        println!("err while storing blob for {} {err:?}", transfer.filename);
+    }
+    commit_staged_file(
+        &transfer.staging_path,
+        &transfer.target_path,
+        transfer.target_perms,
+        transfer.target_owner,
+        transfer.target_group,
+    )
+    .map_err(|err| {
+        format!(
+            "unable to write assembled file to {} {err:?}",
+            transfer.target_path.display()
+        )
+    })?;
+    println!(
+        "wrote {} with perms {:o}",
+        transfer.target_path.display(),
+        transfer.target_perms
+    );
+    Ok(())
+}
+
+/// Commit the transfers that received all of their chunks but weren't committed, because the
+/// daemon stopped in between, returning how many there were.
+pub fn commit_complete(
+    transfers: &mut HashMap<[u8; 32], InFlightTransfer>,
+    transfer_store: &TransferStore,
+    blob_store: &BlobStore,
+) -> usize {
+    let complete = transfers
+        .iter()
+        .filter(|(_, transfer)| transfer.is_complete())
+        .map(|(key, _)| *key)
+        .collect::<Vec<_>>();
+    for key in &complete {
+        let Some(transfer) = transfers.remove(key) else {
+            continue;
+        };
+        if let Err(message) = commit(transfer_store, blob_store, key, &transfer) {
+            println!("err while completing transfer {message}");
+        }
+    }
+    complete.len()
+}
+
+/// Drop the transfers that have not received a chunk within `ttl`, returning how many were.
+pub fn expire(
+    transfers: &mut HashMap<[u8; 32], InFlightTransfer>,
+    transfer_store: &TransferStore,
+    ttl: Duration,
+) -> usize {
+    let expired = transfers
+        .iter()
+        .filter(|(_, transfer)| transfer.last_updated.elapsed() > ttl)
+        .map(|(key, _)| *key)
+        .collect::<Vec<_>>();
+    for key in &expired {
+        let Some(transfer) = transfers.remove(key) else {
+            continue;
+        };
+        println!(
+            "expiring abandoned transfer of {} to {}",
+            transfer.filename,
+            transfer.target_path.display()
+        );
+        if let Err(err) = transfer_store.discard(key, &transfer) {
+            println!("err while removing expired transfer state {err:?}");
+        }
+    }
+    expired.len()
+}
+
+/// A transfer ttl, which can't be zero or every transfer would be dropped between it's chunks.
+pub fn parse_ttl_secs(s: &str) -> Result<u64, String> {
+    match s.parse::<u64>() {
+        Ok(0) => Err("the ttl must be at least 1 second".to_string()),
+        Ok(secs) => Ok(secs),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+/// Periodically drop transfers that have not received a chunk within `ttl`.
+pub async fn reap_expired_transfers(
+    in_flight_transfers: InFlightTransfers,
+    transfer_store: TransferStore,
+    ttl: Duration,
+) {
+    // never spin, however short the ttl.
+    let period = ttl.clamp(Duration::from_secs(1), Duration::from_secs(60));
+    loop {
+        tokio::time::sleep(period).await;
+        let mut lock = in_flight_transfers.lock().await;
+        expire(&mut lock, &transfer_store, ttl);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use agent_lib::test_util::TempDir;
+
+    use super::*;
+
+    fn transfer(dir: &Path, name: &str, idle: Duration) -> InFlightTransfer {
+        InFlightTransfer {
+            filename: name.to_string(),
+            target_path: dir.join(name),
+            target_perms: 0o644,
+            target_owner: None,
+            target_group: None,
+            num_chunks: 2,
+            chunk_size: 4096,
+            codec: Codec::Zstd,
+            content_hash: [0; 32],
+            staging_path: dir.join(format!(".{name}.staging")),
+            last_updated: Instant::now() - idle,
+            received_chunks: BTreeSet::from([0]),
+        }
+    }
+
+    #[test]
+    fn test_parse_ttl_secs() {
+        assert_eq!(parse_ttl_secs("3600"), Ok(3600));
+        assert!(parse_ttl_secs("0").is_err());
+        assert!(parse_ttl_secs("-1").is_err());
+    }
+
+    #[test]
+    fn test_expire_list_and_cancel_transfers() {
+        // the same file uploaded to two targets is two transfers.
+        assert_ne!(
+            transfer_key(&[1; 32], Path::new("/var/lib/casper/bin/1_0_0/casper-node")),
+            transfer_key(&[1; 32], Path::new("/var/lib/casper/bin/2_0_0/casper-node"))
+        );
+
+        let temp = TempDir::new("daemon-transfers");
+        let store = TransferStore::new(temp.path().join("transfers")).unwrap();
+        let mut transfers = HashMap::new();
+        for (key, name, idle) in [
+            ([1; 32], "abandoned", Duration::from_secs(120)),
+            ([2; 32], "active", Duration::ZERO),
+            ([3; 32], "cancelled", Duration::ZERO),
+        ] {
+            let transfer = transfer(temp.path(), name, idle);
+            store.create(&key, &transfer).unwrap();
+            fs::write(&transfer.staging_path, b"partial").unwrap();
+            transfers.insert(key, transfer);
+        }
+
+        assert_eq!(expire(&mut transfers, &store, Duration::from_secs(60)), 1);
+        assert!(!temp.path().join(".abandoned.staging").exists());
+        assert_eq!(store.load_all().unwrap().len(), 2);
+
+        let mut listed = summaries(&transfers);
+        listed.sort_by(|a, b| a.filename.cmp(&b.filename));
+        assert_eq!(
+            listed
+                .iter()
+                .map(|summary| (summary.filename.as_str(), summary.received_chunks))
+                .collect::<Vec<_>>(),
+            [("active", 1), ("cancelled", 1)]
+        );
+
+        let cancelled = cancel(&mut transfers, &store, &[3; 32]).unwrap().unwrap();
+        assert_eq!(cancelled.filename, "cancelled");
+        assert!(!temp.path().join(".cancelled.staging").exists());
+        assert!(cancel(&mut transfers, &store, &[3; 32]).unwrap().is_none());
+        assert_eq!(
+            store.load_all().unwrap().into_keys().collect::<Vec<_>>(),
+            [[2; 32]]
+        );
+        assert_eq!(transfers.len(), 1);
+
+        for chunk_id in 0..2 {
+            fs::write(store.chunk_path(&[2; 32], chunk_id), [chunk_id as u8; 3]).unwrap();
+        }
+        let mut drained = Vec::new();
+        store
+            .drain_chunks(&[2; 32], &transfers[&[2; 32]])
+            .read_to_end(&mut drained)
+            .unwrap();
+        assert_eq!(drained, [0, 0, 0, 1, 1, 1]);
+        assert!((0..2).all(|chunk_id| !store.chunk_path(&[2; 32], chunk_id).exists()));
+    }
+
+    #[test]
+    fn test_commit_complete_transfers() {
+        let temp = TempDir::new("daemon-transfers-complete");
+        let store = TransferStore::new(temp.path().join("transfers")).unwrap();
+        let blob_store = BlobStore::new(temp.path().join("blobs")).unwrap();
+        let mut transfers = HashMap::new();
+        for (key, name, content_hash) in [
+            ([1; 32], "complete", *blake3::hash(b"complete").as_bytes()),
+            ([2; 32], "corrupt", [0; 32]),
+            ([3; 32], "partial", [0; 32]),
+        ] {
+            let mut transfer = transfer(temp.path(), name, Duration::ZERO);
+            transfer.content_hash = content_hash;
+            if name != "partial" {
+                transfer.received_chunks.insert(1);
+            }
+            store.create(&key, &transfer).unwrap();
+            fs::write(&transfer.staging_path, name).unwrap();
+            transfers.insert(key, transfer);
+        }
+
+        assert_eq!(commit_complete(&mut transfers, &store, &blob_store), 2);
+        assert_eq!(fs::read(temp.path().join("complete")).unwrap(), b"complete");
+        assert!(!temp.path().join("corrupt").exists());
+        assert!(!temp.path().join(".corrupt.staging").exists());
+        assert_eq!(transfers.into_keys().collect::<Vec<_>>(), [[3; 32]]);
+        assert_eq!(
+            store.load_all().unwrap().into_keys().collect::<Vec<_>>(),
+            [[3; 32]]
+        );
+    }
+}
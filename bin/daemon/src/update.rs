@@ -0,0 +1,436 @@
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io::{self, Read, Write},
+    net::TcpListener,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        linux::net::SocketAddrExt,
+        unix::{
+            ffi::OsStrExt,
+            fs::PermissionsExt,
+            net::{SocketAddr, UnixDatagram},
+            process::CommandExt,
+        },
+    },
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use agent_lib::{archive::unpack_dir, AgentUpdateRequest};
+use async_mutex::Mutex;
+
+use crate::services::Services;
+
+/// The fd of the listening socket, handed over to the new daemon.
+const LISTEN_FD_ENV: &str = "AGENT_LISTEN_FD";
+/// The fd the new daemon writes a byte to once it's serving.
+const READY_FD_ENV: &str = "AGENT_READY_FD";
+/// How long the daemon in a tarball has to print it's version.
+const VERSION_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long the new daemon has to start serving.
+const READY_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long the new daemon has to stay up once it's serving, to pass the health check.
+const SETTLE_TIME: Duration = Duration::from_secs(3);
+/// How long the old daemon keeps running after an update, so that the response reaches the client.
+const HAND_OVER_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(thiserror::Error, Debug)]
+pub enum UpdateError {
+    #[error("updates aren't allowed, the daemon wasn't started with --allow-update")]
+    NotAllowed,
+    #[error("the agent is already being updated")]
+    InProgress,
+    #[error("{0}")]
+    Invalid(String),
+    #[error("{0}")]
+    RolledBack(String),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// Replaces the daemon with the one in a `cargo xtask dist` tarball.
+///
+/// The new daemon is installed over the old one's binary, and started with the same arguments,
+/// inheriting the listening socket, so that clients can connect throughout. Once it's passed it's
+/// health check the old daemon exits, otherwise the old binary is put back.
+#[derive(Clone)]
+pub struct Updater {
+    allowed: bool,
+    /// The daemon's binary, which is replaced, and the arguments the new daemon is run with.
+    exe: PathBuf,
+    args: Vec<OsString>,
+    /// Where tarballs are unpacked.
+    dir: PathBuf,
+    listen_fd: RawFd,
+    updating: Arc<Mutex<()>>,
+}
+
+impl Updater {
+    pub fn new(
+        allowed: bool,
+        exe: PathBuf,
+        args: Vec<OsString>,
+        dir: PathBuf,
+        listen_fd: RawFd,
+    ) -> Self {
+        Self {
+            allowed,
+            exe,
+            args,
+            dir,
+            listen_fd,
+            updating: Arc::default(),
+        }
+    }
+
+    /// Install and start the new daemon, returning it's pid once it's healthy.
+    pub async fn update(&self, request: AgentUpdateRequest) -> Result<u32, UpdateError> {
+        if !self.allowed {
+            return Err(UpdateError::NotAllowed);
+        }
+        let Some(_updating) = self.updating.try_lock() else {
+            return Err(UpdateError::InProgress);
+        };
+        let updater = self.clone();
+        tokio::task::spawn_blocking(move || updater.install(request))
+            .await
+            .map_err(io::Error::other)?
+    }
+
+    fn install(&self, request: AgentUpdateRequest) -> Result<u32, UpdateError> {
+        let staging = self.dir.join(request.new_version.to_string());
+        if staging.exists() {
+            fs::remove_dir_all(&staging)?;
+        }
+        let installed = self.install_from(&staging, request);
+        if let Err(err) = fs::remove_dir_all(&staging) {
+            println!("err while removing {} {err:?}", staging.display());
+        }
+        installed
+    }
+
+    fn install_from(
+        &self,
+        staging: &Path,
+        request: AgentUpdateRequest,
+    ) -> Result<u32, UpdateError> {
+        let AgentUpdateRequest {
+            new_version,
+            dist_tarball,
+        } = request;
+        unpack_dir(dist_tarball, staging)
+            .map_err(|err| UpdateError::Invalid(format!("unable to unpack the tarball, {err}")))?;
+        let new_exe = staging.join("dist").join("daemon");
+        if !new_exe.is_file() {
+            return Err(UpdateError::Invalid(
+                "the tarball has no dist/daemon".to_string(),
+            ));
+        }
+        check_version(&new_exe, new_version)?;
+
+        // the new binary is copied next to the old one first, so that it replaces it atomically.
+        let staged = sibling(&self.exe, "update");
+        let backup = sibling(&self.exe, "previous");
+        fs::copy(&new_exe, &staged)?;
+        fs::set_permissions(&staged, fs::Permissions::from_mode(0o755))?;
+        fs::copy(&self.exe, &backup)?;
+        fs::rename(&staged, &self.exe)?;
+        println!("installed version {new_version}, starting it");
+        match self.start_new() {
+            Ok(pid) => Ok(pid),
+            Err(reason) => {
+                fs::rename(&backup, &self.exe)?;
+                Err(UpdateError::RolledBack(reason))
+            }
+        }
+    }
+
+    /// Start the installed daemon, and wait for it to become healthy, killing it if it doesn't.
+    fn start_new(&self) -> Result<u32, String> {
+        let (ready_read, ready_write) =
+            pipe().map_err(|err| format!("unable to create a pipe, {err}"))?;
+        let listen_fd = self.listen_fd;
+        let ready_fd = ready_write.as_raw_fd();
+        let mut command = Command::new(&self.exe);
+        command
+            .args(&self.args)
+            .env(LISTEN_FD_ENV, listen_fd.to_string())
+            .env(READY_FD_ENV, ready_fd.to_string())
+            .stdin(Stdio::null());
+        // SAFETY: fcntl is async-signal-safe, and the fds stay open until the child has spawned.
+        unsafe {
+            command.pre_exec(move || {
+                for fd in [listen_fd, ready_fd] {
+                    if libc::fcntl(fd, libc::F_SETFD, 0) == -1 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+        let mut child = command
+            .spawn()
+            .map_err(|err| format!("unable to start the new daemon, {err}"))?;
+        // otherwise the pipe wouldn't be closed when the new daemon exits.
+        drop(ready_write);
+        match wait_healthy(&mut child, File::from(ready_read)) {
+            Ok(()) => Ok(child.id()),
+            Err(reason) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                Err(reason)
+            }
+        }
+    }
+}
+
+/// Check that the daemon in a tarball runs here, and is the version it's said to be.
+fn check_version(exe: &Path, expected: u32) -> Result<(), UpdateError> {
+    let invalid = |message: String| UpdateError::Invalid(message);
+    let mut child = Command::new(exe)
+        .arg("version")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|err| invalid(format!("unable to run the daemon in the tarball, {err}")))?;
+    let deadline = Instant::now() + VERSION_TIMEOUT;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(invalid(format!(
+                "the daemon in the tarball didn't print it's version within {}s",
+                VERSION_TIMEOUT.as_secs()
+            )));
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    };
+    let mut output = String::new();
+    if let Some(mut stdout) = child.stdout.take() {
+        stdout.read_to_string(&mut output)?;
+    }
+    match output.trim().parse::<u32>() {
+        Ok(version) if version == expected => Ok(()),
+        Ok(version) => Err(invalid(format!(
+            "the daemon in the tarball is version {version}, not {expected}"
+        ))),
+        Err(_) => Err(invalid(format!(
+            "the daemon in the tarball didn't print it's version, {status}"
+        ))),
+    }
+}
+
+/// Wait for the new daemon to say it's serving, and then to stay up for [`SETTLE_TIME`].
+fn wait_healthy(child: &mut Child, mut ready: File) -> Result<(), String> {
+    let mut pollfd = libc::pollfd {
+        fd: ready.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    // SAFETY: pollfd is valid for the duration of the call.
+    match unsafe { libc::poll(&mut pollfd, 1, READY_TIMEOUT.as_millis() as i32) } {
+        -1 => {
+            return Err(format!(
+                "unable to wait for the new daemon, {}",
+                io::Error::last_os_error()
+            ))
+        }
+        0 => {
+            return Err(format!(
+                "the new daemon wasn't serving within {}s",
+                READY_TIMEOUT.as_secs()
+            ))
+        }
+        _ => {}
+    }
+    let mut byte = [0];
+    let read = ready
+        .read(&mut byte)
+        .map_err(|err| format!("unable to wait for the new daemon, {err}"))?;
+    if read == 0 {
+        // the pipe was closed without a byte, which happens when the new daemon exits.
+        let status = child
+            .wait()
+            .map_err(|err| format!("unable to wait for the new daemon, {err}"))?;
+        return Err(format!("the new daemon exited before serving, {status}"));
+    }
+    std::thread::sleep(SETTLE_TIME);
+    match child.try_wait() {
+        Ok(None) => Ok(()),
+        Ok(Some(status)) => Err(format!("the new daemon exited after serving, {status}")),
+        Err(err) => Err(format!("unable to check on the new daemon, {err}")),
+    }
+}
+
+/// Once the response to an update has had time to reach the client, stop the services this daemon
+/// runs, for the new daemon to start again, and exit. The services are down from being stopped
+/// until the new daemon has started them.
+pub async fn hand_over(new_pid: u32, services: Services) {
+    tokio::time::sleep(HAND_OVER_DELAY).await;
+    services.shutdown().await;
+    if let Err(err) = notify_main_pid(new_pid) {
+        println!("err while telling systemd the main pid is {new_pid} {err:?}");
+    }
+    println!("exiting, {new_pid} has taken over");
+    std::process::exit(0);
+}
+
+/// Tell systemd, if it runs the daemon, that the new daemon is the service's main process now, so
+/// that it isn't stopped along with this one.
+fn notify_main_pid(pid: u32) -> io::Result<()> {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+    let socket = UnixDatagram::unbound()?;
+    let message = format!("MAINPID={pid}");
+    let addr = match path.as_bytes().strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(&path)?,
+    };
+    socket.send_to_addr(message.as_bytes(), &addr)?;
+    Ok(())
+}
+
+/// The listening socket handed over by the daemon being updated, if this daemon is it's
+/// replacement.
+pub fn inherited_listener() -> io::Result<Option<TcpListener>> {
+    let Some(fd) = take_fd(LISTEN_FD_ENV)? else {
+        return Ok(None);
+    };
+    // SAFETY: the old daemon handed over it's listening socket as this fd, and nothing else here
+    // owns it.
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+    listener.set_nonblocking(true)?;
+    Ok(Some(listener))
+}
+
+/// The pipe to tell the daemon being updated that this one is serving, see [`signal_ready`].
+pub fn ready_pipe() -> io::Result<Option<File>> {
+    // SAFETY: the old daemon handed over the pipe as this fd, and nothing else here owns it.
+    Ok(take_fd(READY_FD_ENV)?.map(|fd| unsafe { File::from_raw_fd(fd) }))
+}
+
+pub fn signal_ready(mut ready: File) {
+    if let Err(err) = ready.write_all(b"1") {
+        println!("err while telling the old daemon this one is serving {err:?}");
+    }
+}
+
+/// Wait for the daemon being updated, which started this one, to exit.
+pub async fn old_daemon_exited() {
+    // SAFETY: getppid has no memory safety requirements.
+    let parent = unsafe { libc::getppid() };
+    while unsafe { libc::getppid() } == parent {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+/// An fd inherited from the old daemon, which isn't passed on to anything this daemon runs.
+fn take_fd(var: &str) -> io::Result<Option<RawFd>> {
+    let Some(value) = std::env::var_os(var) else {
+        return Ok(None);
+    };
+    std::env::remove_var(var);
+    let fd: RawFd = value
+        .to_str()
+        .and_then(|fd| fd.parse().ok())
+        .ok_or_else(|| io::Error::other(format!("{var} isn't an fd, {value:?}")))?;
+    // SAFETY: fcntl has no memory safety requirements.
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(Some(fd))
+}
+
+fn pipe() -> io::Result<(OwnedFd, OwnedFd)> {
+    let mut fds = [0; 2];
+    // SAFETY: fds has room for the two fds pipe2 returns.
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: pipe2 just opened both fds, and nothing else owns them.
+    Ok(unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) })
+}
+
+/// `<exe>.<suffix>`, next to `exe`.
+fn sibling(exe: &Path, suffix: &str) -> PathBuf {
+    let mut name = exe.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(suffix);
+    exe.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use agent_lib::{
+        archive::{pack_dir, ArchiveFilter},
+        test_util::TempDir,
+    };
+
+    use super::*;
+
+    /// A dist tarball with a "daemon" that prints `version`, and otherwise runs `serve`.
+    fn tarball(dir: &Path, version: u32, serve: &str) -> AgentUpdateRequest {
+        let dist = dir.join("tarball").join("dist");
+        fs::create_dir_all(&dist).unwrap();
+        let script =
+            format!("#!/bin/sh\nif [ \"$1\" = version ]; then echo {version}; exit; fi\n{serve}\n");
+        fs::write(dist.join("daemon"), script).unwrap();
+        fs::set_permissions(dist.join("daemon"), fs::Permissions::from_mode(0o755)).unwrap();
+        let (dist_tarball, _) = pack_dir(&dir.join("tarball"), &ArchiveFilter::default()).unwrap();
+        fs::remove_dir_all(dir.join("tarball")).unwrap();
+        AgentUpdateRequest {
+            new_version: 2,
+            dist_tarball,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_update_rolls_back_unless_healthy() {
+        let temp = TempDir::new("daemon-update");
+        let dir = temp.path().to_path_buf();
+        let exe = dir.join("daemon");
+        fs::write(&exe, "old").unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let updater = |allowed| {
+            Updater::new(
+                allowed,
+                exe.clone(),
+                vec!["serve".into()],
+                dir.join("updates"),
+                listener.as_raw_fd(),
+            )
+        };
+
+        assert!(matches!(
+            updater(false).update(tarball(&dir, 2, "")).await,
+            Err(UpdateError::NotAllowed)
+        ));
+        assert!(matches!(
+            updater(true).update(tarball(&dir, 3, "")).await,
+            Err(UpdateError::Invalid(_))
+        ));
+        // exits without serving, so the old binary is put back.
+        assert!(matches!(
+            updater(true).update(tarball(&dir, 2, "exit 1")).await,
+            Err(UpdateError::RolledBack(_))
+        ));
+        assert_eq!(fs::read(&exe).unwrap(), b"old");
+
+        // serves on the inherited socket, and stays up.
+        let serve =
+            "[ \"$AGENT_LISTEN_FD\" ] && printf 1 > /proc/self/fd/$AGENT_READY_FD && exec sleep 30";
+        let pid = updater(true).update(tarball(&dir, 2, serve)).await.unwrap();
+        assert!(fs::read_to_string(&exe).unwrap().contains("echo 2"));
+        assert_eq!(fs::read(sibling(&exe, "previous")).unwrap(), b"old");
+        // SAFETY: kill has no memory safety requirements.
+        unsafe { libc::kill(pid as i32, libc::SIGKILL) };
+    }
+}
@@ -0,0 +1,312 @@
+//! Directory trees on the wire, as zstd compressed tar archives.
+
+use std::{
+    io::Cursor,
+    path::{Path, PathBuf},
+};
+
+use glob::{MatchOptions, Pattern};
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
+use walkdir::WalkDir;
+
+use crate::{codec::Codec, CompressedWireFile, MessageError};
+
+/// Include/exclude globs, matched against paths relative to the root of the directory.
+///
+/// If any include globs are given, only files matching one of them are archived. Excluded
+/// directories are skipped entirely.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, StructOpt)]
+pub struct ArchiveFilter {
+    /// Only include files matching these globs, e.g. `--include '*.log'`.
+    #[structopt(long)]
+    pub include: Vec<String>,
+    /// Exclude files and directories matching these globs, e.g. `--exclude 'storage/**'`.
+    #[structopt(long)]
+    pub exclude: Vec<String>,
+}
+
+struct CompiledFilter {
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+}
+
+/// How globs are matched against relative paths, `*` also matches across `/`.
+pub const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: false,
+    require_literal_leading_dot: false,
+};
+
+impl CompiledFilter {
+    fn new(filter: &ArchiveFilter) -> Result<Self, MessageError> {
+        let compile = |patterns: &[String]| {
+            patterns
+                .iter()
+                .map(|pattern| {
+                    Pattern::new(pattern).map_err(|err| MessageError::InvalidGlob {
+                        pattern: pattern.clone(),
+                        err,
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()
+        };
+        Ok(Self {
+            include: compile(&filter.include)?,
+            exclude: compile(&filter.exclude)?,
+        })
+    }
+
+    fn is_excluded(&self, rel_path: &Path) -> bool {
+        self.exclude
+            .iter()
+            .any(|pattern| pattern.matches_path_with(rel_path, MATCH_OPTIONS))
+    }
+
+    fn is_included(&self, rel_path: &Path) -> bool {
+        self.include.is_empty()
+            || self
+                .include
+                .iter()
+                .any(|pattern| pattern.matches_path_with(rel_path, MATCH_OPTIONS))
+    }
+}
+
+/// The paths under `src_dir` that `filter` keeps, parents before their contents. Symlinks are
+/// listed, not followed.
+pub fn filtered_paths(
+    src_dir: &Path,
+    filter: &ArchiveFilter,
+) -> Result<Vec<PathBuf>, MessageError> {
+    let filter = CompiledFilter::new(filter)?;
+    let walker = WalkDir::new(src_dir)
+        .follow_links(false)
+        .min_depth(1)
+        .into_iter()
+        .filter_entry(|entry| {
+            let rel_path = entry.path().strip_prefix(src_dir).unwrap_or(entry.path());
+            !filter.is_excluded(rel_path)
+        });
+    let mut paths = Vec::new();
+    for entry in walker {
+        let entry = entry.map_err(|err| MessageError::Archive {
+            path: src_dir.to_path_buf(),
+            err: err.into(),
+        })?;
+        let rel_path = entry.path().strip_prefix(src_dir).unwrap_or(entry.path());
+        // with include globs, directories are only created as the parents of included files.
+        if entry.file_type().is_dir() && !filter.include.is_empty() {
+            continue;
+        }
+        if !entry.file_type().is_dir() && !filter.is_included(rel_path) {
+            continue;
+        }
+        paths.push(entry.into_path());
+    }
+    Ok(paths)
+}
+
+/// Pack the directory at `src_dir` into a zstd compressed tar archive, preserving relative paths,
+/// modes and symlinks (which are archived as links, not followed).
+///
+/// Returns the archive and the number of entries in it.
+pub fn pack_dir(
+    src_dir: &Path,
+    filter: &ArchiveFilter,
+) -> Result<(CompressedWireFile, u64), MessageError> {
+    let archive_err = |path: &Path| {
+        let path = path.to_path_buf();
+        move |err| MessageError::Archive { path, err }
+    };
+
+    let encoder = zstd::Encoder::new(Vec::new(), 3).map_err(archive_err(src_dir))?;
+    let mut builder = tar::Builder::new(encoder);
+    builder.follow_symlinks(false);
+
+    let mut entries = 0;
+    for path in filtered_paths(src_dir, filter)? {
+        let rel_path = path.strip_prefix(src_dir).unwrap_or(&path);
+        builder
+            .append_path_with_name(&path, rel_path)
+            .map_err(archive_err(&path))?;
+        entries += 1;
+    }
+
+    let encoder = builder.into_inner().map_err(archive_err(src_dir))?;
+    let zstd_compressed_data = encoder.finish().map_err(archive_err(src_dir))?;
+    let filename = format!("{}.tar.zst", crate::file_name_from_path(src_dir)?);
+    Ok((
+        CompressedWireFile {
+            filename,
+            codec: Codec::Zstd,
+            zstd_compressed_data,
+        },
+        entries,
+    ))
+}
+
+/// Unpack an archive produced by [`pack_dir`] into `target_dir`, creating it if needed.
+///
+/// Entries that would land outside of `target_dir` are rejected by the tar crate.
+pub fn unpack_dir(archive: CompressedWireFile, target_dir: &Path) -> Result<u64, MessageError> {
+    let archive_err = |path: PathBuf| move |err| MessageError::Archive { path, err };
+
+    std::fs::create_dir_all(target_dir).map_err(archive_err(target_dir.to_path_buf()))?;
+    let decoder = archive
+        .codec
+        .decoder(Cursor::new(archive.zstd_compressed_data), None)
+        .map_err(archive_err(target_dir.to_path_buf()))?;
+    let mut archive = tar::Archive::new(decoder);
+    archive.set_preserve_permissions(true);
+    archive.set_overwrite(true);
+
+    let mut entries = 0;
+    for entry in archive
+        .entries()
+        .map_err(archive_err(target_dir.to_path_buf()))?
+    {
+        let mut entry = entry.map_err(archive_err(target_dir.to_path_buf()))?;
+        let path = entry
+            .path()
+            .map_err(archive_err(target_dir.to_path_buf()))?
+            .to_path_buf();
+        let unpacked = entry
+            .unpack_in(target_dir)
+            .map_err(archive_err(target_dir.join(&path)))?;
+        if !unpacked {
+            return Err(MessageError::Archive {
+                path,
+                err: std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "archive entry escapes the target directory",
+                ),
+            });
+        }
+        entries += 1;
+    }
+    Ok(entries)
+}
+
+/// Unpack an archive produced by [`pack_dir`] into a staging directory next to `target_dir`,
+/// then rename it into place, replacing `target_dir` and everything in it.
+///
+/// A failed unpack never leaves a partial tree behind, `target_dir` is only replaced once the
+/// whole archive has been unpacked.
+pub fn unpack_dir_replacing(
+    archive: CompressedWireFile,
+    target_dir: &Path,
+) -> Result<u64, MessageError> {
+    let archive_err = |path: &Path| {
+        let path = path.to_path_buf();
+        move |err| MessageError::Archive { path, err }
+    };
+    let staging_dir = crate::staging_path_for(target_dir).map_err(archive_err(target_dir))?;
+    let entries = match unpack_dir(archive, &staging_dir) {
+        Ok(entries) => entries,
+        Err(err) => {
+            let _ = std::fs::remove_dir_all(&staging_dir);
+            return Err(err);
+        }
+    };
+    let replaced = if target_dir.exists() {
+        let old_dir = crate::staging_path_for(target_dir).map_err(archive_err(target_dir))?;
+        std::fs::rename(target_dir, &old_dir).map_err(archive_err(target_dir))?;
+        Some(old_dir)
+    } else {
+        None
+    };
+    if let Err(err) = std::fs::rename(&staging_dir, target_dir) {
+        if let Some(old_dir) = &replaced {
+            let _ = std::fs::rename(old_dir, target_dir);
+        }
+        let _ = std::fs::remove_dir_all(&staging_dir);
+        return Err(archive_err(target_dir)(err));
+    }
+    if let Some(old_dir) = replaced {
+        std::fs::remove_dir_all(&old_dir).map_err(archive_err(&old_dir))?;
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::PermissionsExt};
+
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn test_pack_and_unpack_preserves_layout_modes_and_symlinks() {
+        let temp = TempDir::new("agent-lib-archive");
+        let root = temp.path().to_path_buf();
+        let src = root.join("network");
+        fs::create_dir_all(src.join("bin")).unwrap();
+        fs::create_dir_all(src.join("validator-1/logs")).unwrap();
+        fs::write(src.join("bin/casper-node"), b"binary").unwrap();
+        fs::set_permissions(
+            src.join("bin/casper-node"),
+            fs::Permissions::from_mode(0o755),
+        )
+        .unwrap();
+        fs::write(src.join("validator-1/logs/stdout.log"), b"log line").unwrap();
+        std::os::unix::fs::symlink("../bin/casper-node", src.join("validator-1/node")).unwrap();
+
+        let (archive, entries) = pack_dir(
+            &src,
+            &ArchiveFilter {
+                include: vec![],
+                exclude: vec!["*/logs".to_string()],
+            },
+        )
+        .unwrap();
+        // bin, bin/casper-node, validator-1, validator-1/node
+        assert_eq!(entries, 4);
+
+        let dst = root.join("unpacked");
+        assert_eq!(unpack_dir(archive, &dst).unwrap(), 4);
+        assert_eq!(fs::read(dst.join("bin/casper-node")).unwrap(), b"binary");
+        let mode = fs::metadata(dst.join("bin/casper-node"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o755);
+        assert_eq!(
+            fs::read_link(dst.join("validator-1/node")).unwrap(),
+            PathBuf::from("../bin/casper-node")
+        );
+        assert!(!dst.join("validator-1/logs").exists());
+
+        let (archive, entries) = pack_dir(
+            &src,
+            &ArchiveFilter {
+                include: vec!["*.log".to_string()],
+                exclude: vec![],
+            },
+        )
+        .unwrap();
+        assert_eq!(entries, 1);
+        let logs = root.join("logs");
+        unpack_dir(archive, &logs).unwrap();
+        assert!(logs.join("validator-1/logs/stdout.log").exists());
+        assert!(!logs.join("bin").exists());
+
+        // unpacking over the target leaves what isn't in the archive alone.
+        fs::write(dst.join("extra"), b"kept").unwrap();
+        let (archive, _) = pack_dir(&src, &ArchiveFilter::default()).unwrap();
+        assert_eq!(unpack_dir(archive, &dst).unwrap(), 6);
+        assert!(dst.join("extra").exists());
+
+        // a corrupt archive leaves the target as it was, a good one replaces it.
+        fs::remove_dir_all(dst.join("validator-1/logs")).unwrap();
+        let (mut archive, _) = pack_dir(&src, &ArchiveFilter::default()).unwrap();
+        let len = archive.zstd_compressed_data.len();
+        let good = archive.clone();
+        archive.zstd_compressed_data.truncate(len / 2);
+        assert!(unpack_dir_replacing(archive, &dst).is_err());
+        assert!(!dst.join("validator-1/logs").exists());
+        assert_eq!(unpack_dir_replacing(good, &dst).unwrap(), 6);
+        assert!(dst.join("validator-1/logs/stdout.log").exists());
+        assert!(!dst.join("extra").exists());
+        assert_eq!(fs::read_dir(&root).unwrap().count(), 3);
+    }
+}
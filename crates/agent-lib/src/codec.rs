@@ -0,0 +1,386 @@
+//! How file data is encoded on the wire.
+//!
+//! The sender picks a [`Compression`], which resolves to the [`Codec`] recorded alongside the
+//! data in [`crate::CompressedWireFile`] and [`crate::CompressedWireFileChunk`], so the receiver
+//! always knows how to decode it.
+
+use std::{
+    fmt,
+    io::{BufRead, Cursor, Read},
+    path::Path,
+    str::FromStr,
+    sync::Arc,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::hash_to_hex;
+
+/// The zstd level used unless another is asked for.
+pub const DEFAULT_ZSTD_LEVEL: i32 = 3;
+
+/// How much of the data [`Compression::Auto`] compresses to decide whether it is worth it.
+pub const AUTO_SAMPLE_SIZE: usize = 1024 * 1024;
+
+/// The names of the codecs this build decodes, as the agent reports them.
+pub const CODECS: [&str; 3] = ["none", "zstd", "zstd-dict"];
+
+/// [`Compression::Auto`] stores data uncompressed if the sample doesn't shrink below this
+/// fraction of it's size.
+const AUTO_MIN_RATIO: f64 = 0.9;
+
+/// How data on the wire is encoded.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Codec {
+    /// Not compressed.
+    None,
+    #[default]
+    Zstd,
+    /// zstd with a dictionary the receiver must already have, see
+    /// [`crate::AgentService::put_dictionary`].
+    ZstdDict { dict_hash: [u8; 32] },
+}
+
+impl Codec {
+    /// The hash of the dictionary needed to decode, if any.
+    pub fn dict_hash(&self) -> Option<[u8; 32]> {
+        match self {
+            Codec::ZstdDict { dict_hash } => Some(*dict_hash),
+            Codec::None | Codec::Zstd => None,
+        }
+    }
+
+    /// A reader of the decoded data. `dictionary` must be the one named by
+    /// [`Codec::ZstdDict`], and is otherwise ignored.
+    pub fn decoder<'a, R: BufRead + 'a>(
+        &self,
+        reader: R,
+        dictionary: Option<&'a Dictionary>,
+    ) -> Result<Box<dyn Read + 'a>, std::io::Error> {
+        Ok(match self {
+            Codec::None => Box::new(reader),
+            Codec::Zstd => Box::new(zstd::Decoder::with_buffer(reader)?),
+            Codec::ZstdDict { dict_hash } => {
+                let dictionary = check_dictionary(dict_hash, dictionary)?;
+                Box::new(zstd::Decoder::with_dictionary(reader, dictionary.data())?)
+            }
+        })
+    }
+
+    /// Decode data that decodes to at most `capacity` bytes, e.g. a chunk.
+    pub fn decode_bulk(
+        &self,
+        data: &[u8],
+        capacity: usize,
+        dictionary: Option<&Dictionary>,
+    ) -> Result<Vec<u8>, std::io::Error> {
+        match self {
+            Codec::None if data.len() > capacity => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{} bytes is more than the expected {capacity}", data.len()),
+            )),
+            Codec::None => Ok(data.to_vec()),
+            Codec::Zstd => zstd::bulk::decompress(data, capacity),
+            Codec::ZstdDict { dict_hash } => {
+                let dictionary = check_dictionary(dict_hash, dictionary)?;
+                zstd::bulk::Decompressor::with_dictionary(dictionary.data())?
+                    .decompress(data, capacity)
+            }
+        }
+    }
+}
+
+fn check_dictionary<'a>(
+    dict_hash: &[u8; 32],
+    dictionary: Option<&'a Dictionary>,
+) -> Result<&'a Dictionary, std::io::Error> {
+    match dictionary {
+        Some(dictionary) if dictionary.hash() == *dict_hash => Ok(dictionary),
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("decoding needs dictionary {}", hash_to_hex(dict_hash)),
+        )),
+    }
+}
+
+/// What a sender compresses with. On the commandline this is `none`, `zstd[:<level>]` or
+/// `auto[:<level>]`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Compression {
+    None,
+    Zstd {
+        level: i32,
+    },
+    /// zstd, unless a sample of the data barely compresses, e.g. a tarball or wasm.
+    Auto {
+        level: i32,
+    },
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::Zstd {
+            level: DEFAULT_ZSTD_LEVEL,
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, level) = match s.split_once(':') {
+            Some((name, level)) => {
+                let level = level
+                    .parse::<i32>()
+                    .ok()
+                    .filter(|level| zstd::compression_level_range().contains(level))
+                    .ok_or_else(|| {
+                        let range = zstd::compression_level_range();
+                        format!(
+                            "zstd level must be between {} and {}",
+                            range.start(),
+                            range.end()
+                        )
+                    })?;
+                (name, Some(level))
+            }
+            None => (s, None),
+        };
+        let level = level.unwrap_or(DEFAULT_ZSTD_LEVEL);
+        match name {
+            "none" if s == "none" => Ok(Compression::None),
+            "zstd" => Ok(Compression::Zstd { level }),
+            "auto" => Ok(Compression::Auto { level }),
+            _ => Err(format!(
+                "unknown compression {s}, expected none, zstd[:<level>] or auto[:<level>]"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Compression::None => write!(f, "none"),
+            Compression::Zstd { level } => write!(f, "zstd:{level}"),
+            Compression::Auto { level } => write!(f, "auto:{level}"),
+        }
+    }
+}
+
+/// A zstd dictionary, e.g. [trained](Self::train) on config and chainspec files, which makes small
+/// files like them compress far better.
+#[derive(Clone)]
+pub struct Dictionary {
+    hash: [u8; 32],
+    data: Arc<[u8]>,
+}
+
+impl Dictionary {
+    pub fn new(data: Vec<u8>) -> Self {
+        Self {
+            hash: blake3::hash(&data).into(),
+            data: data.into(),
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self, std::io::Error> {
+        Ok(Self::new(std::fs::read(path)?))
+    }
+
+    /// Train a dictionary on sample files, at most `max_size` bytes.
+    pub fn train(samples: &[impl AsRef<Path>], max_size: usize) -> Result<Self, std::io::Error> {
+        Ok(Self::new(zstd::dict::from_files(samples, max_size)?))
+    }
+
+    /// blake3 hash of the dictionary, which identifies it on the wire.
+    pub fn hash(&self) -> [u8; 32] {
+        self.hash
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl fmt::Debug for Dictionary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Dictionary")
+            .field("hash", &hash_to_hex(&self.hash))
+            .field("len", &self.data.len())
+            .finish()
+    }
+}
+
+/// A sender's choice of compression, and the dictionary to compress with if any.
+#[derive(Clone, Debug, Default)]
+pub struct Encoding {
+    pub compression: Compression,
+    pub dictionary: Option<Dictionary>,
+}
+
+impl Encoding {
+    pub fn new(compression: Compression, dictionary: Option<Dictionary>) -> Self {
+        Self {
+            compression,
+            dictionary,
+        }
+    }
+
+    /// The codec to encode data starting with `sample` with. For [`Compression::Auto`] the first
+    /// [`AUTO_SAMPLE_SIZE`] bytes of the sample are compressed to see if it is worth it.
+    pub fn codec_for(&self, sample: &[u8]) -> Result<Codec, std::io::Error> {
+        let zstd_codec = match &self.dictionary {
+            Some(dictionary) => Codec::ZstdDict {
+                dict_hash: dictionary.hash(),
+            },
+            None => Codec::Zstd,
+        };
+        match self.compression {
+            Compression::None => Ok(Codec::None),
+            Compression::Zstd { .. } => Ok(zstd_codec),
+            Compression::Auto { .. } if sample.is_empty() => Ok(zstd_codec),
+            Compression::Auto { .. } => {
+                let sample = &sample[..sample.len().min(AUTO_SAMPLE_SIZE)];
+                let compressed = self.encode(zstd_codec, sample)?;
+                if (compressed.len() as f64) < sample.len() as f64 * AUTO_MIN_RATIO {
+                    Ok(zstd_codec)
+                } else {
+                    Ok(Codec::None)
+                }
+            }
+        }
+    }
+
+    fn level(&self) -> i32 {
+        match self.compression {
+            Compression::Zstd { level } | Compression::Auto { level } => level,
+            Compression::None => DEFAULT_ZSTD_LEVEL,
+        }
+    }
+
+    /// Encode data held in memory, e.g. a chunk.
+    pub fn encode(&self, codec: Codec, data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+        match codec {
+            Codec::None => Ok(data.to_vec()),
+            Codec::Zstd => zstd::bulk::compress(data, self.level()),
+            Codec::ZstdDict { dict_hash } => {
+                let dictionary = check_dictionary(&dict_hash, self.dictionary.as_ref())?;
+                zstd::bulk::Compressor::with_dictionary(self.level(), dictionary.data())?
+                    .compress(data)
+            }
+        }
+    }
+
+    /// Encode everything read from `reader`, as a single stream.
+    pub fn encode_reader(
+        &self,
+        codec: Codec,
+        mut reader: impl BufRead,
+    ) -> Result<Vec<u8>, std::io::Error> {
+        let mut encoded = Vec::new();
+        match codec {
+            Codec::None => {
+                reader.read_to_end(&mut encoded)?;
+            }
+            Codec::Zstd => {
+                zstd::stream::copy_encode(reader, &mut encoded, self.level())?;
+            }
+            Codec::ZstdDict { dict_hash } => {
+                let dictionary = check_dictionary(&dict_hash, self.dictionary.as_ref())?;
+                let mut encoder =
+                    zstd::Encoder::with_dictionary(&mut encoded, self.level(), dictionary.data())?;
+                std::io::copy(&mut reader, &mut encoder)?;
+                encoder.finish()?;
+            }
+        }
+        Ok(encoded)
+    }
+}
+
+/// Decode a whole encoded buffer held in memory.
+pub fn decode_all(
+    codec: Codec,
+    data: &[u8],
+    dictionary: Option<&Dictionary>,
+) -> Result<Vec<u8>, std::io::Error> {
+    let mut decoded = Vec::new();
+    codec
+        .decoder(Cursor::new(data), dictionary)?
+        .read_to_end(&mut decoded)?;
+    Ok(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_codecs_roundtrip_and_auto_detection() {
+        let config =
+            b"[network]\nbind_address = '0.0.0.0:35000'\nknown_addresses = []\n".repeat(50);
+        let mut random = vec![0u8; 64 * 1024];
+        let mut state = 0x2545f4914f6cdd1du64;
+        for byte in random.iter_mut() {
+            // xorshift, incompressible enough.
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            *byte = state as u8;
+        }
+
+        let dictionary = Dictionary::new(config[..200].to_vec());
+        for (compression, dictionary, data, expected) in [
+            (Compression::None, None, &config, Codec::None),
+            (Compression::default(), None, &config, Codec::Zstd),
+            (Compression::Auto { level: 19 }, None, &config, Codec::Zstd),
+            (Compression::Auto { level: 3 }, None, &random, Codec::None),
+            (
+                Compression::default(),
+                Some(dictionary.clone()),
+                &config,
+                Codec::ZstdDict {
+                    dict_hash: dictionary.hash(),
+                },
+            ),
+        ] {
+            let encoding = Encoding::new(compression, dictionary.clone());
+            let codec = encoding.codec_for(data).unwrap();
+            assert_eq!(codec, expected, "{compression}");
+            let bulk = encoding.encode(codec, data).unwrap();
+            let streamed = encoding.encode_reader(codec, &data[..]).unwrap();
+            for encoded in [bulk, streamed] {
+                assert_eq!(
+                    &codec
+                        .decode_bulk(&encoded, data.len(), dictionary.as_ref())
+                        .unwrap(),
+                    data
+                );
+                assert_eq!(
+                    &decode_all(codec, &encoded, dictionary.as_ref()).unwrap(),
+                    data
+                );
+            }
+        }
+
+        // the right dictionary is needed to decode.
+        let encoding = Encoding::new(Compression::default(), Some(dictionary.clone()));
+        let codec = encoding.codec_for(&config).unwrap();
+        let encoded = encoding.encode(codec, &config).unwrap();
+        assert!(decode_all(codec, &encoded, None).is_err());
+        let other = Dictionary::new(b"something else entirely".to_vec());
+        assert!(decode_all(codec, &encoded, Some(&other)).is_err());
+    }
+
+    #[test]
+    fn test_parse_compression() {
+        assert_eq!("none".parse(), Ok(Compression::None));
+        assert_eq!("zstd".parse(), Ok(Compression::default()));
+        assert_eq!("zstd:19".parse(), Ok(Compression::Zstd { level: 19 }));
+        assert_eq!("auto:1".parse(), Ok(Compression::Auto { level: 1 }));
+        assert!("zstd:99".parse::<Compression>().is_err());
+        assert!("none:3".parse::<Compression>().is_err());
+        assert!("gzip".parse::<Compression>().is_err());
+    }
+}
@@ -1,15 +1,28 @@
 // pub use casper_client;
 // pub use casper_node;
 // pub use casper_types;
+pub mod archive;
+pub mod codec;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
 pub mod tls;
+pub mod wrapper;
 
+use archive::ArchiveFilter;
+use codec::{Codec, Compression, Dictionary, Encoding};
 use serde::{Deserialize, Serialize};
 use std::{
-    fs::{self, File},
-    io::{BufReader, BufWriter, Cursor, Write},
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write},
+    net::SocketAddr,
+    os::unix::fs::PermissionsExt,
     path::{Path, PathBuf},
+    str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
 };
 use structopt::StructOpt;
+use wrapper::Wrapper;
 
 /// The responsibilities of the Agent are to:
 /// - install required software on the given target
@@ -22,8 +35,6 @@ use structopt::StructOpt;
 /// - start the node launcher
 /// - stop the given node launcher
 ///
-/// - find the node process
-/// - determine mapped ports
 /// - restart the node with a particular wrapper:
 ///     - gdb
 ///     - valgrind
@@ -42,163 +53,1323 @@ use structopt::StructOpt;
 /// Needless to say, but this service is designed to be used in a debug environment
 #[tarpc::service]
 pub trait AgentService {
+    /// Greet the agent, before anything else, to learn it's version and what it supports. Must
+    /// stay the first method, so that agents and clients of any version understand it, and quick
+    /// to answer, so the host is described by [`Self::host_info`] instead.
+    async fn hello(req: HelloRequest) -> AgentInfo;
     /// Push a file to the host running the agent.
     async fn put_file(req: PutFileRequest) -> PutFileResponse;
     /// Fetch a file from the host running the agent.
     async fn fetch_file(req: FetchFileRequest) -> FetchFileResponse;
-    /// Stop a service with the given parameters on the host running the agent.
-    async fn stop_service(request: StartServiceRequest) -> StartServiceResponse;
+    /// Fetch a single chunk of a file from the host running the agent.
+    async fn fetch_file_chunk(req: FetchFileChunkRequest) -> FetchFileChunkResponse;
+    /// Check which of the given blobs (by the blake3 hash of their uncompressed contents) the agent
+    /// already has.
+    async fn has_blobs(req: HasBlobsRequest) -> HasBlobsResponse;
+    /// Write a blob the agent already has to a target path, instead of uploading it again.
+    async fn put_file_from_blob(req: PutFileFromBlobRequest) -> PutFileResponse;
+    /// Garbage collect the agent's blob store by age and total size.
+    async fn gc_blobs(req: GcBlobsRequest) -> GcBlobsResponse;
+    /// Push a directory tree, as a compressed tar archive, to the host running the agent.
+    async fn put_dir(req: PutDirRequest) -> PutDirResponse;
+    /// List a directory tree on the host running the agent, for the client to fetch file by file
+    /// with [`AgentService::fetch_file_chunk`]. Unlike [`AgentService::put_dir`] it's not sent as
+    /// an archive, which would have to be held in memory whole on both sides.
+    async fn fetch_dir(req: FetchDirRequest) -> FetchDirResponse;
+    /// Stop a service on the host running the agent.
+    async fn stop_service(request: StopServiceRequest) -> StopServiceResponse;
     /// Start a service with the given parameters on the host running the agent.
     async fn start_service(request: StartServiceRequest) -> StartServiceResponse;
+    /// Restart a service, starting it if it isn't running.
+    async fn restart_service(request: RestartServiceRequest) -> RestartServiceResponse;
+    /// Enable a service to be started at boot.
+    async fn enable_service(request: EnableServiceRequest) -> EnableServiceResponse;
+    /// Report whether a service is running, how it last exited, and the resources it uses.
+    async fn service_status(request: ServiceStatusRequest) -> ServiceStatusResponse;
+    /// Fetch what the [`Wrapper`] a service was run under wrote, e.g. `perf.data`.
+    async fn fetch_debug_output(request: FetchDebugOutputRequest) -> FetchDebugOutputResponse;
+    /// Send a signal to a service's main process, or a process it or the node runs, e.g. `SIGSTOP`
+    /// to make it hang.
+    async fn signal(request: SignalRequest) -> SignalResponse;
+    /// Collect core dumps of a service, and of the processes it runs, from it's next start.
+    async fn enable_core_dumps(request: EnableCoreDumpsRequest) -> EnableCoreDumpsResponse;
+    /// List the crash bundles collected for a service.
+    async fn list_crashes(request: ListCrashesRequest) -> ListCrashesResponse;
+    /// Fetch a crash bundle: the core, a copy of the binary that crashed, and `crash.yaml`.
+    async fn fetch_crash(request: FetchCrashRequest) -> FetchCrashResponse;
+    /// Find the node's processes, including those run by the launcher, and the ports they listen
+    /// on.
+    async fn find_node_processes(request: FindNodeProcessesRequest) -> FindNodeProcessesResponse;
     /// Transfer a chunk of a file to the host running the agent.
     async fn put_file_chunk(chunk: PutFileChunkRequest) -> PutFileChunkResponse;
+    /// Query the state of a chunked transfer, so that an interrupted upload can be resumed.
+    async fn transfer_status(req: TransferStatusRequest) -> TransferStatusResponse;
+    /// List the chunked transfers currently in flight on the host running the agent.
+    async fn list_transfers() -> ListTransfersResponse;
+    /// Drop an in-flight chunked transfer and any chunks received for it.
+    async fn cancel_transfer(req: CancelTransferRequest) -> CancelTransferResponse;
+    /// Stat a file or directory, without following a final symlink.
+    async fn stat(req: StatRequest) -> StatResponse;
+    /// List the contents of a directory, optionally recursively and filtered by a glob.
+    async fn list_dir(req: ListDirRequest) -> ListDirResponse;
+    /// Compute the blake3 hash of a file on the host running the agent, without transferring it.
+    async fn checksum(req: ChecksumRequest) -> ChecksumResponse;
+    /// Report the size and free space of each mounted filesystem.
+    async fn disk_usage() -> DiskUsageResponse;
+    /// Start following a file, like `tail -f`. New lines are then read with [`Self::poll_follow`].
+    async fn follow_file(req: FollowFileRequest) -> FollowFileResponse;
+    /// Wait for and return the next lines of a followed file.
+    async fn poll_follow(req: PollFollowRequest) -> PollFollowResponse;
+    /// Stop following a file. Follows that aren't polled for a while are also stopped.
+    async fn stop_follow(req: StopFollowRequest) -> StopFollowResponse;
+    /// Run a command the agent allows. It's output is then read with [`Self::poll_exec`].
+    async fn exec(req: ExecRequest) -> ExecResponse;
+    /// Wait for and return the next output of a command, and finally how it exited.
+    async fn poll_exec(req: PollExecRequest) -> PollExecResponse;
+    /// Remove a file, or a directory and everything in it.
+    async fn remove(req: RemoveRequest) -> RemoveResponse;
+    /// Rename or move a file or directory.
+    async fn rename(req: RenameRequest) -> RenameResponse;
+    /// Create a directory and any missing parents, like `mkdir -p`.
+    async fn mkdir(req: MkdirRequest) -> MkdirResponse;
+    /// Check whether the agent has a zstd dictionary, needed to decode [`Codec::ZstdDict`].
+    async fn has_dictionary(req: HasDictionaryRequest) -> HasDictionaryResponse;
+    /// Store a zstd dictionary on the agent, to decode files sent with [`Codec::ZstdDict`].
+    async fn put_dictionary(req: PutDictionaryRequest) -> PutDictionaryResponse;
+    /// Replace the agent with the one in a `cargo xtask dist` tarball, handing over the listening
+    /// socket. The old agent is put back if the new one doesn't come up. Services run by the
+    /// agent's supervisor are stopped by the old agent and started again by the new one, so are
+    /// down for a few seconds.
+    async fn update_agent(request: AgentUpdateRequest) -> AgentUpdateResponse;
+    /// Describe the host the agent runs on: it's OS, CPUs, memory and disks.
+    async fn host_info() -> HostInfoResponse;
+}
+
+/// The version of the wire protocol, bumped whenever a change to [`AgentService`] or it's messages
+/// means that clients and agents built before and after it can't talk to each other.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HelloRequest {
+    pub client_version: u32,
+    pub protocol_version: u32,
+}
+
+/// What an agent is, and what it supports.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AgentInfo {
+    pub agent_version: u32,
+    pub protocol_version: u32,
+    pub features: AgentFeatures,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AgentFeatures {
+    /// The backend the agent manages services with, `systemd` or `supervisor`.
+    pub service_backend: String,
+    /// The backends that would work on the host.
+    pub service_backends: Vec<String>,
+    /// The tools services can be run under, see [`wrapper::Wrapper`], that are installed.
+    pub wrappers: Vec<String>,
+    /// The codecs the agent decodes, see [`Codec`].
+    pub codecs: Vec<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct HostInfo {
+    pub hostname: String,
+    /// e.g. `Ubuntu 22.04.3 LTS`, from `/etc/os-release`.
+    pub os: String,
+    pub kernel: String,
+    pub arch: String,
+    pub cpus: u32,
+    pub memory_total_bytes: u64,
+    pub memory_available_bytes: u64,
+    pub disks: Vec<MountUsage>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum HostInfoResponse {
+    Success {
+        host: HostInfo,
+    },
+    /// The host couldn't be described, e.g. because a filesystem didn't answer in time.
+    Error,
+}
+
+/// The version of this build of the agent, as given to `cargo xtask dist`, or 0 for builds made
+/// some other way.
+pub fn agent_version() -> u32 {
+    option_env!("AGENT_VERSION")
+        .and_then(|version| version.parse().ok())
+        .unwrap_or(0)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AgentUpdateRequest {
+    /// The version the tarball was built as, which the daemon in it must report.
+    pub new_version: u32,
+    /// A `target/dist-<version>.tar` built by `cargo xtask dist`.
+    pub dist_tarball: CompressedWireFile,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum AgentUpdateResponse {
+    /// The new agent is serving, and the old one exits shortly after responding.
+    Success {
+        old_version: u32,
+        new_version: u32,
+        new_pid: u32,
+    },
+    /// The tarball isn't a dist tarball of `new_version`.
+    Invalid {
+        message: String,
+    },
+    /// The new agent didn't pass it's health check, so the old one was put back and is still
+    /// serving.
+    RolledBack {
+        message: String,
+    },
+    /// The agent wasn't started with `--allow-update`, or is already being updated.
+    Forbidden {
+        message: String,
+    },
+    Error,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, StructOpt)]
+pub struct StartServiceRequest {
+    /// Name of the service, e.g. `casper-node-launcher`.
+    pub service: String,
+    #[structopt(flatten)]
+    pub limits: ResourceLimits,
+    /// A debugging or profiling tool to run the service under.
+    #[structopt(subcommand)]
+    pub wrapper: Option<Wrapper>,
+}
+
+/// Limits on the resources a service and everything it spawns may use, enforced with cgroup v2.
+/// Those not given are unlimited.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, StructOpt)]
+#[serde(deny_unknown_fields)]
+pub struct ResourceLimits {
+    /// CPU time, in percent of one CPU, e.g. 150 for one and a half CPUs.
+    #[structopt(long)]
+    pub cpu_quota_percent: Option<u32>,
+    /// Memory, in bytes or with a K, M or G suffix, past which the service is OOM killed.
+    #[structopt(long, parse(try_from_str = parse_size))]
+    pub memory_max: Option<u64>,
+    /// Share of disk IO, from 1 to 10000, relative to the default of 100.
+    #[structopt(long)]
+    pub io_weight: Option<u16>,
+    /// Processes and threads.
+    #[structopt(long)]
+    pub pids_max: Option<u64>,
+}
+
+impl ResourceLimits {
+    pub fn is_unlimited(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum StartServiceResponse {
+    Started,
+    /// The service was already running, and was left alone.
+    AlreadyRunning,
+    NotFound,
+    /// The service manager tried and failed to start the service.
+    Failed {
+        message: String,
+    },
+    Error,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, StructOpt)]
+pub struct StopServiceRequest {
+    pub service: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum StopServiceResponse {
+    Stopped,
+    /// The service wasn't running.
+    NotRunning,
+    NotFound,
+    Failed {
+        message: String,
+    },
+    Error,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, StructOpt)]
+pub struct RestartServiceRequest {
+    pub service: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum RestartServiceResponse {
+    Restarted,
+    NotFound,
+    Failed { message: String },
+    Error,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, StructOpt)]
+pub struct EnableServiceRequest {
+    pub service: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum EnableServiceResponse {
+    Enabled,
+    AlreadyEnabled,
+    NotFound,
+    Failed { message: String },
+    Error,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, StructOpt)]
+pub struct ServiceStatusRequest {
+    pub service: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ServiceStatusResponse {
+    Success { status: Box<ServiceStatus> },
+    NotFound,
+    Error,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, StructOpt)]
+pub struct FetchDebugOutputRequest {
+    pub service: String,
+    /// The run to fetch, defaults to the latest.
+    #[structopt(long)]
+    pub run: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum FetchDebugOutputResponse {
+    /// The files written during `run`, in `dir`, which are fetched one at a time with
+    /// [`FetchFileChunkRequest`]s.
+    Success {
+        run: String,
+        dir: PathBuf,
+        entries: Vec<FileStat>,
+    },
+    /// `run` is still going, so the tool hasn't finished writing.
+    Running {
+        run: String,
+    },
+    /// The service, or the run, doesn't exist, or it was never run under a wrapper.
+    NotFound,
+    Error,
+}
+
+/// Whether a service is running, as the service manager sees it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ServiceState {
+    Active,
+    Reloading,
+    Inactive,
+    /// Stopped after exiting unsuccessfully or being killed.
+    Failed,
+    Activating,
+    Deactivating,
+    /// A state this agent doesn't know about.
+    Other(String),
+}
+
+impl ServiceState {
+    /// Whether the service has a running process, or is about to.
+    pub fn is_running(&self) -> bool {
+        matches!(
+            self,
+            ServiceState::Active | ServiceState::Reloading | ServiceState::Activating
+        )
+    }
+}
+
+impl std::fmt::Display for ServiceState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ServiceState::Active => f.write_str("active"),
+            ServiceState::Reloading => f.write_str("reloading"),
+            ServiceState::Inactive => f.write_str("inactive"),
+            ServiceState::Failed => f.write_str("failed"),
+            ServiceState::Activating => f.write_str("activating"),
+            ServiceState::Deactivating => f.write_str("deactivating"),
+            ServiceState::Other(state) => f.write_str(state),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ServiceStatus {
+    pub service: String,
+    pub state: ServiceState,
+    /// The service manager's finer grained state, e.g. `running` or `dead` for systemd.
+    pub sub_state: String,
+    pub main_pid: Option<u32>,
+    /// Whether the service is started at boot.
+    pub enabled: bool,
+    /// How many times the service manager has restarted the service.
+    pub restarts: u32,
+    /// How the main process last exited, if it has.
+    pub last_exit: Option<ExitReason>,
+    /// Resource use of the main process, if it's running.
+    pub process: Option<ProcessStats>,
+    /// Resource use of the service's cgroup, if the service manager put it in one.
+    pub cgroup: Option<CgroupStats>,
+}
+
+/// How a process exited.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExitReason {
+    Code(i32),
+    Signal(i32),
+}
+
+impl std::fmt::Display for ExitReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExitReason::Code(code) => write!(f, "exit code {code}"),
+            ExitReason::Signal(signal) => match Signal(*signal).name() {
+                Some(name) => write!(f, "signal {signal} ({name})"),
+                None => write!(f, "signal {signal}"),
+            },
+        }
+    }
+}
+
+/// Linux's signal numbers, on x86 and ARM.
+const SIGNALS: &[(&str, i32)] = &[
+    ("SIGHUP", 1),
+    ("SIGINT", 2),
+    ("SIGQUIT", 3),
+    ("SIGILL", 4),
+    ("SIGTRAP", 5),
+    ("SIGABRT", 6),
+    ("SIGBUS", 7),
+    ("SIGFPE", 8),
+    ("SIGKILL", 9),
+    ("SIGUSR1", 10),
+    ("SIGSEGV", 11),
+    ("SIGUSR2", 12),
+    ("SIGPIPE", 13),
+    ("SIGALRM", 14),
+    ("SIGTERM", 15),
+    ("SIGCHLD", 17),
+    ("SIGCONT", 18),
+    ("SIGSTOP", 19),
+    ("SIGTSTP", 20),
+    ("SIGTTIN", 21),
+    ("SIGTTOU", 22),
+    ("SIGXCPU", 24),
+    ("SIGWINCH", 28),
+];
+
+/// A signal, parsed from a name with or without `SIG`, e.g. `SIGSTOP` or `stop`, or a number.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Signal(pub i32);
+
+impl Signal {
+    pub const KILL: Signal = Signal(9);
+    pub const CONT: Signal = Signal(18);
+    pub const STOP: Signal = Signal(19);
+
+    pub fn name(self) -> Option<&'static str> {
+        SIGNALS
+            .iter()
+            .find(|(_, number)| *number == self.0)
+            .map(|(name, _)| *name)
+    }
+}
+
+impl FromStr for Signal {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(number) = s.parse::<i32>() {
+            return Ok(Signal(number));
+        }
+        let name = s.to_ascii_uppercase();
+        let name = name.strip_prefix("SIG").unwrap_or(&name);
+        SIGNALS
+            .iter()
+            .find(|(known, _)| known[3..] == *name)
+            .map(|(_, number)| Signal(*number))
+            .ok_or_else(|| format!("unknown signal {s}"))
+    }
+}
+
+impl std::fmt::Display for Signal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.name() {
+            Some(name) => f.write_str(name),
+            None => write!(f, "signal {}", self.0),
+        }
+    }
+}
+
+/// Resource use of a running process, read from `/proc`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProcessStats {
+    pub uptime_secs: u64,
+    /// Resident set size.
+    pub rss_bytes: u64,
+    /// CPU time spent in user mode.
+    pub user_cpu_millis: u64,
+    /// CPU time spent in the kernel.
+    pub system_cpu_millis: u64,
+    /// Only readable by the process's owner or root.
+    pub open_fds: Option<u64>,
+    pub threads: u64,
+}
+
+/// Resource use of a cgroup v2, i.e. of a service and everything it spawns. Figures of controllers
+/// that aren't enabled for the cgroup are missing.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CgroupStats {
+    /// e.g. `/system.slice/casper-node-launcher.service`.
+    pub path: String,
+    pub memory_bytes: Option<u64>,
+    /// The most memory used since the cgroup was created, on kernels from 5.19.
+    pub memory_peak_bytes: Option<u64>,
+    /// `None` if unlimited.
+    pub memory_max_bytes: Option<u64>,
+    /// Times the memory limit was hit and the OOM killer run.
+    pub oom_events: u64,
+    /// Processes killed by the OOM killer.
+    pub oom_kills: u64,
+    pub cpu_usage_micros: u64,
+    /// Time the service was kept off the CPU by it's CPU quota.
+    pub cpu_throttled_micros: u64,
+    pub pids: Option<u64>,
+    pub pids_max: Option<u64>,
+    pub io_read_bytes: u64,
+    pub io_write_bytes: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, StructOpt)]
+pub struct EnableCoreDumpsRequest {
+    pub service: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum EnableCoreDumpsResponse {
+    /// Crash bundles will be collected into `core_dir` once the service is started again.
+    Enabled {
+        core_dir: PathBuf,
+    },
+    NotFound,
+    /// The service manager doesn't support it, or the agent isn't allowed to set the kernel's core
+    /// pattern.
+    Failed {
+        message: String,
+    },
+    Error,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, StructOpt)]
+pub struct ListCrashesRequest {
+    pub service: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ListCrashesResponse {
+    /// Oldest first.
+    Success {
+        crashes: Vec<CrashBundle>,
+    },
+    NotFound,
+    Error,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, StructOpt)]
+pub struct FetchCrashRequest {
+    pub service: String,
+    /// The crash to fetch, as listed by `list-crashes`, defaults to the latest.
+    #[structopt(long)]
+    pub crash: Option<String>,
+    /// e.g. `--exclude core` to only fetch the binary and `crash.yaml`.
+    #[structopt(flatten)]
+    pub filter: ArchiveFilter,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum FetchCrashResponse {
+    /// The files of the bundle, in `dir`, which are fetched one at a time with
+    /// [`FetchFileChunkRequest`]s, since cores can be large.
+    Success {
+        crash: String,
+        dir: PathBuf,
+        entries: Vec<FileStat>,
+    },
+    /// The service, or the crash, doesn't exist.
+    NotFound,
+    Error,
+}
+
+/// A core dump of a process, kept with a copy of the binary that produced it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CrashBundle {
+    /// Names the bundle, `<unix secs>-<pid>`.
+    pub crash: String,
+    pub pid: u32,
+    pub signal: Signal,
+    /// When it crashed, in seconds since the unix epoch.
+    pub time: u64,
+    /// Where the binary was, a copy of it is in the bundle as `binary`.
+    pub binary: PathBuf,
+    /// The version directory the binary was in, e.g. `1_5_2`.
+    pub version: Option<String>,
+    /// The hex encoded blake3 hash of the binary.
+    pub binary_hash: String,
+    /// The arguments the binary was run with, after it's own path.
+    pub args: Vec<String>,
+    pub core_bytes: u64,
+}
+
+/// The process to send a signal to.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum SignalTarget {
+    /// The main process of a service.
+    Service(String),
+    Pid(u32),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SignalRequest {
+    pub target: SignalTarget,
+    pub signal: Signal,
+    /// Send `SIGCONT` this long after `signal`, even if the client has gone, to end a pause
+    /// started with `SIGSTOP`. A later pause of the same process replaces it, and sending
+    /// `SIGCONT` cancels it.
+    pub resume_after_secs: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum SignalResponse {
+    Sent {
+        pid: u32,
+    },
+    /// The process or service doesn't exist, or the service isn't running.
+    NotFound,
+    /// The agent isn't allowed to signal the process, e.g. it isn't a service's main process, a
+    /// node process, or a descendant of one, or it's owned by another user.
+    Forbidden,
+    Error,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, StructOpt)]
+pub struct FindNodeProcessesRequest {
+    /// The file name of the node's binary.
+    #[structopt(long, default_value = "casper-node")]
+    pub name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum FindNodeProcessesResponse {
+    Success { processes: Vec<NodeProcess> },
+    Error,
+}
+
+/// A running node, found by the file name of it's binary.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NodeProcess {
+    pub pid: u32,
+    pub parent_pid: u32,
+    /// The parent's pid, if it's `casper-node-launcher`.
+    pub launcher_pid: Option<u32>,
+    pub binary: PathBuf,
+    /// The directory the binary is in, if it's named like a version, e.g. `1_5_2`.
+    pub version: Option<String>,
+    /// The arguments the binary was run with, after it's own path.
+    pub args: Vec<String>,
+    /// Only readable by the process's owner or root, so empty if the agent isn't allowed to.
+    pub listening: Vec<ListeningSocket>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl std::fmt::Display for Protocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Protocol::Tcp => f.write_str("tcp"),
+            Protocol::Udp => f.write_str("udp"),
+        }
+    }
+}
+
+/// A socket a process listens on, from `/proc/<pid>/net`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct ListeningSocket {
+    pub protocol: Protocol,
+    pub addr: SocketAddr,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, StructOpt)]
+pub struct FetchFileRequest {
+    pub host_src_path: PathBuf,
+    pub filename: PathBuf,
+    /// How the agent should compress the file: `none`, `zstd[:<level>]` or `auto[:<level>]`.
+    #[structopt(long, default_value = "zstd")]
+    pub compression: Compression,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum FetchFileResponse {
+    Success {
+        file: CompressedWireFile,
+        /// blake3 hash of the uncompressed file.
+        content_hash: [u8; 32],
+    },
+    Error,
+    /// The agent's path policy does not allow access to `path`.
+    Forbidden {
+        path: PathBuf,
+    },
+}
+
+/// The largest (uncompressed) chunk the agent will hold in memory, whether serving a
+/// [`FetchFileChunkRequest`] or receiving a [`PutFileChunkRequest`].
+pub const MAX_CHUNK_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Request a range of a remote file. Each chunk is compressed independently, so a chunk can be
+/// decompressed and written at `chunk_id * chunk_size` without needing any of the others.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FetchFileChunkRequest {
+    pub host_src_path: PathBuf,
+    pub chunk_id: u64,
+    pub chunk_size: u64,
+    /// How the agent should compress the chunk. With [`Compression::Auto`] this is decided for
+    /// each chunk.
+    pub compression: Compression,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum FetchFileChunkResponse {
+    Success {
+        /// Size and modification time of the file at the time the chunk was read, so that a
+        /// resumed fetch can tell if the file changed underneath it.
+        file_size: u64,
+        modified: SystemTime,
+        chunk: CompressedWireFileChunk,
+    },
+    Error,
+    /// The agent's path policy does not allow access to `path`.
+    Forbidden {
+        path: PathBuf,
+    },
+}
+
+/// Cannot be constructed directly from the commandline.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PutDirRequest {
+    /// Directory to unpack the archive into, created if needed.
+    pub target_dir: PathBuf,
+    /// A zstd compressed tar archive, see [`archive::pack_dir`].
+    pub archive: CompressedWireFile,
+    /// Replace `target_dir` and everything in it, including paths left out of the archive by
+    /// it's filter, rather than unpacking over it.
+    pub replace: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum PutDirResponse {
+    Success {
+        entries: u64,
+    },
+    Error,
+    /// The agent's path policy does not allow access to `path`.
+    Forbidden {
+        path: PathBuf,
+    },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, StructOpt)]
+pub struct FetchDirRequest {
+    pub host_src_dir: PathBuf,
+    #[structopt(flatten)]
+    pub filter: ArchiveFilter,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum FetchDirResponse {
+    /// What's in `dir` after filtering, parents before their contents. Files are fetched one at
+    /// a time with [`FetchFileChunkRequest`]s, so neither side holds the tree in memory.
+    Success {
+        dir: PathBuf,
+        entries: Vec<FileStat>,
+    },
+    Error,
+    /// The agent's path policy does not allow access to `path`.
+    Forbidden {
+        path: PathBuf,
+    },
+    /// More than [`MAX_LIST_DIR_ENTRIES`] entries matched, the tree has to be fetched in parts.
+    TooManyEntries,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum MessageError {
+    #[error("file path provided has no 'filename'.")]
+    NoFileName,
+    #[error("file {path} could not be opened {err:?}")]
+    OpenFile { path: PathBuf, err: std::io::Error },
+    #[error("file {path} could not be read {err:?}")]
+    ReadFile { path: PathBuf, err: std::io::Error },
+    #[error("error compressing data from {path} - {err:?}")]
+    Compress { path: PathBuf, err: std::io::Error },
+    #[error("no chunks provided")]
+    NoChunks,
+    #[error("wrong number of chunks provided, expected {expected}, got {actual}")]
+    WrongNumberOfChunks { expected: usize, actual: usize },
+    #[error("chunk {chunk_id} is out of range, file has {num_chunks} chunks")]
+    ChunkOutOfRange { chunk_id: u64, num_chunks: u64 },
+    #[error("chunk size {chunk_size} must be between 1 and {MAX_CHUNK_SIZE} bytes")]
+    InvalidChunkSize { chunk_size: u64 },
+    #[error("access to {path} is forbidden by the agent's path policy")]
+    Forbidden { path: PathBuf },
+    #[error("hash mismatch for {what}, expected {expected} got {actual}")]
+    HashMismatch {
+        what: String,
+        expected: String,
+        actual: String,
+    },
+    #[error("invalid glob {pattern} - {err:?}")]
+    InvalidGlob {
+        pattern: String,
+        err: glob::PatternError,
+    },
+    #[error("error archiving {path} - {err:?}")]
+    Archive { path: PathBuf, err: std::io::Error },
+    #[error("chunks were encoded with different codecs")]
+    MixedCodecs,
+    #[error("more than {MAX_LIST_DIR_ENTRIES} entries in {path}")]
+    TooManyEntries { path: PathBuf },
+}
+
+/// Cannot be constructed directly from the commandline.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PutFileRequest {
+    pub target_perms: u32,
+    pub target_path: PathBuf,
+    /// Optional uid to chown the file to once written.
+    pub target_owner: Option<u32>,
+    /// Optional gid to chown the file to once written.
+    pub target_group: Option<u32>,
+    pub file: CompressedWireFile,
+    /// blake3 hash of the uncompressed file, checked against what was written before it is moved
+    /// into place.
+    pub content_hash: [u8; 32],
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PutFileChunkRequest {
+    /// Uncompressed size of every chunk but the last, the chunk is written at
+    /// `chunk_id * chunk_size` in the target file.
+    pub chunk_size: u64,
+    /// blake3 hash of the whole uncompressed file, which identifies the transfer and is checked
+    /// once all chunks are written.
+    pub content_hash: [u8; 32],
+    pub target_perms: u32,
+    pub target_path: PathBuf,
+    pub target_owner: Option<u32>,
+    pub target_group: Option<u32>,
+    pub chunk: CompressedWireFileChunk,
+}
+
+impl PutFileChunkRequest {
+    pub fn new(
+        chunk_size: u64,
+        content_hash: [u8; 32],
+        target_perms: u32,
+        target_path: PathBuf,
+        target_owner: Option<u32>,
+        target_group: Option<u32>,
+        chunk: CompressedWireFileChunk,
+    ) -> Self {
+        Self {
+            chunk_size,
+            content_hash,
+            target_perms,
+            target_path,
+            target_owner,
+            target_group,
+            chunk,
+        }
+    }
+}
+
+/// Put a file chunk on the host running the agent.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum PutFileChunkResponse {
+    /// The final chunk was received, and the file hashing to `content_hash` was written.
+    Complete {
+        chunk_id: u64,
+        content_hash: [u8; 32],
+    },
+    Progress {
+        chunk_id: u64,
+        seen_chunks: u64,
+    },
+    Error {
+        chunk_id: u64,
+    },
+    /// The agent's path policy does not allow writing to `path`.
+    Forbidden {
+        chunk_id: u64,
+        path: PathBuf,
+    },
+    Duplicate {
+        chunk_id: u64,
+    },
+    /// Every chunk was received, but the assembled file could not be verified or written. The
+    /// transfer is dropped, so it must be uploaded again from the start.
+    Failed {
+        chunk_id: u64,
+        message: String,
+    },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HasBlobsRequest {
+    pub hashes: Vec<[u8; 32]>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum HasBlobsResponse {
+    /// The subset of the requested hashes that are present in the blob store.
+    Success { present: Vec<[u8; 32]> },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PutFileFromBlobRequest {
+    /// blake3 hash of the uncompressed file, as for [`PutFileRequest::content_hash`], which is
+    /// what blobs are keyed by.
+    pub content_hash: [u8; 32],
+    pub target_perms: u32,
+    pub target_path: PathBuf,
+    pub target_owner: Option<u32>,
+    pub target_group: Option<u32>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, StructOpt)]
+pub struct GcBlobsRequest {
+    /// Remove blobs not stored or used within this many seconds.
+    #[structopt(long)]
+    pub max_age_secs: Option<u64>,
+    /// Remove the least recently used blobs until the store is at most this many bytes.
+    #[structopt(long)]
+    pub max_total_bytes: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum GcBlobsResponse {
+    Success {
+        removed: u64,
+        remaining: u64,
+        remaining_bytes: u64,
+    },
+    Error,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransferStatusRequest {
+    pub content_hash: [u8; 32],
+    /// Transfers are per file and target, the same file may be in flight to several targets.
+    pub target_path: PathBuf,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum TransferStatusResponse {
+    /// The transfer has been started, and is waiting on `missing_chunks`.
+    /// Chunks are only accepted with the `chunk_size` and `codec` the transfer was started with.
+    InProgress {
+        num_chunks: u64,
+        chunk_size: u64,
+        codec: Codec,
+        received_chunks: Vec<u64>,
+        missing_chunks: Vec<u64>,
+    },
+    /// No transfer with the given hash is in flight.
+    NotFound,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TransferSummary {
+    pub content_hash: [u8; 32],
+    pub filename: String,
+    pub target_path: PathBuf,
+    pub num_chunks: u64,
+    pub received_chunks: u64,
+    /// Seconds since the last chunk was received.
+    pub idle_secs: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ListTransfersResponse {
+    Success { transfers: Vec<TransferSummary> },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CancelTransferRequest {
+    pub content_hash: [u8; 32],
+    pub target_path: PathBuf,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum CancelTransferResponse {
+    Cancelled,
+    NotFound,
+    Error,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, StructOpt)]
+pub struct StatRequest {
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum StatResponse {
+    Success { stat: FileStat },
+    NotFound,
+    Forbidden { path: PathBuf },
+    Error,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum FileKind {
+    File,
+    Dir,
+    Symlink { target: PathBuf },
+    Other,
+}
+
+/// Metadata of a file on the host running the agent.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FileStat {
+    pub path: PathBuf,
+    pub kind: FileKind,
+    pub size: u64,
+    /// Permission bits, as in `st_mode & 0o7777`.
+    pub mode: u32,
+    pub modified: SystemTime,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// The most entries a single [`ListDirResponse`] will contain, or a [`FetchDirResponse`] may.
+pub const MAX_LIST_DIR_ENTRIES: usize = 10_000;
+
+#[derive(Clone, Debug, Serialize, Deserialize, StructOpt)]
+pub struct ListDirRequest {
+    pub path: PathBuf,
+    /// List the contents of subdirectories too.
+    #[structopt(long, short)]
+    pub recursive: bool,
+    /// Only list entries whose path relative to `path` matches this glob, e.g. `'*/bin/*'`.
+    #[structopt(long)]
+    pub glob: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ListDirResponse {
+    Success {
+        entries: Vec<FileStat>,
+        /// More than [`MAX_LIST_DIR_ENTRIES`] entries matched, and the rest were left out.
+        truncated: bool,
+    },
+    NotFound,
+    Forbidden {
+        path: PathBuf,
+    },
+    Error,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, StructOpt)]
+pub struct ChecksumRequest {
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ChecksumResponse {
+    Success { size: u64, content_hash: [u8; 32] },
+    NotFound,
+    Forbidden { path: PathBuf },
+    Error,
+}
+
+/// Space on a mounted filesystem, as reported by `statvfs`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MountUsage {
+    pub device: String,
+    pub mount_point: PathBuf,
+    pub fs_type: String,
+    pub total_bytes: u64,
+    pub free_bytes: u64,
+    /// Free bytes available to unprivileged users.
+    pub available_bytes: u64,
+    pub total_inodes: u64,
+    pub free_inodes: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum DiskUsageResponse {
+    Success { mounts: Vec<MountUsage> },
+    Error,
+}
+
+/// Where in a file to start following it.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum FollowFrom {
+    Start,
+    End,
+    Offset(u64),
+    LastLines(u64),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FollowFileRequest {
+    pub path: PathBuf,
+    pub from: FollowFrom,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum FollowFileResponse {
+    Started { follow_id: u64 },
+    NotFound,
+    Forbidden { path: PathBuf },
+    Error,
+}
+
+/// The longest a [`PollFollowRequest`] will wait for new lines.
+pub const MAX_FOLLOW_WAIT_MILLIS: u64 = 5000;
+
+/// The most data a single [`PollFollowResponse`] will carry, the rest is left for the next poll.
+pub const MAX_FOLLOW_POLL_BYTES: u64 = 1024 * 1024;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PollFollowRequest {
+    pub follow_id: u64,
+    /// Wait up to this long (capped at [`MAX_FOLLOW_WAIT_MILLIS`]) for new lines before returning
+    /// an empty response.
+    pub wait_millis: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum PollFollowResponse {
+    Lines {
+        /// Complete lines, without their trailing newline.
+        lines: Vec<String>,
+        /// Offset of the next byte to be read in the current file.
+        offset: u64,
+        /// The file was rotated or truncated since the last poll, and is now read from the start.
+        /// Any lines left in the old file were returned first.
+        rotated: bool,
+    },
+    NotFound,
+    Error,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StopFollowRequest {
+    pub follow_id: u64,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-pub struct AgentUpdateRequest {
-    new_version: u32,
-    dist_tarball: CompressedWireFile,
+pub enum StopFollowResponse {
+    Stopped,
+    NotFound,
 }
 
-#[derive(Debug, Serialize, Deserialize, StructOpt)]
-pub enum AgentUpdateResponse {
-    Success {
-        old_version: u32,
-        new_version: u32,
-        new_pid: u16,
+#[derive(Clone, Debug, Serialize, Deserialize, StructOpt)]
+pub struct ExecRequest {
+    /// The executable, which must be on the agent's allowlist, by path or by it's file name.
+    pub program: PathBuf,
+    /// Arguments, after `--` if any start with `-`.
+    pub args: Vec<String>,
+    /// Environment variables, as `KEY=VALUE`, of those the agent allows, such as `RUST_LOG` or
+    /// `TZ`. Commands don't get the agent's environment, only a `PATH` and `LANG` of their own.
+    #[structopt(long = "env", parse(try_from_str = parse_env_var))]
+    pub env: Vec<(String, String)>,
+    /// Working directory, by default the agent's.
+    #[structopt(long)]
+    pub cwd: Option<PathBuf>,
+    /// Kill the command, and anything it spawned, after this long.
+    #[structopt(long, default_value = "60")]
+    pub timeout_secs: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ExecResponse {
+    Started {
+        exec_id: u64,
+    },
+    /// The program isn't on the agent's allowlist, or an environment variable isn't allowed.
+    Forbidden {
+        message: String,
+    },
+    /// The command couldn't be run, e.g. the working directory doesn't exist.
+    Failed {
+        message: String,
     },
+    Error,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize, StructOpt)]
-pub struct StartServiceRequest {
-    // TODO something like a wrapper over systemd, casper-updater, and extended to support other things like heaptrack, valgrind, etc
-    pub wrapper: Option<String>,
+/// The longest a [`PollExecRequest`] will wait for output.
+pub const MAX_EXEC_WAIT_MILLIS: u64 = 5000;
+
+/// The most output a single [`PollExecResponse`] will carry, the rest is left for the next poll.
+pub const MAX_EXEC_POLL_BYTES: u64 = 1024 * 1024;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PollExecRequest {
+    pub exec_id: u64,
+    /// Wait up to this long (capped at [`MAX_EXEC_WAIT_MILLIS`]) for output before returning an
+    /// empty response.
+    pub wait_millis: u64,
 }
 
-#[derive(Debug, Serialize, Deserialize, StructOpt)]
-pub enum StartServiceResponse {
-    Success,
-    Restarted,
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+/// Output of a command, in the order it was read.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OutputChunk {
+    pub stream: OutputStream,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum PollExecResponse {
+    Output {
+        chunks: Vec<OutputChunk>,
+        /// How the command exited, once all of it's output has been returned. The exec is then
+        /// gone.
+        exit: Option<ExitReason>,
+        /// The command was killed for running past it's timeout.
+        timed_out: bool,
+    },
+    NotFound,
     Error,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, StructOpt)]
-pub struct FetchFileRequest {
-    pub host_src_path: PathBuf,
-    pub filename: PathBuf,
+pub struct RemoveRequest {
+    pub path: PathBuf,
+    /// Remove a directory and everything in it. Symlinks are removed, not followed.
+    #[structopt(long, short)]
+    pub recursive: bool,
+    /// Only list what would be removed.
+    #[structopt(long)]
+    pub dry_run: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-pub enum FetchFileResponse {
-    Success { file: CompressedWireFile },
+pub enum RemoveResponse {
+    Success {
+        /// What was (or for a dry run, would be) removed, contents before the directory containing
+        /// them. At most [`MAX_LIST_DIR_ENTRIES`] paths are listed.
+        removed: Vec<PathBuf>,
+        /// The number of paths removed, which may be more than are listed.
+        count: u64,
+    },
+    NotFound,
+    /// `path` is a directory that isn't empty, and `recursive` wasn't set.
+    NotEmpty,
+    Forbidden {
+        path: PathBuf,
+    },
     Error,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, StructOpt)]
-pub struct StopServiceRequest {
-    service: String,
+pub struct RenameRequest {
+    pub from: PathBuf,
+    pub to: PathBuf,
+    /// Replace `to` if it exists. A directory can only replace an empty directory.
+    #[structopt(long)]
+    pub overwrite: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize, StructOpt)]
-pub enum StopServiceResponse {
+#[derive(Debug, Serialize, Deserialize)]
+pub enum RenameResponse {
     Success,
-    Restarted,
+    NotFound,
+    /// `to` exists, and `overwrite` wasn't set.
+    AlreadyExists,
+    Forbidden {
+        path: PathBuf,
+    },
     Error,
 }
 
-#[derive(thiserror::Error, Debug)]
-pub enum MessageError {
-    #[error("file path provided has no 'filename'.")]
-    NoFileName,
-    #[error("file {path} could not be opened {err:?}")]
-    OpenFile { path: PathBuf, err: std::io::Error },
-    #[error("file {path} could not be read {err:?}")]
-    ReadFile { path: PathBuf, err: std::io::Error },
-    #[error("error compressing data from {path} - {err:?}")]
-    Compress { path: PathBuf, err: std::io::Error },
-    #[error("no chunks provided")]
-    NoChunks,
-    #[error("wrong number of chunks provided, expected {expected}, got {actual}")]
-    WrongNumberOfChunks { expected: usize, actual: usize },
+#[derive(Clone, Debug, Serialize, Deserialize, StructOpt)]
+pub struct MkdirRequest {
+    pub path: PathBuf,
+    /// Permission bits of the directories created, in octal.
+    #[structopt(long, default_value = "755", parse(try_from_str = parse_mode))]
+    pub mode: u32,
 }
 
-/// Cannot be constructed directly from the commandline.
 #[derive(Debug, Serialize, Deserialize)]
-pub struct PutFileRequest {
-    pub target_perms: u32,
-    pub target_path: PathBuf,
-    pub file: CompressedWireFile,
+pub enum MkdirResponse {
+    /// The directory exists, `created` are the directories that didn't already, parents first.
+    Success {
+        created: Vec<PathBuf>,
+    },
+    Forbidden {
+        path: PathBuf,
+    },
+    Error,
 }
 
-impl PutFileRequest {
-    /// Loads a file at the given src_path, compresses it's contents using zstd and creates a message containing the compressed data.
-    pub fn new_with_default_perms(
-        src_path: &Path,
-        target_path: &Path,
-    ) -> Result<Self, MessageError> {
-        Ok(Self {
-            target_perms: 0o666,
-            target_path: target_path.to_path_buf(),
-            file: CompressedWireFile::load_and_compress(src_path, target_path)?,
-        })
-    }
-
-    /// Loads a file at the given src_path, compresses it's contents using zstd and creates a message containing the compressed data.
-    pub fn into_chunked_requests(
-        &self,
-        chunk_size: usize,
-    ) -> impl Iterator<Item = PutFileChunkRequest> + '_ {
-        let target_perms = self.target_perms;
-        let target_path = &self.target_path;
-        let file_hash = self.file.blake3_hash();
-        self.file
-            .into_chunks_with_size(chunk_size)
-            .map(move |chunk| PutFileChunkRequest {
-                file_hash,
-                target_perms,
-                target_path: target_path.clone(),
-                chunk,
-            })
-    }
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HasDictionaryRequest {
+    /// blake3 hash of the dictionary, see [`Dictionary::hash`].
+    pub dict_hash: [u8; 32],
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct PutFileChunkRequest {
-    pub file_hash: [u8; 32],
-    pub target_perms: u32,
-    pub target_path: PathBuf,
-    pub chunk: CompressedWireFileChunk,
+#[derive(Debug, Serialize, Deserialize)]
+pub enum HasDictionaryResponse {
+    Present,
+    Missing,
 }
 
-impl PutFileChunkRequest {
-    pub fn new(
-        file_hash: [u8; 32],
-        target_perms: u32,
-        target_path: PathBuf,
-        chunk: CompressedWireFileChunk,
-    ) -> Self {
-        Self {
-            file_hash,
-            target_perms,
-            target_path,
-            chunk,
-        }
-    }
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PutDictionaryRequest {
+    pub dictionary: Vec<u8>,
 }
 
-/// Put a file chunk on the host running the agent.
-#[derive(Debug, Serialize, Deserialize, StructOpt)]
-pub enum PutFileChunkResponse {
-    Complete { chunk_id: u64 },
-    Progress { chunk_id: u64, seen_chunks: u64 },
-    Error { chunk_id: u64 },
-    Duplicate { chunk_id: u64 },
+#[derive(Debug, Serialize, Deserialize)]
+pub enum PutDictionaryResponse {
+    Success { dict_hash: [u8; 32] },
+    Error,
 }
 
-#[derive(Debug, Serialize, Deserialize, StructOpt)]
+#[derive(Debug, Serialize, Deserialize)]
 pub enum PutFileResponse {
-    Success,
+    /// The file was written, `content_hash` is the blake3 hash of what is on disk.
+    Success {
+        content_hash: [u8; 32],
+    },
     Error,
+    /// The agent's path policy does not allow access to `path`.
+    Forbidden {
+        path: PathBuf,
+    },
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -206,7 +1377,217 @@ pub struct CompressedWireFileChunk {
     pub filename: String,
     pub chunk_id: u64,
     pub num_chunks: u64,
+    /// How `zstd_compressed_data_chunk` is encoded, which despite the name may be uncompressed.
+    pub codec: Codec,
     pub zstd_compressed_data_chunk: Vec<u8>,
+    /// blake3 hash of `zstd_compressed_data_chunk`, set by the sender.
+    pub chunk_hash: [u8; 32],
+}
+
+impl CompressedWireFileChunk {
+    /// Generate a blake3 hash of the compressed chunk data.
+    pub fn blake3_hash(&self) -> [u8; 32] {
+        blake3::hash(&self.zstd_compressed_data_chunk).into()
+    }
+
+    /// Check the chunk data against the hash it was sent with.
+    pub fn verify(&self) -> Result<(), MessageError> {
+        check_hash(
+            &format!("chunk {}", self.chunk_id),
+            &self.chunk_hash,
+            &self.blake3_hash(),
+        )
+    }
+
+    /// Read the `chunk_id`th range of `chunk_size` bytes from `src_path`, and encode it as a
+    /// standalone frame. Only a single chunk is ever held in memory. With [`Compression::Auto`]
+    /// whether to compress is decided by the chunk itself.
+    pub fn load_and_compress_range(
+        src_path: &Path,
+        file_size: u64,
+        chunk_id: u64,
+        chunk_size: u64,
+        encoding: &Encoding,
+    ) -> Result<Self, MessageError> {
+        let filename = file_name_from_path(src_path)?;
+        let mut file = File::open(src_path).map_err(|err| MessageError::OpenFile {
+            path: src_path.to_path_buf(),
+            err,
+        })?;
+        let (data, num_chunks) = read_range(&mut file, src_path, file_size, chunk_id, chunk_size)?;
+        let codec = encoding
+            .codec_for(&data)
+            .map_err(|err| MessageError::Compress {
+                path: src_path.to_path_buf(),
+                err,
+            })?;
+        Self::encode(
+            src_path, filename, chunk_id, num_chunks, &data, encoding, codec,
+        )
+    }
+
+    fn encode(
+        src_path: &Path,
+        filename: String,
+        chunk_id: u64,
+        num_chunks: u64,
+        data: &[u8],
+        encoding: &Encoding,
+        codec: Codec,
+    ) -> Result<Self, MessageError> {
+        let zstd_compressed_data_chunk =
+            encoding
+                .encode(codec, data)
+                .map_err(|err| MessageError::Compress {
+                    path: src_path.to_path_buf(),
+                    err,
+                })?;
+        Ok(Self {
+            filename,
+            chunk_id,
+            num_chunks,
+            codec,
+            chunk_hash: blake3::hash(&zstd_compressed_data_chunk).into(),
+            zstd_compressed_data_chunk,
+        })
+    }
+}
+
+/// Read the `chunk_id`th range of `chunk_size` bytes from `file`, returning it and the number of
+/// chunks in the file.
+fn read_range(
+    file: &mut File,
+    src_path: &Path,
+    file_size: u64,
+    chunk_id: u64,
+    chunk_size: u64,
+) -> Result<(Vec<u8>, u64), MessageError> {
+    let num_chunks = num_chunks(file_size, chunk_size);
+    if chunk_id >= num_chunks {
+        return Err(MessageError::ChunkOutOfRange {
+            chunk_id,
+            num_chunks,
+        });
+    }
+    let read_err = |err| MessageError::ReadFile {
+        path: src_path.to_path_buf(),
+        err,
+    };
+    file.seek(SeekFrom::Start(chunk_id * chunk_size))
+        .map_err(read_err)?;
+    let mut data = Vec::new();
+    file.take(chunk_size)
+        .read_to_end(&mut data)
+        .map_err(read_err)?;
+    Ok((data, num_chunks))
+}
+
+/// The number of `chunk_size` chunks needed to cover a file, an empty file is a single chunk.
+fn num_chunks(file_size: u64, chunk_size: u64) -> u64 {
+    std::cmp::max(1, file_size.div_ceil(chunk_size))
+}
+
+/// Streams a file from disk as compressed chunks, for uploads of files too large to hold in
+/// memory.
+///
+/// Each chunk is an independently encoded `chunk_size` range of the file, so memory use is
+/// bounded by the chunk size and the receiver can decode and write any chunk at
+/// `chunk_id * chunk_size` as it arrives. Every chunk is encoded with the same codec, chosen when
+/// the file is opened, so the chunks concatenate into a valid [`CompressedWireFile`] of the whole
+/// file.
+pub struct CompressedChunkReader {
+    src_path: PathBuf,
+    filename: String,
+    file: File,
+    file_size: u64,
+    chunk_size: u64,
+    encoding: Encoding,
+    codec: Codec,
+}
+
+impl CompressedChunkReader {
+    /// Open `src_path` to be sent as `target_path` in chunks of `chunk_size` bytes. With
+    /// [`Compression::Auto`] whether to compress is decided by the start of the file.
+    pub fn open(
+        src_path: &Path,
+        target_path: &Path,
+        chunk_size: u64,
+        encoding: Encoding,
+    ) -> Result<Self, MessageError> {
+        if chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE {
+            return Err(MessageError::InvalidChunkSize { chunk_size });
+        }
+        let open_err = |err| MessageError::OpenFile {
+            path: src_path.to_path_buf(),
+            err,
+        };
+        let mut file = File::open(src_path).map_err(open_err)?;
+        let file_size = file.metadata().map_err(open_err)?.len();
+        let mut sample = Vec::new();
+        (&mut file)
+            .take(codec::AUTO_SAMPLE_SIZE as u64)
+            .read_to_end(&mut sample)
+            .map_err(|err| MessageError::ReadFile {
+                path: src_path.to_path_buf(),
+                err,
+            })?;
+        let codec = encoding
+            .codec_for(&sample)
+            .map_err(|err| MessageError::Compress {
+                path: src_path.to_path_buf(),
+                err,
+            })?;
+        Ok(Self {
+            src_path: src_path.to_path_buf(),
+            filename: file_name_from_path(target_path)?,
+            file,
+            file_size,
+            chunk_size,
+            encoding,
+            codec,
+        })
+    }
+
+    pub fn chunk_size(&self) -> u64 {
+        self.chunk_size
+    }
+
+    /// The codec every chunk is encoded with.
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    pub fn num_chunks(&self) -> u64 {
+        num_chunks(self.file_size, self.chunk_size)
+    }
+
+    /// Read and encode a single chunk.
+    pub fn read_chunk(&mut self, chunk_id: u64) -> Result<CompressedWireFileChunk, MessageError> {
+        let (data, num_chunks) = read_range(
+            &mut self.file,
+            &self.src_path,
+            self.file_size,
+            chunk_id,
+            self.chunk_size,
+        )?;
+        CompressedWireFileChunk::encode(
+            &self.src_path,
+            self.filename.clone(),
+            chunk_id,
+            num_chunks,
+            &data,
+            &self.encoding,
+            self.codec,
+        )
+    }
+
+    /// blake3 hash of the uncompressed file.
+    pub fn content_hash(&self) -> Result<[u8; 32], MessageError> {
+        blake3_hash_file(&self.src_path).map_err(|err| MessageError::ReadFile {
+            path: self.src_path.clone(),
+            err,
+        })
+    }
 }
 
 impl std::fmt::Debug for CompressedWireFileChunk {
@@ -215,6 +1596,7 @@ impl std::fmt::Debug for CompressedWireFileChunk {
             .field("filename", &self.filename)
             .field("chunk_id", &self.chunk_id)
             .field("num_chunks", &self.num_chunks)
+            .field("codec", &self.codec)
             .field(
                 "zstd_compressed_data_chunk",
                 &self.zstd_compressed_data_chunk.len(),
@@ -226,6 +1608,8 @@ impl std::fmt::Debug for CompressedWireFileChunk {
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct CompressedWireFile {
     pub filename: String,
+    /// How `zstd_compressed_data` is encoded, which despite the name may be uncompressed.
+    pub codec: Codec,
     pub zstd_compressed_data: Vec<u8>,
 }
 
@@ -237,6 +1621,20 @@ impl CompressedWireFile {
         hasher.finalize().into()
     }
 
+    /// blake3 hash of the uncompressed file, decoding it as a stream. `dictionary` is needed for
+    /// [`Codec::ZstdDict`].
+    pub fn content_hash(
+        &self,
+        dictionary: Option<&Dictionary>,
+    ) -> Result<[u8; 32], std::io::Error> {
+        let mut hasher = blake3::Hasher::new();
+        let mut decoder = self
+            .codec
+            .decoder(Cursor::new(&self.zstd_compressed_data), dictionary)?;
+        std::io::copy(&mut decoder, &mut hasher)?;
+        Ok(hasher.finalize().into())
+    }
+
     /// Build a file from a list of chunks.
     pub fn from_chunks(mut chunks: Vec<CompressedWireFileChunk>) -> Result<Self, MessageError> {
         let mut zstd_compressed_data = Vec::new();
@@ -252,12 +1650,18 @@ impl CompressedWireFile {
             });
         }
 
+        let codec = chunks[0].codec;
         for chunk in chunks.iter() {
+            if chunk.codec != codec {
+                return Err(MessageError::MixedCodecs);
+            }
+            chunk.verify()?;
             zstd_compressed_data.extend_from_slice(&chunk.zstd_compressed_data_chunk);
         }
 
         Ok(Self {
             filename: chunks[0].filename.clone(),
+            codec,
             zstd_compressed_data,
         })
     }
@@ -268,8 +1672,9 @@ impl CompressedWireFile {
         chunk_size: usize,
     ) -> impl Iterator<Item = CompressedWireFileChunk> + '_ {
         let filename = &self.filename;
+        let codec = self.codec;
         let zstd_compressed_data = &self.zstd_compressed_data;
-        let num_chunks = (zstd_compressed_data.len() + chunk_size - 1) / chunk_size;
+        let num_chunks = zstd_compressed_data.len().div_ceil(chunk_size);
         let chunks = zstd_compressed_data.chunks(chunk_size);
 
         chunks
@@ -278,51 +1683,246 @@ impl CompressedWireFile {
                 filename: filename.clone(),
                 chunk_id: chunk_id as u64,
                 num_chunks: num_chunks as u64,
+                codec,
                 zstd_compressed_data_chunk: chunk.to_vec(),
+                chunk_hash: blake3::hash(chunk).into(),
             })
     }
 
-    /// Load a file and compress it in memory.
-    pub fn load_and_compress(src_path: &Path, target_path: &Path) -> Result<Self, MessageError> {
+    /// Load a file and encode it in memory. With [`Compression::Auto`] whether to compress is
+    /// decided by the start of the file.
+    pub fn load_and_compress(
+        src_path: &Path,
+        target_path: &Path,
+        encoding: &Encoding,
+    ) -> Result<Self, MessageError> {
         let file = File::open(src_path).map_err(|err| MessageError::OpenFile {
             path: src_path.to_path_buf(),
             err,
         })?;
         let filename = file_name_from_path(target_path)?;
-        let reader = BufReader::new(file);
-        let zstd_compressed_data =
-            zstd::encode_all(reader, 3).map_err(|err| MessageError::Compress {
-                path: src_path.to_path_buf(),
-                err,
-            })?;
+        let compress_err = |err| MessageError::Compress {
+            path: src_path.to_path_buf(),
+            err,
+        };
+        let mut reader = BufReader::with_capacity(codec::AUTO_SAMPLE_SIZE, file);
+        let codec = encoding
+            .codec_for(reader.fill_buf().map_err(compress_err)?)
+            .map_err(compress_err)?;
+        let zstd_compressed_data = encoding
+            .encode_reader(codec, reader)
+            .map_err(compress_err)?;
         Ok(CompressedWireFile {
             filename,
+            codec,
             zstd_compressed_data,
         })
     }
 
-    /// Decompresses and then writes a compressed file message to disk as the file it represents.
-    /// Assumes the directory it's writing into exists.
+    /// Decodes and then writes a compressed file message to disk as the file it represents.
+    /// Assumes the directory it's writing into exists, and that the file wasn't encoded with a
+    /// dictionary.
     pub fn into_file_on_disk(self, destination_path: &PathBuf) -> Result<(), std::io::Error> {
-        let mut data = Cursor::new(self.zstd_compressed_data);
+        let data = Cursor::new(self.zstd_compressed_data);
         let file = File::create(destination_path)?;
-        let mut decoder = zstd::Decoder::new(&mut data)?;
+        let mut decoder = self.codec.decoder(data, None)?;
         let mut writer = BufWriter::new(file);
         std::io::copy(&mut decoder, &mut writer)?;
         writer.flush()?;
         Ok(())
     }
 
-    /// On the agent side, deserialized but needs to be put to disk.
-    pub fn into_temp_file_on_disk(self) -> Result<PathBuf, std::io::Error> {
-        let target_temp_path = PathBuf::from("./temp");
-        fs::create_dir_all(&target_temp_path)?;
-        let target_file = target_temp_path.join(&self.filename);
-        self.into_file_on_disk(&target_file)?;
-        Ok(target_temp_path)
+    /// Decodes the file and commits it to `target_path` atomically, with [`write_file_atomic`].
+    /// `dictionary` is needed for [`Codec::ZstdDict`].
+    pub fn write_to_disk_atomic(
+        &self,
+        target_path: &Path,
+        perms: u32,
+        owner: Option<u32>,
+        group: Option<u32>,
+        content_hash: &[u8; 32],
+        dictionary: Option<&Dictionary>,
+    ) -> Result<(), std::io::Error> {
+        let decoder = self
+            .codec
+            .decoder(self.zstd_compressed_data.as_slice(), dictionary)?;
+        write_file_atomic(decoder, target_path, perms, owner, group, content_hash)
+    }
+}
+
+/// Write `contents` to `target_path` atomically.
+///
+/// The contents are written to a staging file alongside the target (see [`staging_path_for`]),
+/// read back and checked against `content_hash`, then committed with [`commit_staged_file`].
+/// Missing parent directories are created.
+pub fn write_file_atomic(
+    mut contents: impl Read,
+    target_path: &Path,
+    perms: u32,
+    owner: Option<u32>,
+    group: Option<u32>,
+    content_hash: &[u8; 32],
+) -> Result<(), std::io::Error> {
+    let staging_path = staging_path_for(target_path)?;
+    let result = create_staging_file(&staging_path)
+        .and_then(|file| {
+            let mut writer = BufWriter::new(file);
+            std::io::copy(&mut contents, &mut writer)?;
+            writer.flush()
+        })
+        .and_then(|_| verify_file_hash(&staging_path, content_hash))
+        .and_then(|_| commit_staged_file(&staging_path, target_path, perms, owner, group));
+    if result.is_err() {
+        let _ = fs::remove_file(&staging_path);
+    }
+    result
+}
+
+/// A unique, hidden path alongside `target_path` to stage writes to it, so the final rename
+/// cannot cross filesystems. Missing parent directories are created.
+pub fn staging_path_for(target_path: &Path) -> Result<PathBuf, std::io::Error> {
+    let filename = file_name_from_path(target_path)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+    let dir = parent_dir(target_path);
+    fs::create_dir_all(&dir)?;
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or_default();
+    // the counter keeps concurrent writers in this process apart, the pid other processes.
+    Ok(dir.join(format!(
+        ".{filename}.agent-tmp.{}.{}.{nanos}",
+        std::process::id(),
+        NEXT_STAGING_ID.fetch_add(1, Ordering::Relaxed)
+    )))
+}
+
+static NEXT_STAGING_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Write `data` to a fresh staging file alongside `path` (see [`staging_path_for`]), fsync it and
+/// rename it over `path`, so readers only ever see the old or the complete new contents.
+pub fn write_then_rename(path: &Path, data: &[u8]) -> Result<(), std::io::Error> {
+    let staging_path = staging_path_for(path)?;
+    let result = create_staging_file(&staging_path)
+        .and_then(|mut file| {
+            file.write_all(data)?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&staging_path, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&staging_path);
+    }
+    result
+}
+
+/// Create a staging file from [`staging_path_for`], failing rather than sharing it if it exists.
+pub fn create_staging_file(staging_path: &Path) -> Result<File, std::io::Error> {
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(staging_path)
+}
+
+/// Commit a fully written staging file to `target_path`: fsync it, apply `perms` and optionally
+/// chown it, then rename it into place and fsync the directory so the rename is durable.
+pub fn commit_staged_file(
+    staging_path: &Path,
+    target_path: &Path,
+    perms: u32,
+    owner: Option<u32>,
+    group: Option<u32>,
+) -> Result<(), std::io::Error> {
+    let file = File::open(staging_path)?;
+    file.sync_all()?;
+    file.set_permissions(fs::Permissions::from_mode(perms))?;
+    if owner.is_some() || group.is_some() {
+        std::os::unix::fs::chown(staging_path, owner, group)?;
+    }
+    fs::rename(staging_path, target_path)?;
+    File::open(parent_dir(target_path))?.sync_all()
+}
+
+fn parent_dir(path: &Path) -> PathBuf {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+/// Generate a blake3 hash of the file at `path`, streaming it's contents through the hasher.
+pub fn blake3_hash_file(path: &Path) -> Result<[u8; 32], std::io::Error> {
+    let mut hasher = blake3::Hasher::new();
+    std::io::copy(&mut BufReader::new(File::open(path)?), &mut hasher)?;
+    Ok(hasher.finalize().into())
+}
+
+/// Check the file at `path` hashes to `expected`, as an [`std::io::ErrorKind::InvalidData`] error.
+pub fn verify_file_hash(path: &Path, expected: &[u8; 32]) -> Result<(), std::io::Error> {
+    check_hash(
+        &path.display().to_string(),
+        expected,
+        &blake3_hash_file(path)?,
+    )
+    .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))
+}
+
+/// Compare two blake3 hashes, describing a mismatch as being for `what`.
+pub fn check_hash(what: &str, expected: &[u8; 32], actual: &[u8; 32]) -> Result<(), MessageError> {
+    if expected != actual {
+        return Err(MessageError::HashMismatch {
+            what: what.to_string(),
+            expected: hash_to_hex(expected),
+            actual: hash_to_hex(actual),
+        });
+    }
+    Ok(())
+}
+
+/// Hex encode a blake3 hash, e.g. for display or use as a file name.
+pub fn hash_to_hex(hash: &[u8; 32]) -> String {
+    hash.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Parse a hex encoded blake3 hash, as produced by [`hash_to_hex`].
+pub fn hash_from_hex(hex: &str) -> Option<[u8; 32]> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    let mut hash = [0u8; 32];
+    for (i, byte) in hash.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(hash)
+}
+
+/// Parse octal permission bits, e.g. `755` or `0o755`.
+pub fn parse_mode(s: &str) -> Result<u32, std::num::ParseIntError> {
+    u32::from_str_radix(s.trim_start_matches("0o"), 8)
+}
+
+/// Parse an environment variable given as `KEY=VALUE`.
+pub fn parse_env_var(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("expected KEY=VALUE, not {s:?}")),
     }
 }
 
+/// Parse a size in bytes, optionally with a binary `K`, `M` or `G` suffix, e.g. `512M`.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let (number, multiplier) = match s.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&s[..i], 1 << 10),
+        Some((i, 'M' | 'm')) => (&s[..i], 1 << 20),
+        Some((i, 'G' | 'g')) => (&s[..i], 1 << 30),
+        _ => (s, 1),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .ok_or_else(|| format!("invalid size {s:?}"))
+}
+
 pub fn file_name_from_path(target_path: &Path) -> Result<String, MessageError> {
     let filename = target_path
         .file_name()
@@ -335,6 +1935,7 @@ pub fn file_name_from_path(target_path: &Path) -> Result<String, MessageError> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_util::TempDir;
 
     #[test]
     fn test_chunking_and_reassembling() {
@@ -343,6 +1944,7 @@ mod tests {
         let zstd_compressed_data = vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
         let compressed_wire_file = CompressedWireFile {
             filename: filename.clone(),
+            codec: Codec::Zstd,
             zstd_compressed_data: zstd_compressed_data.clone(),
         };
 
@@ -381,4 +1983,149 @@ mod tests {
             zstd_compressed_data
         );
     }
+
+    #[test]
+    fn test_write_to_disk_atomic_creates_parents_and_applies_perms() {
+        let temp = TempDir::new("agent-lib-atomic");
+        let dir = temp.path().to_path_buf();
+        let target_path = dir.join("nested/dirs/test.txt");
+        let contents = b"some file contents".to_vec();
+        let file = CompressedWireFile {
+            filename: "test.txt".to_string(),
+            codec: Codec::Zstd,
+            zstd_compressed_data: zstd::encode_all(Cursor::new(&contents), 3).unwrap(),
+        };
+
+        let content_hash = blake3::hash(&contents).into();
+        assert_eq!(file.content_hash(None).unwrap(), content_hash);
+
+        // a file that does not match it's content hash is never moved into place.
+        assert!(file
+            .write_to_disk_atomic(&target_path, 0o640, None, None, &[0; 32], None)
+            .is_err());
+        assert!(!target_path.exists());
+
+        file.write_to_disk_atomic(&target_path, 0o640, None, None, &content_hash, None)
+            .unwrap();
+
+        assert_eq!(fs::read(&target_path).unwrap(), contents);
+        let mode = fs::metadata(&target_path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o640);
+        // no temp files should be left behind next to the target.
+        let entries = fs::read_dir(target_path.parent().unwrap()).unwrap().count();
+        assert_eq!(entries, 1);
+    }
+
+    #[test]
+    fn test_hash_hex_roundtrip() {
+        let hash = CompressedWireFile {
+            filename: "test.txt".to_string(),
+            codec: Codec::Zstd,
+            zstd_compressed_data: vec![1, 2, 3],
+        }
+        .blake3_hash();
+        let hex = hash_to_hex(&hash);
+        assert_eq!(hex.len(), 64);
+        assert_eq!(hash_from_hex(&hex), Some(hash));
+        assert_eq!(hash_from_hex("not a hash"), None);
+    }
+
+    #[test]
+    fn test_parse_signal() {
+        assert_eq!("SIGSTOP".parse(), Ok(Signal::STOP));
+        assert_eq!("cont".parse(), Ok(Signal::CONT));
+        assert_eq!("9".parse(), Ok(Signal::KILL));
+        assert!("SIGNOPE".parse::<Signal>().is_err());
+        assert_eq!(Signal(10).to_string(), "SIGUSR1");
+        assert_eq!(ExitReason::Signal(11).to_string(), "signal 11 (SIGSEGV)");
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("512M"), Ok(512 * 1024 * 1024));
+        assert_eq!(parse_size("2g"), Ok(2 * 1024 * 1024 * 1024));
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("1.5G").is_err());
+        assert!(parse_size("99999999999G").is_err());
+    }
+
+    #[test]
+    fn test_range_chunks_decompress_to_original() {
+        let temp = TempDir::new("agent-lib-range");
+        let path = temp.path().join("file");
+        let contents = (0..10_000u32)
+            .flat_map(|i| i.to_le_bytes())
+            .collect::<Vec<u8>>();
+        fs::write(&path, &contents).unwrap();
+
+        let chunk_size = 4096;
+        let file_size = contents.len() as u64;
+        let first = CompressedWireFileChunk::load_and_compress_range(
+            &path,
+            file_size,
+            0,
+            chunk_size,
+            &Encoding::default(),
+        )
+        .unwrap();
+        let mut reassembled = Vec::new();
+        for chunk_id in 0..first.num_chunks {
+            let chunk = CompressedWireFileChunk::load_and_compress_range(
+                &path,
+                file_size,
+                chunk_id,
+                chunk_size,
+                &Encoding::default(),
+            )
+            .unwrap();
+            reassembled
+                .extend(zstd::decode_all(Cursor::new(chunk.zstd_compressed_data_chunk)).unwrap());
+        }
+        assert_eq!(reassembled, contents);
+        assert!(CompressedWireFileChunk::load_and_compress_range(
+            &path,
+            file_size,
+            first.num_chunks,
+            chunk_size,
+            &Encoding::default()
+        )
+        .is_err());
+        assert_eq!(
+            blake3_hash_file(&path).unwrap(),
+            <[u8; 32]>::from(blake3::hash(&contents))
+        );
+    }
+
+    #[test]
+    fn test_chunk_reader_reassembles_into_compressed_file() {
+        let temp = TempDir::new("agent-lib-reader");
+        let path = temp.path().join("file");
+        let contents = (0..10_000u32)
+            .flat_map(|i| i.to_le_bytes())
+            .collect::<Vec<u8>>();
+        fs::write(&path, &contents).unwrap();
+
+        let mut reader = CompressedChunkReader::open(
+            &path,
+            Path::new("/remote/file"),
+            4096,
+            Encoding::default(),
+        )
+        .unwrap();
+        assert_eq!(reader.num_chunks(), 10);
+        let chunks = (0..reader.num_chunks())
+            .map(|chunk_id| reader.read_chunk(chunk_id).unwrap())
+            .collect::<Vec<_>>();
+        assert!(chunks.iter().all(|chunk| chunk.filename == "file"));
+        let mut corrupt = chunks.clone();
+        corrupt[3].zstd_compressed_data_chunk[0] ^= 1;
+        assert!(CompressedWireFile::from_chunks(corrupt).is_err());
+        let file = CompressedWireFile::from_chunks(chunks).unwrap();
+        assert_eq!(
+            zstd::decode_all(Cursor::new(file.zstd_compressed_data)).unwrap(),
+            contents
+        );
+        assert!(CompressedChunkReader::open(&path, &path, 0, Encoding::default()).is_err());
+    }
 }
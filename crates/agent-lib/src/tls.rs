@@ -127,10 +127,24 @@ where
     Codec: Serializer<SinkItem> + Deserializer<Item>,
     CodecFn: Fn() -> Codec,
 {
-    println!("serving tls connections on {addr}");
-    let acceptor = TlsAcceptor::from(Arc::new(config));
-    let listener = TcpListener::bind(addr).await?;
+    listen_on(TcpListener::bind(addr).await?, config, codec_fn)
+}
+
+/// Like [`listen`], on a socket that is already listening, e.g. one handed over by another
+/// process.
+pub fn listen_on<Item, SinkItem, Codec, CodecFn>(
+    listener: TcpListener,
+    config: ServerConfig,
+    codec_fn: CodecFn,
+) -> io::Result<TlsIncoming<Item, SinkItem, Codec, CodecFn>>
+where
+    Item: for<'de> Deserialize<'de>,
+    Codec: Serializer<SinkItem> + Deserializer<Item>,
+    CodecFn: Fn() -> Codec,
+{
     let local_addr = listener.local_addr()?;
+    println!("serving tls connections on {local_addr}");
+    let acceptor = TlsAcceptor::from(Arc::new(config));
     Ok(TlsIncoming {
         acceptor,
         accept: None,
@@ -220,6 +234,26 @@ pub async fn serve<I, SinkItem, Codec, CodecFn>(
     key_file: PathBuf,
     codec_fn: CodecFn,
 ) -> Result<TlsIncoming<I, SinkItem, Codec, CodecFn>, anyhow::Error>
+where
+    I: for<'de> Deserialize<'de>,
+    Codec: Serializer<SinkItem> + Deserializer<I>,
+    CodecFn: Fn() -> Codec,
+{
+    serve_on(
+        TcpListener::bind(addr).await?,
+        cert_file,
+        key_file,
+        codec_fn,
+    )
+}
+
+/// Like [`serve`], on a socket that is already listening.
+pub fn serve_on<I, SinkItem, Codec, CodecFn>(
+    listener: TcpListener,
+    cert_file: PathBuf,
+    key_file: PathBuf,
+    codec_fn: CodecFn,
+) -> Result<TlsIncoming<I, SinkItem, Codec, CodecFn>, anyhow::Error>
 where
     I: for<'de> Deserialize<'de>,
     Codec: Serializer<SinkItem> + Deserializer<I>,
@@ -233,7 +267,7 @@ where
         .with_no_client_auth()
         .with_single_cert(vec![cert], key)?;
 
-    let mut listener = listen::<I, SinkItem, Codec, CodecFn>(&addr, config, codec_fn).await?;
+    let mut listener = listen_on::<I, SinkItem, Codec, CodecFn>(listener, config, codec_fn)?;
 
     listener
         .config_mut()
@@ -270,6 +304,16 @@ pub async fn connect(
         .await?)
 }
 
+/// Frames a client connection with the same frame limit as [`serve`], so that the client can send
+/// and receive messages as large as the daemon does, e.g. a whole directory archive.
+pub fn client_framed(
+    stream: client::TlsStream<TcpStream>,
+) -> Framed<client::TlsStream<TcpStream>, LengthDelimitedCodec> {
+    LengthDelimitedCodec::builder()
+        .max_frame_length(u32::MAX as usize)
+        .new_framed(stream)
+}
+
 fn load_key(key_file: &Path) -> Result<rustls::PrivateKey, anyhow::Error> {
     let mut reader = BufReader::new(File::open(key_file)?);
     Ok(rustls::PrivateKey(
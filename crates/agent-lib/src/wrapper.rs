@@ -0,0 +1,218 @@
+//! Debugging and profiling tools a service can be run under, see
+//! [`crate::StartServiceRequest::wrapper`].
+
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
+
+/// A tool to run a service under. What the tool writes is kept per run, and fetched with
+/// [`crate::AgentService::fetch_debug_output`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, StructOpt)]
+pub enum Wrapper {
+    /// Run under gdb, writing a backtrace of every thread to `gdb.log` when it crashes or is
+    /// stopped.
+    Gdb {
+        /// More gdb commands to run after the backtrace: `bt`, `info registers`, `info threads`,
+        /// `info frame`, `info sharedlibrary` or `info signals`.
+        #[structopt(long = "ex")]
+        commands: Vec<GdbCommand>,
+    },
+    /// Run under valgrind, writing `valgrind.<pid>.log`.
+    Valgrind {
+        /// `memcheck`, `massif`, `helgrind` or `callgrind`.
+        #[structopt(long, default_value = "memcheck")]
+        tool: ValgrindTool,
+        /// Describe each leak in detail, for memcheck.
+        #[structopt(long)]
+        leak_check: bool,
+    },
+    /// Sample stacks with `perf record`, writing `perf.data`.
+    Perf {
+        /// Samples per second.
+        #[structopt(long, default_value = "99")]
+        frequency: u32,
+        /// Record call graphs, unwinding with `fp`, `dwarf` or `lbr`.
+        #[structopt(long)]
+        call_graph: Option<CallGraph>,
+    },
+    /// Record heap allocations with heaptrack, writing a `heaptrack` recording.
+    Heaptrack,
+    /// Trace system calls with strace, writing `strace.log`.
+    Strace {
+        /// Only trace these system calls, as in `strace -e trace=`, e.g. `network` or
+        /// `openat,read`.
+        #[structopt(long)]
+        trace: Option<String>,
+        /// Log the time of each call and how long it took.
+        #[structopt(long)]
+        timestamps: bool,
+    },
+}
+
+/// The names of the tools, which are also the programs run.
+pub const WRAPPER_TOOLS: [&str; 5] = ["gdb", "valgrind", "perf", "heaptrack", "strace"];
+
+impl Wrapper {
+    /// The name of the tool.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Wrapper::Gdb { .. } => "gdb",
+            Wrapper::Valgrind { .. } => "valgrind",
+            Wrapper::Perf { .. } => "perf",
+            Wrapper::Heaptrack => "heaptrack",
+            Wrapper::Strace { .. } => "strace",
+        }
+    }
+}
+
+/// The gdb commands a service's crash can be inspected with, besides the backtrace of every
+/// thread. Only these read-only commands are accepted, as gdb can also run shell commands and
+/// write files.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GdbCommand {
+    Bt,
+    InfoRegisters,
+    InfoThreads,
+    InfoFrame,
+    InfoSharedlibrary,
+    InfoSignals,
+}
+
+impl GdbCommand {
+    const ALL: [GdbCommand; 6] = [
+        GdbCommand::Bt,
+        GdbCommand::InfoRegisters,
+        GdbCommand::InfoThreads,
+        GdbCommand::InfoFrame,
+        GdbCommand::InfoSharedlibrary,
+        GdbCommand::InfoSignals,
+    ];
+
+    /// The command as gdb takes it.
+    pub fn command(&self) -> &'static str {
+        match self {
+            GdbCommand::Bt => "bt",
+            GdbCommand::InfoRegisters => "info registers",
+            GdbCommand::InfoThreads => "info threads",
+            GdbCommand::InfoFrame => "info frame",
+            GdbCommand::InfoSharedlibrary => "info sharedlibrary",
+            GdbCommand::InfoSignals => "info signals",
+        }
+    }
+}
+
+impl FromStr for GdbCommand {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.split_whitespace().collect::<Vec<_>>().join(" ");
+        GdbCommand::ALL
+            .into_iter()
+            .find(|command| command.command() == s)
+            .ok_or_else(|| {
+                let commands = GdbCommand::ALL.map(|command| command.command());
+                format!(
+                    "unsupported gdb command {s}, expected one of {}",
+                    commands.join(", ")
+                )
+            })
+    }
+}
+
+impl fmt::Display for GdbCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.command())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ValgrindTool {
+    Memcheck,
+    Massif,
+    Helgrind,
+    Callgrind,
+}
+
+impl FromStr for ValgrindTool {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "memcheck" => Ok(ValgrindTool::Memcheck),
+            "massif" => Ok(ValgrindTool::Massif),
+            "helgrind" => Ok(ValgrindTool::Helgrind),
+            "callgrind" => Ok(ValgrindTool::Callgrind),
+            _ => Err(format!(
+                "unknown valgrind tool {s}, expected memcheck, massif, helgrind or callgrind"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ValgrindTool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValgrindTool::Memcheck => write!(f, "memcheck"),
+            ValgrindTool::Massif => write!(f, "massif"),
+            ValgrindTool::Helgrind => write!(f, "helgrind"),
+            ValgrindTool::Callgrind => write!(f, "callgrind"),
+        }
+    }
+}
+
+/// How `perf record` unwinds stacks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CallGraph {
+    /// Frame pointers, cheap but needs a binary built with them.
+    Fp,
+    /// Copies of the stack unwound with debug info, large but works with any binary.
+    Dwarf,
+    /// Last branch records, on recent Intel CPUs.
+    Lbr,
+}
+
+impl FromStr for CallGraph {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fp" => Ok(CallGraph::Fp),
+            "dwarf" => Ok(CallGraph::Dwarf),
+            "lbr" => Ok(CallGraph::Lbr),
+            _ => Err(format!("unknown call graph {s}, expected fp, dwarf or lbr")),
+        }
+    }
+}
+
+impl fmt::Display for CallGraph {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CallGraph::Fp => write!(f, "fp"),
+            CallGraph::Dwarf => write!(f, "dwarf"),
+            CallGraph::Lbr => write!(f, "lbr"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_read_only_gdb_commands_parse() {
+        assert_eq!(
+            "info  registers".parse::<GdbCommand>(),
+            Ok(GdbCommand::InfoRegisters)
+        );
+        assert_eq!("bt".parse::<GdbCommand>(), Ok(GdbCommand::Bt));
+        for command in [
+            "shell id",
+            "dump memory /tmp/m 0 1",
+            "info registers; shell id",
+            "",
+        ] {
+            assert!(command.parse::<GdbCommand>().is_err(), "{command}");
+        }
+    }
+}
@@ -61,7 +61,10 @@ fn create_dist_tarball(
         .run()?;
     }
 
-    cmd!("cargo", "build", "--release").run()?;
+    // the daemon reports it's version, which an update checks against the one asked for.
+    cmd!("cargo", "build", "--release")
+        .env("AGENT_VERSION", version.to_string())
+        .run()?;
     cmd!("mkdir", "-p", "target/dist/assets").run()?;
     // copy artifacts to dist dir.
     cmd!("cp", "target/release/daemon", "target/dist").run()?;